@@ -5,11 +5,8 @@ cargo build
 
 cargo run
 
-# Assemble the assembly
-as -o bin/out.o bin/out.s
-
-# Use linker to create executable
-ld -o bin/out bin/out.o
+# Assemble and link against the C runtime, which provides _start and calls main
+gcc -o bin/out bin/out.s
 
 # Run the program
 ./bin/out
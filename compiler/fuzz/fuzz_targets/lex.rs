@@ -0,0 +1,10 @@
+#![no_main]
+// The lexer must turn any text into tokens, with Error tokens for what it does not know,
+// instead of panicking
+use libfuzzer_sys::fuzz_target;
+
+fuzz_target!(|data: &[u8]| {
+    if let Ok(source) = std::str::from_utf8(data) {
+        compiler::lex(source);
+    }
+});
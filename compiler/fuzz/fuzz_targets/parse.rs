@@ -0,0 +1,10 @@
+#![no_main]
+// The parser must give a program or report what is wrong with it for any tokens the lexer
+// makes, instead of panicking
+use libfuzzer_sys::fuzz_target;
+
+fuzz_target!(|data: &[u8]| {
+    if let Ok(source) = std::str::from_utf8(data) {
+        let _ = compiler::parse(compiler::lex(source));
+    }
+});
@@ -0,0 +1,9 @@
+#!/bin/bash
+
+# Start the corpus of both fuzz targets from the C files of the tests
+cd "$(dirname "$0")"
+
+for target in lex parse; do
+    mkdir -p corpus/$target
+    cp ../tests/lexer_tests/*.c ../tests/parser_tests/*.c corpus/$target/
+done
@@ -0,0 +1,48 @@
+// The assembly the backend generates, one line at a time, so passes like the peephole
+// optimizer can inspect and rewrite instructions before they are printed
+
+#[derive(Debug, PartialEq, Clone)]
+pub enum Line {
+    Label(String),
+    Instruction(Instruction),
+    Directive(String), // Section switches, symbol visibility and data, printed as is
+}
+
+// An opcode and its operands as they are written, e.g ldr with w9 and [x29, -8]
+#[derive(Debug, PartialEq, Clone)]
+pub struct Instruction {
+    pub opcode: String,
+    pub operands: Vec<String>,
+}
+
+impl Instruction {
+    pub fn new(opcode: &str, operands: &[&str]) -> Self {
+        Instruction {
+            opcode: opcode.to_string(),
+            operands: operands.iter().map(|operand| operand.to_string()).collect(),
+        }
+    }
+}
+
+// Appends an instruction to the code being generated
+pub fn emit(code: &mut Vec<Line>, opcode: &str, operands: &[&str]) {
+    code.push(Line::Instruction(Instruction::new(opcode, operands)));
+}
+
+pub fn label(code: &mut Vec<Line>, label: i32) {
+    code.push(Line::Label(format!(".L{}", label)));
+}
+
+pub fn directive(code: &mut Vec<Line>, directive: &str) {
+    code.push(Line::Directive(directive.to_string()));
+}
+
+// Prints the lines as an assembly file
+pub fn render(lines: &[Line]) -> String {
+    let mut assembly = "".to_string();
+    for line in lines {
+        assembly += &format!("{}\n", line);
+    }
+
+    assembly
+}
@@ -0,0 +1,370 @@
+use std::collections::HashSet;
+
+use crate::{
+    asm::{directive, render, Line},
+    gen,
+    ir::{BlockId, IrFunction, IrGlobal, IrProgram, IrType, VReg},
+    regalloc::{allocate, Location},
+    riscv,
+    types::align_to,
+    wasm, x86,
+};
+
+// What a target machine implements to turn the IR into assembly. The lexer, the parser and
+// the passes over the IR are shared, only the last step differs between the targets
+
+pub trait Backend {
+    fn generate(&self, program: &IrProgram) -> Vec<Line>;
+
+    // Cleans up the generated code, nothing by default
+    fn optimize(&self, code: Vec<Line>) -> Vec<Line> {
+        code
+    }
+}
+
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum Target {
+    Aarch64,
+    X86_64Linux,
+    Riscv64Linux,
+    Wasm32,
+}
+
+impl Target {
+    // The names --target takes
+    pub const NAMES: [&'static str; 4] = ["aarch64", "x86_64-linux", "riscv64-linux", "wasm32"];
+
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "aarch64" => Some(Target::Aarch64),
+            "x86_64-linux" => Some(Target::X86_64Linux),
+            "riscv64-linux" => Some(Target::Riscv64Linux),
+            "wasm32" => Some(Target::Wasm32),
+            _ => None,
+        }
+    }
+
+    pub fn backend(&self) -> Box<dyn Backend> {
+        match self {
+            Target::Aarch64 => Box::new(gen::Aarch64),
+            Target::X86_64Linux => Box::new(x86::X86_64),
+            Target::Riscv64Linux => Box::new(riscv::Riscv64),
+            Target::Wasm32 => Box::new(wasm::Wasm32),
+        }
+    }
+}
+
+// Where everything of the current function lives. Offsets are below the frame pointer, where
+// the saved callee-saved registers come first, then the slots and then the spilled registers
+#[derive(Default)]
+pub struct Frame {
+    pub slot_offsets: Vec<usize>,
+    pub spill_offsets: Vec<usize>,
+    pub saved_registers: Vec<(usize, usize)>, // Register numbers given to the allocator
+    pub size: usize,
+    pub locations: Vec<Option<Location>>, // Indexed by VReg
+    pub vregs: Vec<IrType>,               // Indexed by VReg
+    pub first_block_label: i32,           // Block n gets label first_block_label + n
+    pub epilogue_label: i32,
+}
+
+impl Frame {
+    // Assigns registers and lays out the frame: saved registers, slots aligned for their
+    // type, then 8 bytes per spilled register, rounded up to keep the stack 16 byte aligned.
+    // The labels of the blocks and the epilogue start at first_block_label
+    pub fn new(
+        function: &IrFunction,
+        register_count: usize,
+        first_callee_saved: usize,
+        first_block_label: i32,
+    ) -> Self {
+        let allocation = allocate(function, register_count, first_callee_saved);
+        let mut frame_size = 0;
+
+        let mut callee_saved: Vec<usize> = allocation
+            .used_registers
+            .iter()
+            .copied()
+            .filter(|register| *register >= first_callee_saved)
+            .collect();
+        callee_saved.sort();
+
+        let mut saved_registers = vec![];
+        for register in callee_saved {
+            frame_size += 8;
+            saved_registers.push((register, frame_size));
+        }
+
+        let mut slot_offsets = vec![];
+        for slot in &function.slots {
+            frame_size = align_to(frame_size + slot.size, slot.align);
+            slot_offsets.push(frame_size);
+        }
+
+        let mut spill_offsets = vec![];
+        frame_size = align_to(frame_size, 8);
+        for _ in 0..allocation.spill_slots {
+            frame_size += 8;
+            spill_offsets.push(frame_size);
+        }
+
+        Frame {
+            slot_offsets,
+            spill_offsets,
+            saved_registers,
+            size: align_to(frame_size, 16),
+            locations: allocation.locations,
+            vregs: function.vregs.clone(),
+            first_block_label,
+            epilogue_label: first_block_label + function.blocks.len() as i32,
+        }
+    }
+
+    // How many labels new needs reserved for the function
+    pub fn label_count(function: &IrFunction) -> i32 {
+        function.blocks.len() as i32 + 1
+    }
+}
+
+// The registers a backend hands to the allocator, the caller-saved ones first since they cost
+// no save and restore, and how it reaches the spill slots below its frame pointer
+pub struct Registers {
+    pub names: &'static [&'static str],
+    pub first_callee_saved: usize,
+    pub scratch: [&'static str; 2], // Spilled operands are loaded into these, results into the first
+    pub load: fn(&mut Vec<Line>, &str, usize), // Loads all 64 bits of a register from an offset
+    pub store: fn(&mut Vec<Line>, &str, usize),
+}
+
+// State of one compilation: label numbering, the sections collected on the side and the
+// frame of the function being generated. Each generate call starts from a fresh context,
+// so compilations do not share labels and give the same output every time
+pub struct CodegenContext {
+    last_label: i32,              // Labels follow the scheme .L1, .L2 etc
+    pub frame: Frame,             // Layout of the function currently being generated
+    pub rodata: Vec<Line>,        // Jump tables, emitted in .rodata after the functions
+    pub defined: HashSet<String>, // Functions of the program, the others are linked in
+    registers: &'static Registers,
+}
+
+impl CodegenContext {
+    pub fn new(program: &IrProgram, registers: &'static Registers) -> Self {
+        CodegenContext {
+            last_label: 0,
+            frame: Frame::default(),
+            rodata: vec![],
+            defined: program
+                .functions
+                .iter()
+                .map(|function| function.name.clone())
+                .collect(),
+            registers,
+        }
+    }
+
+    // Reserves count consecutive labels and returns the first of them
+    pub fn reserve_labels(&mut self, count: i32) -> i32 {
+        let free_label = self.last_label + 1;
+        self.last_label += count; // Vil være sist brukte label
+        free_label
+    }
+
+    // Assigns the registers of the function and the labels of its blocks
+    pub fn enter_function(&mut self, function: &IrFunction) {
+        let first_block_label = self.reserve_labels(Frame::label_count(function));
+        self.frame = Frame::new(
+            function,
+            self.registers.names.len(),
+            self.registers.first_callee_saved,
+            first_block_label,
+        );
+    }
+
+    pub fn block_label(&self, block: BlockId) -> String {
+        format!(".L{}", self.frame.first_block_label + block.0 as i32)
+    }
+
+    pub fn ty(&self, vreg: VReg) -> IrType {
+        self.frame.vregs[vreg.0]
+    }
+
+    // The register holding vreg, loading it into a scratch register first when it was spilled
+    pub fn read(&self, vreg: VReg, scratch: usize, code: &mut Vec<Line>) -> &'static str {
+        match self.frame.locations[vreg.0] {
+            Some(Location::Register(register)) => self.registers.names[register],
+            Some(Location::Spill(slot)) => {
+                let scratch_register = self.registers.scratch[scratch];
+                (self.registers.load)(code, scratch_register, self.frame.spill_offsets[slot]);
+                scratch_register
+            }
+            None => panic!("{:?} is read but never allocated", vreg),
+        }
+    }
+
+    // The register to compute vreg in, see write_back
+    pub fn target(&self, vreg: VReg) -> &'static str {
+        match self.frame.locations[vreg.0] {
+            Some(Location::Register(register)) => self.registers.names[register],
+            _ => self.registers.scratch[0],
+        }
+    }
+
+    // Stores a result computed in the scratch register to the spill slot of vreg
+    pub fn write_back(&self, vreg: VReg, code: &mut Vec<Line>) {
+        if let Some(Location::Spill(slot)) = self.frame.locations[vreg.0] {
+            let scratch_register = self.registers.scratch[0];
+            (self.registers.store)(code, scratch_register, self.frame.spill_offsets[slot]);
+        }
+    }
+
+    // The label every entry of a jump table from jump_table_range jumps to, the default for
+    // values without a case
+    pub fn jump_table_targets(
+        &self,
+        cases: &[(i64, BlockId)],
+        (min, range): (i64, i64),
+        default: BlockId,
+    ) -> Vec<String> {
+        (min..min + range)
+            .map(|value| {
+                let block = cases
+                    .iter()
+                    .find(|case| case.0 == value)
+                    .map_or(default, |case| case.1);
+                self.block_label(block)
+            })
+            .collect()
+    }
+
+    // The jump tables collected while generating the functions, in .rodata after them
+    pub fn emit_rodata(&mut self, code: &mut Vec<Line>) {
+        let jump_tables = std::mem::take(&mut self.rodata);
+        if !jump_tables.is_empty() {
+            directive(code, ".section .rodata");
+            code.extend(jump_tables);
+        }
+    }
+}
+
+// A jump table pays off once there are a few cases and they cover most of their range
+const JUMP_TABLE_MIN_CASES: usize = 4;
+const JUMP_TABLE_MAX_RANGE: i64 = 1024;
+
+// The lowest case value and the number of entries when the cases of a switch are dense enough
+// for a jump table, None when comparing them one at a time is better
+pub fn jump_table_range(cases: &[(i64, BlockId)]) -> Option<(i64, i64)> {
+    let min = cases.iter().map(|case| case.0).min()?;
+    let max = cases.iter().map(|case| case.0).max()?;
+    let range = max - min + 1;
+    let dense = cases.len() >= JUMP_TABLE_MIN_CASES
+        && range <= JUMP_TABLE_MAX_RANGE
+        && range <= 3 * cases.len() as i64;
+    dense.then_some((min, range))
+}
+
+// The assembler symbol of a global on the ELF targets. File scope variables keep their C name
+// so they link with gcc compiled code, static locals become local labels like .Lf.n.3 that
+// stay out of the symbol table
+pub fn elf_symbol(name: &str) -> String {
+    if name.contains('.') {
+        format!(".L{}", name)
+    } else {
+        name.to_string()
+    }
+}
+
+// Read-only data goes in .rodata, all zero data only reserves space in .bss. Addresses of
+// other symbols are written with the pointer directive of the target, .xword or .quad. Read-only
+// data with addresses in it goes in .data.rel.ro like gcc puts it, where the loader can still
+// relocate it in a position independent executable
+pub fn global_asm(code: &mut Vec<Line>, global: &IrGlobal, pointer_directive: &str) {
+    let section = if global.is_const && !global.image.relocations.is_empty() {
+        ".section .data.rel.ro"
+    } else if global.is_const {
+        ".section .rodata"
+    } else if global.image.is_zero() {
+        ".bss"
+    } else {
+        ".data"
+    };
+
+    directive(code, section);
+    if !global.is_static {
+        directive(code, &format!(".global {}", global.symbol));
+    }
+    directive(code, &format!(".balign {}", global.align));
+    code.push(Line::Label(elf_symbol(&global.symbol)));
+
+    if section == ".bss" {
+        directive(code, &format!(".zero {}", global.size.max(1)));
+        return;
+    }
+
+    // Bytes between the relocations, runs of zeros are collapsed
+    let mut offset = 0;
+    let mut relocations = global.image.relocations.clone();
+    relocations.sort_by_key(|relocation| relocation.offset);
+    for relocation in relocations {
+        data_bytes_asm(code, &global.image.bytes[offset..relocation.offset]);
+        directive(
+            code,
+            &format!(
+                "{} {}{:+}",
+                pointer_directive,
+                elf_symbol(&relocation.symbol),
+                relocation.addend
+            ),
+        );
+        offset = relocation.offset + 8;
+    }
+    data_bytes_asm(code, &global.image.bytes[offset..]);
+}
+
+// .byte for the values, .zero for stretches of zeros
+fn data_bytes_asm(code: &mut Vec<Line>, bytes: &[u8]) {
+    let mut index = 0;
+    while index < bytes.len() {
+        let run = bytes[index..]
+            .iter()
+            .take_while(|byte| (**byte == 0) == (bytes[index] == 0))
+            .count();
+
+        if bytes[index] == 0 {
+            directive(code, &format!(".zero {}", run));
+        } else {
+            let values: Vec<String> = bytes[index..index + run]
+                .iter()
+                .map(|byte| byte.to_string())
+                .collect();
+            directive(code, &format!(".byte {}", values.join(", ")));
+        }
+        index += run;
+    }
+}
+
+pub struct Generator {
+    root: IrProgram,
+    target: Target,
+    peephole: bool, // Off to see the code exactly as it was generated
+}
+
+impl Generator {
+    pub fn new(root_node: IrProgram, target: Target, peephole: bool) -> Self {
+        Generator {
+            root: root_node,
+            target,
+            peephole,
+        }
+    }
+
+    pub fn walk_da_tree(&self) -> String {
+        let backend = self.target.backend();
+
+        let mut assembly = backend.generate(&self.root);
+        if self.peephole {
+            assembly = backend.optimize(assembly);
+        }
+
+        render(&assembly)
+    }
+}
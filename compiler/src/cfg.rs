@@ -0,0 +1,176 @@
+use crate::ir::{BlockId, IrFunction};
+
+// The control flow graph questions the SSA passes ask: predecessors, a visiting order and
+// dominators. Blocks no path from the entry block reaches have no dominator and are left out
+// of the order and the dominator tree
+
+// The distinct blocks jumping to every block
+pub fn predecessors(function: &IrFunction) -> Vec<Vec<BlockId>> {
+    let mut predecessors = vec![vec![]; function.blocks.len()];
+    for (index, block) in function.blocks.iter().enumerate() {
+        for successor in block.terminator.successors() {
+            if !predecessors[successor.0].contains(&BlockId(index)) {
+                predecessors[successor.0].push(BlockId(index));
+            }
+        }
+    }
+    predecessors
+}
+
+// The distinct blocks a block can jump to, in the order the terminator names them
+pub fn successors(function: &IrFunction, block: BlockId) -> Vec<BlockId> {
+    let mut successors = vec![];
+    for successor in function.blocks[block.0].terminator.successors() {
+        if !successors.contains(&successor) {
+            successors.push(successor);
+        }
+    }
+    successors
+}
+
+// Every reachable block after all of its predecessors, except for those jumping back to it
+pub fn reverse_postorder(function: &IrFunction) -> Vec<BlockId> {
+    let mut visited = vec![false; function.blocks.len()];
+    let mut postorder = vec![];
+    // Blocks with the index of the next successor to visit
+    let mut stack = vec![(BlockId(0), 0)];
+    visited[0] = true;
+
+    while let Some((block, next)) = stack.pop() {
+        let successors = successors(function, block);
+        match successors.get(next) {
+            Some(successor) => {
+                stack.push((block, next + 1));
+                if !visited[successor.0] {
+                    visited[successor.0] = true;
+                    stack.push((*successor, 0));
+                }
+            }
+            None => postorder.push(block),
+        }
+    }
+
+    postorder.reverse();
+    postorder
+}
+
+pub struct Dominators {
+    idom: Vec<Option<BlockId>>, // The entry block is its own immediate dominator
+    children: Vec<Vec<BlockId>>,
+}
+
+impl Dominators {
+    // The iterative algorithm of Cooper, Harvey and Kennedy, walking up from both
+    // predecessors until the paths meet
+    pub fn new(function: &IrFunction) -> Self {
+        let order = reverse_postorder(function);
+        let predecessors = predecessors(function);
+        let mut position = vec![usize::MAX; function.blocks.len()];
+        for (index, block) in order.iter().enumerate() {
+            position[block.0] = index;
+        }
+
+        let mut idom: Vec<Option<BlockId>> = vec![None; function.blocks.len()];
+        idom[0] = Some(BlockId(0));
+        let mut changed = true;
+        while changed {
+            changed = false;
+            for block in order.iter().skip(1) {
+                let mut new_idom: Option<BlockId> = None;
+                for predecessor in &predecessors[block.0] {
+                    if idom[predecessor.0].is_none() {
+                        continue;
+                    }
+                    new_idom = Some(match new_idom {
+                        None => *predecessor,
+                        Some(other) => intersect(&idom, &position, *predecessor, other),
+                    });
+                }
+                if new_idom.is_some() && idom[block.0] != new_idom {
+                    idom[block.0] = new_idom;
+                    changed = true;
+                }
+            }
+        }
+
+        let mut children = vec![vec![]; function.blocks.len()];
+        for block in order.iter().skip(1) {
+            let parent = idom[block.0].expect("Reachable blocks have a dominator");
+            children[parent.0].push(*block);
+        }
+
+        Dominators { idom, children }
+    }
+
+    pub fn idom(&self, block: BlockId) -> Option<BlockId> {
+        match self.idom[block.0] {
+            Some(idom) if idom != block => Some(idom),
+            _ => None,
+        }
+    }
+
+    // The blocks block immediately dominates
+    pub fn children(&self, block: BlockId) -> &[BlockId] {
+        &self.children[block.0]
+    }
+
+    pub fn is_reachable(&self, block: BlockId) -> bool {
+        self.idom[block.0].is_some()
+    }
+
+    // Whether every path from the entry block to b passes through a
+    pub fn dominates(&self, a: BlockId, b: BlockId) -> bool {
+        let mut block = b;
+        loop {
+            if block == a {
+                return true;
+            }
+            match self.idom(block) {
+                Some(idom) => block = idom,
+                None => return false,
+            }
+        }
+    }
+
+    // The blocks where the dominance of every block ends, where its definitions meet others
+    pub fn frontiers(&self, function: &IrFunction) -> Vec<Vec<BlockId>> {
+        let mut frontiers = vec![vec![]; function.blocks.len()];
+        for (index, predecessors) in predecessors(function).iter().enumerate() {
+            let block = BlockId(index);
+            if predecessors.len() < 2 || !self.is_reachable(block) {
+                continue;
+            }
+            let idom = self.idom[index];
+            for predecessor in predecessors {
+                let mut runner = *predecessor;
+                while self.is_reachable(runner) && Some(runner) != idom {
+                    if !frontiers[runner.0].contains(&block) {
+                        frontiers[runner.0].push(block);
+                    }
+                    match self.idom(runner) {
+                        Some(parent) => runner = parent,
+                        None => break,
+                    }
+                }
+            }
+        }
+        frontiers
+    }
+}
+
+fn intersect(
+    idom: &[Option<BlockId>],
+    position: &[usize],
+    mut a: BlockId,
+    mut b: BlockId,
+) -> BlockId {
+    while a != b {
+        while position[a.0] > position[b.0] {
+            a = idom[a.0].expect("Processed blocks have a dominator");
+        }
+        while position[b.0] > position[a.0] {
+            b = idom[b.0].expect("Processed blocks have a dominator");
+        }
+    }
+    a
+}
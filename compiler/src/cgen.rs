@@ -0,0 +1,528 @@
+use std::collections::HashSet;
+
+use crate::{
+    parser::{
+        Expr, ExprNode, FunctionNode, GlobalNode, Initializer, ProgramNode, Statement,
+        StatementNode, Storage, Variable,
+    },
+    token::TokenType,
+    types::{StructRef, Type},
+};
+
+// C source for --emit=c, printed from the syntax tree so gcc can compile the same program
+// and the exit codes can be compared with our own. Every operation is parenthesized and every
+// implicit conversion written out: chars promoted to int, arrays decayed to the address of
+// their first element and values converted to the type they are assigned or returned as.
+// Struct types and static locals are moved to file scope, with names made unique
+
+pub fn emit_program(program: &ProgramNode) -> String {
+    let mut emitter = CEmitter {
+        identifiers: identifiers(program),
+        ..Default::default()
+    };
+
+    let mut globals = "".to_string();
+    for global in &program.globals {
+        globals += &emitter.global_definition(global);
+    }
+
+    let mut functions = "".to_string();
+    let mut prototypes = "".to_string();
+    for function in &program.body {
+        prototypes += &format!("{};\n", emitter.function_header(function));
+        functions += "\n";
+        functions += &emitter.function_definition(function);
+    }
+
+    // Functions called without a definition, typed by the arguments of the first call
+    let mut declared: Vec<String> = vec![];
+    for (name, return_type, args) in emitter.calls.clone() {
+        if !program.function_names.contains(&name) && !declared.contains(&name) {
+            let params: Vec<String> = args
+                .iter()
+                .map(|arg| emitter.declaration(arg, "".to_string()))
+                .collect();
+            let declarator = format!("{}({})", name, parameter_list(params));
+            prototypes += &format!("{};\n", emitter.declaration(&return_type, declarator));
+            declared.push(name);
+        }
+    }
+
+    let mut out = "".to_string();
+    let definitions = emitter.struct_definitions();
+    for (_, name) in &emitter.structs {
+        out += &format!("{};\n", name);
+    }
+    if !definitions.is_empty() {
+        out += &format!("\n{}", definitions);
+    }
+    if !globals.is_empty() {
+        out += &format!("\n{}", globals);
+    }
+    out += &format!("\n{}", prototypes);
+    out += &functions;
+
+    out
+}
+
+fn parameter_list(params: Vec<String>) -> String {
+    if params.is_empty() {
+        "void".to_string()
+    } else {
+        params.join(", ")
+    }
+}
+
+// Every ordinary identifier of the program, which the names of hoisted static locals must not
+// take: globals, functions, parameters and locals. Functions called without a definition are
+// found by printing the functions once
+fn identifiers(program: &ProgramNode) -> HashSet<String> {
+    let mut scout = CEmitter::default();
+    for function in &program.body {
+        scout.function_definition(function);
+    }
+
+    let mut identifiers: HashSet<String> =
+        scout.calls.into_iter().map(|(name, _, _)| name).collect();
+    for global in &program.globals {
+        identifiers.insert(global.variable.name.clone());
+    }
+    for function in &program.body {
+        identifiers.insert(function.name.clone());
+        for variable in function.params.iter().chain(&function.locals) {
+            identifiers.insert(variable.name.clone());
+        }
+    }
+    identifiers
+}
+
+fn number(value: i32) -> String {
+    if value == i32::MIN {
+        "(-2147483647 - 1)".to_string()
+    } else if value < 0 {
+        format!("({})", value)
+    } else {
+        value.to_string()
+    }
+}
+
+fn indentation(depth: usize) -> String {
+    "    ".repeat(depth)
+}
+
+#[derive(Default)]
+struct CEmitter {
+    structs: Vec<(StructRef, String)>, // Every struct type met so far, with its unique C name
+    calls: Vec<(String, Type, Vec<Type>)>, // Callees with their return and argument types
+    statics: Vec<(String, String)>,    // Static locals by symbol, with their unique C name
+    identifiers: HashSet<String>,      // Names the program declares itself
+    return_type: Option<Type>,         // What the function being printed returns
+}
+
+impl CEmitter {
+    fn variable_name(&mut self, variable: &Variable) -> String {
+        match &variable.storage {
+            Storage::Global(symbol) if symbol.contains('.') => self.static_name(symbol),
+            _ => variable.name.clone(),
+        }
+    }
+
+    // f_x for a static x in f, a number is appended when the program already has the name
+    fn static_name(&mut self, symbol: &str) -> String {
+        if let Some((_, name)) = self.statics.iter().find(|(other, _)| other == symbol) {
+            return name.clone();
+        }
+
+        let (function_and_name, _) = symbol.rsplit_once('.').expect("Static without a number");
+        let base = function_and_name.replace('.', "_");
+        let taken = |name: &str| {
+            self.identifiers.contains(name) || self.statics.iter().any(|(_, other)| other == name)
+        };
+        let name = if !taken(&base) {
+            base
+        } else {
+            let mut number = self.statics.len();
+            while taken(&format!("{}_{}", base, number)) {
+                number += 1;
+            }
+            format!("{}_{}", base, number)
+        };
+
+        self.statics.push((symbol.to_string(), name.clone()));
+        name
+    }
+
+    // struct tag or union tag, a number is appended to tags declared more than once and
+    // to anonymous structs
+    fn struct_name(&mut self, struct_ref: &StructRef) -> String {
+        if let Some((_, name)) = self.structs.iter().find(|(other, _)| other == struct_ref) {
+            return name.clone();
+        }
+
+        let struct_type = struct_ref.borrow();
+        let keyword = if struct_type.is_union {
+            "union"
+        } else {
+            "struct"
+        };
+        let tag = struct_type.tag.clone().unwrap_or("anon".to_string());
+        let taken = |name: &str| {
+            self.structs
+                .iter()
+                .any(|(_, other)| other.split(' ').nth(1) == Some(name))
+        };
+        let name = if struct_type.tag.is_some() && !taken(&tag) {
+            format!("{} {}", keyword, tag)
+        } else {
+            let mut number = self.structs.len();
+            while taken(&format!("{}_{}", tag, number)) {
+                number += 1;
+            }
+            format!("{} {}_{}", keyword, tag, number)
+        };
+
+        self.structs.push((struct_ref.clone(), name.clone()));
+        name
+    }
+
+    // The type wrapped around a declarator, int *p[3] or int (*p)[3]. An empty declarator
+    // gives the type name used in casts and prototypes
+    fn declaration(&mut self, ty: &Type, declarator: String) -> String {
+        let specifier = match ty {
+            Type::Int => "int".to_string(),
+            Type::Char => "signed char".to_string(),
+            Type::Void => "void".to_string(),
+            Type::Struct(struct_ref) => self.struct_name(struct_ref),
+            Type::Pointer(base) => {
+                let declarator = if base.is_array() {
+                    format!("(*{})", declarator)
+                } else {
+                    format!("*{}", declarator)
+                };
+                return self.declaration(base, declarator);
+            }
+            Type::Array(element, length) => {
+                return self.declaration(element, format!("{}[{}]", declarator, length))
+            }
+        };
+
+        if declarator.is_empty() {
+            specifier
+        } else {
+            format!("{} {}", specifier, declarator)
+        }
+    }
+
+    // const belongs to the object itself, after the * of a pointer or before the element type
+    fn variable_declaration(&mut self, variable: &Variable) -> String {
+        let name = self.variable_name(variable);
+        if !variable.is_const {
+            return self.declaration(&variable.ty, name);
+        }
+
+        let mut innermost = &variable.ty;
+        while let Type::Array(element, _) = innermost {
+            innermost = element;
+        }
+        match innermost {
+            Type::Pointer(_) => self.declaration(&variable.ty, format!("const {}", name)),
+            _ => format!("const {}", self.declaration(&variable.ty, name)),
+        }
+    }
+
+    // Definitions of the structs met while printing the program. One holding another by value
+    // comes after it, pointers only need the forward declarations
+    fn struct_definitions(&mut self) -> String {
+        let mut out = "".to_string();
+        let mut defined = vec![];
+        let mut index = 0;
+        while index < self.structs.len() {
+            let struct_ref = self.structs[index].0.clone();
+            self.define_struct(&struct_ref, &mut defined, &mut out);
+            index += 1;
+        }
+        out
+    }
+
+    fn define_struct(
+        &mut self,
+        struct_ref: &StructRef,
+        defined: &mut Vec<StructRef>,
+        out: &mut String,
+    ) {
+        if defined.contains(struct_ref) || !struct_ref.borrow().complete {
+            return;
+        }
+        defined.push(struct_ref.clone());
+
+        let members = struct_ref.borrow().members.clone();
+        for member in &members {
+            let mut ty = &member.ty;
+            while let Type::Array(element, _) = ty {
+                ty = element;
+            }
+            if let Type::Struct(inner) = ty {
+                self.define_struct(inner, defined, out);
+            }
+        }
+
+        let name = self.struct_name(struct_ref);
+        *out += &format!("{} {{\n", name);
+        for member in &members {
+            *out += &format!(
+                "{}{};\n",
+                indentation(1),
+                self.declaration(&member.ty, member.name.clone())
+            );
+        }
+        *out += "};\n";
+    }
+
+    fn global_definition(&mut self, global: &GlobalNode) -> String {
+        let storage = if global.is_static { "static " } else { "" };
+        let mut out = format!("{}{}", storage, self.variable_declaration(&global.variable));
+        if let Some(initializer) = &global.initializer {
+            out += &format!(" = {}", self.initializer(initializer, &global.variable.ty));
+        }
+        out + ";\n"
+    }
+
+    fn function_header(&mut self, function: &FunctionNode) -> String {
+        let storage = if function.is_static { "static " } else { "" };
+        let params: Vec<String> = function
+            .params
+            .iter()
+            .map(|param| self.variable_declaration(param))
+            .collect();
+        let declarator = format!("{}({})", function.name, parameter_list(params));
+        format!(
+            "{}{}",
+            storage,
+            self.declaration(&function.return_type, declarator)
+        )
+    }
+
+    fn function_definition(&mut self, function: &FunctionNode) -> String {
+        self.return_type = Some(function.return_type.clone());
+        let mut out = format!("{}\n{{\n", self.function_header(function));
+        for statement_node in &function.body {
+            self.statement(statement_node, 1, &mut out);
+        }
+        out + "}\n"
+    }
+
+    // Braced lists follow the type, every element converted to the type it initializes
+    fn initializer(&mut self, initializer: &Initializer, ty: &Type) -> String {
+        match (initializer, ty) {
+            (Initializer::Expr(value), _) => self.converted(value, ty),
+            (Initializer::List(items), Type::Array(element, _)) => {
+                let items: Vec<String> = items
+                    .iter()
+                    .map(|item| self.initializer(item, element))
+                    .collect();
+                format!("{{{}}}", items.join(", "))
+            }
+            (Initializer::List(items), Type::Struct(struct_ref)) => {
+                let members = struct_ref.borrow().members.clone();
+                let items: Vec<String> = items
+                    .iter()
+                    .zip(members.iter())
+                    .map(|(item, member)| self.initializer(item, &member.ty))
+                    .collect();
+                format!("{{{}}}", items.join(", "))
+            }
+            (Initializer::List(items), _) => {
+                let items: Vec<String> = items
+                    .iter()
+                    .take(1)
+                    .map(|item| self.initializer(item, ty))
+                    .collect();
+                format!("{{{}}}", items.join(", "))
+            }
+        }
+    }
+
+    // Statements without a sub-statement, as they appear in the head of a for loop. Static
+    // locals are defined at file scope and leave nothing behind
+    fn simple_statement(&mut self, statement: &Statement) -> String {
+        match statement {
+            Statement::Declaration(variable, _)
+                if matches!(variable.storage, Storage::Global(_)) =>
+            {
+                ";".to_string()
+            }
+            Statement::Declaration(variable, initializer) => {
+                let mut out = self.variable_declaration(variable);
+                if let Some(initializer) = initializer {
+                    out += &format!(" = {}", self.initializer(initializer, &variable.ty));
+                }
+                out + ";"
+            }
+            Statement::Expression(expr_node) => format!("{};", self.value(expr_node)),
+            Statement::Return(Some(expr_node)) => {
+                let return_type = self.return_type.clone().expect("Return outside a function");
+                format!("return {};", self.converted(expr_node, &return_type))
+            }
+            Statement::Return(None) => "return;".to_string(),
+            Statement::Break => "break;".to_string(),
+            Statement::Continue => "continue;".to_string(),
+            _ => ";".to_string(),
+        }
+    }
+
+    fn statement(&mut self, statement_node: &StatementNode, depth: usize, out: &mut String) {
+        let indent = indentation(depth);
+        match &statement_node.statement {
+            Statement::Declaration(variable, _)
+                if matches!(variable.storage, Storage::Global(_)) => {}
+            Statement::Block(statements) => {
+                *out += &format!("{}{{\n", indent);
+                for statement_node in statements {
+                    self.statement(statement_node, depth + 1, out);
+                }
+                *out += &format!("{}}}\n", indent);
+            }
+            Statement::Switch(condition, body, _) => {
+                *out += &format!("{}switch ({})\n", indent, self.operand(condition));
+                self.body(body, depth, out);
+            }
+            Statement::Case(value, body) => {
+                *out += &format!("{}case {}:\n", indent, number(*value));
+                self.statement(body, depth, out);
+            }
+            Statement::Default(body) => {
+                *out += &format!("{}default:\n", indent);
+                self.statement(body, depth, out);
+            }
+            Statement::If(condition, then, otherwise) => {
+                *out += &format!("{}if ({})\n", indent, self.value(condition));
+                self.body(then, depth, out);
+                if let Some(otherwise) = otherwise {
+                    *out += &format!("{}else\n", indent);
+                    self.body(otherwise, depth, out);
+                }
+            }
+            Statement::While(condition, body) => {
+                *out += &format!("{}while ({})\n", indent, self.value(condition));
+                self.body(body, depth, out);
+            }
+            Statement::For(init, condition, step, body) => {
+                let init = self.simple_statement(&init.statement);
+                let condition = match condition {
+                    Some(condition) => format!(" {}", self.value(condition)),
+                    None => "".to_string(),
+                };
+                let step = match step {
+                    Some(step) => format!(" {}", self.value(step)),
+                    None => "".to_string(),
+                };
+                *out += &format!("{}for ({}{};{})\n", indent, init, condition, step);
+                self.body(body, depth, out);
+            }
+            statement => *out += &format!("{}{}\n", indent, self.simple_statement(statement)),
+        }
+    }
+
+    // The statement governed by an if, a loop or a switch, a block stays level with its head
+    fn body(&mut self, body: &StatementNode, depth: usize, out: &mut String) {
+        match body.statement {
+            Statement::Block(_) => self.statement(body, depth, out),
+            _ => self.statement(body, depth + 1, out),
+        }
+    }
+
+    // An expression used for its value, an array becomes the address of its first element
+    fn value(&mut self, node: &ExprNode) -> String {
+        let text = self.expression(node);
+        if node.ty.is_array() {
+            format!("(&{}[0])", text)
+        } else {
+            text
+        }
+    }
+
+    // Operands of arithmetic, chars are promoted to int
+    fn operand(&mut self, node: &ExprNode) -> String {
+        let text = self.value(node);
+        if node.ty == Type::Char {
+            format!("((int){})", text)
+        } else {
+            text
+        }
+    }
+
+    // The value converted to ty like an assignment does, structs are copied as they are
+    fn converted(&mut self, node: &ExprNode, ty: &Type) -> String {
+        let text = self.value(node);
+        if node.ty.decay() == *ty || ty.is_struct() {
+            text
+        } else {
+            format!("(({}){})", self.declaration(ty, "".to_string()), text)
+        }
+    }
+
+    fn expression(&mut self, node: &ExprNode) -> String {
+        match &node.expr {
+            Expr::Number(value) => number(*value),
+            Expr::Variable(variable) => self.variable_name(variable),
+            Expr::UnaryOp(operator @ (TokenType::BitAnd | TokenType::Mul), Some(operand)) => {
+                let operand = match operator {
+                    TokenType::BitAnd => self.expression(operand),
+                    _ => self.value(operand),
+                };
+                format!("({}{})", operator, operand)
+            }
+            Expr::UnaryOp(operator, Some(operand)) => {
+                format!("({}{})", operator, self.operand(operand))
+            }
+            Expr::UnaryOp(operator, None) => panic!("Missing operand for {}", operator),
+            Expr::BinaryOp(operator, left, right) => {
+                let text = format!(
+                    "({} {} {})",
+                    self.operand(left),
+                    operator,
+                    self.operand(right)
+                );
+                // The difference of two pointers is a long in C
+                if left.ty.base().is_some()
+                    && right.ty.base().is_some()
+                    && *operator == TokenType::Minus
+                {
+                    format!("((int){})", text)
+                } else {
+                    text
+                }
+            }
+            Expr::Assign(target, value) => {
+                let value = self.converted(value, &target.ty);
+                format!("({} = {})", self.expression(target), value)
+            }
+            Expr::Member(base, member) => format!("{}.{}", self.expression(base), member.name),
+            Expr::Conditional(condition, then, otherwise) => format!(
+                "({} ? {} : {})",
+                self.value(condition),
+                self.converted(then, &node.ty),
+                self.converted(otherwise, &node.ty)
+            ),
+            Expr::Comma(left, right) => {
+                format!("({}, {})", self.value(left), self.value(right))
+            }
+            Expr::Cast(ty, operand) => {
+                format!(
+                    "(({}){})",
+                    self.declaration(ty, "".to_string()),
+                    self.value(operand)
+                )
+            }
+            Expr::Call(name, args) => {
+                self.calls.push((
+                    name.clone(),
+                    node.ty.clone(),
+                    args.iter().map(|arg| arg.ty.decay()).collect(),
+                ));
+                let args: Vec<String> = args.iter().map(|arg| self.value(arg)).collect();
+                format!("{}({})", name, args.join(", "))
+            }
+        }
+    }
+}
@@ -0,0 +1,134 @@
+use std::collections::HashMap;
+
+use crate::{
+    cfg::Dominators,
+    ir::{BinaryOp, BlockId, Conversion, Instr, IrFunction, IrProgram, IrType, UnaryOp, VReg},
+};
+
+// Common subexpression elimination on SSA form. Walking the dominator tree, an instruction
+// computing what a dominating one already computed is dropped and its readers use the earlier
+// register. Loads are left alone since a store in between may change what they read. Copies
+// and phis choosing between the same value everywhere are also replaced by their source
+
+// What a pure instruction computes, independent of where it puts the result
+#[derive(Debug, PartialEq, Eq, Hash)]
+enum Expression {
+    Const(IrType, i64),
+    Unary(UnaryOp, VReg),
+    Binary(BinaryOp, IrType, VReg, VReg),
+    Convert(Conversion, VReg),
+    Frame(usize),
+    Global(String),
+}
+
+fn expression(function: &IrFunction, instr: &Instr) -> Option<Expression> {
+    Some(match instr {
+        Instr::Const { dst, value } => Expression::Const(function.vregs[dst.0], *value),
+        Instr::Unary { op, src, .. } => Expression::Unary(*op, *src),
+        Instr::Binary { op, dst, lhs, rhs } => {
+            let commutative = matches!(
+                op,
+                BinaryOp::Add | BinaryOp::Mul | BinaryOp::Eq | BinaryOp::Ne
+            );
+            let (lhs, rhs) = if commutative && rhs < lhs {
+                (rhs, lhs)
+            } else {
+                (lhs, rhs)
+            };
+            Expression::Binary(*op, function.vregs[dst.0], *lhs, *rhs)
+        }
+        Instr::Convert {
+            conversion, src, ..
+        } => Expression::Convert(*conversion, *src),
+        Instr::FrameAddr { slot, .. } => Expression::Frame(*slot),
+        Instr::GlobalAddr { symbol, .. } => Expression::Global(symbol.clone()),
+        _ => return None,
+    })
+}
+
+pub fn eliminate_common_subexpressions(program: &mut IrProgram) {
+    for function in &mut program.functions {
+        eliminate_in_function(function);
+    }
+}
+
+fn eliminate_in_function(function: &mut IrFunction) {
+    let dominators = Dominators::new(function);
+    let mut replacements: HashMap<VReg, VReg> = HashMap::new();
+    let mut available: HashMap<Expression, VReg> = HashMap::new();
+
+    // None marks leaving a block, forgetting what it made available
+    let mut worklist = vec![Some(BlockId(0))];
+    let mut added: Vec<Vec<Expression>> = vec![];
+    while let Some(entry) = worklist.pop() {
+        let Some(block) = entry else {
+            for expression in added.pop().expect("Entered block") {
+                available.remove(&expression);
+            }
+            continue;
+        };
+
+        let mut made_available = vec![];
+        let instrs = std::mem::take(&mut function.blocks[block.0].instrs);
+        let mut kept = vec![];
+        for mut instr in instrs {
+            instr.rename_uses(|vreg| resolve(&replacements, vreg));
+
+            let replacement = match &instr {
+                Instr::Copy { dst, src } if function.vregs[dst.0] == function.vregs[src.0] => {
+                    Some((*dst, *src))
+                }
+                Instr::Phi { dst, incoming } => {
+                    let mut values = incoming
+                        .iter()
+                        .map(|(_, value)| *value)
+                        .filter(|value| value != dst);
+                    match values.next() {
+                        Some(first) if values.all(|value| value == first) => Some((*dst, first)),
+                        _ => None,
+                    }
+                }
+                _ => match (instr.dst(), expression(function, &instr)) {
+                    (Some(dst), Some(key)) => match available.get(&key) {
+                        Some(earlier) => Some((dst, *earlier)),
+                        None => {
+                            made_available.push(expression(function, &instr).expect("Pure"));
+                            available.insert(key, dst);
+                            None
+                        }
+                    },
+                    _ => None,
+                },
+            };
+
+            match replacement {
+                Some((dst, src)) => {
+                    replacements.insert(dst, src);
+                }
+                None => kept.push(instr),
+            }
+        }
+        function.blocks[block.0].instrs = kept;
+
+        added.push(made_available);
+        worklist.push(None);
+        worklist.extend(dominators.children(block).iter().rev().map(|c| Some(*c)));
+    }
+
+    // Phis read values from blocks the walk may have visited before the replacements were known
+    for block in &mut function.blocks {
+        for instr in &mut block.instrs {
+            instr.rename_uses(|vreg| resolve(&replacements, vreg));
+        }
+        block
+            .terminator
+            .rename_vregs(|vreg| resolve(&replacements, vreg));
+    }
+}
+
+fn resolve(replacements: &HashMap<VReg, VReg>, mut vreg: VReg) -> VReg {
+    while let Some(replacement) = replacements.get(&vreg) {
+        vreg = *replacement;
+    }
+    vreg
+}
@@ -0,0 +1,223 @@
+use std::collections::HashMap;
+
+use crate::{
+    ir::{BlockId, Instr, IrFunction, IrProgram, VReg},
+    parser::{ProgramNode, Statement, StatementNode},
+};
+
+// Dead code elimination, run from -O1. On the syntax tree it drops statements control can
+// never reach, like the ones after a return, and expression statements computing a value
+// nobody uses. On the IR it drops blocks no path from the entry block leads to and
+// instructions computing values nothing needs
+
+pub fn eliminate_dead_code(program: &mut ProgramNode) {
+    for function in &mut program.body {
+        eliminate_in_list(&mut function.body);
+    }
+}
+
+// A warning at the first statement of every stretch of unreachable code
+pub fn warn_unreachable_code(program: &ProgramNode) -> Vec<String> {
+    let mut warnings = vec![];
+    for function in &program.body {
+        warn_in_list(&function.name, &function.body, &mut warnings);
+    }
+    warnings
+}
+
+fn eliminate_in_list(statements: &mut Vec<StatementNode>) {
+    let (dead, _) = dead_statements(statements);
+    let mut index = 0;
+    statements.retain(|_| {
+        index += 1;
+        !dead[index - 1]
+    });
+
+    for statement in statements.iter_mut() {
+        eliminate_in_statement(statement);
+    }
+    statements.retain(|statement| statement.statement != Statement::Empty);
+}
+
+fn eliminate_in_statement(node: &mut StatementNode) {
+    match &mut node.statement {
+        Statement::Expression(expr) if !expr.has_side_effects() => {
+            node.statement = Statement::Empty;
+        }
+        Statement::Block(statements) => eliminate_in_list(statements),
+        Statement::Switch(_, body, _)
+        | Statement::Case(_, body)
+        | Statement::Default(body)
+        | Statement::While(_, body)
+        | Statement::For(_, _, _, body) => eliminate_in_statement(body),
+        Statement::If(_, then, otherwise) => {
+            eliminate_in_statement(then);
+            if let Some(otherwise) = otherwise {
+                eliminate_in_statement(otherwise);
+            }
+        }
+        _ => {}
+    }
+}
+
+fn warn_in_list(function: &str, statements: &[StatementNode], warnings: &mut Vec<String>) {
+    let (dead, _) = dead_statements(statements);
+    for (index, statement) in statements.iter().enumerate() {
+        if dead[index] && (index == 0 || !dead[index - 1]) {
+            warnings.push(format!(
+                "Warning: unreachable code in function {}: {} [-Wunreachable-code]",
+                function,
+                statement.to_string().trim()
+            ));
+        }
+        if !dead[index] {
+            warn_in_statement(function, statement, warnings);
+        }
+    }
+}
+
+fn warn_in_statement(function: &str, node: &StatementNode, warnings: &mut Vec<String>) {
+    match &node.statement {
+        Statement::Block(statements) => warn_in_list(function, statements, warnings),
+        Statement::Switch(_, body, _)
+        | Statement::Case(_, body)
+        | Statement::Default(body)
+        | Statement::While(_, body)
+        | Statement::For(_, _, _, body) => warn_in_statement(function, body, warnings),
+        Statement::If(_, then, otherwise) => {
+            warn_in_statement(function, then, warnings);
+            if let Some(otherwise) = otherwise {
+                warn_in_statement(function, otherwise, warnings);
+            }
+        }
+        _ => {}
+    }
+}
+
+// Which statements of the list follow one that never completes, up to the next case label, and
+// whether control can continue after the list
+fn dead_statements(statements: &[StatementNode]) -> (Vec<bool>, bool) {
+    let mut dead = vec![];
+    let mut reachable = true;
+
+    for statement in statements {
+        if !reachable && has_case_label(statement) {
+            reachable = true;
+        }
+        dead.push(!reachable);
+        if reachable {
+            reachable = can_complete(statement);
+        }
+    }
+
+    (dead, reachable)
+}
+
+// Whether control can continue after the statement. A switch or a loop is assumed to, a break
+// in its body lands right after it
+fn can_complete(node: &StatementNode) -> bool {
+    match &node.statement {
+        Statement::Return(_) | Statement::Break | Statement::Continue => false,
+        Statement::If(_, then, Some(otherwise)) => can_complete(then) || can_complete(otherwise),
+        Statement::Block(statements) => dead_statements(statements).1,
+        Statement::Case(_, body) | Statement::Default(body) => can_complete(body),
+        _ => true,
+    }
+}
+
+// A case label makes the code after it reachable from the switch. Labels of a nested switch
+// belong to that switch
+fn has_case_label(node: &StatementNode) -> bool {
+    match &node.statement {
+        Statement::Case(_, _) | Statement::Default(_) => true,
+        Statement::Block(statements) => statements.iter().any(has_case_label),
+        Statement::If(_, then, otherwise) => {
+            has_case_label(then) || otherwise.as_deref().is_some_and(has_case_label)
+        }
+        Statement::While(_, body) | Statement::For(_, _, _, body) => has_case_label(body),
+        _ => false,
+    }
+}
+
+// Keeps the blocks reachable from the entry block in their order, renumbering the jumps. Phis
+// forget the blocks that are gone
+pub fn remove_unreachable_in_function(function: &mut IrFunction) {
+    let mut reachable = vec![false; function.blocks.len()];
+    let mut worklist = vec![BlockId(0)];
+    while let Some(block) = worklist.pop() {
+        if reachable[block.0] {
+            continue;
+        }
+        reachable[block.0] = true;
+        worklist.extend(function.blocks[block.0].terminator.successors());
+    }
+
+    let mut renumbered = vec![None; function.blocks.len()];
+    let mut count = 0;
+    for (index, is_reachable) in reachable.iter().enumerate() {
+        if *is_reachable {
+            renumbered[index] = Some(BlockId(count));
+            count += 1;
+        }
+    }
+
+    let blocks = std::mem::take(&mut function.blocks);
+    for (index, mut block) in blocks.into_iter().enumerate() {
+        if reachable[index] {
+            block
+                .terminator
+                .rename_blocks(|old| renumbered[old.0].expect("Jump to a removed block"));
+            for instr in &mut block.instrs {
+                if let Instr::Phi { incoming, .. } = instr {
+                    incoming.retain(|(block, _)| reachable[block.0]);
+                    for (block, _) in incoming {
+                        *block = renumbered[block.0].expect("Reachable");
+                    }
+                }
+            }
+            function.blocks.push(block);
+        }
+    }
+}
+
+pub fn remove_dead_code(program: &mut IrProgram) {
+    for function in &mut program.functions {
+        remove_unreachable_in_function(function);
+        remove_dead_instructions(function);
+    }
+}
+
+// Keeps the instructions with side effects, the ones deciding where control goes and what is
+// returned, and everything computing their operands. A register written in several places is
+// needed as soon as one reader is
+pub fn remove_dead_instructions(function: &mut IrFunction) {
+    let mut definitions: HashMap<VReg, Vec<&Instr>> = HashMap::new();
+    let mut worklist = vec![];
+    for block in &function.blocks {
+        for instr in &block.instrs {
+            match instr.dst() {
+                Some(dst) if instr.is_pure() => definitions.entry(dst).or_default().push(instr),
+                _ => worklist.extend(instr.uses()),
+            }
+        }
+        worklist.extend(block.terminator.uses());
+    }
+
+    let mut live = vec![false; function.vregs.len()];
+    while let Some(vreg) = worklist.pop() {
+        if live[vreg.0] {
+            continue;
+        }
+        live[vreg.0] = true;
+        for instr in definitions.get(&vreg).into_iter().flatten() {
+            worklist.extend(instr.uses());
+        }
+    }
+
+    for block in &mut function.blocks {
+        block.instrs.retain(|instr| match instr.dst() {
+            Some(dst) if instr.is_pure() => live[dst.0],
+            _ => true,
+        });
+    }
+}
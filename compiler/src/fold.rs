@@ -0,0 +1,153 @@
+use crate::{
+    parser::{
+        evaluate_constant, Expr, ExprNode, Initializer, ProgramNode, Statement, StatementNode,
+    },
+    token::TokenType,
+    types::Type,
+};
+
+// Constant folding and algebraic simplification of the expression trees, run before lowering
+// from -O1. Operators whose operands are all constant are replaced by their value, computed
+// with the wraparound of int like the generated code would. Division by zero is left for the
+// program to run into instead of being decided at compile time
+
+pub fn fold_program(program: &mut ProgramNode) {
+    for function in &mut program.body {
+        for statement in &mut function.body {
+            fold_statement(statement);
+        }
+    }
+}
+
+fn fold_statement(node: &mut StatementNode) {
+    match &mut node.statement {
+        Statement::Return(Some(expr)) | Statement::Expression(expr) => fold_expr(expr),
+        Statement::Declaration(_, Some(initializer)) => fold_initializer(initializer),
+        Statement::Block(statements) => {
+            for statement in statements {
+                fold_statement(statement);
+            }
+        }
+        Statement::Switch(value, body, _) => {
+            fold_expr(value);
+            fold_statement(body);
+        }
+        Statement::Case(_, body) | Statement::Default(body) => fold_statement(body),
+        Statement::If(condition, then, otherwise) => {
+            fold_expr(condition);
+            fold_statement(then);
+            if let Some(otherwise) = otherwise {
+                fold_statement(otherwise);
+            }
+        }
+        Statement::While(condition, body) => {
+            fold_expr(condition);
+            fold_statement(body);
+        }
+        Statement::For(init, condition, step, body) => {
+            fold_statement(init);
+            for expr in [condition, step].into_iter().flatten() {
+                fold_expr(expr);
+            }
+            fold_statement(body);
+        }
+        Statement::Declaration(_, None)
+        | Statement::Return(None)
+        | Statement::Empty
+        | Statement::Break
+        | Statement::Continue => {}
+    }
+}
+
+fn fold_initializer(initializer: &mut Initializer) {
+    match initializer {
+        Initializer::Expr(expr) => fold_expr(expr),
+        Initializer::List(items) => {
+            for item in items {
+                fold_initializer(item);
+            }
+        }
+    }
+}
+
+// Folds the operands first, so a parent sees constants where its subtrees were constant
+fn fold_expr(node: &mut ExprNode) {
+    match &mut node.expr {
+        Expr::Number(_) | Expr::Variable(_) => {}
+        Expr::UnaryOp(_, operand) => {
+            if let Some(operand) = operand {
+                fold_expr(operand);
+            }
+        }
+        Expr::BinaryOp(_, left, right) | Expr::Assign(left, right) | Expr::Comma(left, right) => {
+            fold_expr(left);
+            fold_expr(right);
+        }
+        Expr::Member(base, _) => fold_expr(base),
+        Expr::Conditional(condition, then, otherwise) => {
+            fold_expr(condition);
+            fold_expr(then);
+            fold_expr(otherwise);
+        }
+        Expr::Cast(_, operand) => fold_expr(operand),
+        Expr::Call(_, args) => {
+            for arg in args {
+                fold_expr(arg);
+            }
+        }
+    }
+
+    if let Some(simplified) = simplify(node) {
+        *node = simplified;
+    }
+}
+
+// The constant value of an operator, or an operand it reduces to
+fn simplify(node: &ExprNode) -> Option<ExprNode> {
+    if !matches!(node.ty, Type::Int | Type::Char) {
+        return simplify_identity(node);
+    }
+
+    match &node.expr {
+        Expr::UnaryOp(_, _) | Expr::BinaryOp(_, _, _) | Expr::Cast(_, _) => {
+            match evaluate_constant(node) {
+                Some(value) => Some(ExprNode {
+                    expr: Expr::Number(value),
+                    ty: node.ty.clone(),
+                }),
+                None => simplify_identity(node),
+            }
+        }
+        _ => None,
+    }
+}
+
+// x + 0, x - 0, x * 1, x / 1 and x * 0, plus the logical operators whose left operand alone
+// decides the result. The operand replacing the operator has to be of the same type, e.g not
+// an array decaying in p + 0
+fn simplify_identity(node: &ExprNode) -> Option<ExprNode> {
+    let Expr::BinaryOp(operator, left, right) = &node.expr else {
+        return None;
+    };
+    let left_value = evaluate_constant(left);
+    let right_value = evaluate_constant(right);
+    let keep = |operand: &ExprNode| (operand.ty == node.ty).then(|| operand.clone());
+    let number = |value: i32| {
+        Some(ExprNode {
+            expr: Expr::Number(value),
+            ty: node.ty.clone(),
+        })
+    };
+
+    match (operator, left_value, right_value) {
+        (TokenType::Plus, _, Some(0)) | (TokenType::Minus, _, Some(0)) => keep(left),
+        (TokenType::Plus, Some(0), _) => keep(right),
+        (TokenType::Mul, _, Some(1)) | (TokenType::Div, _, Some(1)) => keep(left),
+        (TokenType::Mul, Some(1), _) => keep(right),
+        (TokenType::Mul, _, Some(0)) if !left.has_side_effects() => number(0),
+        (TokenType::Mul, Some(0), _) if !right.has_side_effects() => number(0),
+        (TokenType::And, Some(0), _) => number(0),
+        (TokenType::Or, Some(value), _) if value != 0 => number(1),
+        _ => None,
+    }
+}
@@ -0,0 +1,474 @@
+// Differential fuzzing. Programs from generate.rs are run by the interpreter, the reference,
+// and compiled at every optimization level and run in the simulator. Where the tools are
+// installed they are also built for x86-64 and from the --emit=c output by gcc -fwrapv, run
+// from the --emit=llvm output by lli, built for RV64 and run by qemu-riscv64 and run from the
+// wasm32 output by wasmtime. A program where any of them disagrees with the interpreter, or the
+// compiler fails, is reduced while the same disagreement stays, by removing lines and blocks
+// and replacing expressions by constants, and written to fuzz-<seed>.c with the original next
+// to it as fuzz-<seed>-original.c. The compiler runs as a child process, so that a panic or
+// the parse errors of a broken candidate during reduction only end that run
+use crate::generate;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::process::{Command, Output};
+
+// What runs the program the compiler made
+#[derive(Clone, Copy, PartialEq)]
+enum Runner {
+    Compiler, // --run and --simulate run it themselves
+    Gcc,      // Built by gcc -fwrapv and run
+    Qemu,     // Built by riscv64-linux-gnu-gcc -static and run by qemu-riscv64
+    Lli,
+    Wasmtime, // wasmtime --invoke main, which prints what main returns
+}
+
+struct Configuration {
+    options: &'static [&'static str],
+    runner: Runner,
+}
+
+impl Configuration {
+    fn describe(&self) -> String {
+        let runner = match self.runner {
+            Runner::Compiler => "",
+            Runner::Gcc => " with gcc",
+            Runner::Qemu => " with qemu-riscv64",
+            Runner::Lli => " with lli",
+            Runner::Wasmtime => " with wasmtime",
+        };
+        format!("{}{}", self.options.join(" "), runner)
+    }
+}
+
+const fn configuration(options: &'static [&'static str], runner: Runner) -> Configuration {
+    Configuration { options, runner }
+}
+
+// What the program is checked with, the first one is the reference. The ones needing a tool
+// that is not installed are left out
+const CONFIGURATIONS: [Configuration; 11] = [
+    configuration(&["--run", "-O0"], Runner::Compiler),
+    configuration(&["--simulate", "-O0"], Runner::Compiler),
+    configuration(&["--simulate", "-O1"], Runner::Compiler),
+    configuration(&["--simulate", "-O2"], Runner::Compiler),
+    configuration(&["--target=x86_64-linux", "-O0"], Runner::Gcc),
+    configuration(&["--target=x86_64-linux", "-O2"], Runner::Gcc),
+    configuration(&["--emit=c", "-O0"], Runner::Gcc),
+    configuration(&["--emit=c", "-O2"], Runner::Gcc),
+    configuration(&["--emit=llvm", "-O2"], Runner::Lli),
+    configuration(&["--target=riscv64-linux", "-O2"], Runner::Qemu),
+    configuration(&["--target=wasm32", "-O2"], Runner::Wasmtime),
+];
+
+fn installed(tool: &str, args: &[&str]) -> bool {
+    Command::new(tool)
+        .args(args)
+        .output()
+        .is_ok_and(|result| result.status.success())
+}
+
+#[derive(PartialEq, Debug)]
+enum Outcome {
+    Exit(i32),
+    Failed(String), // Panics, crashes and the errors of the compiler
+}
+
+impl Outcome {
+    fn describe(&self) -> String {
+        match self {
+            Outcome::Exit(code) => format!("exit code {}", code),
+            Outcome::Failed(message) => format!("failure: {}", message),
+        }
+    }
+}
+
+// The exit code of a process, errors are reported on stderr
+fn outcome_of(command: &mut Command) -> Outcome {
+    let result = match command.output() {
+        Ok(result) => result,
+        Err(error) => return Outcome::Failed(format!("Cannot start {:?}: {}", command, error)),
+    };
+    match result.status.code() {
+        Some(code) if result.stderr.is_empty() => Outcome::Exit(code),
+        code => failure(&result, code),
+    }
+}
+
+fn failure(result: &Output, code: Option<i32>) -> Outcome {
+    // A panic gives its message on the line after the location
+    let stderr = String::from_utf8_lossy(&result.stderr);
+    let lines: Vec<&str> = stderr
+        .lines()
+        .filter(|line| !line.trim().is_empty())
+        .collect();
+    let message = match lines.as_slice() {
+        [] => "no message".to_string(),
+        [first, second, ..] if first.ends_with(':') => format!("{} {}", first, second),
+        [first, ..] => first.to_string(),
+    };
+    match code {
+        Some(code) => Outcome::Failed(format!("{} (status {})", message, code)),
+        None => Outcome::Failed(format!("{} (killed by a signal)", message)),
+    }
+}
+
+// Exit codes only keep the low byte, what wasmtime prints is cut down the same way
+fn wasmtime_outcome(command: &mut Command) -> Outcome {
+    let result = match command.output() {
+        Ok(result) => result,
+        Err(error) => return Outcome::Failed(format!("Cannot start wasmtime: {}", error)),
+    };
+    let printed = String::from_utf8_lossy(&result.stdout);
+    match printed.trim().parse::<i64>() {
+        Ok(value) if result.status.success() => Outcome::Exit((value & 0xFF) as i32),
+        _ => failure(&result, result.status.code()),
+    }
+}
+
+struct Checker {
+    compiler: PathBuf,
+    scratch: PathBuf,
+    configurations: Vec<Configuration>,
+    lli_options: &'static [&'static str], // -opaque-pointers for LLVM before 15
+}
+
+impl Checker {
+    // The configurations the tools are installed for
+    fn new(compiler: PathBuf, scratch: PathBuf) -> Self {
+        let gcc = installed("gcc", &["--version"]);
+        let native_x86 = cfg!(all(target_arch = "x86_64", target_os = "linux"));
+        let opaque_pointers = installed("lli", &["-opaque-pointers", "--version"]);
+        let lli = opaque_pointers || installed("lli", &["--version"]);
+        let qemu = installed("riscv64-linux-gnu-gcc", &["--version"])
+            && installed("qemu-riscv64", &["--version"]);
+        let wasmtime = installed("wasmtime", &["--version"]);
+
+        let configurations = CONFIGURATIONS
+            .into_iter()
+            .filter(|configuration| match configuration.runner {
+                Runner::Compiler => true,
+                Runner::Gcc if configuration.options[0] == "--emit=c" => gcc,
+                Runner::Gcc => gcc && native_x86,
+                Runner::Lli => lli,
+                Runner::Qemu => qemu,
+                Runner::Wasmtime => wasmtime,
+            })
+            .collect();
+        let lli_options: &[&str] = if opaque_pointers {
+            &["-opaque-pointers"]
+        } else {
+            &[]
+        };
+        Checker {
+            compiler,
+            scratch,
+            configurations,
+            lli_options,
+        }
+    }
+
+    fn outcome(&self, source: &str, index: usize) -> Outcome {
+        if let Err(error) = fs::write(&self.scratch, source) {
+            return Outcome::Failed(format!(
+                "Cannot write {}: {}",
+                self.scratch.display(),
+                error
+            ));
+        }
+        let configuration = &self.configurations[index];
+        if configuration.runner == Runner::Compiler {
+            return outcome_of(
+                Command::new(&self.compiler)
+                    .args(configuration.options)
+                    .arg(&self.scratch),
+            );
+        }
+
+        // The compiler writes its output next to the program for the tool
+        let extension = match configuration.options[0] {
+            "--emit=c" => "out.c",
+            "--emit=llvm" => "ll",
+            "--target=wasm32" => "wat",
+            _ => "s",
+        };
+        let output = self.scratch.with_extension(extension);
+        let compiled = outcome_of(
+            Command::new(&self.compiler)
+                .args(configuration.options)
+                .arg("-o")
+                .arg(&output)
+                .arg(&self.scratch),
+        );
+        if let Outcome::Failed(_) = compiled {
+            return compiled;
+        }
+
+        let executable = self.scratch.with_extension("exe");
+        let gcc = match configuration.runner {
+            Runner::Lli => {
+                return outcome_of(Command::new("lli").args(self.lli_options).arg(&output))
+            }
+            Runner::Wasmtime => {
+                return wasmtime_outcome(
+                    Command::new("wasmtime")
+                        .args(["--invoke", "main"])
+                        .arg(&output),
+                )
+            }
+            Runner::Qemu => Command::new("riscv64-linux-gnu-gcc")
+                .arg("-static")
+                .arg("-o")
+                .arg(&executable)
+                .arg(&output)
+                .output(),
+            _ => Command::new("gcc")
+                .args(["-fwrapv", "-w", "-o"])
+                .arg(&executable)
+                .arg(&output)
+                .output(),
+        };
+        match gcc {
+            Ok(result) if result.status.success() => {}
+            Ok(result) => return failure(&result, result.status.code()),
+            Err(error) => return Outcome::Failed(format!("Cannot start gcc: {}", error)),
+        }
+        match configuration.runner {
+            Runner::Qemu => outcome_of(Command::new("qemu-riscv64").arg(&executable)),
+            _ => outcome_of(&mut Command::new(&executable)),
+        }
+    }
+
+    // The first configuration that disagrees with the reference
+    fn mismatch(&self, source: &str, expected: &Outcome) -> Option<(usize, Outcome)> {
+        for index in 1..self.configurations.len() {
+            let actual = self.outcome(source, index);
+            if actual != *expected {
+                return Some((index, actual));
+            }
+        }
+        None
+    }
+
+    // Still wrong the same way: the configuration disagrees with the reference, and fails if
+    // it failed before
+    fn reproduces(&self, source: &str, index: usize, failed: bool) -> bool {
+        let expected = self.outcome(source, 0);
+        if let Outcome::Failed(_) = expected {
+            return false;
+        }
+        let actual = self.outcome(source, index);
+        actual != expected && matches!(actual, Outcome::Failed(_)) == failed
+    }
+
+    // Shrinks the program while the mismatch reproduces, until none of the passes gets it any
+    // smaller
+    fn reduce(&self, source: &str, index: usize, failed: bool) -> String {
+        let keeps = |lines: &[String]| self.reproduces(&(lines.join("\n") + "\n"), index, failed);
+        let mut lines: Vec<String> = source.lines().map(str::to_string).collect();
+        loop {
+            let size: usize = lines.iter().map(String::len).sum();
+            remove_chunks(&mut lines, &keeps);
+            remove_blocks(&mut lines, &keeps);
+            simplify_expressions(&mut lines, &keeps);
+            if lines.iter().map(String::len).sum::<usize>() == size {
+                break;
+            }
+        }
+        lines.join("\n") + "\n"
+    }
+}
+
+// Removes chunks of lines, from half the program down to single lines
+fn remove_chunks(lines: &mut Vec<String>, keeps: &dyn Fn(&[String]) -> bool) {
+    let mut chunk = lines.len() / 2;
+    while chunk >= 1 {
+        let mut start = 0;
+        let mut removed = false;
+        while start < lines.len() {
+            let end = (start + chunk).min(lines.len());
+            let candidate = [&lines[..start], &lines[end..]].concat();
+            if keeps(&candidate) {
+                *lines = candidate;
+                removed = true;
+            } else {
+                start += chunk;
+            }
+        }
+        // Another round at the same size after progress, smaller chunks otherwise
+        if !removed {
+            chunk /= 2;
+        }
+    }
+}
+
+fn brace_depth(line: &str) -> i32 {
+    line.chars()
+        .map(|ch| match ch {
+            '{' => 1,
+            '}' => -1,
+            _ => 0,
+        })
+        .sum()
+}
+
+// Removes a block with the statement opening it, or only the opening and closing lines to
+// keep the statements inside. A function or an if with an else branch goes the same way
+fn remove_blocks(lines: &mut Vec<String>, keeps: &dyn Fn(&[String]) -> bool) {
+    let mut start = 0;
+    while start < lines.len() {
+        if brace_depth(&lines[start]) <= 0 {
+            start += 1;
+            continue;
+        }
+
+        let mut depth = 0;
+        let mut end = start;
+        while end < lines.len() {
+            depth += brace_depth(&lines[end]);
+            if depth <= 0 {
+                break;
+            }
+            end += 1;
+        }
+        if end == lines.len() {
+            start += 1;
+            continue;
+        }
+
+        let removed = [&lines[..start], &lines[end + 1..]].concat();
+        let unwrapped = [&lines[..start], &lines[start + 1..end], &lines[end + 1..]].concat();
+        if keeps(&removed) {
+            *lines = removed;
+        } else if lines[end].trim() == "}" && keeps(&unwrapped) {
+            *lines = unwrapped;
+        } else {
+            start += 1;
+        }
+    }
+}
+
+// The index of the parenthesis closing the one at open
+fn closing_parenthesis(line: &str, open: usize) -> Option<usize> {
+    let mut depth = 0;
+    for (index, ch) in line.char_indices().skip_while(|(index, _)| *index < open) {
+        match ch {
+            '(' => depth += 1,
+            ')' => depth -= 1,
+            _ => {}
+        }
+        if depth == 0 {
+            return Some(index);
+        }
+    }
+    None
+}
+
+// Replaces parenthesized expressions by 0 or 1
+fn simplify_expressions(lines: &mut [String], keeps: &dyn Fn(&[String]) -> bool) {
+    for number in 0..lines.len() {
+        let mut open = 0;
+        while let Some(offset) = lines[number][open..].find('(') {
+            open += offset;
+            let Some(close) = closing_parenthesis(&lines[number], open) else {
+                break;
+            };
+
+            let original = lines[number].clone();
+            let mut simplified = false;
+            for constant in ["0", "1"] {
+                lines[number] = format!(
+                    "{}{}{}",
+                    &original[..open],
+                    constant,
+                    &original[close + 1..]
+                );
+                if keeps(lines) {
+                    simplified = true;
+                    break;
+                }
+            }
+            if !simplified {
+                lines[number] = original;
+                open += 1;
+            }
+        }
+    }
+}
+
+// Checks count programs starting from the seed and gives back how many of them failed. The
+// program for a seed is always the same, --fuzz=1 --seed=<seed> checks it again
+pub fn fuzz(count: usize, seed: u64) -> Result<usize, String> {
+    let compiler = std::env::current_exe()
+        .map_err(|error| format!("Cannot find the compiler executable: {}", error))?;
+    let checker = Checker::new(
+        compiler,
+        std::env::temp_dir().join(format!("fuzz-{}.c", std::process::id())),
+    );
+
+    let mut failures = 0;
+    for program_seed in seed..seed + count as u64 {
+        let source = generate::generate_program(program_seed);
+
+        // Generated programs are valid, one the reference cannot run shows a bug in the
+        // generator or the front end. There is nothing to compare it with, so it is not reduced
+        let expected = checker.outcome(&source, 0);
+        if let Outcome::Failed(_) = expected {
+            failures += 1;
+            let header = format!(
+                "// Seed {}: {} gave {}\n",
+                program_seed,
+                checker.configurations[0].describe(),
+                expected.describe()
+            );
+            let path = format!("fuzz-{}.c", program_seed);
+            write(Path::new(&path), &format!("{}{}", header, source))?;
+            print!("{}", header);
+            println!("Written to {}", path);
+            continue;
+        }
+
+        let Some((index, actual)) = checker.mismatch(&source, &expected) else {
+            continue;
+        };
+        failures += 1;
+
+        let failed = matches!(actual, Outcome::Failed(_));
+        let reduced = checker.reduce(&source, index, failed);
+        let expected = checker.outcome(&reduced, 0);
+        let actual = checker.outcome(&reduced, index);
+        let header = format!(
+            "// Seed {}: {} gave {}, {} gave {}\n",
+            program_seed,
+            checker.configurations[0].describe(),
+            expected.describe(),
+            checker.configurations[index].describe(),
+            actual.describe()
+        );
+
+        let path = format!("fuzz-{}.c", program_seed);
+        let original = format!("fuzz-{}-original.c", program_seed);
+        write(Path::new(&path), &format!("{}{}", header, reduced))?;
+        write(Path::new(&original), &format!("{}{}", header, source))?;
+        print!("{}", header);
+        println!(
+            "Reduced from {} to {} lines in {}",
+            source.lines().count(),
+            reduced.lines().count(),
+            path
+        );
+    }
+
+    let _ = fs::remove_file(&checker.scratch);
+    for extension in ["out.c", "ll", "wat", "s", "exe"] {
+        let _ = fs::remove_file(checker.scratch.with_extension(extension));
+    }
+    println!(
+        "{} programs from seed {}, {} mismatches",
+        count, seed, failures
+    );
+    Ok(failures)
+}
+
+fn write(path: &Path, contents: &str) -> Result<(), String> {
+    fs::write(path, contents).map_err(|error| format!("Cannot write {}: {}", path.display(), error))
+}
@@ -1,478 +1,489 @@
 use crate::{
-    parser::{Expr, ExprNode, FunctionNode, ProgramNode, Statement, StatementNode},
-    token::TokenType,
+    asm::{directive, emit, label, Line},
+    backend::{elf_symbol, global_asm, jump_table_range, Backend, CodegenContext, Registers},
+    ir::{
+        BinaryOp, BlockId, Conversion, Instr, IrFunction, IrProgram, IrType, Terminator, UnaryOp,
+        VReg,
+    },
+    peephole,
 };
-use lazy_static::lazy_static;
-use std::sync::{Arc, Mutex};
-
-// A list, treated as a stack to keep track of labels where they are needed, will only contain the last used number of the label
-// Assumes scheme .L1, .L2 etc
-// Is used f.ex to short circ && or ||
-lazy_static! {
-    static ref STACK: Arc<Mutex<Vec<i32>>> = {
-        let stack = Mutex::new(vec![1]);
-        Arc::new(stack)
-    };
-}
 
-impl ExprNode {
-    pub fn generate_assembly(&self) -> String {
-        match &self.expr {
-            Expr::Number(num) => format!("\n\tmov w0, #{}", num),
+// The AArch64 backend, following the calling convention of the AAPCS64 except that char is
+// signed, as it is on every target
+pub struct Aarch64;
 
-            Expr::UnaryOp(operator, expr) => match operator {
-                TokenType::Minus => {
-                    let expr_asm = expr.as_ref().unwrap().generate_assembly();
-                    format!("{}\n\tneg x0, x0", expr_asm)
-                }
-                TokenType::BitComplement => {
-                    let expr_asm = expr.as_ref().unwrap().generate_assembly();
-                    format!("{}\n\tmvn x0, x0", expr_asm)
-                }
-                TokenType::Not => {
-                    let expr_asm = expr.as_ref().unwrap().generate_assembly();
-                    format!(
-                        "{}\n\tcmp x0, #0
-                        \n\tmov x0, #0
-                        \n\tcset x0, eq",
-                        expr_asm
-                    )
-                }
+impl Backend for Aarch64 {
+    fn generate(&self, program: &IrProgram) -> Vec<Line> {
+        program.generate_assembly(&mut CodegenContext::new(program, &REGISTERS))
+    }
 
-                _ => "Unsupported operator".to_string(),
-            },
-            Expr::BinaryOp(operator, left_expr, right_expr) => {
-                match operator {
-                    TokenType::Plus => {
-                        let mut addition_asm = "".to_string();
-
-                        // Values will lie in x0
-                        let add_left_expr_asm = left_expr.generate_assembly();
-                        let add_right_expr_asm = right_expr.generate_assembly();
-
-                        // Store left_expr_asm on stack, will lie in x0,
-                        addition_asm += &format!(
-                            "{}\n\tsub sp, sp, #16
-                            \n\tstr x0, [sp, 12]{}
-                            \n\tldr x1, [sp, 12]
-                            \n\tadd x0, x0,x1
-                            \n\tadd sp, sp, 16",
-                            add_left_expr_asm, add_right_expr_asm
-                        );
-
-                        addition_asm
-                    }
-                    TokenType::Minus => {
-                        let mut subtraction_asm = "".to_string();
-
-                        let sub_left_expr_asm = left_expr.generate_assembly();
-                        let sub_right_expr_asm = right_expr.generate_assembly();
-
-                        subtraction_asm += &format!(
-                            "{}\n\tsub sp, sp, #16
-                            \n\tstr x0, [sp, 12]
-                            {}\n\tldr x1, [sp, 12]
-                            \n\tsub x0, x1, x0
-                            \n\tadd sp, sp, 16",
-                            sub_left_expr_asm, sub_right_expr_asm
-                        );
-
-                        subtraction_asm
-                    }
-                    TokenType::Mul => {
-                        let mut multiplication_asm = "".to_string();
-
-                        let mul_left_expr_asm = left_expr.generate_assembly();
-                        let mul_right_expr_asm = right_expr.generate_assembly();
-
-                        multiplication_asm += &format!(
-                            "{}\n\tsub sp, sp, #16
-                            \n\tstr x0, [sp, 12]\n\t{}
-                            \n\tldr x1, [sp, 12]
-                            \n\tmul x0, x1, x0
-                            \n\tadd sp, sp, 16",
-                            mul_left_expr_asm, mul_right_expr_asm
-                        );
-
-                        multiplication_asm
-                    }
+    fn optimize(&self, code: Vec<Line>) -> Vec<Line> {
+        peephole::optimize(code)
+    }
+}
 
-                    TokenType::Div => {
-                        let mut division_asm = "".to_string();
+// Registers by number, named w or x by register_name. x0-x8 are left as scratch registers
+// for the code generator, as are x16-x17 which spilled values go through
+const REGISTERS: Registers = Registers {
+    names: &[
+        "9", "10", "11", "12", "13", "14", "15", "19", "20", "21", "22", "23", "24", "25", "26",
+        "27", "28",
+    ],
+    first_callee_saved: 7,
+    scratch: ["16", "17"],
+    load: load_spill,
+    store: store_spill,
+};
 
-                        let div_left_expr_asm = left_expr.generate_assembly();
-                        let div_right_expr_as = right_expr.generate_assembly();
+fn load_spill(code: &mut Vec<Line>, register: &str, offset: usize) {
+    frame_access(code, "ldr", &format!("x{}", register), offset);
+}
 
-                        division_asm +=
-                            &format!("{}\n\tsub sp, sp, #16\n\tstr x0, [sp, 12]\n\t{}\n\tldr x1, [sp, 12]\n\tsdiv x0, x1, x0\n\tadd sp, sp, 16", div_left_expr_asm, div_right_expr_as);
+fn store_spill(code: &mut Vec<Line>, register: &str, offset: usize) {
+    frame_access(code, "str", &format!("x{}", register), offset);
+}
 
-                        division_asm
-                    }
+// The register holding vreg, named for its type
+fn read(ctx: &CodegenContext, vreg: VReg, scratch: usize, code: &mut Vec<Line>) -> String {
+    register_name(ctx.read(vreg, scratch, code), ctx.ty(vreg))
+}
 
-                    TokenType::Or => {
-                        let mut or_asm = "".to_string();
-
-                        let or_left_expr_asm = left_expr.generate_assembly();
-                        let or_right_expr_asm = right_expr.generate_assembly();
-
-                        let mut stack = STACK.lock().unwrap();
-
-                        // Bør returnere 1 i første omgang
-                        let stack_top = stack.pop();
-                        let free_label = stack_top.unwrap() + 1; // 2 for første label
-
-                        or_asm += &format!(
-                            "{}
-                            \n\tsub sp, sp, #16
-                            \n\tstr x0, [sp, 12]
-                            \n\t{}
-                            \n\tldr x1, [sp, 12]
-                            \n\tcmp x1, 0
-                            \n\tbne .L{} 
-                            \n\tcmp x0, 0
-                            \n\tbeq .L{}
-                            \n.L{}:
-                            \n\tmov x0, 1
-                            \n\tb   .L{}
-                            \n.L{}:
-                            \n\tmov x0, 0
-                            \n.L{}:
-                            \n\tadd sp, sp, 16",
-                            or_left_expr_asm,
-                            or_right_expr_asm,
-                            free_label,
-                            free_label + 1,
-                            free_label,
-                            free_label + 2,
-                            free_label + 1,
-                            free_label + 2
-                        );
-
-                        stack.push(free_label + 2); // Vil være sist brukte label
-                        or_asm
-                    }
+// The register to compute vreg in, named for its type
+fn target(ctx: &CodegenContext, vreg: VReg) -> String {
+    register_name(ctx.target(vreg), ctx.ty(vreg))
+}
 
-                    TokenType::And => {
-                        let mut and_asm = "".to_string();
-
-                        let and_left_expr_asm = left_expr.generate_assembly();
-                        let and_right_expr_asm = right_expr.generate_assembly();
-
-                        let mut stack = STACK.lock().unwrap();
-
-                        let stack_top = stack.pop();
-                        let free_label = stack_top.unwrap() + 1; // 5 i første omgang, kanskje
-
-                        and_asm += &format!(
-                            "{}
-                            \n\tsub sp, sp, #16
-                            \n\tstr x0, [sp, 12]
-                            \n\t{}
-                            \n\tldr x1, [sp, 12]
-                            \n\tcmp x1, 0
-                            \n\tbeq .L{}
-                            \n\tcmp x0, 0
-                            \n\tbeq .L{}
-                            \n\tmov x0, 1
-                            \n\tb   .L{}
-                            \n.L{}:
-                            \n\tmov w0, 0
-                            \n.L{}:
-                            \n\tadd sp, sp, 16",
-                            and_left_expr_asm,
-                            and_right_expr_asm,
-                            free_label,
-                            free_label,
-                            free_label + 1,
-                            free_label,
-                            free_label + 1
-                        );
-
-                        stack.push(free_label + 1);
-                        and_asm
-                    }
-                    TokenType::Eq => {
-                        let mut equal_asm = "".to_string();
-
-                        let equal_left_expr_asm = left_expr.generate_assembly();
-                        let equal_right_expr_asm = right_expr.generate_assembly();
-
-                        equal_asm += &format!(
-                            "
-                        {}
-                        \n\tsub sp, sp, #16
-                        \n\tstr x0, [sp, 12]
-                        \n\t{}
-                        \n\tldr x1, [sp, 12]
-                        \n\tcmp x1, x0
-                        \n\tcset x0, eq
-                        \n\tadd sp, sp, 16",
-                            equal_left_expr_asm, equal_right_expr_asm
-                        );
-
-                        equal_asm
-                    }
-                    TokenType::Neq => {
-                        let mut not_equal_asm = "".to_string();
-
-                        let not_equal_left_expr_asm = left_expr.generate_assembly();
-                        let not_equal_right_expr_asm = right_expr.generate_assembly();
-
-                        not_equal_asm += &format!(
-                            "
-                        {}
-                        \n\tsub sp, sp, #16
-                        \n\tstr x0, [sp, 12]
-                        \n\t{}
-                        \n\tldr x1, [sp, 12]
-                        \n\tcmp x0, x1
-                        \n\tcset x0, ne
-                        \n\tadd sp, sp , 16",
-                            not_equal_left_expr_asm, not_equal_right_expr_asm
-                        );
-
-                        not_equal_asm
-                    }
-                    TokenType::Lt => {
-                        let mut less_than_asm = "".to_string();
-
-                        let less_than_left_expr_asm = left_expr.generate_assembly();
-                        let less_than_right_expr_asm = right_expr.generate_assembly();
-
-                        let mut stack = STACK.lock().unwrap();
-
-                        let stack_top = stack.pop();
-                        let free_label = stack_top.unwrap() + 1;
-
-                        less_than_asm += &format!(
-                            "
-                        {}
-                        \n\tsub sp, sp, 16
-                        \n\tstr x0, [sp, 12]
-                        \n\t{}
-                        \n\tldr x1, [sp, 12]
-                        \n\tcmp x1, x0
-                        \n\tblt .L{}
-                        \n\tmov w0, 0
-                        \n\t b .L{}
-                        \n.L{}:
-                        \n\tmov w0, 1
-                        \n.L{}:
-                        \n\tadd sp, sp, 16",
-                            less_than_left_expr_asm,
-                            less_than_right_expr_asm,
-                            free_label,
-                            free_label + 1,
-                            free_label,
-                            free_label + 1
-                        );
-
-                        stack.push(free_label + 1);
-                        less_than_asm
-                    }
-                    TokenType::Gt => {
-                        let mut greater_than_asm = "".to_string();
-
-                        let greater_than_left_expr_asm = left_expr.generate_assembly();
-                        let greater_than_right_expr_asm = right_expr.generate_assembly();
-                        let mut stack = STACK.lock().unwrap();
-
-                        let stack_top = stack.pop();
-                        let free_label = stack_top.unwrap() + 1;
-
-                        greater_than_asm += &format!(
-                            "
-                        {}
-                        \n\tsub sp, sp, 16
-                        \n\tstr x0, [sp, 12]
-                        \n\t{}
-                        \n\tldr x1, [sp, 12]
-                        \n\tcmp x1, x0
-                        \n\tbgt .L{}
-                        \n\tmov w0, 0
-                        \n\t b .L{}
-                        \n.L{}:
-                        \n\tmov w0, 1
-                        \n.L{}:
-                        \n\tadd sp, sp, 16",
-                            greater_than_left_expr_asm,
-                            greater_than_right_expr_asm,
-                            free_label,
-                            free_label + 1,
-                            free_label,
-                            free_label + 1
-                        );
-                        stack.push(free_label + 1);
-                        greater_than_asm
-                    }
-                    TokenType::Le => {
-                        let mut less_eq_than_asm = "".to_string();
-
-                        let less_eq_than_left_expr_asm = left_expr.generate_assembly();
-                        let less_eq_than_right_expr_asm = right_expr.generate_assembly();
-                        let mut stack = STACK.lock().unwrap();
-
-                        let stack_top = stack.pop();
-                        let free_label = stack_top.unwrap() + 1;
-
-                        less_eq_than_asm += &format!(
-                            "
-                        {}
-                        \n\tsub sp, sp, #16
-                        \n\tstr x0, [sp, 16]
-                        \n\t{}
-                        \n\tldr x1, [sp, 16]
-                        \n\tcmp x1, x0
-                        \n\tble .L{}
-                        \n\tmov x0, 0
-                        \n\tb   .L{}
-                        \n.L{}:
-                        \n\tmov x0, 1
-                        \n.L{}:
-                        \n\tadd sp, sp, 16",
-                            less_eq_than_left_expr_asm,
-                            less_eq_than_right_expr_asm,
-                            free_label,
-                            free_label + 1,
-                            free_label,
-                            free_label + 1
-                        );
-
-                        stack.push(free_label + 1);
-                        less_eq_than_asm
-                    }
-                    TokenType::Ge => {
-                        let mut greater_eq_than_asm = "".to_string();
-
-                        let greater_eq_than_left_expr_asm = left_expr.generate_assembly();
-                        let greater_eq_than_right_expr_asm = right_expr.generate_assembly();
-                        let mut stack = STACK.lock().unwrap();
-
-                        let stack_top = stack.pop();
-                        let free_label = stack_top.unwrap() + 1;
-
-                        greater_eq_than_asm += &format!(
-                            "
-                        {}
-                        \n\tsub sp, sp, #16
-                        \n\tstr x0, [sp, 16]
-                        \n\t{}
-                        \n\tldr x1, [sp, 16]
-                        \n\tcmp x1, x0
-                        \n\tbge .L{}
-                        \n\tmov x0, 0
-                        \n\tb   .L{}
-                        \n.L{}:
-                        \n\tmov x0, 1
-                        \n.L{}:
-                        \n\tadd sp, sp, 16",
-                            greater_eq_than_left_expr_asm,
-                            greater_eq_than_right_expr_asm,
-                            free_label,
-                            free_label + 1,
-                            free_label,
-                            free_label + 1
-                        );
-
-                        stack.push(free_label + 1);
-                        greater_eq_than_asm
-                    }
-                    _ => format!("Unsupported operator: {}", operator),
-                }
-            }
+// w names the low 32 bits of a register, x all 64
+fn register_name(number: &str, ty: IrType) -> String {
+    match ty {
+        IrType::Ptr => format!("x{}", number),
+        _ => format!("w{}", number),
+    }
+}
+
+// mov only takes 16 bit immediates, wider values are built 16 bits at a time
+fn load_immediate(code: &mut Vec<Line>, register: &str, value: i64) {
+    if (-0xFFFF..=0xFFFF).contains(&value) {
+        emit(code, "mov", &[register, &format!("#{}", value)]);
+        return;
+    }
+
+    // A w register only has the low 32 bits to fill
+    let (bits, shifts) = if register.starts_with('w') {
+        (value as u32 as u64, &[16][..])
+    } else {
+        (value as u64, &[16, 32, 48][..])
+    };
+    emit(code, "movz", &[register, &format!("#{}", bits & 0xFFFF)]);
+    for shift in shifts {
+        let chunk = (bits >> shift) & 0xFFFF;
+        if chunk != 0 {
+            emit(
+                code,
+                "movk",
+                &[register, &format!("#{}", chunk), &format!("lsl {}", shift)],
+            );
         }
     }
 }
 
-impl ProgramNode {
-    pub fn generate_assembly(&self) -> String {
-        let mut program_body_asm = "\t.text\n".to_string(); // Boilerplate to define texe-section of prog
+// Puts the address x29 - offset in register
+fn frame_address(code: &mut Vec<Line>, register: &str, offset: usize) {
+    if offset <= 4095 {
+        emit(code, "sub", &[register, "x29", &format!("#{}", offset)]);
+    } else {
+        load_immediate(code, register, offset as i64);
+        emit(code, "sub", &[register, "x29", register]);
+    }
+}
+
+// Loads or stores register at x29 - offset, going through x8 when the offset is out of reach
+fn frame_access(code: &mut Vec<Line>, instruction: &str, register: &str, offset: usize) {
+    if offset <= 256 {
+        emit(
+            code,
+            instruction,
+            &[register, &format!("[x29, -{}]", offset)],
+        );
+    } else {
+        frame_address(code, "x8", offset);
+        emit(code, instruction, &[register, "[x8]"]);
+    }
+}
+
+// Grows or shrinks the stack by size bytes, x16 holds sizes too wide for an immediate
+fn adjust_stack(code: &mut Vec<Line>, instruction: &str, size: usize) {
+    if size <= 4095 {
+        emit(code, instruction, &["sp", "sp", &format!("#{}", size)]);
+    } else {
+        load_immediate(code, "x16", size as i64);
+        emit(code, instruction, &["sp", "sp", "x16"]);
+    }
+}
 
-        // Prepending function names
-        for function_name in self.function_names.clone() {
-            program_body_asm += &format!("\t.global _{}\n", function_name);
+// Copies size bytes from the address in x2 to the address in x3, eight at a time and then
+// the remainder
+fn copy_asm(ctx: &mut CodegenContext, code: &mut Vec<Line>, size: usize) {
+    let words = size / 8;
+    if words > 0 {
+        let loop_label = ctx.reserve_labels(1);
+        load_immediate(code, "x4", words as i64);
+        label(code, loop_label);
+        emit(code, "ldr", &["x5", "[x2]", "8"]);
+        emit(code, "str", &["x5", "[x3]", "8"]);
+        emit(code, "subs", &["x4", "x4", "1"]);
+        emit(code, "bne", &[&format!(".L{}", loop_label)]);
+    }
+    for _ in 0..size % 8 {
+        emit(code, "ldrb", &["w5", "[x2]", "1"]);
+        emit(code, "strb", &["w5", "[x3]", "1"]);
+    }
+}
+
+// Clears size bytes starting at the address in x1, eight at a time and then the remainder
+fn zero_fill_asm(ctx: &mut CodegenContext, code: &mut Vec<Line>, size: usize) {
+    let words = size / 8;
+    if words > 0 {
+        let loop_label = ctx.reserve_labels(1);
+        load_immediate(code, "x2", words as i64);
+        label(code, loop_label);
+        emit(code, "str", &["xzr", "[x1]", "8"]);
+        emit(code, "subs", &["x2", "x2", "1"]);
+        emit(code, "bne", &[&format!(".L{}", loop_label)]);
+    }
+    for _ in 0..size % 8 {
+        emit(code, "strb", &["wzr", "[x1]", "1"]);
+    }
+}
+
+impl IrProgram {
+    pub fn generate_assembly(&self, ctx: &mut CodegenContext) -> Vec<Line> {
+        let mut code = vec![];
+        directive(&mut code, ".text"); // Boilerplate to define texe-section of prog
+
+        // Prepending function names, static functions stay local to the file
+        for function in &self.functions {
+            if !function.is_static {
+                directive(&mut code, &format!(".global {}", function.name));
+            }
         }
 
         // Generating assembly for instructions
-        for function in self.body.clone() {
-            program_body_asm += &format!("\n_{}:{}\n", function.name, function.generate_assembly());
+        for function in &self.functions {
+            code.push(Line::Label(function.name.clone()));
+            function.generate_assembly(ctx, &mut code);
+        }
+
+        ctx.emit_rodata(&mut code);
+
+        for global in &self.globals {
+            global_asm(&mut code, global, ".xword");
         }
 
-        program_body_asm
+        code
     }
 }
 
-impl FunctionNode {
-    pub fn generate_assembly(&self) -> String {
-        let mut function_body_asm = "".to_string();
-        for statement in &self.body {
-            function_body_asm += &format!("\n\t{}", statement.generate_assembly());
+impl IrFunction {
+    pub fn generate_assembly(&self, ctx: &mut CodegenContext, code: &mut Vec<Line>) {
+        ctx.enter_function(self);
+
+        // Prologue, x29 points just above the saved registers and the locals
+        emit(code, "stp", &["x29", "x30", "[sp, -16]!"]);
+        emit(code, "mov", &["x29", "sp"]);
+        if ctx.frame.size > 0 {
+            adjust_stack(code, "sub", ctx.frame.size);
+        }
+        for (register, offset) in &ctx.frame.saved_registers {
+            frame_access(
+                code,
+                "str",
+                &format!("x{}", REGISTERS.names[*register]),
+                *offset,
+            );
         }
 
-        function_body_asm
+        for (index, block) in self.blocks.iter().enumerate() {
+            code.push(Line::Label(ctx.block_label(BlockId(index))));
+            for instr in &block.instrs {
+                instr.generate_assembly(ctx, code);
+            }
+            block.terminator.generate_assembly(ctx, code);
+        }
+
+        // Epilogue, every return jumps here with its value in w0
+        label(code, ctx.frame.epilogue_label);
+        release_frame(ctx, code);
+        emit(code, "ret", &[]);
+    }
+}
+
+// Restores the saved registers and pops the frame, leaving x30 as the caller left it
+fn release_frame(ctx: &CodegenContext, code: &mut Vec<Line>) {
+    for (register, offset) in &ctx.frame.saved_registers {
+        frame_access(
+            code,
+            "ldr",
+            &format!("x{}", REGISTERS.names[*register]),
+            *offset,
+        );
+    }
+    emit(code, "mov", &["sp", "x29"]);
+    emit(code, "ldp", &["x29", "x30", "[sp]", "16"]);
+}
+
+// Arguments go in x0-x7 in order. Their values never live there, so they can be moved in one
+// at a time
+fn pass_arguments(ctx: &CodegenContext, code: &mut Vec<Line>, args: &[VReg]) {
+    for (index, arg) in args.iter().enumerate() {
+        let value = read(ctx, *arg, 0, code);
+        let register = register_name(&index.to_string(), ctx.frame.vregs[arg.0]);
+        emit(code, "mov", &[&register, &value]);
     }
 }
 
-impl StatementNode {
-    pub fn generate_assembly(&self) -> String {
-        match &self.statement {
-            Statement::Return(expr_node) => {
-                let expr_asm = expr_node.generate_assembly();
-                format!("{}\n\tret", expr_asm)
+impl Instr {
+    pub fn generate_assembly(&self, ctx: &mut CodegenContext, code: &mut Vec<Line>) {
+        match self {
+            Instr::Const { dst, value } => {
+                load_immediate(code, &target(ctx, *dst), *value);
             }
 
-            Statement::Assignment(
-                TokenType::IntKeyword,
-                TokenType::Identifier,
-                TokenType::Assign,
-                expr_node,
-            ) => {
-                let expr_asm = expr_node.as_ref().unwrap().generate_assembly();
-                format!(
-                    "\n\tsub sp, sp, #16
-                    \n\t{}
-                    \n\t
-                    str x0, [sp,12]
-                    \n\tmov x0, 0
-                    \n\tadd sp, sp, 16\n\t",
-                    expr_asm
-                )
+            Instr::Copy { dst, src } => {
+                let src = read(ctx, *src, 0, code);
+                emit(code, "mov", &[&target(ctx, *dst), &src]);
             }
-            Statement::Assignment(
-                TokenType::CharKeyword,
-                TokenType::Identifier,
-                TokenType::Assign,
-                expr_node,
-            ) => {
-                let expr_asm = expr_node.as_ref().unwrap().generate_assembly();
-                format!(
-                    "\n\tsub sp, sp, #16
-                    \n\t{}
-                    \n\t
-                    str x0, [sp,12]
-                    \n\tmov x0, 0
-                    \n\tadd sp, sp, 16\n\t",
-                    expr_asm
-                )
+
+            Instr::Unary { op, dst, src } => {
+                let src = read(ctx, *src, 0, code);
+                let instruction = match op {
+                    UnaryOp::Neg => "neg",
+                    UnaryOp::Not => "mvn",
+                };
+                emit(code, instruction, &[&target(ctx, *dst), &src]);
             }
-            _ => "Unsupported statement".to_string(),
+
+            Instr::Binary { op, dst, lhs, rhs } => {
+                let lhs_name = read(ctx, *lhs, 0, code);
+                let rhs_name = read(ctx, *rhs, 1, code);
+                let dst_name = target(ctx, *dst);
+
+                // Addresses compare unsigned, integers signed
+                let is_address = ctx.frame.vregs[lhs.0] == IrType::Ptr;
+                let condition = match op {
+                    BinaryOp::Eq => "eq",
+                    BinaryOp::Ne => "ne",
+                    BinaryOp::Lt if is_address => "lo",
+                    BinaryOp::Le if is_address => "ls",
+                    BinaryOp::Gt if is_address => "hi",
+                    BinaryOp::Ge if is_address => "hs",
+                    BinaryOp::Lt => "lt",
+                    BinaryOp::Le => "le",
+                    BinaryOp::Gt => "gt",
+                    BinaryOp::Ge => "ge",
+                    _ => "",
+                };
+
+                match op {
+                    BinaryOp::Add => emit(code, "add", &[&dst_name, &lhs_name, &rhs_name]),
+                    BinaryOp::Sub => emit(code, "sub", &[&dst_name, &lhs_name, &rhs_name]),
+                    BinaryOp::Mul => emit(code, "mul", &[&dst_name, &lhs_name, &rhs_name]),
+                    BinaryOp::Div => emit(code, "sdiv", &[&dst_name, &lhs_name, &rhs_name]),
+                    _ => {
+                        emit(code, "cmp", &[&lhs_name, &rhs_name]);
+                        emit(code, "cset", &[&dst_name, condition]);
+                    }
+                }
+            }
+
+            Instr::Convert {
+                conversion,
+                dst,
+                src,
+            } => {
+                let src = read(ctx, *src, 0, code);
+                let dst_name = target(ctx, *dst);
+                match conversion {
+                    Conversion::Sext => emit(code, "sxtw", &[&dst_name, &src]),
+                    Conversion::Trunc => {
+                        emit(code, "mov", &[&dst_name, &src.replacen('x', "w", 1)])
+                    }
+                    Conversion::SextI8 => emit(code, "sxtb", &[&dst_name, &src]),
+                }
+            }
+
+            Instr::FrameAddr { dst, slot } => {
+                frame_address(code, &target(ctx, *dst), ctx.frame.slot_offsets[*slot]);
+            }
+
+            // The page of the symbol, then its offset within the page
+            Instr::GlobalAddr { dst, symbol } => {
+                let dst_name = target(ctx, *dst);
+                let symbol = elf_symbol(symbol);
+                emit(code, "adrp", &[&dst_name, &symbol]);
+                emit(
+                    code,
+                    "add",
+                    &[&dst_name, &dst_name, &format!(":lo12:{}", symbol)],
+                );
+            }
+
+            // Chars are signed and sign extended to 32 bits, like C promotes them
+            Instr::Load { dst, ty, addr } => {
+                let addr = read(ctx, *addr, 0, code);
+                let instruction = match ty {
+                    IrType::I8 => "ldrsb",
+                    _ => "ldr",
+                };
+                emit(
+                    code,
+                    instruction,
+                    &[&target(ctx, *dst), &format!("[{}]", addr)],
+                );
+            }
+
+            Instr::Store { ty, addr, value } => {
+                let addr = read(ctx, *addr, 0, code);
+                let value = read(ctx, *value, 1, code);
+                let instruction = match ty {
+                    IrType::I8 => "strb",
+                    _ => "str",
+                };
+                emit(code, instruction, &[&value, &format!("[{}]", addr)]);
+            }
+
+            Instr::MemCopy { dst, src, size } => {
+                let dst = read(ctx, *dst, 0, code);
+                let src = read(ctx, *src, 1, code);
+                emit(code, "mov", &["x3", &dst]);
+                emit(code, "mov", &["x2", &src]);
+                copy_asm(ctx, code, *size);
+            }
+
+            Instr::MemZero { dst, size } => {
+                let dst = read(ctx, *dst, 0, code);
+                emit(code, "mov", &["x1", &dst]);
+                zero_fill_asm(ctx, code, *size);
+            }
+
+            Instr::Param { dst, index } => {
+                let ty = ctx.frame.vregs[dst.0];
+                emit(
+                    code,
+                    "mov",
+                    &[&target(ctx, *dst), &register_name(&index.to_string(), ty)],
+                );
+            }
+
+            // The result comes back in w0, or x0 for a pointer
+            Instr::Call {
+                dst,
+                function,
+                args,
+            } => {
+                pass_arguments(ctx, code, args);
+                emit(code, "bl", &[function]);
+                let result = register_name("0", ctx.ty(*dst));
+                emit(code, "mov", &[&target(ctx, *dst), &result]);
+            }
+
+            Instr::Phi { .. } => panic!("Phi left for code generation, out-of-ssa did not run"),
+        }
+
+        if let Some(dst) = self.dst() {
+            ctx.write_back(dst, code);
         }
     }
 }
 
-pub struct Generator {
-    root: ProgramNode,
-}
+impl Terminator {
+    pub fn generate_assembly(&self, ctx: &mut CodegenContext, code: &mut Vec<Line>) {
+        match self {
+            Terminator::Jump(block) => {
+                emit(code, "b", &[&ctx.block_label(*block)]);
+            }
 
-impl Generator {
-    pub fn new(root_node: ProgramNode) -> Self {
-        Generator { root: root_node }
-    }
+            Terminator::Branch {
+                condition,
+                then,
+                otherwise,
+            } => {
+                let condition = read(ctx, *condition, 0, code);
+                emit(code, "cbnz", &[&condition, &ctx.block_label(*then)]);
+                emit(code, "b", &[&ctx.block_label(*otherwise)]);
+            }
 
-    pub fn walk_da_tree(&self) -> String {
-        let mut assembly = "".to_string();
-        assembly += &self.root.generate_assembly();
+            Terminator::Switch {
+                value,
+                cases,
+                default,
+            } => {
+                let value = read(ctx, *value, 0, code);
+                switch_asm(ctx, code, &value, cases, *default);
+            }
 
-        assembly
+            Terminator::Return(value) => {
+                let result = register_name("0", ctx.ty(*value));
+                let value = read(ctx, *value, 0, code);
+                emit(code, "mov", &[&result, &value]);
+                emit(code, "b", &[&format!(".L{}", ctx.frame.epilogue_label)]);
+            }
+
+            // The callee returns straight to our caller, through the x30 we got
+            Terminator::TailCall { function, args } => {
+                pass_arguments(ctx, code, args);
+                release_frame(ctx, code);
+                emit(code, "b", &[function]);
+            }
+        }
+    }
+}
+
+// Jumps to the block of the case matching the 32 bit value. Dense cases index a table of
+// offsets in .rodata, sparse ones are compared one at a time
+fn switch_asm(
+    ctx: &mut CodegenContext,
+    code: &mut Vec<Line>,
+    value: &str,
+    cases: &[(i64, BlockId)],
+    default: BlockId,
+) {
+    let default_label = ctx.block_label(default);
+
+    if let Some(table_range @ (min, range)) = jump_table_range(cases) {
+        let table_label = ctx.reserve_labels(2);
+        let base_label = table_label + 1;
+        let table = format!(".L{}", table_label);
+        let base = format!(".L{}", base_label);
+
+        // Rebase to 0, anything outside the table is unsigned above its last index.
+        // Writing w16 clears the upper half of x16, so it can index the table as is
+        load_immediate(code, "w17", min);
+        emit(code, "sub", &["w16", value, "w17"]);
+        load_immediate(code, "w17", range - 1);
+        emit(code, "cmp", &["w16", "w17"]);
+        emit(code, "b.hi", &[&default_label]);
+
+        // The entries are offsets from the adr, so the table needs no relocations
+        emit(code, "adrp", &["x17", &table]);
+        emit(code, "add", &["x17", "x17", &format!(":lo12:{}", table)]);
+        emit(code, "ldrsw", &["x17", "[x17, x16, lsl 2]"]);
+        label(code, base_label);
+        emit(code, "adr", &["x16", &base]);
+        emit(code, "add", &["x17", "x16", "x17"]);
+        emit(code, "br", &["x17"]);
+
+        directive(&mut ctx.rodata, ".balign 4");
+        label(&mut ctx.rodata, table_label);
+        for target in ctx.jump_table_targets(cases, table_range, default) {
+            directive(&mut ctx.rodata, &format!(".word {} - {}", target, base));
+        }
+    } else {
+        for (case_value, block) in cases {
+            load_immediate(code, "w17", *case_value);
+            emit(code, "cmp", &[value, "w17"]);
+            emit(code, "b.eq", &[&ctx.block_label(*block)]);
+        }
+        emit(code, "b", &[&default_label]);
     }
 }
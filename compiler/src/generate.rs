@@ -0,0 +1,517 @@
+// Random programs for differential fuzzing, in the spirit of Csmith but limited to what the
+// compiler supports. The programs are well typed and kept free of undefined behaviour apart
+// from int overflow, which wraps in our code and with gcc -fwrapv: every variable is
+// initialized, indexes stay inside their arrays, pointers point to live objects, divisors are
+// nonzero constants, loops count up to a small bound and functions only call the ones defined
+// before them. Only main changes globals, the order a call and the other operands of an
+// expression are evaluated in is up to the compiler and must not matter. Every statement is on
+// a line of its own and a loop keeps its counter in its head, so removing lines while reducing
+// a failing program cannot make it loop forever
+
+// xorshift64*, small and the same on every machine, so a seed always gives the same program
+pub struct Random(u64);
+
+impl Random {
+    pub fn new(seed: u64) -> Self {
+        // The state must never be zero
+        Random(seed.wrapping_mul(0x9e37_79b9_7f4a_7c15) | 1)
+    }
+
+    pub fn next_u64(&mut self) -> u64 {
+        self.0 ^= self.0 >> 12;
+        self.0 ^= self.0 << 25;
+        self.0 ^= self.0 >> 27;
+        self.0.wrapping_mul(0x2545_f491_4f6c_dd1d)
+    }
+
+    // A number in 0..bound
+    pub fn below(&mut self, bound: usize) -> usize {
+        (self.next_u64() % bound as u64) as usize
+    }
+
+    pub fn chance(&mut self, percent: usize) -> bool {
+        self.below(100) < percent
+    }
+
+    pub fn pick<'a, T>(&mut self, items: &'a [T]) -> &'a T {
+        &items[self.below(items.len())]
+    }
+}
+
+const MAX_FUNCTIONS: usize = 4;
+const MAX_PARAMS: usize = 3;
+const MAX_STATEMENTS: usize = 6; // Per block
+const MAX_BLOCK_DEPTH: usize = 3;
+const MAX_EXPRESSION_DEPTH: usize = 4;
+const MAX_LOOP_COUNT: usize = 4;
+const MAX_LOOP_DEPTH: usize = 2;
+
+#[derive(Clone, PartialEq)]
+enum Kind {
+    Int,
+    Char,
+    Array(usize), // Of ints
+    Pair,         // struct pair
+    Pointer,      // To an int
+}
+
+#[derive(Clone)]
+struct Variable {
+    name: String,
+    kind: Kind,
+}
+
+// A loop counter and the number of iterations, it can index arrays at least that long
+#[derive(Clone)]
+struct Counter {
+    name: String,
+    count: usize,
+}
+
+struct ProgramGenerator {
+    random: Random,
+    out: String,
+    scopes: Vec<Vec<Variable>>, // Globals first
+    counters: Vec<Counter>,
+    functions: Vec<(String, usize)>, // Callable functions and their number of parameters
+    next_name: usize,
+    indent: usize,
+    in_main: bool,
+}
+
+// The C source of the program for the seed
+pub fn generate_program(seed: u64) -> String {
+    let mut generator = ProgramGenerator {
+        random: Random::new(seed),
+        out: "".to_string(),
+        scopes: vec![vec![]],
+        counters: vec![],
+        functions: vec![],
+        next_name: 0,
+        indent: 0,
+        in_main: false,
+    };
+    generator.program();
+    generator.out
+}
+
+impl ProgramGenerator {
+    fn fresh_name(&mut self, prefix: &str) -> String {
+        self.next_name += 1;
+        format!("{}{}", prefix, self.next_name)
+    }
+
+    fn line(&mut self, text: &str) {
+        self.out += &format!("{}{}\n", "    ".repeat(self.indent), text);
+    }
+
+    // The variables that may be changed, or have their address taken
+    fn assignable(&self) -> Vec<Variable> {
+        let skip = if self.in_main { 0 } else { 1 };
+        self.scopes[skip..].concat()
+    }
+
+    fn declare(&mut self, name: String, kind: Kind) {
+        self.scopes
+            .last_mut()
+            .expect("No open scope")
+            .push(Variable { name, kind });
+    }
+
+    fn program(&mut self) {
+        self.line("struct pair { int first; char second; int third; };");
+        self.line("");
+
+        for _ in 0..1 + self.random.below(4) {
+            self.global();
+        }
+        self.line("");
+
+        for _ in 0..self.random.below(MAX_FUNCTIONS) {
+            self.function(false);
+            self.line("");
+        }
+        self.function(true);
+    }
+
+    fn global(&mut self) {
+        let name = self.fresh_name("g");
+        let static_keyword = if self.random.chance(30) {
+            "static "
+        } else {
+            ""
+        };
+        match self.random.below(4) {
+            0 => {
+                let value = self.constant();
+                self.line(&format!("{}int {} = {};", static_keyword, name, value));
+                self.declare(name, Kind::Int);
+            }
+            1 => {
+                let value = self.random.below(128);
+                self.line(&format!("{}char {} = {};", static_keyword, name, value));
+                self.declare(name, Kind::Char);
+            }
+            2 => {
+                let length = 1 + self.random.below(MAX_LOOP_COUNT + 2);
+                let values: Vec<String> = (0..length).map(|_| self.constant()).collect();
+                self.line(&format!(
+                    "{}int {}[{}] = {{{}}};",
+                    static_keyword,
+                    name,
+                    length,
+                    values.join(", ")
+                ));
+                self.declare(name, Kind::Array(length));
+            }
+            _ => {
+                let (first, second, third) =
+                    (self.constant(), self.random.below(128), self.constant());
+                self.line(&format!(
+                    "{}struct pair {} = {{{}, {}, {}}};",
+                    static_keyword, name, first, second, third
+                ));
+                self.declare(name, Kind::Pair);
+            }
+        }
+    }
+
+    fn function(&mut self, is_main: bool) {
+        let (name, params) = if is_main {
+            ("main".to_string(), 0)
+        } else {
+            (self.fresh_name("f"), self.random.below(MAX_PARAMS + 1))
+        };
+
+        self.in_main = is_main;
+        self.scopes.push(vec![]);
+        let mut declarations = vec![];
+        for _ in 0..params {
+            let param = self.fresh_name("p");
+            let kind = if self.random.chance(25) {
+                Kind::Char
+            } else {
+                Kind::Int
+            };
+            let type_name = if kind == Kind::Char { "char" } else { "int" };
+            declarations.push(format!("{} {}", type_name, param));
+            self.declare(param, kind);
+        }
+
+        let static_keyword = if !is_main && self.random.chance(50) {
+            "static "
+        } else {
+            ""
+        };
+        self.line(&format!(
+            "{}int {}({}) {{",
+            static_keyword,
+            name,
+            declarations.join(", ")
+        ));
+        self.indent += 1;
+        self.statements(0);
+        if is_main {
+            self.checksum();
+        } else {
+            let value = self.expression(0);
+            self.line(&format!("return {};", value));
+        }
+        self.indent -= 1;
+        self.line("}");
+        self.scopes.pop();
+
+        self.functions.push((name, params));
+    }
+
+    // Main returns a sum over every object it can see, so that a wrong value anywhere shows in
+    // the exit code. The int values are added with their higher bytes, the exit code only
+    // keeps the lowest
+    fn checksum(&mut self) {
+        let mut values = vec![];
+        for variable in self.scopes.concat() {
+            match variable.kind {
+                Kind::Int => values.push(variable.name),
+                Kind::Char => values.push(variable.name),
+                Kind::Array(length) => {
+                    values.extend((0..length).map(|index| format!("{}[{}]", variable.name, index)))
+                }
+                Kind::Pair => {
+                    values.push(format!("{}.first", variable.name));
+                    values.push(format!("{}.second", variable.name));
+                    values.push(format!("{}.third", variable.name));
+                }
+                Kind::Pointer => values.push(format!("(*{})", variable.name)),
+            }
+        }
+
+        let checksum = self.fresh_name("checksum");
+        self.line(&format!("int {} = 0;", checksum));
+        for value in values {
+            self.line(&format!(
+                "{0} = ((({0} * 31) + {1}) + (({1} / 256) + ({1} / 65536)));",
+                checksum, value
+            ));
+        }
+        self.line(&format!("return {};", checksum));
+    }
+
+    fn statements(&mut self, depth: usize) {
+        for _ in 0..1 + self.random.below(MAX_STATEMENTS) {
+            self.statement(depth);
+        }
+    }
+
+    // The statements of a nested block, in a scope of their own
+    fn block(&mut self, depth: usize) {
+        self.indent += 1;
+        self.scopes.push(vec![]);
+        self.statements(depth + 1);
+        self.scopes.pop();
+        self.indent -= 1;
+    }
+
+    fn statement(&mut self, depth: usize) {
+        let nested = depth < MAX_BLOCK_DEPTH;
+        match self.random.below(10) {
+            0..=2 => self.declaration(),
+            3..=5 => self.assignment(),
+            6 if nested => {
+                let condition = self.expression(0);
+                self.line(&format!("if ({}) {{", condition));
+                self.block(depth);
+                if self.random.chance(50) {
+                    self.line("} else {");
+                    self.block(depth);
+                }
+                self.line("}");
+            }
+            7 if nested && self.counters.len() < MAX_LOOP_DEPTH => {
+                let counter = Counter {
+                    name: self.fresh_name("i"),
+                    count: 1 + self.random.below(MAX_LOOP_COUNT),
+                };
+                self.line(&format!(
+                    "for (int {0} = 0; {0} < {1}; {0} = {0} + 1) {{",
+                    counter.name, counter.count
+                ));
+                self.counters.push(counter);
+                self.block(depth);
+                self.counters.pop();
+                self.line("}");
+            }
+            8 if nested => {
+                let value = self.expression(0);
+                self.line(&format!("switch ({}) {{", value));
+                for case in 0..1 + self.random.below(3) {
+                    self.line(&format!("case {}:", case));
+                    self.block(depth);
+                    if self.random.chance(70) {
+                        self.line("    break;");
+                    }
+                }
+                if self.random.chance(50) {
+                    self.line("default:");
+                    self.block(depth);
+                }
+                self.line("}");
+            }
+            9 if depth > 0 && !self.in_main && self.random.chance(30) => {
+                let value = self.expression(0);
+                self.line(&format!("return {};", value));
+            }
+            _ => self.assignment(),
+        }
+    }
+
+    fn declaration(&mut self) {
+        let name = self.fresh_name("v");
+        match self.random.below(6) {
+            0 => {
+                let value = self.expression(0);
+                self.line(&format!("char {} = {};", name, value));
+                self.declare(name, Kind::Char);
+            }
+            1 => {
+                let length = 1 + self.random.below(MAX_LOOP_COUNT + 2);
+                let values: Vec<String> = (0..length).map(|_| self.expression(2)).collect();
+                self.line(&format!(
+                    "int {}[{}] = {{{}}};",
+                    name,
+                    length,
+                    values.join(", ")
+                ));
+                self.declare(name, Kind::Array(length));
+            }
+            2 => {
+                let (first, second, third) =
+                    (self.expression(2), self.expression(2), self.expression(2));
+                self.line(&format!(
+                    "struct pair {} = {{{}, {}, {}}};",
+                    name, first, second, third
+                ));
+                self.declare(name, Kind::Pair);
+            }
+            3 => {
+                let target = self.int_lvalue();
+                self.line(&format!("int *{} = &{};", name, target));
+                self.declare(name, Kind::Pointer);
+            }
+            _ => {
+                let value = self.expression(0);
+                self.line(&format!("int {} = {};", name, value));
+                self.declare(name, Kind::Int);
+            }
+        }
+    }
+
+    fn assignment(&mut self) {
+        let target = if self.random.chance(20) {
+            let chars: Vec<Variable> = self
+                .assignable()
+                .into_iter()
+                .filter(|variable| variable.kind == Kind::Char)
+                .collect();
+            match chars.as_slice() {
+                [] => self.int_lvalue(),
+                chars => self.random.pick(chars).name.clone(),
+            }
+        } else {
+            self.int_lvalue()
+        };
+        let value = self.expression(0);
+        self.line(&format!("{} = {};", target, value));
+    }
+
+    // An int object that can be assigned or have its address taken. A local int is declared
+    // first when there is none
+    fn int_lvalue(&mut self) -> String {
+        let mut choices = vec![];
+        for variable in self.assignable() {
+            match variable.kind {
+                Kind::Int => choices.push(variable.name.clone()),
+                Kind::Pair => {
+                    choices.push(format!("{}.first", variable.name));
+                    choices.push(format!("{}.third", variable.name));
+                }
+                Kind::Pointer => choices.push(format!("*{}", variable.name)),
+                Kind::Array(length) => {
+                    let index = self.index(length);
+                    choices.push(format!("{}[{}]", variable.name, index));
+                }
+                Kind::Char => {}
+            }
+        }
+
+        if choices.is_empty() {
+            let name = self.fresh_name("v");
+            let value = self.constant();
+            self.line(&format!("int {} = {};", name, value));
+            self.declare(name.clone(), Kind::Int);
+            return name;
+        }
+        self.random.pick(&choices).clone()
+    }
+
+    // A constant index, or a loop counter that stays below the length
+    fn index(&mut self, length: usize) -> String {
+        let counters: Vec<&Counter> = self
+            .counters
+            .iter()
+            .filter(|counter| counter.count <= length)
+            .collect();
+        if !counters.is_empty() && self.random.chance(60) {
+            return self.random.pick(&counters).name.clone();
+        }
+        self.random.below(length).to_string()
+    }
+
+    fn constant(&mut self) -> String {
+        match self.random.below(10) {
+            0 => self
+                .random
+                .pick(&["2147483647", "65535", "255", "128", "1000000"])
+                .to_string(),
+            1 => format!("-{}", self.random.below(100)),
+            _ => self.random.below(20).to_string(),
+        }
+    }
+
+    // An int valued expression, fully parenthesized so it means the same in every compiler
+    fn expression(&mut self, depth: usize) -> String {
+        if depth >= MAX_EXPRESSION_DEPTH || self.random.chance(25) {
+            return self.leaf();
+        }
+
+        match self.random.below(12) {
+            0..=3 => {
+                let operator = self
+                    .random
+                    .pick(&["+", "-", "*", "<", ">", "<=", ">=", "==", "!=", "&&", "||"]);
+                let left = self.expression(depth + 1);
+                let right = self.expression(depth + 1);
+                format!("({} {} {})", left, operator, right)
+            }
+            4 => {
+                let divisor = 2 + self.random.below(8);
+                format!("({} / {})", self.expression(depth + 1), divisor)
+            }
+            5 => {
+                let operator = self.random.pick(&["-", "!", "~"]);
+                format!("({} {})", operator, self.expression(depth + 1))
+            }
+            6 => format!(
+                "({} ? {} : {})",
+                self.expression(depth + 1),
+                self.expression(depth + 1),
+                self.expression(depth + 1)
+            ),
+            7 => {
+                let type_name = self.random.pick(&["char", "int"]);
+                format!("(({}) {})", type_name, self.expression(depth + 1))
+            }
+            8 if !self.functions.is_empty() => {
+                let (name, params) = self.random.pick(&self.functions).clone();
+                let args: Vec<String> = (0..params).map(|_| self.expression(depth + 1)).collect();
+                format!("{}({})", name, args.join(", "))
+            }
+            9 => format!(
+                "({}, {})",
+                self.expression(depth + 1),
+                self.expression(depth + 1)
+            ),
+            _ => self.leaf(),
+        }
+    }
+
+    // A constant or the value of an object in scope
+    fn leaf(&mut self) -> String {
+        let mut choices = vec![];
+        for variable in self.scopes.concat() {
+            match variable.kind {
+                Kind::Int | Kind::Char => choices.push(variable.name.clone()),
+                Kind::Pair => {
+                    let member = self.random.pick(&["first", "second", "third"]);
+                    choices.push(format!("{}.{}", variable.name, member));
+                }
+                Kind::Pointer => choices.push(format!("(*{})", variable.name)),
+                Kind::Array(length) => {
+                    let index = self.index(length);
+                    if self.random.chance(30) {
+                        choices.push(format!("(*({} + {}))", variable.name, index));
+                    } else {
+                        choices.push(format!("{}[{}]", variable.name, index));
+                    }
+                }
+            }
+        }
+        for counter in &self.counters {
+            choices.push(counter.name.clone());
+        }
+
+        if choices.is_empty() || self.random.chance(30) {
+            return self.constant();
+        }
+        self.random.pick(&choices).clone()
+    }
+}
@@ -0,0 +1,193 @@
+use std::collections::{HashMap, HashSet};
+
+use crate::ir::{Block, BlockId, Instr, IrFunction, IrProgram, Slot, Terminator, VReg};
+
+// Inlining of small functions, run on the IR from -O2. A call to a function defined in this
+// file that is small and can never end up calling itself is replaced by a copy of its body.
+// Static functions nobody calls afterwards are dropped, the others have to stay since other
+// files may call them
+
+// Instructions and terminators a function may have to be inlined
+const INLINE_LIMIT: usize = 40;
+
+// Inlining stops growing a function once it has this many instructions
+const GROWTH_LIMIT: usize = 2000;
+
+pub fn inline_functions(program: &mut IrProgram) {
+    let recursive = recursive_functions(program);
+    let candidates: HashMap<String, IrFunction> = program
+        .functions
+        .iter()
+        .filter(|function| size(function) <= INLINE_LIMIT && !recursive.contains(&function.name))
+        .map(|function| (function.name.clone(), function.clone()))
+        .collect();
+
+    for function in &mut program.functions {
+        while size(function) < GROWTH_LIMIT {
+            let Some((block, index, callee)) = find_inlinable_call(function, &candidates) else {
+                break;
+            };
+            inline_call(function, block, index, callee);
+        }
+    }
+
+    remove_uncalled_static_functions(program);
+}
+
+fn size(function: &IrFunction) -> usize {
+    function
+        .blocks
+        .iter()
+        .map(|block| block.instrs.len() + 1)
+        .sum()
+}
+
+fn callees(function: &IrFunction) -> Vec<&str> {
+    let mut callees = vec![];
+    for block in &function.blocks {
+        for instr in &block.instrs {
+            if let Instr::Call { function, .. } = instr {
+                callees.push(function.as_str());
+            }
+        }
+        if let Terminator::TailCall { function, .. } = &block.terminator {
+            callees.push(function.as_str());
+        }
+    }
+    callees
+}
+
+// Functions that can reach a call to themselves, directly or through others
+fn recursive_functions(program: &IrProgram) -> HashSet<String> {
+    let calls: HashMap<&str, Vec<&str>> = program
+        .functions
+        .iter()
+        .map(|function| (function.name.as_str(), callees(function)))
+        .collect();
+
+    let mut recursive = HashSet::new();
+    for function in &program.functions {
+        let mut visited = HashSet::new();
+        let mut worklist = calls[function.name.as_str()].clone();
+        while let Some(callee) = worklist.pop() {
+            if callee == function.name {
+                recursive.insert(function.name.clone());
+                break;
+            }
+            if visited.insert(callee) {
+                worklist.extend(calls.get(callee).into_iter().flatten());
+            }
+        }
+    }
+    recursive
+}
+
+fn find_inlinable_call<'a>(
+    function: &IrFunction,
+    candidates: &'a HashMap<String, IrFunction>,
+) -> Option<(BlockId, usize, &'a IrFunction)> {
+    for (block_index, block) in function.blocks.iter().enumerate() {
+        for (index, instr) in block.instrs.iter().enumerate() {
+            if let Instr::Call { function: name, .. } = instr {
+                if let Some(callee) = candidates.get(name) {
+                    if callee.name != function.name {
+                        return Some((BlockId(block_index), index, callee));
+                    }
+                }
+            }
+        }
+    }
+    None
+}
+
+// Splits the block at the call, the code after it continuing in a new block. The body of the
+// callee is appended with fresh registers, slots and blocks, its parameters copied from the
+// arguments and every return copying its value to the result of the call
+fn inline_call(function: &mut IrFunction, block: BlockId, index: usize, callee: &IrFunction) {
+    let Instr::Call { dst, args, .. } = function.blocks[block.0].instrs[index].clone() else {
+        panic!("Inlining something that is not a call");
+    };
+
+    let rest = function.blocks[block.0].instrs.split_off(index + 1);
+    function.blocks[block.0].instrs.pop(); // The call itself
+    let continuation = BlockId(function.blocks.len());
+    let terminator = std::mem::replace(
+        &mut function.blocks[block.0].terminator,
+        Terminator::Jump(BlockId(continuation.0 + 1)),
+    );
+    function.blocks.push(Block {
+        instrs: rest,
+        terminator,
+    });
+
+    let vreg_base = function.vregs.len();
+    let slot_base = function.slots.len();
+    let block_base = function.blocks.len();
+    function.vregs.extend(callee.vregs.iter().copied());
+    function.slots.extend(callee.slots.iter().map(|slot| Slot {
+        name: format!("{}.{}", callee.name, slot.name),
+        size: slot.size,
+        align: slot.align,
+    }));
+
+    let rename = |vreg: VReg| VReg(vreg.0 + vreg_base);
+    for callee_block in &callee.blocks {
+        let mut instrs = vec![];
+        for instr in &callee_block.instrs {
+            let mut instr = instr.clone();
+            instr.rename_vregs(rename);
+            instrs.push(match instr {
+                Instr::Param { dst, index } => Instr::Copy {
+                    dst,
+                    src: args[index],
+                },
+                Instr::FrameAddr { dst, slot } => Instr::FrameAddr {
+                    dst,
+                    slot: slot + slot_base,
+                },
+                instr => instr,
+            });
+        }
+
+        let mut terminator = callee_block.terminator.clone();
+        terminator.rename_vregs(rename);
+        terminator.rename_blocks(|target| BlockId(target.0 + block_base));
+        let terminator = match terminator {
+            Terminator::Return(value) => {
+                instrs.push(Instr::Copy { dst, src: value });
+                Terminator::Jump(continuation)
+            }
+            Terminator::TailCall { function, args } => {
+                instrs.push(Instr::Call {
+                    dst,
+                    function,
+                    args,
+                });
+                Terminator::Jump(continuation)
+            }
+            terminator => terminator,
+        };
+
+        function.blocks.push(Block { instrs, terminator });
+    }
+}
+
+// A static function is only reachable through calls in this file, once they are all inlined
+// it is not needed anymore
+fn remove_uncalled_static_functions(program: &mut IrProgram) {
+    loop {
+        let called: HashSet<String> = program
+            .functions
+            .iter()
+            .flat_map(|function| callees(function).into_iter().map(str::to_string))
+            .collect();
+
+        let count = program.functions.len();
+        program
+            .functions
+            .retain(|function| !function.is_static || called.contains(&function.name));
+        if program.functions.len() == count {
+            return;
+        }
+    }
+}
@@ -0,0 +1,453 @@
+use std::collections::HashMap;
+
+use crate::{
+    parser::{
+        Expr, ExprNode, FunctionNode, Initializer, ProgramNode, Statement, StatementNode, Storage,
+        SwitchCases,
+    },
+    token::TokenType,
+    types::{align_to, Type},
+};
+
+// Runs a program straight from its syntax tree, for --run and as a reference for the native
+// code. Memory is one array of bytes addressed like the target: globals are laid out from
+// their data images at the bottom, above the unused null page, and the frames of the calls
+// are stacked on top of them. Values are kept as i64, ints sign extended from 32 bits, and
+// the arithmetic wraps and divides like the AArch64 code does, x / 0 being 0
+
+const NULL_PAGE: usize = 16;
+const STACK_SIZE: usize = 1 << 20;
+const MAX_CALL_DEPTH: usize = 10000; // Every call nests in the calls of the interpreter itself
+
+// Runs main and gives back what it returns, the exit code of the program
+pub fn run_program(program: &ProgramNode) -> Result<i32, String> {
+    let mut interpreter = Interpreter::new(program);
+    let main = interpreter.function("main")?;
+    let code = interpreter.call(main, vec![])?;
+    Ok(code as i32)
+}
+
+// How a statement hands control back to the one around it
+#[derive(Debug, PartialEq)]
+enum Flow {
+    Normal,
+    Break,
+    Continue,
+    Return(i64),
+}
+
+// The label a switch jumps to, searched for through the statements of its body
+#[derive(Debug, PartialEq)]
+enum CaseLabel {
+    Case(i32),
+    Default,
+}
+
+struct Interpreter<'a> {
+    functions: HashMap<&'a str, &'a FunctionNode>,
+    globals: HashMap<&'a str, usize>, // Address of every global by symbol
+    memory: Vec<u8>,
+    stack_pointer: usize,
+    frames: Vec<Vec<usize>>, // Addresses of the locals of every active call
+}
+
+impl<'a> Interpreter<'a> {
+    fn new(program: &'a ProgramNode) -> Self {
+        let mut globals = HashMap::new();
+        let mut end = NULL_PAGE;
+        for global in &program.globals {
+            if let Storage::Global(symbol) = &global.variable.storage {
+                end = align_to(end, global.variable.ty.align());
+                globals.insert(symbol.as_str(), end);
+                end += global.variable.ty.size();
+            }
+        }
+
+        let stack_base = align_to(end, 16);
+        let mut memory = vec![0; stack_base + STACK_SIZE];
+        for global in &program.globals {
+            if let Storage::Global(symbol) = &global.variable.storage {
+                let address = globals[symbol.as_str()];
+                let bytes = &global.image.bytes;
+                memory[address..address + bytes.len()].copy_from_slice(bytes);
+                for relocation in &global.image.relocations {
+                    let target = globals
+                        .get(relocation.symbol.as_str())
+                        .copied()
+                        .unwrap_or(0);
+                    let pointer = (target as i64 + relocation.addend).to_le_bytes();
+                    let offset = address + relocation.offset;
+                    memory[offset..offset + 8].copy_from_slice(&pointer);
+                }
+            }
+        }
+
+        Interpreter {
+            functions: program
+                .body
+                .iter()
+                .map(|function| (function.name.as_str(), function))
+                .collect(),
+            globals,
+            memory,
+            stack_pointer: stack_base,
+            frames: vec![],
+        }
+    }
+
+    fn function(&self, name: &str) -> Result<&'a FunctionNode, String> {
+        match self.functions.get(name) {
+            Some(function) => Ok(function),
+            None => Err(format!("Call to undefined function {}", name)),
+        }
+    }
+
+    // Gives every local its own place in a new frame, the parameters get the arguments
+    fn call(&mut self, function: &'a FunctionNode, args: Vec<i64>) -> Result<i64, String> {
+        if self.frames.len() == MAX_CALL_DEPTH {
+            return Err(format!("Call depth exceeded in call to {}", function.name));
+        }
+
+        let saved_stack_pointer = self.stack_pointer;
+        let mut frame = vec![];
+        for local in &function.locals {
+            let address = align_to(self.stack_pointer, local.ty.align());
+            self.stack_pointer = address + local.ty.size().max(1);
+            frame.push(address);
+        }
+        if self.stack_pointer > self.memory.len() {
+            self.stack_pointer = saved_stack_pointer;
+            return Err(format!("Stack overflow in call to {}", function.name));
+        }
+        for ((param, arg), address) in function.params.iter().zip(args).zip(&frame) {
+            self.store(&param.ty, *address, arg)?;
+        }
+
+        self.frames.push(frame);
+        let mut result = Ok(0); // Falling off the end of a function returns 0
+        for statement_node in &function.body {
+            match self.execute(statement_node, &mut None) {
+                Ok(Flow::Return(value)) => {
+                    result = Ok(convert(value, &function.return_type));
+                    break;
+                }
+                Ok(_) => {}
+                Err(error) => {
+                    result = Err(error);
+                    break;
+                }
+            }
+        }
+        self.frames.pop();
+        self.stack_pointer = saved_stack_pointer;
+
+        result
+    }
+
+    fn bytes(&self, address: i64, size: usize) -> Result<std::ops::Range<usize>, String> {
+        let start = address as usize;
+        if address < NULL_PAGE as i64 || start + size > self.memory.len() {
+            return Err(format!("Invalid memory access at address {:#x}", address));
+        }
+        Ok(start..start + size)
+    }
+
+    // Scalars are read sign extended, arrays and structs are used through their address
+    fn load(&self, ty: &Type, address: i64) -> Result<i64, String> {
+        let bytes = &self.memory[self.bytes(address, ty.size())?];
+        Ok(match ty {
+            Type::Char => bytes[0] as i8 as i64,
+            Type::Int => i32::from_le_bytes(bytes.try_into().expect("4 bytes")) as i64,
+            Type::Pointer(_) => i64::from_le_bytes(bytes.try_into().expect("8 bytes")),
+            Type::Array(_, _) | Type::Struct(_) => address,
+            Type::Void => panic!("Load of a void value"),
+        })
+    }
+
+    // Scalars keep their low bytes, a struct is copied from the address in value
+    fn store(&mut self, ty: &Type, address: usize, value: i64) -> Result<(), String> {
+        let range = self.bytes(address as i64, ty.size())?;
+        match ty {
+            Type::Struct(_) | Type::Array(_, _) => {
+                let source = self.bytes(value, ty.size())?;
+                self.memory.copy_within(source, range.start);
+            }
+            _ => self.memory[range].copy_from_slice(&value.to_le_bytes()[..ty.size()]),
+        }
+        Ok(())
+    }
+
+    fn execute(
+        &mut self,
+        statement_node: &'a StatementNode,
+        seek: &mut Option<CaseLabel>,
+    ) -> Result<Flow, String> {
+        match &statement_node.statement {
+            Statement::Case(value, body) => {
+                if *seek == Some(CaseLabel::Case(*value)) {
+                    *seek = None;
+                }
+                self.execute(body, seek)
+            }
+            Statement::Default(body) => {
+                if *seek == Some(CaseLabel::Default) {
+                    *seek = None;
+                }
+                self.execute(body, seek)
+            }
+            Statement::Block(statements) => {
+                for statement_node in statements {
+                    let flow = self.execute(statement_node, seek)?;
+                    if flow != Flow::Normal {
+                        return Ok(flow);
+                    }
+                }
+                Ok(Flow::Normal)
+            }
+            Statement::If(condition, then, otherwise) => {
+                if seek.is_some() {
+                    // A label in the then branch skips the else branch like its code does
+                    let flow = self.execute(then, seek)?;
+                    return match otherwise {
+                        Some(otherwise) if seek.is_some() => self.execute(otherwise, seek),
+                        _ => Ok(flow),
+                    };
+                }
+                if self.evaluate(condition)? != 0 {
+                    self.execute(then, seek)
+                } else if let Some(otherwise) = otherwise {
+                    self.execute(otherwise, seek)
+                } else {
+                    Ok(Flow::Normal)
+                }
+            }
+            Statement::While(condition, body) => self.run_loop(Some(condition), None, body, seek),
+            Statement::For(init, condition, step, body) => {
+                if seek.is_none() {
+                    self.execute(init, seek)?;
+                }
+                self.run_loop(condition.as_ref(), step.as_ref(), body, seek)
+            }
+            // Labels inside belong to the inner switch
+            Statement::Switch(_, _, _) if seek.is_some() => Ok(Flow::Normal),
+            Statement::Switch(condition, body, cases) => {
+                let value = self.evaluate(condition)? as i32;
+                let SwitchCases {
+                    values,
+                    has_default,
+                } = cases;
+                let mut label = if values.contains(&value) {
+                    Some(CaseLabel::Case(value))
+                } else if *has_default {
+                    Some(CaseLabel::Default)
+                } else {
+                    return Ok(Flow::Normal);
+                };
+
+                match self.execute(body, &mut label)? {
+                    Flow::Break => Ok(Flow::Normal),
+                    flow => Ok(flow),
+                }
+            }
+            _ if seek.is_some() => Ok(Flow::Normal),
+            Statement::Return(Some(expr_node)) => Ok(Flow::Return(self.evaluate(expr_node)?)),
+            Statement::Return(None) => Ok(Flow::Return(0)),
+            Statement::Declaration(variable, initializer) => {
+                if let (Storage::Local(index), Some(initializer)) = (&variable.storage, initializer)
+                {
+                    let address = self.local_address(*index);
+                    self.initialize(address, &variable.ty, initializer)?;
+                }
+                Ok(Flow::Normal)
+            }
+            Statement::Expression(expr_node) => {
+                self.evaluate(expr_node)?;
+                Ok(Flow::Normal)
+            }
+            Statement::Empty => Ok(Flow::Normal),
+            Statement::Break => Ok(Flow::Break),
+            Statement::Continue => Ok(Flow::Continue),
+        }
+    }
+
+    // while and for loops. A switch may jump into the body, which then runs from the label
+    // before the loop carries on as usual
+    fn run_loop(
+        &mut self,
+        condition: Option<&'a ExprNode>,
+        step: Option<&'a ExprNode>,
+        body: &'a StatementNode,
+        seek: &mut Option<CaseLabel>,
+    ) -> Result<Flow, String> {
+        loop {
+            if seek.is_none() {
+                if let Some(condition) = condition {
+                    if self.evaluate(condition)? == 0 {
+                        return Ok(Flow::Normal);
+                    }
+                }
+            }
+
+            match self.execute(body, seek)? {
+                Flow::Break => return Ok(Flow::Normal),
+                Flow::Return(value) => return Ok(Flow::Return(value)),
+                _ if seek.is_some() => return Ok(Flow::Normal),
+                _ => {}
+            }
+
+            if let Some(step) = step {
+                self.evaluate(step)?;
+            }
+        }
+    }
+
+    // Zero fills the object and stores the values of the initializer over it
+    fn initialize(
+        &mut self,
+        address: usize,
+        ty: &Type,
+        initializer: &'a Initializer,
+    ) -> Result<(), String> {
+        let range = self.bytes(address as i64, ty.size())?;
+        self.memory[range].fill(0);
+
+        let mut stores = vec![];
+        initializer.flatten(ty, 0, &mut stores);
+        for (offset, value, ty) in stores {
+            let value = self.evaluate(value)?;
+            self.store(&ty, address + offset, convert(value, &ty))?;
+        }
+        Ok(())
+    }
+
+    fn local_address(&self, index: usize) -> usize {
+        self.frames.last().expect("No active call")[index]
+    }
+
+    fn address(&mut self, node: &'a ExprNode) -> Result<i64, String> {
+        match &node.expr {
+            Expr::Variable(variable) => match &variable.storage {
+                Storage::Local(index) => Ok(self.local_address(*index) as i64),
+                Storage::Global(symbol) => Ok(self.globals[symbol.as_str()] as i64),
+            },
+            Expr::UnaryOp(TokenType::Mul, Some(operand)) => self.evaluate(operand),
+            Expr::Member(base, member) => Ok(self.address(base)? + member.offset as i64),
+            // Struct values of assignments, conditionals and commas are their address
+            _ if node.ty.is_struct() => self.evaluate(node),
+            _ => Err(format!("{} is not an lvalue", node)),
+        }
+    }
+
+    fn evaluate(&mut self, node: &'a ExprNode) -> Result<i64, String> {
+        match &node.expr {
+            Expr::Number(num) => Ok(*num as i64),
+            Expr::Variable(_) | Expr::Member(_, _) | Expr::UnaryOp(TokenType::Mul, _) => {
+                let address = self.address(node)?;
+                self.load(&node.ty, address)
+            }
+            Expr::UnaryOp(TokenType::BitAnd, Some(operand)) => self.address(operand),
+            Expr::UnaryOp(operator, Some(operand)) => {
+                let value = self.evaluate(operand)?;
+                let is_pointer = operand.ty.base().is_some();
+                match operator {
+                    TokenType::Minus if is_pointer => Ok(value.wrapping_neg()),
+                    TokenType::Minus => Ok((value as i32).wrapping_neg() as i64),
+                    TokenType::BitComplement => Ok(!value),
+                    TokenType::Not => Ok((value == 0) as i64),
+                    _ => Err(format!("Unsupported unary operator {}", operator)),
+                }
+            }
+            Expr::UnaryOp(operator, None) => Err(format!("Missing operand for {}", operator)),
+            Expr::BinaryOp(TokenType::And, left, right) => {
+                Ok((self.evaluate(left)? != 0 && self.evaluate(right)? != 0) as i64)
+            }
+            Expr::BinaryOp(TokenType::Or, left, right) => {
+                Ok((self.evaluate(left)? != 0 || self.evaluate(right)? != 0) as i64)
+            }
+            Expr::BinaryOp(operator, left, right) => {
+                let lhs = self.evaluate(left)?;
+                let rhs = self.evaluate(right)?;
+                binary(operator, left, lhs, right, rhs)
+            }
+            Expr::Assign(target, value) => {
+                let address = self.address(target)?;
+                let value = self.evaluate(value)?;
+                self.store(&target.ty, address as usize, convert(value, &target.ty))?;
+                self.load(&target.ty, address)
+            }
+            Expr::Conditional(condition, then, otherwise) => {
+                let branch = if self.evaluate(condition)? != 0 {
+                    then
+                } else {
+                    otherwise
+                };
+                let value = self.evaluate(branch)?;
+                Ok(convert(value, &node.ty.decay()))
+            }
+            Expr::Comma(left, right) => {
+                self.evaluate(left)?;
+                self.evaluate(right)
+            }
+            Expr::Cast(ty, operand) => {
+                let value = self.evaluate(operand)?;
+                Ok(convert(value, ty))
+            }
+            Expr::Call(name, args) => {
+                let function = self.function(name)?;
+                let mut values = vec![];
+                for arg in args {
+                    values.push(self.evaluate(arg)?);
+                }
+                Ok(convert(self.call(function, values)?, &node.ty))
+            }
+        }
+    }
+}
+
+// The value as ty holds it, chars and ints are truncated and sign extended back
+fn convert(value: i64, ty: &Type) -> i64 {
+    match ty {
+        Type::Char => value as i8 as i64,
+        Type::Int => value as i32 as i64,
+        _ => value,
+    }
+}
+
+// Arithmetic and comparisons, pointers move and count by whole elements
+fn binary(
+    operator: &TokenType,
+    left: &ExprNode,
+    lhs: i64,
+    right: &ExprNode,
+    rhs: i64,
+) -> Result<i64, String> {
+    let value = match (operator, left.ty.base(), right.ty.base()) {
+        (TokenType::Minus, Some(base), Some(_)) => {
+            convert(lhs.wrapping_sub(rhs) / base.size() as i64, &Type::Int)
+        }
+        (TokenType::Plus, Some(base), None) => {
+            lhs.wrapping_add(rhs.wrapping_mul(base.size() as i64))
+        }
+        (TokenType::Minus, Some(base), None) => {
+            lhs.wrapping_sub(rhs.wrapping_mul(base.size() as i64))
+        }
+        (TokenType::Plus, None, Some(base)) => {
+            rhs.wrapping_add(lhs.wrapping_mul(base.size() as i64))
+        }
+        (TokenType::Plus, _, _) => (lhs as i32).wrapping_add(rhs as i32) as i64,
+        (TokenType::Minus, _, _) => (lhs as i32).wrapping_sub(rhs as i32) as i64,
+        (TokenType::Mul, _, _) => (lhs as i32).wrapping_mul(rhs as i32) as i64,
+        (TokenType::Div, _, _) => match rhs as i32 {
+            0 => 0,
+            divisor => (lhs as i32).wrapping_div(divisor) as i64,
+        },
+        (TokenType::Eq, _, _) => (lhs == rhs) as i64,
+        (TokenType::Neq, _, _) => (lhs != rhs) as i64,
+        (TokenType::Lt, _, _) => (lhs < rhs) as i64,
+        (TokenType::Le, _, _) => (lhs <= rhs) as i64,
+        (TokenType::Gt, _, _) => (lhs > rhs) as i64,
+        (TokenType::Ge, _, _) => (lhs >= rhs) as i64,
+        _ => return Err(format!("Unsupported binary operator {}", operator)),
+    };
+    Ok(value)
+}
@@ -0,0 +1,354 @@
+use crate::parser::DataImage;
+
+// Target independent three-address code between the AST and the backends. A function is a
+// list of basic blocks over an unlimited supply of typed virtual registers, every block ends
+// in exactly one terminator and memory is only touched through explicit loads and stores
+
+// Types of virtual registers and of memory accesses. Registers are I32 or Ptr, chars are
+// promoted to I32 when loaded and truncated again when stored
+#[derive(Debug, PartialEq, Eq, Hash, Clone, Copy)]
+pub enum IrType {
+    I8,
+    I32,
+    Ptr,
+}
+
+#[derive(Debug, PartialEq, Eq, Hash, Clone, Copy, PartialOrd, Ord)]
+pub struct VReg(pub usize);
+
+#[derive(Debug, PartialEq, Eq, Hash, Clone, Copy, PartialOrd, Ord)]
+pub struct BlockId(pub usize);
+
+#[derive(Debug, PartialEq, Eq, Hash, Clone, Copy)]
+pub enum UnaryOp {
+    Neg,
+    Not, // Bitwise, logical not is a comparison with zero
+}
+
+// Arithmetic wraps at the width of the destination, comparisons yield 0 or 1 as I32
+#[derive(Debug, PartialEq, Eq, Hash, Clone, Copy)]
+pub enum BinaryOp {
+    Add,
+    Sub,
+    Mul,
+    Div,
+    Eq,
+    Ne,
+    Lt,
+    Le,
+    Gt,
+    Ge,
+}
+
+#[derive(Debug, PartialEq, Eq, Hash, Clone, Copy)]
+pub enum Conversion {
+    Sext,   // I32 to Ptr, keeping the sign
+    Trunc,  // Ptr to I32, dropping the upper half
+    SextI8, // I32 to I32, keeping only the low byte and its sign like a store and load of a char
+}
+
+#[derive(Debug, PartialEq, Clone)]
+pub enum Instr {
+    Const {
+        dst: VReg,
+        value: i64,
+    },
+    Copy {
+        dst: VReg,
+        src: VReg,
+    },
+    Unary {
+        op: UnaryOp,
+        dst: VReg,
+        src: VReg,
+    },
+    Binary {
+        op: BinaryOp,
+        dst: VReg,
+        lhs: VReg,
+        rhs: VReg,
+    },
+    Convert {
+        conversion: Conversion,
+        dst: VReg,
+        src: VReg,
+    },
+    FrameAddr {
+        dst: VReg,
+        slot: usize,
+    },
+    GlobalAddr {
+        dst: VReg,
+        symbol: String,
+    },
+    Load {
+        dst: VReg,
+        ty: IrType,
+        addr: VReg,
+    },
+    Store {
+        ty: IrType,
+        addr: VReg,
+        value: VReg,
+    },
+    MemCopy {
+        dst: VReg,
+        src: VReg,
+        size: usize,
+    },
+    MemZero {
+        dst: VReg,
+        size: usize,
+    },
+    Param {
+        dst: VReg,
+        index: usize, // Only at the start of the entry block, before anything else
+    },
+    Call {
+        dst: VReg,
+        function: String,
+        args: Vec<VReg>,
+    },
+    // The value coming from the predecessor control arrived from. Phis only exist between
+    // mem2reg and out-of-ssa, at the start of their block with one entry per predecessor
+    Phi {
+        dst: VReg,
+        incoming: Vec<(BlockId, VReg)>,
+    },
+}
+
+#[derive(Debug, PartialEq, Clone)]
+pub enum Terminator {
+    Jump(BlockId),
+    Branch {
+        condition: VReg, // Taken when not zero
+        then: BlockId,
+        otherwise: BlockId,
+    },
+    Switch {
+        value: VReg,
+        cases: Vec<(i64, BlockId)>,
+        default: BlockId,
+    },
+    Return(VReg),
+    // Returns what the call returns, reusing the frame of the caller
+    TailCall {
+        function: String,
+        args: Vec<VReg>,
+    },
+}
+
+#[derive(Debug, PartialEq, Clone)]
+pub struct Block {
+    pub instrs: Vec<Instr>,
+    pub terminator: Terminator,
+}
+
+// A stack object of the function, addressed through FrameAddr
+#[derive(Debug, PartialEq, Clone)]
+pub struct Slot {
+    pub name: String,
+    pub size: usize,
+    pub align: usize,
+}
+
+#[derive(Debug, PartialEq, Clone)]
+pub struct IrFunction {
+    pub name: String,
+    pub is_static: bool,
+    pub ret: IrType,         // I32 for void functions, which return an unused 0
+    pub params: Vec<IrType>, // Also those no Param reads anymore, a signature may need them
+    pub slots: Vec<Slot>,
+    pub blocks: Vec<Block>, // Indexed by BlockId, the entry block first
+    pub vregs: Vec<IrType>, // Type of every virtual register, indexed by VReg
+}
+
+#[derive(Debug, PartialEq, Clone)]
+pub struct IrGlobal {
+    pub symbol: String,
+    pub size: usize,
+    pub align: usize,
+    pub image: DataImage,
+    pub is_const: bool,
+    pub is_static: bool,
+}
+
+#[derive(Debug, PartialEq, Clone)]
+pub struct IrProgram {
+    pub functions: Vec<IrFunction>,
+    pub globals: Vec<IrGlobal>,
+}
+
+impl Instr {
+    // The register the instruction writes, if any
+    pub fn dst(&self) -> Option<VReg> {
+        match self {
+            Instr::Const { dst, .. }
+            | Instr::Copy { dst, .. }
+            | Instr::Unary { dst, .. }
+            | Instr::Binary { dst, .. }
+            | Instr::Convert { dst, .. }
+            | Instr::FrameAddr { dst, .. }
+            | Instr::GlobalAddr { dst, .. }
+            | Instr::Load { dst, .. }
+            | Instr::Param { dst, .. }
+            | Instr::Call { dst, .. }
+            | Instr::Phi { dst, .. } => Some(*dst),
+            Instr::Store { .. } | Instr::MemCopy { .. } | Instr::MemZero { .. } => None,
+        }
+    }
+
+    // The registers the instruction reads
+    pub fn uses(&self) -> Vec<VReg> {
+        match self {
+            Instr::Const { .. }
+            | Instr::FrameAddr { .. }
+            | Instr::GlobalAddr { .. }
+            | Instr::Param { .. } => vec![],
+            Instr::Copy { src, .. } | Instr::Unary { src, .. } | Instr::Convert { src, .. } => {
+                vec![*src]
+            }
+            Instr::Binary { lhs, rhs, .. } => vec![*lhs, *rhs],
+            Instr::Load { addr, .. } => vec![*addr],
+            Instr::Store { addr, value, .. } => vec![*addr, *value],
+            Instr::MemCopy { dst, src, .. } => vec![*dst, *src],
+            Instr::MemZero { dst, .. } => vec![*dst],
+            Instr::Call { args, .. } => args.clone(),
+            Instr::Phi { incoming, .. } => incoming.iter().map(|(_, value)| *value).collect(),
+        }
+    }
+
+    // Replaces every register the instruction reads or writes with rename(register)
+    pub fn rename_vregs(&mut self, rename: impl Fn(VReg) -> VReg) {
+        self.rename_uses(&rename);
+        if let Some(dst) = self.dst_mut() {
+            *dst = rename(*dst);
+        }
+    }
+
+    fn dst_mut(&mut self) -> Option<&mut VReg> {
+        match self {
+            Instr::Const { dst, .. }
+            | Instr::Copy { dst, .. }
+            | Instr::Unary { dst, .. }
+            | Instr::Binary { dst, .. }
+            | Instr::Convert { dst, .. }
+            | Instr::FrameAddr { dst, .. }
+            | Instr::GlobalAddr { dst, .. }
+            | Instr::Load { dst, .. }
+            | Instr::Param { dst, .. }
+            | Instr::Call { dst, .. }
+            | Instr::Phi { dst, .. } => Some(dst),
+            Instr::Store { .. } | Instr::MemCopy { .. } | Instr::MemZero { .. } => None,
+        }
+    }
+
+    // Replaces every register the instruction reads with rename(register)
+    pub fn rename_uses(&mut self, rename: impl Fn(VReg) -> VReg) {
+        match self {
+            Instr::Const { .. }
+            | Instr::FrameAddr { .. }
+            | Instr::GlobalAddr { .. }
+            | Instr::Param { .. } => {}
+            Instr::Copy { src, .. } | Instr::Unary { src, .. } | Instr::Convert { src, .. } => {
+                *src = rename(*src)
+            }
+            Instr::Binary { lhs, rhs, .. } => {
+                *lhs = rename(*lhs);
+                *rhs = rename(*rhs);
+            }
+            Instr::Load { addr, .. } => *addr = rename(*addr),
+            Instr::Store { addr, value, .. } => {
+                *addr = rename(*addr);
+                *value = rename(*value);
+            }
+            Instr::MemCopy { dst, src, .. } => {
+                *dst = rename(*dst);
+                *src = rename(*src);
+            }
+            Instr::MemZero { dst, .. } => *dst = rename(*dst),
+            Instr::Call { args, .. } => {
+                for arg in args {
+                    *arg = rename(*arg);
+                }
+            }
+            Instr::Phi { incoming, .. } => {
+                for (_, value) in incoming {
+                    *value = rename(*value);
+                }
+            }
+        }
+    }
+
+    // Whether removing the instruction changes nothing but the value of its destination
+    pub fn is_pure(&self) -> bool {
+        !matches!(
+            self,
+            Instr::Store { .. }
+                | Instr::MemCopy { .. }
+                | Instr::MemZero { .. }
+                | Instr::Call { .. }
+        )
+    }
+}
+
+impl Terminator {
+    pub fn uses(&self) -> Vec<VReg> {
+        match self {
+            Terminator::Jump(_) => vec![],
+            Terminator::Branch { condition, .. } => vec![*condition],
+            Terminator::Switch { value, .. } => vec![*value],
+            Terminator::Return(value) => vec![*value],
+            Terminator::TailCall { args, .. } => args.clone(),
+        }
+    }
+
+    // The blocks control can continue in
+    pub fn successors(&self) -> Vec<BlockId> {
+        match self {
+            Terminator::Jump(block) => vec![*block],
+            Terminator::Branch {
+                then, otherwise, ..
+            } => vec![*then, *otherwise],
+            Terminator::Switch { cases, default, .. } => cases
+                .iter()
+                .map(|(_, block)| *block)
+                .chain([*default])
+                .collect(),
+            Terminator::Return(_) | Terminator::TailCall { .. } => vec![],
+        }
+    }
+
+    pub fn rename_vregs(&mut self, rename: impl Fn(VReg) -> VReg) {
+        match self {
+            Terminator::Jump(_) => {}
+            Terminator::Branch { condition, .. } => *condition = rename(*condition),
+            Terminator::Switch { value, .. } | Terminator::Return(value) => *value = rename(*value),
+            Terminator::TailCall { args, .. } => {
+                for arg in args {
+                    *arg = rename(*arg);
+                }
+            }
+        }
+    }
+
+    // Replaces every block the terminator jumps to with rename(block)
+    pub fn rename_blocks(&mut self, rename: impl Fn(BlockId) -> BlockId) {
+        match self {
+            Terminator::Jump(block) => *block = rename(*block),
+            Terminator::Branch {
+                then, otherwise, ..
+            } => {
+                *then = rename(*then);
+                *otherwise = rename(*otherwise);
+            }
+            Terminator::Switch { cases, default, .. } => {
+                for (_, block) in cases {
+                    *block = rename(*block);
+                }
+                *default = rename(*default);
+            }
+            Terminator::Return(_) | Terminator::TailCall { .. } => {}
+        }
+    }
+}
@@ -1,6 +1,3 @@
-use std::fs::File;
-use std::io::{BufRead, BufReader};
-
 use crate::token::{Token, TokenType};
 
 // MAPS
@@ -11,13 +8,16 @@ use crate::token::{
 // REGEX
 use crate::token::{CHAR_LITERAL, IDENTIFIERS, INTEGER_LITERAL};
 
-pub fn get_lexemes(source_file: &str) -> Vec<String> {
+// The tokens of C source text. Anything the lexer does not know becomes an Error token, so
+// every input gives tokens for the parser to report on
+pub fn lex(source: &str) -> Vec<Token> {
+    tokenize_lexemes(split_lexemes(source))
+}
+
+pub fn split_lexemes(source: &str) -> Vec<String> {
     let mut lexemes: Vec<String> = Vec::new();
 
-    let file = File::open(source_file).expect("Failed to open file");
-    let reader = BufReader::new(file);
-    for line in reader.lines() {
-        let line = line.expect("Failed to read line");
+    for line in source.lines() {
         let chars: Vec<char> = line.chars().collect();
         let mut buffer = String::new();
 
@@ -45,6 +45,9 @@ pub fn get_lexemes(source_file: &str) -> Vec<String> {
                 }
                 lexemes.push(buffer.clone());
                 buffer.clear();
+            } else if ch == '/' && chars.get(index + 1) == Some(&'/') {
+                // A comment runs to the end of the line
+                break;
             } else if PUNCTUATOR_MAP.contains_key(&ch.to_string().as_str()) {
                 buffer.push(ch);
                 index += 1;
@@ -61,7 +64,10 @@ pub fn get_lexemes(source_file: &str) -> Vec<String> {
                     index += 2;
                 } else {
                     match (ch, peek) {
-                        ('|', Some(&'|')) | ('&', Some(&'&')) | ('!', Some(&'=')) => {
+                        ('|', Some(&'|'))
+                        | ('&', Some(&'&'))
+                        | ('!', Some(&'='))
+                        | ('-', Some(&'>')) => {
                             buffer.push(ch);
                             buffer.push(*peek.unwrap());
                             index += 2;
@@ -75,8 +81,8 @@ pub fn get_lexemes(source_file: &str) -> Vec<String> {
                 lexemes.push(buffer.clone());
                 buffer.clear();
             } else if COMPARATOR_MAP.contains_key(&ch.to_string().as_str()) {
-                let peek = chars[index + 1];
-                if (ch == '<' || ch == '>' || ch == '!') && (peek == '=') {
+                let peek = chars.get(index + 1);
+                if (ch == '<' || ch == '>' || ch == '!') && peek == Some(&'=') {
                     buffer.push(chars[index]);
                     index += 1;
                     buffer.push(chars[index]);
@@ -92,11 +98,12 @@ pub fn get_lexemes(source_file: &str) -> Vec<String> {
                 //Sjekker om tegnet er en apostrof
                 buffer.push(ch);
                 index += 1;
-                if chars[index] != '\'' {
+                // A ' at the end of the line stays alone and is reported by the tokenizer
+                if let Some(&next) = chars.get(index).filter(|&&next| next != '\'') {
                     //Sjekker om det er hva som helst etter apostrofen, tar kun 1 tegn.
-                    buffer.push(chars[index]);
+                    buffer.push(next);
                     index += 1;
-                    if chars[index] == '\'' {
+                    if chars.get(index) == Some(&'\'') {
                         //Ser etter neste apostrof
                         buffer.push(chars[index]);
                         index += 1;
@@ -127,6 +134,11 @@ pub fn tokenize_lexemes(lexemes: Vec<String>) -> Vec<Token> {
                 value: Some(lexeme.to_string()),
                 token_type: TokenType::IntLit,
             });
+        } else if char_regex.is_match(&lexeme) {
+            tokens.push(Token {
+                value: Some(lexeme.to_string()),
+                token_type: TokenType::Char,
+            });
         } else if identifiers_regex.is_match(&lexeme) {
             // If lexeme has value in map
             if let Some(token_type) = KEYWORD_MAP.get(lexeme.as_str()) {
@@ -140,11 +152,6 @@ pub fn tokenize_lexemes(lexemes: Vec<String>) -> Vec<Token> {
                     token_type: TokenType::Identifier,
                 });
             }
-        } else if char_regex.is_match(&lexeme) {
-            tokens.push(Token {
-                value: Some(lexeme.to_string()),
-                token_type: TokenType::Char,
-            });
         } else if PUNCTUATOR_MAP.contains_key(lexeme.as_str()) {
             if let Some(token_type) = PUNCTUATOR_MAP.get(lexeme.as_str()) {
                 tokens.push(Token {
@@ -167,7 +174,6 @@ pub fn tokenize_lexemes(lexemes: Vec<String>) -> Vec<Token> {
                 });
             }
         } else {
-            println!("Unrecognized {}", lexeme);
             tokens.push(Token {
                 value: Some(lexeme.to_string()),
                 token_type: TokenType::Error,
@@ -0,0 +1,288 @@
+// The compiler as a library, for tools like the fuzz targets, a test runner or an editor. lex,
+// parse, compile, run and simulate are the entry points, the modules behind them are private
+// apart from the syntax tree and tokens they exchange. The compiler binary in main.rs only
+// handles the arguments and the files
+mod asm;
+mod backend;
+mod cfg;
+mod cgen;
+mod cse;
+mod dce;
+mod fold;
+mod gen;
+mod inline;
+mod interp;
+mod ir;
+mod lex;
+mod licm;
+mod llvm;
+mod lower;
+mod parse_util;
+mod parser;
+mod peephole;
+mod pretty_printer;
+mod regalloc;
+mod riscv;
+mod sccp;
+mod sim;
+mod ssa;
+mod tailcall;
+mod token;
+mod types;
+mod wasm;
+mod x86;
+
+use parser::Parser;
+
+pub use backend::Target;
+pub use parser::{
+    DataImage, Expr, ExprNode, FunctionNode, GlobalNode, Initializer, ProgramNode, Relocation,
+    Statement, StatementNode, Storage, SwitchCases, Variable,
+};
+pub use token::{Token, TokenType};
+pub use types::{Member, StructRef, StructType, Type};
+
+// The parser and the passes recurse as deep as the program nests, which the parser limits, and
+// run also as deep as the calls of the program go, up to its own limit. The entry points do
+// their work on a thread with a stack that takes both, whatever stack the caller has
+const STACK_SIZE: usize = 256 << 20;
+
+// What compile gives back
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum Emit {
+    Tokens,
+    Ast,
+    Asm,
+    Ir,
+    Llvm,
+    C,
+}
+
+// The passes over the IR by the name --print-after takes
+pub const PASSES: [&str; 8] = [
+    "inline",
+    "tailcall",
+    "dce",
+    "mem2reg",
+    "sccp",
+    "cse",
+    "licm",
+    "out-of-ssa",
+];
+
+#[derive(Clone)]
+pub struct Options {
+    pub emit: Emit,
+    pub opt_level: u8, // -O0 compiles the tree as written, -O1 folds constants, -O2 also inlines
+    pub peephole: bool, // Cleaning up the generated assembly, on unless debugging code generation
+    pub warn_unreachable: bool, // Warnings come with the output, they do not stop the compilation
+    pub print_after: Vec<String>, // Passes to dump the IR after
+    pub target: Target,
+}
+
+impl Default for Options {
+    fn default() -> Self {
+        Options {
+            emit: Emit::Asm,
+            opt_level: 0,
+            peephole: true,
+            warn_unreachable: false,
+            print_after: vec![],
+            target: Target::Aarch64,
+        }
+    }
+}
+
+// What a successful compile or run gives: the text or the exit code, with the warnings of
+// warn_unreachable and the IR after each pass of print_after for the caller to show
+#[derive(Debug, PartialEq, Clone, Default)]
+pub struct Output<T> {
+    pub value: T,
+    pub warnings: Vec<String>,
+    pub dumps: Vec<(String, String)>, // Pass name and the IR after it, in the order they ran
+}
+
+// What is wrong with a program, one message per error in the order they were found
+#[derive(Debug, PartialEq, Clone, Default)]
+pub struct Diagnostics {
+    pub errors: Vec<String>,
+}
+
+impl Diagnostics {
+    fn error(message: String) -> Self {
+        Diagnostics {
+            errors: vec![message],
+        }
+    }
+}
+
+// The tokens of the source, what the lexer does not know becomes an Error token
+pub fn lex(source: &str) -> Vec<Token> {
+    lex::lex(source)
+}
+
+// The syntax tree of the whole program, or every error the parser found in it
+pub fn parse(tokens: Vec<Token>) -> Result<ProgramNode, Diagnostics> {
+    on_large_stack(|| parse_tokens(tokens))
+}
+
+fn parse_tokens(tokens: Vec<Token>) -> Result<ProgramNode, Diagnostics> {
+    let mut parser = Parser::new(tokens);
+    let program = parser.parse_program();
+    match program {
+        Some(program) if parser.diagnostics.is_empty() => Ok(program),
+        _ if parser.diagnostics.is_empty() => Err(Diagnostics::error(
+            "Error: Failed to parse program".to_string(),
+        )),
+        _ => Err(Diagnostics {
+            errors: parser.diagnostics,
+        }),
+    }
+}
+
+// Compiles the source to the form options.emit asks for
+pub fn compile(source: &str, options: &Options) -> Result<Output<String>, Diagnostics> {
+    on_large_stack(|| compile_source(source, options))
+}
+
+fn compile_source(source: &str, options: &Options) -> Result<Output<String>, Diagnostics> {
+    if options.emit == Emit::Tokens {
+        return Ok(Output {
+            value: dump_tokens(&lex(source)),
+            ..Output::default()
+        });
+    }
+
+    let (program, warnings) = front_end(source, options)?;
+    let mut output = Output {
+        warnings,
+        ..Output::default()
+    };
+    match options.emit {
+        Emit::Ast => {
+            output.value = program.to_string();
+            return Ok(output);
+        }
+        Emit::C => {
+            output.value = cgen::emit_program(&program);
+            return Ok(output);
+        }
+        _ => {}
+    }
+
+    // Lowering
+    let mut ir = lower::lower_program(&program);
+    let mut passes = vec![];
+    if options.opt_level >= 2 {
+        passes.extend(["inline", "tailcall"]);
+    }
+    if options.opt_level >= 1 {
+        passes.extend(["dce", "mem2reg", "sccp", "cse", "licm", "dce", "out-of-ssa"]);
+    }
+    for pass in passes {
+        run_pass(pass, &mut ir);
+        if options.print_after.iter().any(|name| name == pass) {
+            output.dumps.push((pass.to_string(), ir.to_string()));
+        }
+    }
+
+    output.value = match options.emit {
+        Emit::Ir => ir.to_string(),
+        Emit::Llvm => llvm::emit_program(&ir),
+        // Generating
+        _ => backend::Generator::new(ir, options.target, options.peephole).walk_da_tree(),
+    };
+    Ok(output)
+}
+
+// Interprets the program, giving what its main returns
+pub fn run(source: &str, options: &Options) -> Result<Output<i32>, Diagnostics> {
+    on_large_stack(|| {
+        let (program, warnings) = front_end(source, options)?;
+        let value = interp::run_program(&program)
+            .map_err(|error| Diagnostics::error(format!("Error: {}", error)))?;
+        Ok(Output {
+            value,
+            warnings,
+            dumps: vec![],
+        })
+    })
+}
+
+// Compiles the program to AArch64 assembly and runs it in the simulator, giving x0 when main
+// returns
+pub fn simulate(source: &str, options: &Options) -> Result<Output<i32>, Diagnostics> {
+    if options.target != Target::Aarch64 {
+        return Err(Diagnostics::error(
+            "Error: The simulator only runs code for aarch64".to_string(),
+        ));
+    }
+    let options = Options {
+        emit: Emit::Asm,
+        ..options.clone()
+    };
+    let output = compile(source, &options)?;
+    let x0 = sim::run_assembly(&output.value)
+        .map_err(|error| Diagnostics::error(format!("Error: {}", error)))?;
+    Ok(Output {
+        value: x0 as i32,
+        warnings: output.warnings,
+        dumps: output.dumps,
+    })
+}
+
+// Runs work on a thread of its own with STACK_SIZE of stack, a panic in it goes on in the caller
+fn on_large_stack<T: Send>(work: impl FnOnce() -> T + Send) -> T {
+    std::thread::scope(|scope| {
+        let thread = std::thread::Builder::new()
+            .stack_size(STACK_SIZE)
+            .spawn_scoped(scope, work)
+            .expect("Failed to start the compiler thread");
+        thread
+            .join()
+            .unwrap_or_else(|panic| std::panic::resume_unwind(panic))
+    })
+}
+
+// Parses the program and optimizes its syntax tree, with the warnings found on the way
+fn front_end(source: &str, options: &Options) -> Result<(ProgramNode, Vec<String>), Diagnostics> {
+    let mut program = parse_tokens(lex(source))?;
+
+    let mut warnings = vec![];
+    if options.warn_unreachable {
+        warnings = dce::warn_unreachable_code(&program);
+    }
+
+    // Optimizing
+    if options.opt_level >= 1 {
+        fold::fold_program(&mut program);
+        dce::eliminate_dead_code(&mut program);
+    }
+    Ok((program, warnings))
+}
+
+// One token per line, its type and the text it was made from
+fn dump_tokens(tokens: &[Token]) -> String {
+    let mut dump = "".to_string();
+    for token in tokens {
+        match &token.value {
+            Some(value) => dump += &format!("{:?} {}\n", token.token_type, value),
+            None => dump += &format!("{:?}\n", token.token_type),
+        }
+    }
+    dump
+}
+
+fn run_pass(pass: &str, ir: &mut ir::IrProgram) {
+    match pass {
+        "inline" => inline::inline_functions(ir),
+        "tailcall" => tailcall::optimize_tail_calls(ir),
+        "dce" => dce::remove_dead_code(ir),
+        "mem2reg" => ssa::mem2reg(ir),
+        "sccp" => sccp::propagate_constants(ir),
+        "cse" => cse::eliminate_common_subexpressions(ir),
+        "licm" => licm::hoist_loop_invariants(ir),
+        "out-of-ssa" => ssa::out_of_ssa(ir),
+        _ => panic!("Unknown pass {}", pass),
+    }
+}
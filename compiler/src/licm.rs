@@ -0,0 +1,195 @@
+use std::collections::HashSet;
+
+use crate::{
+    cfg::{self, Dominators},
+    ir::{BinaryOp, Block, BlockId, Instr, IrFunction, IrProgram, Terminator, VReg},
+};
+
+// Loop-invariant code motion on SSA form. A natural loop is found by its back edge, a jump to
+// a block dominating the jumping one. Instructions of the loop whose operands are all computed
+// outside of it give the same value on every iteration and move to a preheader, a block every
+// entry to the loop passes through. Only instructions that can not trap or touch memory are
+// moved, they may now run when the loop body would not have
+
+pub fn hoist_loop_invariants(program: &mut IrProgram) {
+    for function in &mut program.functions {
+        // Moving code out of an inner loop may make it invariant in the outer one
+        while hoist_once(function) {}
+    }
+}
+
+struct Loop {
+    header: BlockId,
+    body: HashSet<BlockId>,
+}
+
+// The natural loops of the function, inner loops first
+fn find_loops(function: &IrFunction, dominators: &Dominators) -> Vec<Loop> {
+    let predecessors = cfg::predecessors(function);
+    let mut loops: Vec<Loop> = vec![];
+
+    for index in 0..function.blocks.len() {
+        let latch = BlockId(index);
+        if !dominators.is_reachable(latch) {
+            continue;
+        }
+        for header in cfg::successors(function, latch) {
+            if !dominators.dominates(header, latch) {
+                continue;
+            }
+
+            // Everything reaching the latch without passing through the header
+            let mut body = HashSet::from([header]);
+            let mut worklist = vec![latch];
+            while let Some(block) = worklist.pop() {
+                if body.insert(block) {
+                    worklist.extend(&predecessors[block.0]);
+                }
+            }
+
+            match loops.iter_mut().find(|other| other.header == header) {
+                Some(other) => other.body.extend(body),
+                None => loops.push(Loop { header, body }),
+            }
+        }
+    }
+
+    loops.sort_by_key(|found| found.body.len());
+    loops
+}
+
+fn is_hoistable(instr: &Instr) -> bool {
+    match instr {
+        Instr::Binary { op, .. } => *op != BinaryOp::Div, // Dividing by zero may trap
+        Instr::Const { .. }
+        | Instr::Copy { .. }
+        | Instr::Unary { .. }
+        | Instr::Convert { .. }
+        | Instr::FrameAddr { .. }
+        | Instr::GlobalAddr { .. } => true,
+        _ => false,
+    }
+}
+
+// Hoists the invariants of the innermost loop having any, returning whether there was one
+fn hoist_once(function: &mut IrFunction) -> bool {
+    let dominators = Dominators::new(function);
+    let order = cfg::reverse_postorder(function);
+
+    for found in find_loops(function, &dominators) {
+        if found.header == BlockId(0) {
+            continue; // The parameters are read in the entry block, it has no preheader
+        }
+
+        let defined: HashSet<_> = found
+            .body
+            .iter()
+            .flat_map(|block| &function.blocks[block.0].instrs)
+            .filter_map(|instr| instr.dst())
+            .collect();
+
+        // Positions of the invariant instructions, in an order where operands come first
+        let mut invariant = vec![];
+        let mut hoisted = HashSet::new();
+        let mut changed = true;
+        while changed {
+            changed = false;
+            for block in order.iter().filter(|block| found.body.contains(block)) {
+                for (index, instr) in function.blocks[block.0].instrs.iter().enumerate() {
+                    let Some(dst) = instr.dst() else {
+                        continue;
+                    };
+                    if hoisted.contains(&dst) || !is_hoistable(instr) {
+                        continue;
+                    }
+                    let operands_outside = instr
+                        .uses()
+                        .iter()
+                        .all(|vreg| !defined.contains(vreg) || hoisted.contains(vreg));
+                    if operands_outside {
+                        hoisted.insert(dst);
+                        invariant.push((*block, index));
+                        changed = true;
+                    }
+                }
+            }
+        }
+
+        if invariant.is_empty() {
+            continue;
+        }
+
+        let preheader = preheader(function, &found);
+        let instrs: Vec<Instr> = invariant
+            .iter()
+            .map(|(block, index)| function.blocks[block.0].instrs[*index].clone())
+            .collect();
+        for block in &found.body {
+            function.blocks[block.0]
+                .instrs
+                .retain(|instr| !instr.dst().is_some_and(|dst| hoisted.contains(&dst)));
+        }
+        function.blocks[preheader.0].instrs.extend(instrs);
+        return true;
+    }
+
+    false
+}
+
+// The block before the header that every entry to the loop comes through, made if needed.
+// Phis of the header get the values from outside the loop through a phi in the preheader
+fn preheader(function: &mut IrFunction, found: &Loop) -> BlockId {
+    let outside: Vec<BlockId> = cfg::predecessors(function)[found.header.0]
+        .iter()
+        .filter(|block| !found.body.contains(block))
+        .copied()
+        .collect();
+
+    if let [single] = outside[..] {
+        if cfg::successors(function, single) == [found.header] {
+            return single;
+        }
+    }
+
+    let preheader = BlockId(function.blocks.len());
+    let mut phis = vec![];
+    for instr in &mut function.blocks[found.header.0].instrs {
+        let Instr::Phi { dst, incoming } = instr else {
+            continue;
+        };
+        let (entering, mut inside): (Vec<_>, Vec<_>) = incoming
+            .iter()
+            .copied()
+            .partition(|(block, _)| outside.contains(block));
+        let value = match entering[..] {
+            [(_, value)] => value,
+            _ => {
+                function.vregs.push(function.vregs[dst.0]);
+                let value = VReg(function.vregs.len() - 1);
+                phis.push(Instr::Phi {
+                    dst: value,
+                    incoming: entering,
+                });
+                value
+            }
+        };
+        inside.push((preheader, value));
+        *incoming = inside;
+    }
+
+    for block in &outside {
+        function.blocks[block.0].terminator.rename_blocks(|target| {
+            if target == found.header {
+                preheader
+            } else {
+                target
+            }
+        });
+    }
+    function.blocks.push(Block {
+        instrs: phis,
+        terminator: Terminator::Jump(found.header),
+    });
+
+    preheader
+}
@@ -0,0 +1,681 @@
+use crate::{
+    cfg::Dominators,
+    ir::{
+        BinaryOp, BlockId, Conversion, Instr, IrFunction, IrGlobal, IrProgram, IrType, Terminator,
+        UnaryOp, VReg,
+    },
+};
+
+// Textual LLVM IR for --emit=llvm, translated from our IR after its passes so the two can be
+// compared on the same program, e.g with lli or llc. Slots become allocas, ints are i32 and
+// addresses ptr values, moved with getelementptr i8. The other 64 bit registers, such as the
+// offsets added to addresses, are i64, and only meet an address through inttoptr or ptrtoint
+// where C converts between the two, e.g for a null pointer or a pointer difference. Registers
+// written more than once, like the copies out-of-ssa leaves, or read where their definition
+// does not dominate also live in an alloca, the others are SSA values as they are
+
+pub fn emit_program(program: &IrProgram) -> String {
+    let mut out = "".to_string();
+
+    for global in &program.globals {
+        out += &global_definition(global);
+    }
+
+    // Functions called without a definition, typed by the first call. A tail call returns
+    // what the caller does
+    let mut declared: Vec<&str> = vec![];
+    for function in &program.functions {
+        for block in &function.blocks {
+            let calls = block.instrs.iter().filter_map(|instr| match instr {
+                Instr::Call {
+                    dst,
+                    function: callee,
+                    args,
+                } => Some((callee, args, function.vregs[dst.0])),
+                _ => None,
+            });
+            let tail_call = match &block.terminator {
+                Terminator::TailCall {
+                    function: callee,
+                    args,
+                } => Some((callee, args, function.ret)),
+                _ => None,
+            };
+            for (name, args, result) in calls.chain(tail_call) {
+                let defined = program.functions.iter().any(|other| other.name == *name);
+                if !defined && !declared.contains(&name.as_str()) {
+                    declared.push(name);
+                    let types: Vec<&str> = args
+                        .iter()
+                        .map(|arg| value_type(function.vregs[arg.0]))
+                        .collect();
+                    out += &format!(
+                        "declare {} @{}({})\n",
+                        value_type(result),
+                        name,
+                        types.join(", ")
+                    );
+                }
+            }
+        }
+    }
+    out += "declare void @llvm.memcpy.p0.p0.i64(ptr, ptr, i64, i1)\n";
+    out += "declare void @llvm.memset.p0.i64(ptr, i8, i64, i1)\n";
+
+    for function in &program.functions {
+        out += "\n";
+        out += &FunctionEmitter::new(function).emit();
+    }
+
+    out
+}
+
+// The type of parameters, results and memory, where a Ptr is always a C pointer
+fn value_type(ty: IrType) -> &'static str {
+    match ty {
+        IrType::Ptr => "ptr",
+        _ => "i32",
+    }
+}
+
+fn memory_type(ty: IrType) -> &'static str {
+    match ty {
+        IrType::I8 => "i8",
+        IrType::I32 => "i32",
+        IrType::Ptr => "ptr",
+    }
+}
+
+fn alignment(ty: IrType) -> usize {
+    match ty {
+        IrType::I8 => 1,
+        IrType::I32 => 4,
+        IrType::Ptr => 8,
+    }
+}
+
+// The type and the c"" string of the bytes, printable ones as they are
+fn byte_array(bytes: &[u8]) -> (String, String) {
+    let escaped: String = bytes
+        .iter()
+        .map(|byte| match byte {
+            b' '..=b'~' if *byte != b'"' && *byte != b'\\' => (*byte as char).to_string(),
+            _ => format!("\\{:02X}", byte),
+        })
+        .collect();
+    (
+        format!("[{} x i8]", bytes.len()),
+        format!("c\"{}\"", escaped),
+    )
+}
+
+// Images with addresses in them become packed structs of the bytes and the pointers between.
+// Globals keep their C name, so code clang compiles against the same header links with ours.
+// Static locals, named function.name.n, are private like the .L labels of the assembly
+fn global_definition(global: &IrGlobal) -> String {
+    let linkage = if global.symbol.contains('.') {
+        "private "
+    } else if global.is_static {
+        "internal "
+    } else {
+        ""
+    };
+    let kind = if global.is_const {
+        "constant"
+    } else {
+        "global"
+    };
+    let mut bytes = global.image.bytes.clone();
+    bytes.resize(global.size.max(1), 0);
+
+    let (ty, initializer) = if global.image.is_zero() {
+        (
+            format!("[{} x i8]", bytes.len()),
+            "zeroinitializer".to_string(),
+        )
+    } else if global.image.relocations.is_empty() {
+        byte_array(&bytes)
+    } else {
+        let mut relocations = global.image.relocations.clone();
+        relocations.sort_by_key(|relocation| relocation.offset);
+
+        let mut fields = vec![];
+        let mut offset = 0;
+        for relocation in relocations {
+            if relocation.offset > offset {
+                fields.push(byte_array(&bytes[offset..relocation.offset]));
+            }
+            fields.push((
+                "ptr".to_string(),
+                format!(
+                    "getelementptr (i8, ptr @{}, i64 {})",
+                    relocation.symbol, relocation.addend
+                ),
+            ));
+            offset = relocation.offset + 8;
+        }
+        if offset < bytes.len() {
+            fields.push(byte_array(&bytes[offset..]));
+        }
+
+        let types: Vec<&str> = fields.iter().map(|(ty, _)| ty.as_str()).collect();
+        let values: Vec<String> = fields
+            .iter()
+            .map(|(ty, value)| format!("{} {}", ty, value))
+            .collect();
+        (
+            format!("<{{ {} }}>", types.join(", ")),
+            format!("<{{ {} }}>", values.join(", ")),
+        )
+    };
+
+    format!(
+        "@{} = {}{} {} {}, align {}\n",
+        global.symbol, linkage, kind, ty, initializer, global.align
+    )
+}
+
+struct FunctionEmitter<'a> {
+    function: &'a IrFunction,
+    in_memory: Vec<bool>, // Indexed by VReg, whether it lives in an alloca
+    addresses: Vec<bool>, // Indexed by VReg, whether it is a ptr rather than an integer
+    temps: usize,
+    out: String,
+}
+
+impl<'a> FunctionEmitter<'a> {
+    fn new(function: &'a IrFunction) -> Self {
+        FunctionEmitter {
+            function,
+            in_memory: registers_in_memory(function),
+            addresses: addresses(function),
+            temps: 0,
+            out: "".to_string(),
+        }
+    }
+
+    fn line(&mut self, text: &str) {
+        self.out += &format!("  {}\n", text);
+    }
+
+    fn temp(&mut self) -> String {
+        self.temps += 1;
+        format!("%t{}", self.temps)
+    }
+
+    fn ty(&self, vreg: VReg) -> &'static str {
+        match self.function.vregs[vreg.0] {
+            IrType::Ptr if self.addresses[vreg.0] => "ptr",
+            IrType::Ptr => "i64",
+            _ => "i32",
+        }
+    }
+
+    // The operand reading vreg, loading it first when it lives in memory
+    fn value(&mut self, vreg: VReg) -> String {
+        if !self.in_memory[vreg.0] {
+            return format!("%v{}", vreg.0);
+        }
+        let temp = self.temp();
+        let ty = self.ty(vreg);
+        self.line(&format!("{} = load {}, ptr %v{}.addr", temp, ty, vreg.0));
+        temp
+    }
+
+    // The operand reading vreg as ty, converting between ptr and i64 where the two meet
+    fn value_as(&mut self, vreg: VReg, ty: &str) -> String {
+        let from = self.ty(vreg);
+        let value = self.value(vreg);
+        self.convert(value, from, ty)
+    }
+
+    fn convert(&mut self, value: String, from: &str, to: &str) -> String {
+        let conversion = match (from, to) {
+            ("i64", "ptr") => "inttoptr",
+            ("ptr", "i64") => "ptrtoint",
+            _ => return value,
+        };
+        let temp = self.temp();
+        self.line(&format!(
+            "{} = {} {} {} to {}",
+            temp, conversion, from, value, to
+        ));
+        temp
+    }
+
+    // Gives vreg the value computed by the instruction
+    fn define(&mut self, vreg: VReg, instruction: &str) {
+        let ty = self.ty(vreg);
+        self.define_as(vreg, ty, instruction);
+    }
+
+    // Gives vreg the value of type ty computed by the instruction. A register that is both
+    // written an address and an integer holds it as an address
+    fn define_as(&mut self, vreg: VReg, ty: &str, instruction: &str) {
+        let vreg_ty = self.ty(vreg);
+        if !self.in_memory[vreg.0] && ty == vreg_ty {
+            self.line(&format!("%v{} = {}", vreg.0, instruction));
+            return;
+        }
+        let temp = self.temp();
+        self.line(&format!("{} = {}", temp, instruction));
+        let value = self.convert(temp, ty, vreg_ty);
+        if self.in_memory[vreg.0] {
+            self.line(&format!(
+                "store {} {}, ptr %v{}.addr",
+                vreg_ty, value, vreg.0
+            ));
+        } else {
+            self.line(&format!("%v{} = {}", vreg.0, copy(vreg_ty, &value)));
+        }
+    }
+
+    fn emit(mut self) -> String {
+        let function = self.function;
+        let linkage = if function.is_static { "internal " } else { "" };
+        let params: Vec<String> = function
+            .params
+            .iter()
+            .enumerate()
+            .map(|(index, ty)| format!("{} %p{}", value_type(*ty), index))
+            .collect();
+        self.out += &format!(
+            "define {}{} @{}({}) {{\n",
+            linkage,
+            value_type(function.ret),
+            function.name,
+            params.join(", ")
+        );
+
+        // Our entry block may be jumped back to, LLVM's may not
+        self.out += "entry:\n";
+        for (index, slot) in function.slots.iter().enumerate() {
+            self.line(&format!(
+                "%s{} = alloca [{} x i8], align {}",
+                index,
+                slot.size.max(1),
+                slot.align
+            ));
+        }
+        for index in 0..function.vregs.len() {
+            if self.in_memory[index] {
+                let ty = self.ty(VReg(index));
+                self.line(&format!("%v{}.addr = alloca {}", index, ty));
+            }
+        }
+        self.line("br label %bb0");
+
+        for (index, block) in function.blocks.iter().enumerate() {
+            self.out += &format!("bb{}:\n", index);
+            for instr in &block.instrs {
+                self.instr(instr);
+            }
+            self.terminator(&block.terminator);
+        }
+
+        self.out += "}\n";
+        self.out
+    }
+
+    fn instr(&mut self, instr: &Instr) {
+        match instr {
+            Instr::Const { dst, value } => {
+                let (ty, value) = match self.function.vregs[dst.0] {
+                    IrType::Ptr => ("i64", *value),
+                    _ => ("i32", *value as i32 as i64),
+                };
+                self.define_as(*dst, ty, &format!("add {} 0, {}", ty, value));
+            }
+
+            Instr::Copy { dst, src } => {
+                let ty = self.ty(*src);
+                let src = self.value(*src);
+                self.define_as(*dst, ty, &copy(ty, &src));
+            }
+
+            Instr::Unary { op, dst, src } => {
+                let ty = self.ty(*dst);
+                let src = self.value_as(*src, ty);
+                let instruction = match op {
+                    UnaryOp::Neg => format!("sub {} 0, {}", ty, src),
+                    UnaryOp::Not => format!("xor {} {}, -1", ty, src),
+                };
+                self.define(*dst, &instruction);
+            }
+
+            Instr::Binary { op, dst, lhs, rhs } => self.binary(*op, *dst, *lhs, *rhs),
+
+            Instr::Convert {
+                conversion,
+                dst,
+                src,
+            } => {
+                let instruction = match conversion {
+                    Conversion::Sext => {
+                        let src = self.value(*src);
+                        format!("sext i32 {} to i64", src)
+                    }
+                    Conversion::Trunc => {
+                        let src = self.value_as(*src, "i64");
+                        format!("trunc i64 {} to i32", src)
+                    }
+                    Conversion::SextI8 => {
+                        let src = self.value(*src);
+                        let byte = self.temp();
+                        self.line(&format!("{} = trunc i32 {} to i8", byte, src));
+                        format!("sext i8 {} to i32", byte)
+                    }
+                };
+                let ty = match conversion {
+                    Conversion::Sext => "i64",
+                    _ => "i32",
+                };
+                self.define_as(*dst, ty, &instruction);
+            }
+
+            Instr::FrameAddr { dst, slot } => {
+                self.define_as(*dst, "ptr", &copy("ptr", &format!("%s{}", slot)));
+            }
+
+            Instr::GlobalAddr { dst, symbol } => {
+                self.define_as(*dst, "ptr", &copy("ptr", &format!("@{}", symbol)));
+            }
+
+            // Chars are sign extended to 32 bits, like C promotes them
+            Instr::Load { dst, ty, addr } => {
+                let pointer = self.value_as(*addr, "ptr");
+                let load = format!(
+                    "load {}, ptr {}, align {}",
+                    memory_type(*ty),
+                    pointer,
+                    alignment(*ty)
+                );
+                if *ty == IrType::I8 {
+                    let byte = self.temp();
+                    self.line(&format!("{} = {}", byte, load));
+                    self.define(*dst, &format!("sext i8 {} to i32", byte));
+                } else {
+                    self.define_as(*dst, memory_type(*ty), &load);
+                }
+            }
+
+            Instr::Store { ty, addr, value } => {
+                let pointer = self.value_as(*addr, "ptr");
+                let mut value = self.value_as(*value, value_type(*ty));
+                if *ty == IrType::I8 {
+                    let byte = self.temp();
+                    self.line(&format!("{} = trunc i32 {} to i8", byte, value));
+                    value = byte;
+                }
+                self.line(&format!(
+                    "store {} {}, ptr {}, align {}",
+                    memory_type(*ty),
+                    value,
+                    pointer,
+                    alignment(*ty)
+                ));
+            }
+
+            Instr::MemCopy { dst, src, size } => {
+                let dst = self.value_as(*dst, "ptr");
+                let src = self.value_as(*src, "ptr");
+                self.line(&format!(
+                    "call void @llvm.memcpy.p0.p0.i64(ptr {}, ptr {}, i64 {}, i1 false)",
+                    dst, src, size
+                ));
+            }
+
+            Instr::MemZero { dst, size } => {
+                let dst = self.value_as(*dst, "ptr");
+                self.line(&format!(
+                    "call void @llvm.memset.p0.i64(ptr {}, i8 0, i64 {}, i1 false)",
+                    dst, size
+                ));
+            }
+
+            Instr::Param { dst, index } => {
+                let ty = value_type(self.function.params[*index]);
+                self.define_as(*dst, ty, &copy(ty, &format!("%p{}", index)));
+            }
+
+            Instr::Call {
+                dst,
+                function,
+                args,
+            } => {
+                let result = self.function.vregs[dst.0];
+                let call = self.call(result, function, args);
+                self.define_as(*dst, value_type(result), &call);
+            }
+
+            Instr::Phi { .. } => panic!("Phi left for LLVM IR, out-of-ssa did not run"),
+        }
+    }
+
+    // Adding to or subtracting from an address moves it with getelementptr, subtracting two
+    // addresses or doing other arithmetic on them works on the integers they convert to
+    fn binary(&mut self, op: BinaryOp, dst: VReg, lhs: VReg, rhs: VReg) {
+        let is_address = self.function.vregs[lhs.0] == IrType::Ptr;
+        let base = match (op, self.addresses[lhs.0], self.addresses[rhs.0]) {
+            (BinaryOp::Add, true, _) | (BinaryOp::Sub, true, false) => Some((lhs, rhs)),
+            (BinaryOp::Add, false, true) => Some((rhs, lhs)),
+            _ => None,
+        };
+        if let Some((base, offset)) = base {
+            let base = self.value(base);
+            let mut offset = self.value_as(offset, "i64");
+            if op == BinaryOp::Sub {
+                let negated = self.temp();
+                self.line(&format!("{} = sub i64 0, {}", negated, offset));
+                offset = negated;
+            }
+            let instruction = format!("getelementptr i8, ptr {}, i64 {}", base, offset);
+            self.define_as(dst, "ptr", &instruction);
+            return;
+        }
+
+        // Addresses compare unsigned, integers signed, as pointers when either is one
+        let predicate = match op {
+            BinaryOp::Eq => "eq",
+            BinaryOp::Ne => "ne",
+            BinaryOp::Lt if is_address => "ult",
+            BinaryOp::Le if is_address => "ule",
+            BinaryOp::Gt if is_address => "ugt",
+            BinaryOp::Ge if is_address => "uge",
+            BinaryOp::Lt => "slt",
+            BinaryOp::Le => "sle",
+            BinaryOp::Gt => "sgt",
+            BinaryOp::Ge => "sge",
+            _ => {
+                let instruction = match op {
+                    BinaryOp::Add => "add",
+                    BinaryOp::Sub => "sub",
+                    BinaryOp::Mul => "mul",
+                    _ => "sdiv",
+                };
+                let ty = match self.function.vregs[dst.0] {
+                    IrType::Ptr => "i64",
+                    _ => "i32",
+                };
+                let lhs = self.value_as(lhs, ty);
+                let rhs = self.value_as(rhs, ty);
+                self.define_as(dst, ty, &format!("{} {} {}, {}", instruction, ty, lhs, rhs));
+                return;
+            }
+        };
+        let ty = if self.addresses[lhs.0] || self.addresses[rhs.0] {
+            "ptr"
+        } else {
+            self.ty(lhs)
+        };
+        let lhs = self.value_as(lhs, ty);
+        let rhs = self.value_as(rhs, ty);
+        let condition = self.temp();
+        self.line(&format!(
+            "{} = icmp {} {} {}, {}",
+            condition, predicate, ty, lhs, rhs
+        ));
+        self.define(dst, &format!("zext i1 {} to i32", condition));
+    }
+
+    fn call(&mut self, result: IrType, function: &str, args: &[VReg]) -> String {
+        let args: Vec<String> = args
+            .iter()
+            .map(|arg| {
+                let ty = value_type(self.function.vregs[arg.0]);
+                format!("{} {}", ty, self.value_as(*arg, ty))
+            })
+            .collect();
+        format!(
+            "call {} @{}({})",
+            value_type(result),
+            function,
+            args.join(", ")
+        )
+    }
+
+    fn terminator(&mut self, terminator: &Terminator) {
+        match terminator {
+            Terminator::Jump(block) => self.line(&format!("br label %bb{}", block.0)),
+
+            Terminator::Branch {
+                condition,
+                then,
+                otherwise,
+            } => {
+                let ty = self.ty(*condition);
+                let zero = if ty == "ptr" { "null" } else { "0" };
+                let value = self.value(*condition);
+                let condition = self.temp();
+                self.line(&format!(
+                    "{} = icmp ne {} {}, {}",
+                    condition, ty, value, zero
+                ));
+                self.line(&format!(
+                    "br i1 {}, label %bb{}, label %bb{}",
+                    condition, then.0, otherwise.0
+                ));
+            }
+
+            Terminator::Switch {
+                value,
+                cases,
+                default,
+            } => {
+                let value = self.value(*value);
+                let cases: Vec<String> = cases
+                    .iter()
+                    .map(|(case, block)| format!("i32 {}, label %bb{}", *case as i32, block.0))
+                    .collect();
+                self.line(&format!(
+                    "switch i32 {}, label %bb{} [ {} ]",
+                    value,
+                    default.0,
+                    cases.join(" ")
+                ));
+            }
+
+            Terminator::Return(value) => {
+                let ty = value_type(self.function.ret);
+                let value = self.value_as(*value, ty);
+                self.line(&format!("ret {} {}", ty, value));
+            }
+
+            Terminator::TailCall { function, args } => {
+                let ret = self.function.ret;
+                let call = self.call(ret, function, args);
+                let result = self.temp();
+                self.line(&format!("{} = tail {}", result, call));
+                self.line(&format!("ret {} {}", value_type(ret), result));
+            }
+        }
+    }
+}
+
+// LLVM has no copy instruction, so a value is copied by adding nothing to it
+fn copy(ty: &str, value: &str) -> String {
+    match ty {
+        "ptr" => format!("getelementptr i8, ptr {}, i64 0", value),
+        _ => format!("add {} {}, 0", ty, value),
+    }
+}
+
+// Which registers of type Ptr hold addresses rather than offsets and other 64 bit integers:
+// those the address of a slot or global, a pointer parameter, load or result flows into. One
+// address among the definitions of a register makes it an address
+fn addresses(function: &IrFunction) -> Vec<bool> {
+    let mut addresses = vec![false; function.vregs.len()];
+    let mut changed = true;
+    while changed {
+        changed = false;
+        for instr in function.blocks.iter().flat_map(|block| &block.instrs) {
+            let is_address = match instr {
+                Instr::FrameAddr { .. } | Instr::GlobalAddr { .. } => true,
+                Instr::Param { dst, .. } | Instr::Load { dst, .. } | Instr::Call { dst, .. } => {
+                    function.vregs[dst.0] == IrType::Ptr
+                }
+                Instr::Copy { src, .. } => addresses[src.0],
+                Instr::Binary {
+                    op: BinaryOp::Add,
+                    lhs,
+                    rhs,
+                    ..
+                } => addresses[lhs.0] || addresses[rhs.0],
+                Instr::Binary {
+                    op: BinaryOp::Sub,
+                    lhs,
+                    rhs,
+                    ..
+                } => addresses[lhs.0] && !addresses[rhs.0],
+                _ => false,
+            };
+            if let Some(dst) = instr.dst() {
+                if is_address && !addresses[dst.0] {
+                    addresses[dst.0] = true;
+                    changed = true;
+                }
+            }
+        }
+    }
+    addresses
+}
+
+// Registers that are not written exactly once, or read somewhere their definition does not
+// dominate, which SSA values have to be
+fn registers_in_memory(function: &IrFunction) -> Vec<bool> {
+    let dominators = Dominators::new(function);
+    let mut definitions: Vec<Vec<(BlockId, usize)>> = vec![vec![]; function.vregs.len()];
+    for (index, block) in function.blocks.iter().enumerate() {
+        for (position, instr) in block.instrs.iter().enumerate() {
+            if let Some(dst) = instr.dst() {
+                definitions[dst.0].push((BlockId(index), position));
+            }
+        }
+    }
+
+    let mut in_memory: Vec<bool> = definitions.iter().map(|defs| defs.len() != 1).collect();
+    let mut check_use = |vreg: VReg, block: BlockId, position: usize| {
+        if let [(def_block, def_position)] = definitions[vreg.0][..] {
+            let dominated = if def_block == block {
+                def_position < position
+            } else {
+                dominators.dominates(def_block, block)
+            };
+            if !dominated {
+                in_memory[vreg.0] = true;
+            }
+        }
+    };
+    for (index, block) in function.blocks.iter().enumerate() {
+        for (position, instr) in block.instrs.iter().enumerate() {
+            for vreg in instr.uses() {
+                check_use(vreg, BlockId(index), position);
+            }
+        }
+        for vreg in block.terminator.uses() {
+            check_use(vreg, BlockId(index), block.instrs.len());
+        }
+    }
+
+    in_memory
+}
@@ -0,0 +1,725 @@
+use crate::{
+    ir::{
+        BinaryOp, Block, BlockId, Conversion, Instr, IrFunction, IrGlobal, IrProgram, IrType, Slot,
+        Terminator, UnaryOp, VReg,
+    },
+    parser::{
+        Expr, ExprNode, FunctionNode, GlobalNode, Initializer, ProgramNode, Statement,
+        StatementNode, Storage, SwitchCases,
+    },
+    token::TokenType,
+    types::Type,
+};
+
+// Type of the register holding a value of a C type, aggregates are held by their address
+fn value_type(ty: &Type) -> IrType {
+    match ty {
+        Type::Int | Type::Char | Type::Void => IrType::I32,
+        _ => IrType::Ptr,
+    }
+}
+
+// Width of the load or store of a scalar C type
+fn memory_type(ty: &Type) -> IrType {
+    match ty {
+        Type::Char => IrType::I8,
+        Type::Int => IrType::I32,
+        _ => IrType::Ptr,
+    }
+}
+
+pub fn lower_program(program: &ProgramNode) -> IrProgram {
+    IrProgram {
+        functions: program.body.iter().map(lower_function).collect(),
+        globals: program.globals.iter().map(lower_global).collect(),
+    }
+}
+
+fn lower_global(global: &GlobalNode) -> IrGlobal {
+    let Storage::Global(symbol) = &global.variable.storage else {
+        panic!("Global {} has no symbol", global.variable.name);
+    };
+
+    IrGlobal {
+        symbol: symbol.clone(),
+        size: global.variable.ty.size(),
+        align: global.variable.ty.align(),
+        image: global.image.clone(),
+        is_const: global.variable.is_const,
+        is_static: global.is_static,
+    }
+}
+
+fn lower_function(function: &FunctionNode) -> IrFunction {
+    let mut builder = FunctionBuilder::new(function);
+
+    // Arguments arrive in registers and are stored to the slots of the parameters. All of
+    // them are read first, a backend may need to move them out of each others registers
+    let values: Vec<VReg> = function
+        .params
+        .iter()
+        .enumerate()
+        .map(|(index, param)| {
+            let value = builder.new_vreg(value_type(&param.ty));
+            builder.emit(Instr::Param { dst: value, index });
+            value
+        })
+        .collect();
+    for (param, value) in function.params.iter().zip(values) {
+        let addr = builder.lower_address(&ExprNode::new(Expr::Variable(param.clone())));
+        builder.emit(Instr::Store {
+            ty: memory_type(&param.ty),
+            addr,
+            value,
+        });
+    }
+
+    for statement in &function.body {
+        builder.lower_statement(statement);
+    }
+
+    // Falling off the end of a function returns 0
+    let zero = builder.constant(builder.function.ret, 0);
+    builder.set_terminator(Terminator::Return(zero));
+
+    builder.finish()
+}
+
+// Labels of the enclosing switch, for its case labels to find their blocks
+struct SwitchBlocks {
+    cases: Vec<(i32, BlockId)>,
+    default: BlockId,
+}
+
+// Appends instructions to the current block. Code following a jump, such as the statements
+// after a return, goes in a fresh block that nothing jumps to
+struct FunctionBuilder {
+    function: IrFunction,
+    terminators: Vec<Option<Terminator>>,
+    current: BlockId,
+    switches: Vec<SwitchBlocks>, // Enclosing switches, innermost last
+    breaks: Vec<BlockId>,        // Blocks a break jumps to, innermost last
+    continues: Vec<BlockId>,     // Blocks a continue jumps to, innermost last
+    return_type: Type,
+}
+
+impl FunctionBuilder {
+    fn new(function: &FunctionNode) -> Self {
+        let slots = function
+            .locals
+            .iter()
+            .map(|local| Slot {
+                name: local.name.clone(),
+                size: local.ty.size(),
+                align: local.ty.align(),
+            })
+            .collect();
+
+        let mut builder = FunctionBuilder {
+            function: IrFunction {
+                name: function.name.clone(),
+                is_static: function.is_static,
+                ret: value_type(&function.return_type),
+                params: function
+                    .params
+                    .iter()
+                    .map(|param| value_type(&param.ty))
+                    .collect(),
+                slots,
+                blocks: vec![],
+                vregs: vec![],
+            },
+            terminators: vec![],
+            current: BlockId(0),
+            switches: vec![],
+            breaks: vec![],
+            continues: vec![],
+            return_type: function.return_type.clone(),
+        };
+        builder.current = builder.new_block();
+
+        builder
+    }
+
+    fn finish(mut self) -> IrFunction {
+        for (block, terminator) in self.function.blocks.iter_mut().zip(self.terminators) {
+            block.terminator = terminator.expect("Every block is terminated");
+        }
+
+        self.function
+    }
+
+    fn new_block(&mut self) -> BlockId {
+        self.function.blocks.push(Block {
+            instrs: vec![],
+            terminator: Terminator::Jump(BlockId(0)), // Replaced when the block is terminated
+        });
+        self.terminators.push(None);
+
+        BlockId(self.function.blocks.len() - 1)
+    }
+
+    fn new_vreg(&mut self, ty: IrType) -> VReg {
+        self.function.vregs.push(ty);
+        VReg(self.function.vregs.len() - 1)
+    }
+
+    fn vreg_type(&self, vreg: VReg) -> IrType {
+        self.function.vregs[vreg.0]
+    }
+
+    fn emit(&mut self, instr: Instr) {
+        self.function.blocks[self.current.0].instrs.push(instr);
+    }
+
+    fn set_terminator(&mut self, terminator: Terminator) {
+        self.terminators[self.current.0] = Some(terminator);
+    }
+
+    // Ends the current block and continues in a new one that is only reachable by a jump
+    fn terminate(&mut self, terminator: Terminator) {
+        self.set_terminator(terminator);
+        self.current = self.new_block();
+    }
+
+    // Continues in block, falling through to it from the current one
+    fn switch_to(&mut self, block: BlockId) {
+        self.set_terminator(Terminator::Jump(block));
+        self.current = block;
+    }
+
+    fn constant(&mut self, ty: IrType, value: i64) -> VReg {
+        let dst = self.new_vreg(ty);
+        self.emit(Instr::Const { dst, value });
+        dst
+    }
+
+    fn binary(&mut self, op: BinaryOp, ty: IrType, lhs: VReg, rhs: VReg) -> VReg {
+        let dst = self.new_vreg(ty);
+        self.emit(Instr::Binary { op, dst, lhs, rhs });
+        dst
+    }
+
+    fn convert(&mut self, conversion: Conversion, src: VReg) -> VReg {
+        let ty = match conversion {
+            Conversion::Sext => IrType::Ptr,
+            Conversion::Trunc | Conversion::SextI8 => IrType::I32,
+        };
+        let dst = self.new_vreg(ty);
+        self.emit(Instr::Convert {
+            conversion,
+            dst,
+            src,
+        });
+        dst
+    }
+
+    fn load(&mut self, ty: &Type, addr: VReg) -> VReg {
+        let dst = self.new_vreg(value_type(ty));
+        self.emit(Instr::Load {
+            dst,
+            ty: memory_type(ty),
+            addr,
+        });
+        dst
+    }
+
+    // Adds a constant byte offset to an address
+    fn offset(&mut self, addr: VReg, offset: usize) -> VReg {
+        if offset == 0 {
+            return addr;
+        }
+        let offset = self.constant(IrType::Ptr, offset as i64);
+        self.binary(BinaryOp::Add, IrType::Ptr, addr, offset)
+    }
+
+    // Converts a value to how a C type is held in a register, as assignment and casts do
+    fn convert_to(&mut self, value: VReg, ty: &Type) -> VReg {
+        match (self.vreg_type(value), ty) {
+            (IrType::Ptr, Type::Char) => {
+                let truncated = self.convert(Conversion::Trunc, value);
+                self.convert(Conversion::SextI8, truncated)
+            }
+            (_, Type::Char) => self.convert(Conversion::SextI8, value),
+            (IrType::Ptr, Type::Int) => self.convert(Conversion::Trunc, value),
+            (IrType::I32, Type::Pointer(_)) => self.convert(Conversion::Sext, value),
+            _ => value,
+        }
+    }
+
+    // Stores value, converted to ty, at addr and returns the converted value
+    fn store(&mut self, ty: &Type, addr: VReg, value: &ExprNode) -> VReg {
+        let value_reg = self.lower_expr(value);
+
+        if ty.is_struct() {
+            self.emit(Instr::MemCopy {
+                dst: addr,
+                src: value_reg,
+                size: ty.size(),
+            });
+            return addr;
+        }
+
+        let converted = if *ty == Type::Char && value.ty == Type::Char {
+            value_reg
+        } else {
+            self.convert_to(value_reg, ty)
+        };
+        self.emit(Instr::Store {
+            ty: memory_type(ty),
+            addr,
+            value: converted,
+        });
+        converted
+    }
+
+    fn lower_statement(&mut self, node: &StatementNode) {
+        match &node.statement {
+            Statement::Return(Some(expr_node)) => {
+                let value = self.lower_expr(expr_node);
+                let value = self.convert_to(value, &self.return_type.clone());
+                self.terminate(Terminator::Return(value));
+            }
+            Statement::Return(None) => {
+                let zero = self.constant(IrType::I32, 0);
+                self.terminate(Terminator::Return(zero));
+            }
+
+            Statement::Expression(expr_node) => {
+                self.lower_expr(expr_node);
+            }
+
+            Statement::Empty | Statement::Declaration(_, None) => {}
+
+            Statement::Declaration(variable, Some(initializer)) => {
+                let Storage::Local(slot) = variable.storage else {
+                    return; // Static locals are initialized in their data section
+                };
+                let addr = self.new_vreg(IrType::Ptr);
+                self.emit(Instr::FrameAddr { dst: addr, slot });
+
+                if let Initializer::Expr(expr_node) = initializer {
+                    self.store(&variable.ty, addr, expr_node);
+                    return;
+                }
+
+                // Everything not named in the list is zero
+                self.emit(Instr::MemZero {
+                    dst: addr,
+                    size: variable.ty.size(),
+                });
+
+                let mut stores = vec![];
+                initializer.flatten(&variable.ty, 0, &mut stores);
+                for (offset, expr_node, ty) in stores {
+                    let element = self.offset(addr, offset);
+                    self.store(&ty, element, expr_node);
+                }
+            }
+
+            Statement::Block(statements) => {
+                for statement in statements {
+                    self.lower_statement(statement);
+                }
+            }
+
+            Statement::Switch(condition, body, cases) => self.lower_switch(condition, body, cases),
+
+            Statement::Case(value, body) => {
+                let block = self
+                    .switches
+                    .last()
+                    .and_then(|switch| switch.cases.iter().find(|case| case.0 == *value))
+                    .map(|case| case.1)
+                    .expect("Case outside of a switch");
+                self.switch_to(block);
+                self.lower_statement(body);
+            }
+
+            Statement::Default(body) => {
+                let block = self
+                    .switches
+                    .last()
+                    .map(|switch| switch.default)
+                    .expect("Default outside of a switch");
+                self.switch_to(block);
+                self.lower_statement(body);
+            }
+
+            Statement::Break => {
+                let end = *self
+                    .breaks
+                    .last()
+                    .expect("Break outside of a loop or switch");
+                self.terminate(Terminator::Jump(end));
+            }
+
+            Statement::Continue => {
+                let next = *self.continues.last().expect("Continue outside of a loop");
+                self.terminate(Terminator::Jump(next));
+            }
+
+            Statement::If(condition, then, otherwise) => {
+                let condition = self.lower_expr(condition);
+                let then_block = self.new_block();
+                let otherwise_block = self.new_block();
+                let end_block = self.new_block();
+                self.set_terminator(Terminator::Branch {
+                    condition,
+                    then: then_block,
+                    otherwise: otherwise_block,
+                });
+
+                self.current = then_block;
+                self.lower_statement(then);
+                self.set_terminator(Terminator::Jump(end_block));
+
+                self.current = otherwise_block;
+                if let Some(otherwise) = otherwise {
+                    self.lower_statement(otherwise);
+                }
+                self.switch_to(end_block);
+            }
+
+            Statement::While(condition, body) => {
+                let condition_block = self.new_block();
+                self.switch_to(condition_block);
+                self.lower_loop(Some(condition), None, body, condition_block);
+            }
+
+            Statement::For(init, condition, step, body) => {
+                self.lower_statement(init);
+                let condition_block = self.new_block();
+                self.switch_to(condition_block);
+                self.lower_loop(condition.as_ref(), step.as_ref(), body, condition_block);
+            }
+        }
+    }
+
+    // The condition is tested in the current block, which the end of the body jumps back to
+    // after the step. A continue jumps to the step
+    fn lower_loop(
+        &mut self,
+        condition: Option<&ExprNode>,
+        step: Option<&ExprNode>,
+        body: &StatementNode,
+        condition_block: BlockId,
+    ) {
+        let body_block = self.new_block();
+        let step_block = self.new_block();
+        let end_block = self.new_block();
+
+        match condition {
+            Some(condition) => {
+                let condition = self.lower_expr(condition);
+                self.set_terminator(Terminator::Branch {
+                    condition,
+                    then: body_block,
+                    otherwise: end_block,
+                });
+            }
+            None => self.set_terminator(Terminator::Jump(body_block)),
+        }
+
+        self.current = body_block;
+        self.breaks.push(end_block);
+        self.continues.push(step_block);
+        self.lower_statement(body);
+        self.continues.pop();
+        self.breaks.pop();
+
+        self.switch_to(step_block);
+        if let Some(step) = step {
+            self.lower_expr(step);
+        }
+        self.set_terminator(Terminator::Jump(condition_block));
+        self.current = end_block;
+    }
+
+    // Every case gets a block up front, the body then falls through them in order
+    fn lower_switch(&mut self, condition: &ExprNode, body: &StatementNode, cases: &SwitchCases) {
+        let value = self.lower_expr(condition);
+
+        let case_blocks: Vec<(i32, BlockId)> = cases
+            .values
+            .iter()
+            .map(|value| (*value, self.new_block()))
+            .collect();
+        let default_block = self.new_block();
+        let end_block = self.new_block();
+        let missing_block = if cases.has_default {
+            default_block
+        } else {
+            end_block
+        };
+
+        self.terminate(Terminator::Switch {
+            value,
+            cases: case_blocks
+                .iter()
+                .map(|(value, block)| (*value as i64, *block))
+                .collect(),
+            default: missing_block,
+        });
+
+        self.switches.push(SwitchBlocks {
+            cases: case_blocks,
+            default: default_block,
+        });
+        self.breaks.push(end_block);
+
+        self.lower_statement(body);
+
+        self.breaks.pop();
+        self.switches.pop();
+
+        // Without a default label its block is never entered
+        if !cases.has_default {
+            self.switch_to(default_block);
+        }
+        self.switch_to(end_block);
+    }
+
+    // Puts the address of an lvalue in a register
+    fn lower_address(&mut self, node: &ExprNode) -> VReg {
+        match &node.expr {
+            Expr::Variable(variable) => {
+                let dst = self.new_vreg(IrType::Ptr);
+                match &variable.storage {
+                    Storage::Local(slot) => self.emit(Instr::FrameAddr { dst, slot: *slot }),
+                    Storage::Global(symbol) => self.emit(Instr::GlobalAddr {
+                        dst,
+                        symbol: symbol.clone(),
+                    }),
+                }
+                dst
+            }
+            Expr::UnaryOp(TokenType::Mul, Some(operand)) => self.lower_expr(operand),
+            Expr::Member(base, member) => {
+                let base_addr = self.lower_address(base);
+                self.offset(base_addr, member.offset)
+            }
+            _ => panic!("{} is not an lvalue", node),
+        }
+    }
+
+    // Puts the value of an expression in a register
+    fn lower_expr(&mut self, node: &ExprNode) -> VReg {
+        match &node.expr {
+            Expr::Number(num) => self.constant(IrType::I32, *num as i64),
+
+            // Arrays and structs are used through their address
+            Expr::Variable(_) | Expr::Member(_, _) | Expr::UnaryOp(TokenType::Mul, _) => {
+                let addr = self.lower_address(node);
+                if node.ty.is_array() || node.ty.is_struct() {
+                    return addr;
+                }
+                self.load(&node.ty, addr)
+            }
+
+            Expr::UnaryOp(TokenType::BitAnd, Some(operand)) => self.lower_address(operand),
+
+            Expr::UnaryOp(operator, Some(operand)) => {
+                let src = self.lower_expr(operand);
+                let ty = self.vreg_type(src);
+                match operator {
+                    TokenType::Minus | TokenType::BitComplement => {
+                        let op = if *operator == TokenType::Minus {
+                            UnaryOp::Neg
+                        } else {
+                            UnaryOp::Not
+                        };
+                        let dst = self.new_vreg(ty);
+                        self.emit(Instr::Unary { op, dst, src });
+                        dst
+                    }
+                    TokenType::Not => {
+                        let zero = self.constant(ty, 0);
+                        self.binary(BinaryOp::Eq, IrType::I32, src, zero)
+                    }
+                    _ => panic!("Unsupported unary operator {}", operator),
+                }
+            }
+
+            Expr::UnaryOp(operator, None) => panic!("Missing operand for {}", operator),
+
+            Expr::BinaryOp(TokenType::And | TokenType::Or, left, right) => {
+                self.lower_logical(node, left, right)
+            }
+
+            Expr::BinaryOp(operator, left, right) => {
+                let lhs = self.lower_expr(left);
+                let rhs = self.lower_expr(right);
+                self.lower_binary(operator, left, lhs, right, rhs)
+            }
+
+            Expr::Assign(target, value) => {
+                let addr = self.lower_address(target);
+                self.store(&target.ty, addr, value)
+            }
+
+            // Only the chosen branch runs, both leave their value in the same register
+            Expr::Conditional(condition, then, otherwise) => {
+                let result = self.new_vreg(value_type(&node.ty));
+                let condition = self.lower_expr(condition);
+
+                let then_block = self.new_block();
+                let otherwise_block = self.new_block();
+                let end_block = self.new_block();
+                self.set_terminator(Terminator::Branch {
+                    condition,
+                    then: then_block,
+                    otherwise: otherwise_block,
+                });
+
+                for (block, branch) in [(then_block, then), (otherwise_block, otherwise)] {
+                    self.current = block;
+                    let value = self.lower_expr(branch);
+                    let value = if node.ty.is_struct() {
+                        value
+                    } else {
+                        self.convert_to(value, &node.ty.decay())
+                    };
+                    self.emit(Instr::Copy {
+                        dst: result,
+                        src: value,
+                    });
+                    self.set_terminator(Terminator::Jump(end_block));
+                }
+
+                self.current = end_block;
+                result
+            }
+
+            Expr::Comma(left, right) => {
+                self.lower_expr(left);
+                self.lower_expr(right)
+            }
+
+            Expr::Cast(ty, operand) => {
+                let value = self.lower_expr(operand);
+                if *ty == Type::Char && operand.ty == Type::Char {
+                    return value;
+                }
+                self.convert_to(value, ty)
+            }
+
+            // A char comes back sign extended again, the callee need not have done it
+            Expr::Call(function, args) => {
+                let args = args.iter().map(|arg| self.lower_expr(arg)).collect();
+                let dst = self.new_vreg(value_type(&node.ty));
+                self.emit(Instr::Call {
+                    dst,
+                    function: function.clone(),
+                    args,
+                });
+                match node.ty {
+                    Type::Char => self.convert(Conversion::SextI8, dst),
+                    _ => dst,
+                }
+            }
+        }
+    }
+
+    fn lower_binary(
+        &mut self,
+        operator: &TokenType,
+        left: &ExprNode,
+        lhs: VReg,
+        right: &ExprNode,
+        rhs: VReg,
+    ) -> VReg {
+        let op = match operator {
+            TokenType::Plus => BinaryOp::Add,
+            TokenType::Minus => BinaryOp::Sub,
+            TokenType::Mul => BinaryOp::Mul,
+            TokenType::Div => BinaryOp::Div,
+            TokenType::Eq => BinaryOp::Eq,
+            TokenType::Neq => BinaryOp::Ne,
+            TokenType::Lt => BinaryOp::Lt,
+            TokenType::Le => BinaryOp::Le,
+            TokenType::Gt => BinaryOp::Gt,
+            TokenType::Ge => BinaryOp::Ge,
+            _ => panic!("Unsupported binary operator {}", operator),
+        };
+
+        match (op, left.ty.base(), right.ty.base()) {
+            // Pointer difference counts elements between the two
+            (BinaryOp::Sub, Some(base), Some(_)) => {
+                let bytes = self.binary(BinaryOp::Sub, IrType::Ptr, lhs, rhs);
+                let size = self.constant(IrType::Ptr, base.size() as i64);
+                let elements = self.binary(BinaryOp::Div, IrType::Ptr, bytes, size);
+                self.convert(Conversion::Trunc, elements)
+            }
+            // Pointer + integer advances by whole elements
+            (BinaryOp::Add | BinaryOp::Sub, Some(base), None) => {
+                let step = self.scale(rhs, base.size());
+                self.binary(op, IrType::Ptr, lhs, step)
+            }
+            (BinaryOp::Add, None, Some(base)) => {
+                let step = self.scale(lhs, base.size());
+                self.binary(op, IrType::Ptr, step, rhs)
+            }
+            // Comparisons with a pointer compare addresses, a 0 on the other side is null
+            (_, Some(_), None) | (_, None, Some(_)) => {
+                let lhs = self.convert_to(lhs, &Type::pointer_to(Type::Int));
+                let rhs = self.convert_to(rhs, &Type::pointer_to(Type::Int));
+                self.binary(op, IrType::I32, lhs, rhs)
+            }
+            _ => self.binary(op, IrType::I32, lhs, rhs),
+        }
+    }
+
+    // Widens an integer to an address offset of whole elements
+    fn scale(&mut self, index: VReg, size: usize) -> VReg {
+        let index = self.convert(Conversion::Sext, index);
+        if size == 1 {
+            return index;
+        }
+        let size = self.constant(IrType::Ptr, size as i64);
+        self.binary(BinaryOp::Mul, IrType::Ptr, index, size)
+    }
+
+    // && and || only evaluate the right side when the left does not decide the result
+    fn lower_logical(&mut self, node: &ExprNode, left: &ExprNode, right: &ExprNode) -> VReg {
+        let is_and = matches!(node.expr, Expr::BinaryOp(TokenType::And, _, _));
+        let result = self.new_vreg(IrType::I32);
+        let lhs = self.lower_expr(left);
+
+        let right_block = self.new_block();
+        let short_block = self.new_block();
+        let end_block = self.new_block();
+        let (then, otherwise) = if is_and {
+            (right_block, short_block)
+        } else {
+            (short_block, right_block)
+        };
+        self.set_terminator(Terminator::Branch {
+            condition: lhs,
+            then,
+            otherwise,
+        });
+
+        // The left side alone decides, false for && and true for ||
+        self.current = short_block;
+        let decided = self.constant(IrType::I32, (!is_and) as i64);
+        self.emit(Instr::Copy {
+            dst: result,
+            src: decided,
+        });
+        self.set_terminator(Terminator::Jump(end_block));
+
+        self.current = right_block;
+        let rhs = self.lower_expr(right);
+        let zero = self.constant(self.vreg_type(rhs), 0);
+        let truth = self.binary(BinaryOp::Ne, IrType::I32, rhs, zero);
+        self.emit(Instr::Copy {
+            dst: result,
+            src: truth,
+        });
+        self.set_terminator(Terminator::Jump(end_block));
+
+        self.current = end_block;
+        result
+    }
+}
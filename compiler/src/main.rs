@@ -1,57 +1,187 @@
 use std::fs;
-use std::vec;
-mod gen;
-mod lex;
-mod parse_util;
-mod parser;
-mod pretty_printer;
-mod token;
+use std::process;
 
-fn main() {
-    compile();
-}
+use compiler::{Diagnostics, Emit, Options, Output, Target, PASSES};
 
-pub fn test_lexer(lex_this: &str) -> Vec<token::Token> {
-    let mut _lexemes: Vec<String> = vec![];
-    let mut _tokens: Vec<token::Token> = vec![];
+mod fuzz;
+mod generate;
 
-    _lexemes = lex::get_lexemes(lex_this);
-    _tokens = lex::tokenize_lexemes(_lexemes);
+// The command line, how to compile and what to do with the result
+struct Arguments {
+    input: String,
+    output: Option<String>, // Assembly goes to bin/out.s and the other forms to stdout when not given
+    options: Options,
+    run: bool, // Interpret the program instead of compiling it, exiting with what main returns
+    simulate: bool, // Run the generated AArch64 code in the simulator, exiting with x0
+    fuzz: Option<usize>, // Check this many generated programs instead of compiling the input
+    seed: Option<u64>, // Of the first generated program, from the clock when not given
+}
 
-    _tokens
+fn main() {
+    match parse_args(std::env::args().skip(1)) {
+        Ok(arguments) if arguments.fuzz.is_some() => {
+            let seed = arguments.seed.unwrap_or_else(|| {
+                std::time::SystemTime::now()
+                    .duration_since(std::time::UNIX_EPOCH)
+                    .map(|time| time.as_secs())
+                    .unwrap_or(0)
+            });
+            match fuzz::fuzz(arguments.fuzz.unwrap_or(0), seed) {
+                Ok(0) => {}
+                Ok(_) => process::exit(1),
+                Err(error) => {
+                    eprintln!("Error: {}", error);
+                    process::exit(1);
+                }
+            }
+        }
+        Ok(arguments) => compile_file(&arguments),
+        Err(error) => {
+            eprintln!("Error: {}", error);
+            eprintln!("Usage: compiler [--emit=tokens|ast|asm|ir|llvm|c] [--run] [--simulate] [--fuzz=<count>] [--seed=<n>] [--target=aarch64|x86_64-linux|riscv64-linux|wasm32] [-O<level>] [--no-peephole] [-Wunreachable-code] [--print-after=<pass>] [-o <output>] [<input.c>]");
+            process::exit(1);
+        }
+    }
 }
 
-pub fn print_tokens(tokens: Vec<token::Token>) {
-    for token in &tokens {
-        println!("Value: {:?}\nType: {:?}", token.value, token.token_type);
-        println!();
+// Without arguments the compiler builds tests/parser_tests/return_int.c into bin/out.s
+fn parse_args(args: impl Iterator<Item = String>) -> Result<Arguments, String> {
+    let mut arguments = Arguments {
+        input: "tests/parser_tests/return_int.c".to_string(),
+        output: None,
+        options: Options::default(),
+        run: false,
+        simulate: false,
+        fuzz: None,
+        seed: None,
+    };
+    let options = &mut arguments.options;
+
+    let mut args = args.peekable();
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--emit=tokens" => options.emit = Emit::Tokens,
+            "--emit=ast" => options.emit = Emit::Ast,
+            "--emit=asm" => options.emit = Emit::Asm,
+            "--emit=ir" => options.emit = Emit::Ir,
+            "--emit=llvm" => options.emit = Emit::Llvm,
+            "--emit=c" => options.emit = Emit::C,
+            "--run" => arguments.run = true,
+            "--simulate" => arguments.simulate = true,
+            "--no-peephole" => options.peephole = false,
+            "-Wunreachable-code" => options.warn_unreachable = true,
+            "-O" => options.opt_level = 1,
+            _ if arg.starts_with("--target=") => {
+                let name = &arg["--target=".len()..];
+                match Target::from_name(name) {
+                    Some(target) => options.target = target,
+                    None => {
+                        return Err(format!(
+                            "Unknown target {}, expected one of {}",
+                            name,
+                            Target::NAMES.join(", ")
+                        ))
+                    }
+                }
+            }
+            _ if arg.starts_with("--fuzz=") => match arg["--fuzz=".len()..].parse() {
+                Ok(count) => arguments.fuzz = Some(count),
+                Err(_) => return Err(format!("Invalid program count {}", arg)),
+            },
+            _ if arg.starts_with("--seed=") => match arg["--seed=".len()..].parse() {
+                Ok(seed) => arguments.seed = Some(seed),
+                Err(_) => return Err(format!("Invalid seed {}", arg)),
+            },
+            _ if arg.starts_with("--print-after=") => {
+                let pass = &arg["--print-after=".len()..];
+                if !PASSES.contains(&pass) {
+                    return Err(format!(
+                        "Unknown pass {}, expected one of {}",
+                        pass,
+                        PASSES.join(", ")
+                    ));
+                }
+                options.print_after.push(pass.to_string());
+            }
+            _ if arg.starts_with("-O") => match arg[2..].parse() {
+                Ok(level) => options.opt_level = level,
+                Err(_) => return Err(format!("Invalid optimization level {}", arg)),
+            },
+            "-o" => match args.next() {
+                Some(output) => arguments.output = Some(output),
+                None => return Err("-o needs a file name".to_string()),
+            },
+            _ if arg.starts_with('-') => return Err(format!("Unknown option {}", arg)),
+            _ => arguments.input = arg,
+        }
+    }
+
+    // The simulator runs the assembly, there is nothing to run in the other forms
+    if arguments.simulate && arguments.options.emit != Emit::Asm {
+        return Err(
+            "--simulate runs the assembly, it cannot be used with --emit other than asm"
+                .to_string(),
+        );
     }
+
+    Ok(arguments)
 }
 
-pub fn compile() {
-    // Lexing
-    let return_int = "tests/parser_tests/return_int.c";
-    let output_assembly_path = "bin/out.s";
-    let mut _lexemes: Vec<String> = vec![];
-    let mut _tokens: Vec<token::Token> = vec![];
+fn report(diagnostics: Diagnostics) -> ! {
+    eprint!("{}", diagnostics);
+    process::exit(1);
+}
 
-    _lexemes = lex::get_lexemes(return_int);
-    _tokens = lex::tokenize_lexemes(_lexemes);
+// Warnings and IR dumps go to stderr, ahead of the output
+fn show<T>(output: Output<T>) -> T {
+    for warning in &output.warnings {
+        eprintln!("{}", warning);
+    }
+    for (pass, ir) in &output.dumps {
+        eprintln!("*** IR after {} ***", pass);
+        eprint!("{}", ir);
+    }
+    output.value
+}
 
-    // Parsing
-    let mut parser = parser::Parser::new(_tokens);
+fn compile_file(arguments: &Arguments) {
+    let source = match fs::read_to_string(&arguments.input) {
+        Ok(source) => source,
+        Err(e) => {
+            eprintln!("Error reading {}: {}", arguments.input, e);
+            process::exit(1);
+        }
+    };
+    let options = &arguments.options;
 
-    let program_node = parser.parse_program().expect("Failed to parse program");
-    println!("{}", program_node);
+    if arguments.run {
+        match compiler::run(&source, options) {
+            Ok(output) => process::exit(show(output)),
+            Err(diagnostics) => report(diagnostics),
+        }
+    }
 
-    // Generating
-    let generator = gen::Generator::new(program_node);
+    if arguments.simulate {
+        match compiler::simulate(&source, options) {
+            Ok(output) => process::exit(show(output)),
+            Err(diagnostics) => report(diagnostics),
+        }
+    }
 
-    let asm = generator.walk_da_tree();
-    println!();
+    let output = match compiler::compile(&source, options) {
+        Ok(output) => show(output),
+        Err(diagnostics) => report(diagnostics),
+    };
 
-    match fs::write(output_assembly_path, asm) {
-        Ok(_) => println!("File generated at {}", output_assembly_path),
-        Err(e) => eprintln!("Error writing to {}: {}", output_assembly_path, e),
+    // Text output goes to the -o file or stdout, assembly to bin/out.s by default
+    let path = match (&arguments.output, options.emit) {
+        (Some(path), _) => path.as_str(),
+        (None, Emit::Asm) => "bin/out.s",
+        (None, _) => return print!("{}", output),
+    };
+    match fs::write(path, output) {
+        Ok(_) if options.emit == Emit::Asm => println!("File generated at {}", path),
+        Ok(_) => {}
+        Err(e) => eprintln!("Error writing to {}: {}", path, e),
     }
 }
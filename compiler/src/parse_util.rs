@@ -1,4 +1,4 @@
-use crate::parser::Parser;
+use crate::parser::{Parser, MAX_NESTING};
 use crate::token::{Token, TokenType};
 
 impl Parser {
@@ -12,7 +12,38 @@ impl Parser {
         if self.token_index + offset >= self.token_stream.len() {
             return None;
         }
-        return self.token_stream.get(self.token_index + offset);
+        self.token_stream.get(self.token_index + offset)
+    }
+
+    // Sjekker typen til neste token uten å konsumere den
+    pub fn next_is(&mut self, expected: TokenType) -> bool {
+        matches!(self.peek(0), Some(token) if token.token_type == expected)
+    }
+
+    // The type of the next token when it is one of the expected, e.g the operators of a
+    // precedence level
+    pub fn next_of(&mut self, expected: &[TokenType]) -> Option<TokenType> {
+        match self.peek(0) {
+            Some(token) if expected.contains(&token.token_type) => Some(token.token_type.clone()),
+            _ => None,
+        }
+    }
+
+    // Records an error, the caller decides whether parsing goes on
+    pub fn error(&mut self, message: String) {
+        self.diagnostics.push(message);
+    }
+
+    // Parses one level deeper, past MAX_NESTING it records an error instead of recursing on
+    pub fn nested<T>(&mut self, parse: impl FnOnce(&mut Self) -> Option<T>) -> Option<T> {
+        if self.depth >= MAX_NESTING {
+            self.error(format!("Error: Nesting deeper than {} levels", MAX_NESTING));
+            return None;
+        }
+        self.depth += 1;
+        let result = parse(self);
+        self.depth -= 1;
+        result
     }
 
     // Forvent token, e.g ved funksjoner forventes en struktur
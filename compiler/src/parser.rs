@@ -1,21 +1,78 @@
+use std::collections::HashMap;
+
 use crate::token::{Token, TokenType};
+use crate::types::{Member, StructRef, Type};
+
+#[derive(Debug, PartialEq, Clone)]
+pub enum Storage {
+    Local(usize),   // Index into the locals of the function declaring it
+    Global(String), // C name of a file scope variable, function.name.n for a static local
+}
+
+#[derive(Debug, PartialEq, Clone)]
+pub struct Variable {
+    pub name: String,
+    pub ty: Type,
+    pub storage: Storage,
+    pub is_const: bool,
+}
 
 #[derive(Debug, PartialEq, Clone)]
 pub enum Expr {
     Number(i32),
+    Variable(Variable),
     UnaryOp(TokenType, Option<Box<ExprNode>>),
     BinaryOp(TokenType, Box<ExprNode>, Box<ExprNode>),
+    Assign(Box<ExprNode>, Box<ExprNode>),
+    Member(Box<ExprNode>, Member),
+    Conditional(Box<ExprNode>, Box<ExprNode>, Box<ExprNode>),
+    Comma(Box<ExprNode>, Box<ExprNode>),
+    Cast(Type, Box<ExprNode>),
+    Call(String, Vec<ExprNode>), // Arguments already converted to the parameter types
 }
 
 #[derive(Debug, PartialEq, Clone)]
 pub struct ExprNode {
     pub expr: Expr,
+    pub ty: Type,
+}
+
+// Braced lists nest once per array dimension or struct, missing elements are zero
+#[derive(Debug, PartialEq, Clone)]
+pub enum Initializer {
+    Expr(ExprNode),
+    List(Vec<Initializer>),
 }
 
 #[derive(Debug, PartialEq, Clone)]
 pub enum Statement {
-    Return(ExprNode),
-    Assignment(TokenType, TokenType, TokenType, Option<Box<ExprNode>>),
+    Return(Option<ExprNode>), // No value in a function returning void
+    Declaration(Variable, Option<Initializer>),
+    Expression(ExprNode),
+    Empty, // A lone ; or a declaration that only introduces a struct tag
+    Block(Vec<StatementNode>),
+    Switch(ExprNode, Box<StatementNode>, SwitchCases),
+    Case(i32, Box<StatementNode>),
+    Default(Box<StatementNode>),
+    Break,
+    If(ExprNode, Box<StatementNode>, Option<Box<StatementNode>>),
+    While(ExprNode, Box<StatementNode>),
+    // The initialization is a declaration, an expression statement or empty, a missing
+    // condition loops forever
+    For(
+        Box<StatementNode>,
+        Option<ExprNode>,
+        Option<ExprNode>,
+        Box<StatementNode>,
+    ),
+    Continue,
+}
+
+// The case values of a switch in the order they appear, known before its body is generated
+#[derive(Debug, PartialEq, Clone, Default)]
+pub struct SwitchCases {
+    pub values: Vec<i32>,
+    pub has_default: bool,
 }
 
 #[derive(Debug, PartialEq, Clone)]
@@ -25,20 +82,376 @@ pub struct StatementNode {
 
 #[derive(Debug, PartialEq, Clone)]
 pub struct FunctionNode {
-    pub return_value: TokenType,
-    pub name: String, // Kan evt være expected tokentype Ident
+    pub return_type: Type,
+    pub name: String,          // Kan evt være expected tokentype Ident
+    pub params: Vec<Variable>, // Also the first locals, stored to their slots on entry
     pub body: Vec<StatementNode>,
+    pub locals: Vec<Variable>, // Every stack variable declared in the body, indexed by slot
+    pub is_static: bool,       // Internal linkage, not visible to the linker
+}
+
+// A symbol pointing into the data of another global, e.g int *p = &x;
+#[derive(Debug, PartialEq, Clone)]
+pub struct Relocation {
+    pub offset: usize,
+    pub symbol: String,
+    pub addend: i64,
+}
+
+// The initial value of a global, evaluated at compile time
+#[derive(Debug, PartialEq, Clone, Default)]
+pub struct DataImage {
+    pub bytes: Vec<u8>,
+    pub relocations: Vec<Relocation>,
+}
+
+// A file scope variable or a static local, living in .data, .bss or .rodata
+#[derive(Debug, PartialEq, Clone)]
+pub struct GlobalNode {
+    pub variable: Variable,
+    pub initializer: Option<Initializer>,
+    pub image: DataImage,
+    pub is_static: bool,
 }
 
 #[derive(Debug, PartialEq, Clone)]
 pub struct ProgramNode {
     pub function_names: Vec<String>, // Stores all function names in program
     pub body: Vec<FunctionNode>,
+    pub globals: Vec<GlobalNode>,
+}
+
+impl ExprNode {
+    // Wraps an expression together with the type it evaluates to
+    pub fn new(expr: Expr) -> Self {
+        let ty = match &expr {
+            Expr::Number(_) => Type::Int,
+            Expr::Variable(variable) => variable.ty.clone(),
+            Expr::UnaryOp(TokenType::BitAnd, Some(operand)) => Type::pointer_to(operand.ty.clone()),
+            Expr::UnaryOp(TokenType::Mul, Some(operand)) => {
+                operand.ty.base().cloned().unwrap_or(Type::Int)
+            }
+            Expr::UnaryOp(_, _) => Type::Int,
+            Expr::BinaryOp(TokenType::Plus | TokenType::Minus, left, right) => {
+                match (left.ty.base(), right.ty.base()) {
+                    (Some(_), Some(_)) => Type::Int, // Pointer difference
+                    (Some(base), None) | (None, Some(base)) => Type::pointer_to(base.clone()),
+                    (None, None) => Type::Int,
+                }
+            }
+            Expr::BinaryOp(_, _, _) => Type::Int,
+            Expr::Assign(target, _) => target.ty.clone(),
+            Expr::Member(_, member) => member.ty.clone(),
+            // A pointer branch wins over a null pointer constant in the other one
+            Expr::Conditional(_, then, otherwise) => {
+                match (then.ty.decay(), otherwise.ty.decay()) {
+                    (ty @ (Type::Pointer(_) | Type::Struct(_)), _) => ty,
+                    (_, ty @ Type::Pointer(_)) => ty,
+                    _ => Type::Int,
+                }
+            }
+            Expr::Comma(_, right) => right.ty.decay(),
+            Expr::Cast(ty, _) => ty.clone(),
+            Expr::Call(_, _) => Type::Int, // parse_call knows the return type
+        };
+
+        ExprNode { expr, ty }
+    }
+
+    // Whether the expression designates (part of) a variable declared const
+    pub fn is_const(&self) -> bool {
+        match &self.expr {
+            Expr::Variable(variable) => variable.is_const,
+            Expr::Member(base, _) => base.is_const(),
+            // Elements of a const array, a[i] being *(a + i)
+            Expr::UnaryOp(TokenType::Mul, Some(operand)) => match &operand.expr {
+                Expr::BinaryOp(_, left, right) => {
+                    (left.ty.is_array() && left.is_const())
+                        || (right.ty.is_array() && right.is_const())
+                }
+                _ => false,
+            },
+            _ => false,
+        }
+    }
+
+    // Only variables, dereferences and their members designate storage that can be assigned or addressed
+    pub fn is_lvalue(&self) -> bool {
+        match &self.expr {
+            Expr::Variable(_) | Expr::UnaryOp(TokenType::Mul, _) => true,
+            Expr::Member(base, _) => base.is_lvalue(),
+            _ => false,
+        }
+    }
+
+    // Whether evaluating the expression can change the state of the program
+    pub fn has_side_effects(&self) -> bool {
+        match &self.expr {
+            Expr::Number(_) | Expr::Variable(_) => false,
+            Expr::Assign(_, _) | Expr::Call(_, _) => true,
+            Expr::UnaryOp(_, operand) => operand
+                .as_ref()
+                .is_some_and(|operand| operand.has_side_effects()),
+            Expr::BinaryOp(_, left, right) | Expr::Comma(left, right) => {
+                left.has_side_effects() || right.has_side_effects()
+            }
+            Expr::Member(base, _) => base.has_side_effects(),
+            Expr::Conditional(condition, then, otherwise) => {
+                condition.has_side_effects()
+                    || then.has_side_effects()
+                    || otherwise.has_side_effects()
+            }
+            Expr::Cast(_, operand) => operand.has_side_effects(),
+        }
+    }
+
+    // A call of a void function somewhere in the expression whose missing value is used. Only
+    // the left of a comma throws its value away
+    pub fn void_value(&self) -> Option<&ExprNode> {
+        let operands: Vec<&ExprNode> = match &self.expr {
+            Expr::Number(_) | Expr::Variable(_) => vec![],
+            Expr::UnaryOp(_, operand) => operand.iter().map(|operand| operand.as_ref()).collect(),
+            Expr::BinaryOp(_, left, right) | Expr::Assign(left, right) => vec![left, right],
+            Expr::Member(base, _) => vec![base],
+            Expr::Conditional(condition, then, otherwise) => vec![condition, then, otherwise],
+            Expr::Comma(left, right) => {
+                return left.void_value().or_else(|| right.void_value());
+            }
+            Expr::Cast(_, operand) => vec![operand],
+            Expr::Call(_, args) => args.iter().collect(),
+        };
+        operands.into_iter().find_map(|operand| {
+            if operand.ty == Type::Void {
+                Some(operand)
+            } else {
+                operand.void_value()
+            }
+        })
+    }
+}
+
+impl Initializer {
+    // Collects the scalar stores the initializer performs as (offset, value, type), relative to the object start
+    pub fn flatten<'a>(
+        &'a self,
+        ty: &Type,
+        offset: usize,
+        stores: &mut Vec<(usize, &'a ExprNode, Type)>,
+    ) {
+        match (self, ty) {
+            (Initializer::List(items), Type::Array(element, _)) => {
+                for (index, item) in items.iter().enumerate() {
+                    item.flatten(element, offset + index * element.size(), stores);
+                }
+            }
+            (Initializer::List(items), Type::Struct(struct_ref)) => {
+                let members = struct_ref.borrow().members.clone();
+                for (item, member) in items.iter().zip(members.iter()) {
+                    item.flatten(&member.ty, offset + member.offset, stores);
+                }
+            }
+            (Initializer::List(items), _) => {
+                if let Some(item) = items.first() {
+                    item.flatten(ty, offset, stores);
+                }
+            }
+            (Initializer::Expr(value), _) => stores.push((offset, value, ty.clone())),
+        }
+    }
+}
+
+impl DataImage {
+    pub fn is_zero(&self) -> bool {
+        self.relocations.is_empty() && self.bytes.iter().all(|byte| *byte == 0)
+    }
+}
+
+// Evaluates an integer constant expression at compile time, None if it is not constant
+// Whether value can be passed to a parameter, or returned from a function, of type ty: integers
+// for integers and pointers for pointers, where a constant 0 is also a null pointer
+fn converts_to(value: &ExprNode, ty: &Type) -> bool {
+    match (ty.base(), value.ty.decay()) {
+        (_, Type::Struct(_)) => false,
+        (Some(_), Type::Pointer(_)) => true,
+        (Some(_), _) => evaluate_constant(value) == Some(0),
+        (None, Type::Pointer(_)) => false,
+        (None, _) => true,
+    }
+}
+
+pub fn evaluate_constant(node: &ExprNode) -> Option<i32> {
+    match &node.expr {
+        Expr::Number(num) => Some(*num),
+        Expr::Cast(Type::Char, operand) => Some(evaluate_constant(operand)? as i8 as i32),
+        Expr::Cast(_, operand) => evaluate_constant(operand),
+        Expr::Conditional(condition, then, otherwise) => {
+            if evaluate_constant(condition)? != 0 {
+                evaluate_constant(then)
+            } else {
+                evaluate_constant(otherwise)
+            }
+        }
+        Expr::UnaryOp(operator, Some(operand)) => {
+            let value = evaluate_constant(operand)?;
+            match operator {
+                TokenType::Minus => Some(value.wrapping_neg()),
+                TokenType::BitComplement => Some(!value),
+                TokenType::Not => Some((value == 0) as i32),
+                _ => None,
+            }
+        }
+        Expr::BinaryOp(operator, left, right) => {
+            let left = evaluate_constant(left)?;
+            let right = evaluate_constant(right)?;
+            match operator {
+                TokenType::Plus => Some(left.wrapping_add(right)),
+                TokenType::Minus => Some(left.wrapping_sub(right)),
+                TokenType::Mul => Some(left.wrapping_mul(right)),
+                TokenType::Div => left.checked_div(right),
+                TokenType::And => Some((left != 0 && right != 0) as i32),
+                TokenType::Or => Some((left != 0 || right != 0) as i32),
+                TokenType::Eq => Some((left == right) as i32),
+                TokenType::Neq => Some((left != right) as i32),
+                TokenType::Lt => Some((left < right) as i32),
+                TokenType::Gt => Some((left > right) as i32),
+                TokenType::Le => Some((left <= right) as i32),
+                TokenType::Ge => Some((left >= right) as i32),
+                _ => None,
+            }
+        }
+        _ => None,
+    }
+}
+
+// Evaluates an address constant as a symbol plus an offset, e.g &grid[1][2] or buffer + 4
+pub fn evaluate_address(node: &ExprNode) -> Option<(String, i64)> {
+    // Arrays decay to their address
+    if node.ty.is_array() && node.is_lvalue() {
+        return evaluate_lvalue_address(node);
+    }
+
+    match &node.expr {
+        Expr::UnaryOp(TokenType::BitAnd, Some(operand)) => evaluate_lvalue_address(operand),
+        Expr::Cast(Type::Pointer(_), operand) => evaluate_address(operand),
+        Expr::BinaryOp(operator @ (TokenType::Plus | TokenType::Minus), left, right) => {
+            let (pointer, integer) = match (left.ty.base(), right.ty.base()) {
+                (Some(_), None) => (left, right),
+                (None, Some(_)) if *operator == TokenType::Plus => (right, left),
+                _ => return None,
+            };
+            let (symbol, addend) = evaluate_address(pointer)?;
+            let step = evaluate_constant(integer)? as i64 * pointer.ty.base()?.size() as i64;
+
+            match operator {
+                TokenType::Plus => Some((symbol, addend + step)),
+                _ => Some((symbol, addend - step)),
+            }
+        }
+        _ => None,
+    }
+}
+
+fn evaluate_lvalue_address(node: &ExprNode) -> Option<(String, i64)> {
+    match &node.expr {
+        Expr::Variable(Variable {
+            storage: Storage::Global(symbol),
+            ..
+        }) => Some((symbol.clone(), 0)),
+        Expr::Member(base, member) => {
+            let (symbol, addend) = evaluate_lvalue_address(base)?;
+            Some((symbol, addend + member.offset as i64))
+        }
+        Expr::UnaryOp(TokenType::Mul, Some(operand)) => evaluate_address(operand),
+        _ => None,
+    }
+}
+
+// Ordinary identifiers, variables, functions and enumerators, share a namespace
+enum Symbol {
+    Variable(Variable),
+    Function(Signature),
+    Enumerator(i32),
+}
+
+// What a call needs to know about a function, from its prototype or definition
+#[derive(Clone, PartialEq)]
+struct Signature {
+    returns: Type,
+    params: Vec<Type>,
+    is_static: bool,
+    defined: bool,
+}
+
+// The parameters of a function, ( void ) and ( ) both meaning none
+struct FunctionHeader {
+    name: String,
+    return_type: Type,
+    params: Vec<(Option<String>, Type, bool)>, // Name, type and whether it is const
+    is_static: bool,
+}
+
+// Calls pass every argument in a register
+const MAX_PARAMS: usize = 8;
+
+// How deep factors, statements and struct specifiers may nest together. C asks for at least 127
+// nested blocks and 63 nested parentheses, which fit together. The parser and the passes after
+// it recurse over the nesting, at this depth they take a few MiB of the stack the library runs
+// them on, even in a debug build
+pub const MAX_NESTING: usize = 256;
+
+// Struct, union and enum tags share the other namespace
+#[derive(Clone)]
+enum Tag {
+    Struct(StructRef),
+    Enum,
+}
+
+#[derive(Default)]
+struct Scope {
+    identifiers: HashMap<String, Symbol>,
+    tags: HashMap<String, Tag>,
+}
+
+// Tokens that can start a type name
+pub fn is_type_keyword(token_type: &TokenType) -> bool {
+    matches!(
+        token_type,
+        TokenType::IntKeyword
+            | TokenType::CharKeyword
+            | TokenType::StructKeyword
+            | TokenType::UnionKeyword
+            | TokenType::EnumKeyword
+            | TokenType::ConstKeyword
+    )
+}
+
+// What the specifiers of a declaration say, e.g static const int
+struct Specifiers {
+    ty: Type,
+    is_static: bool,
+    is_const: bool,
+}
+
+// What a declarator adds to the specifiers, e.g *const p or grid[3][4]
+struct Declarator {
+    name: Option<String>,
+    ty: Type,
+    open: bool,     // The outermost array dimension was left empty
+    is_const: bool, // The declared object itself is read-only
 }
 
 pub struct Parser {
     pub token_index: usize,
     pub token_stream: Vec<Token>,
+    scopes: Vec<Scope>,           // File scope first, innermost scope last
+    locals: Vec<Variable>,        // Locals of the function being parsed
+    globals: Vec<GlobalNode>,     // File scope variables and static locals seen so far
+    function_name: String,        // Function being parsed, names its static locals
+    return_type: Type,            // What the function being parsed returns
+    switches: Vec<SwitchCases>,   // Cases of the enclosing switches, innermost last
+    loops: usize,                 // Number of enclosing loops, for break and continue
+    pub depth: usize,             // Nesting of what is being parsed, up to MAX_NESTING
+    pub diagnostics: Vec<String>, // Errors found so far, in the order they were found
 }
 
 impl Parser {
@@ -47,347 +460,1383 @@ impl Parser {
         Parser {
             token_index: 0,
             token_stream: tokens,
+            scopes: vec![Scope::default()],
+            locals: vec![],
+            globals: vec![],
+            function_name: String::new(),
+            return_type: Type::Int,
+            switches: vec![],
+            loops: 0,
+            depth: 0,
+            diagnostics: vec![],
         }
     }
 
     // Function to increase readability
+    // The comma operator binds loosest, evaluating left to right and yielding the last value
     fn parse_expression(&mut self) -> Option<ExprNode> {
-        self.parse_or()
+        let mut expression = self.parse_assign()?;
+
+        while self.next_is(TokenType::Comma) {
+            self.consume(); // Consume ','
+            let right = self.parse_assign()?;
+            expression = ExprNode::new(Expr::Comma(Box::new(expression), Box::new(right)));
+        }
+
+        Some(expression)
     }
 
-    fn parse_or(&mut self) -> Option<ExprNode> {
-        let and = self.parse_and();
+    // Assignment is right associative
+    fn parse_assign(&mut self) -> Option<ExprNode> {
+        let target = self.parse_conditional()?;
+
+        if !self.next_is(TokenType::Assign) {
+            return Some(target);
+        }
+        self.consume(); // Consume '='
+
+        if !target.is_lvalue() || target.ty.is_array() {
+            self.error(format!("Error: Expression {} is not assignable", target));
+            return None;
+        }
+        if target.is_const() {
+            self.error(format!("Error: Cannot assign to read-only {}", target));
+            return None;
+        }
+
+        let value = self.nested(Self::parse_assign)?;
+        if (target.ty.is_struct() || value.ty.is_struct()) && target.ty != value.ty {
+            self.error(format!(
+                "Error: Cannot assign {} to {}",
+                value.ty, target.ty
+            ));
+            return None;
+        }
+
+        Some(ExprNode::new(Expr::Assign(
+            Box::new(target),
+            Box::new(value),
+        )))
+    }
+
+    // c ? a : b, where only the chosen branch is evaluated. The middle may be any expression,
+    // the last branch nests to the right so a ? b : c ? d : e groups as a ? b : (c ? d : e)
+    fn parse_conditional(&mut self) -> Option<ExprNode> {
+        let condition = self.parse_or()?;
+
+        if !self.next_is(TokenType::QuestionMark) {
+            return Some(condition);
+        }
+        self.consume(); // Consume '?'
+
+        let then = self.nested(Self::parse_expression)?;
+
+        if let Err(error) = self.expect(TokenType::Colon) {
+            self.error(format!("Error {}", error));
+            return None;
+        }
+        self.consume(); // Consume ':'
+
+        let otherwise = self.nested(Self::parse_conditional)?;
+
+        if condition.ty.is_struct() {
+            self.error(format!(
+                "Error: Condition {} has type {}, not a scalar",
+                condition, condition.ty
+            ));
+            return None;
+        }
+
+        let compatible = match (then.ty.decay(), otherwise.ty.decay()) {
+            (Type::Pointer(_), Type::Pointer(_)) | (Type::Struct(_), Type::Struct(_)) => {
+                then.ty.decay() == otherwise.ty.decay()
+            }
+            (Type::Pointer(_), _) => evaluate_constant(&otherwise) == Some(0),
+            (_, Type::Pointer(_)) => evaluate_constant(&then) == Some(0),
+            (Type::Struct(_), _) | (_, Type::Struct(_)) => false,
+            _ => true,
+        };
+        if !compatible {
+            self.error(format!(
+                "Error: Type mismatch in conditional expression, {} and {}",
+                then.ty, otherwise.ty
+            ));
+            return None;
+        }
+
+        Some(ExprNode::new(Expr::Conditional(
+            Box::new(condition),
+            Box::new(then),
+            Box::new(otherwise),
+        )))
+    }
 
-        let mut complete_and = and.clone();
+    fn parse_or(&mut self) -> Option<ExprNode> {
+        let mut complete_and = self.parse_and()?;
 
-        let mut current_token = self.peek(0).expect("Token is None");
-        while current_token.token_type == TokenType::Or {
-            let operator = current_token.token_type.clone();
+        while let Some(operator) = self.next_of(&[TokenType::Or]) {
             self.consume();
-            let next_and = self.parse_and();
-
-            let new_and = Some(ExprNode {
-                expr: Expr::BinaryOp(
-                    operator,
-                    Box::new(complete_and.clone().expect("Incomplete and part")),
-                    Box::new(next_and.expect("Next and part failed")),
-                ),
-            });
+            let next_and = self.parse_and()?;
 
-            complete_and = new_and.clone();
-            current_token = self.peek(0).expect("Token is None");
+            complete_and = ExprNode::new(Expr::BinaryOp(
+                operator,
+                Box::new(complete_and),
+                Box::new(next_and),
+            ));
         }
 
-        complete_and
+        Some(complete_and)
     }
 
     fn parse_and(&mut self) -> Option<ExprNode> {
-        let eqality = self.parse_eqality();
-
-        let mut complete_equality = eqality.clone();
+        let mut complete_equality = self.parse_eqality()?;
 
-        let mut current_token = self.peek(0).expect("Token is None");
-        while current_token.token_type == TokenType::And {
-            let operator = current_token.token_type.clone();
+        while let Some(operator) = self.next_of(&[TokenType::And]) {
             self.consume();
-            let next_equality = self.parse_eqality();
-
-            let new_equality = Some(ExprNode {
-                expr: Expr::BinaryOp(
-                    operator,
-                    Box::new(complete_equality.clone().expect("Incomplete equality part")),
-                    Box::new(next_equality.expect("Next equality part failed")),
-                ),
-            });
+            let next_equality = self.parse_eqality()?;
 
-            complete_equality = new_equality.clone();
-            current_token = self.peek(0).expect("Token is None");
+            complete_equality = ExprNode::new(Expr::BinaryOp(
+                operator,
+                Box::new(complete_equality),
+                Box::new(next_equality),
+            ));
         }
 
-        complete_equality
+        Some(complete_equality)
     }
 
     fn parse_eqality(&mut self) -> Option<ExprNode> {
-        let relational = self.parse_relation();
-
-        let mut complete_relational = relational.clone();
+        let mut complete_relational = self.parse_relation()?;
 
-        let mut current_token = self.peek(0).expect("Token is None");
-        while current_token.token_type == TokenType::Eq
-            || current_token.token_type == TokenType::Neq
-        {
-            let operator = current_token.token_type.clone();
+        while let Some(operator) = self.next_of(&[TokenType::Eq, TokenType::Neq]) {
             self.consume();
-            let next_relational = self.parse_relation();
-
-            let new_relational = Some(ExprNode {
-                expr: Expr::BinaryOp(
-                    operator,
-                    Box::new(
-                        complete_relational
-                            .clone()
-                            .expect("Incomplete relational part"),
-                    ),
-                    Box::new(next_relational.expect("Next relational part failed")),
-                ),
-            });
+            let next_relational = self.parse_relation()?;
 
-            complete_relational = new_relational.clone();
-            current_token = self.peek(0).expect("Token is None");
+            complete_relational = ExprNode::new(Expr::BinaryOp(
+                operator,
+                Box::new(complete_relational),
+                Box::new(next_relational),
+            ));
         }
 
-        complete_relational
+        Some(complete_relational)
     }
 
     fn parse_relation(&mut self) -> Option<ExprNode> {
-        let additive = self.parse_add();
-        let mut complete_additive = additive.clone();
-
-        let mut current_token = self.peek(0).expect("Token is None");
-        while current_token.token_type == TokenType::Lt
-            || current_token.token_type == TokenType::Gt
-            || current_token.token_type == TokenType::Le
-            || current_token.token_type == TokenType::Ge
-        {
-            let operator = current_token.token_type.clone();
-            self.consume(); // consume operator
-
-            let next_additive = self.parse_add();
-            let new_additive = Some(ExprNode {
-                expr: Expr::BinaryOp(
-                    operator,
-                    Box::new(complete_additive.clone().expect("Incomplete additive part")),
-                    Box::new(next_additive.expect("Next additive part failed")),
-                ),
-            });
+        let mut complete_additive = self.parse_add()?;
 
-            complete_additive = new_additive.clone();
+        let operators = [TokenType::Lt, TokenType::Gt, TokenType::Le, TokenType::Ge];
+        while let Some(operator) = self.next_of(&operators) {
+            self.consume(); // consume operator
 
-            current_token = self.peek(0).expect("Token is None");
+            let next_additive = self.parse_add()?;
+            complete_additive = ExprNode::new(Expr::BinaryOp(
+                operator,
+                Box::new(complete_additive),
+                Box::new(next_additive),
+            ));
         }
 
-        complete_additive
+        Some(complete_additive)
     }
 
     fn parse_term(&mut self) -> Option<ExprNode> {
         // Parser for factor
-        let factor = self.parse_factor();
-
-        // Placeholder, en finere måte å gjøre det på? Nei
-        let mut complete_factor = factor.clone();
+        let mut complete_factor = self.parse_factor()?;
 
         // Skal peke på neste token i stream
-        let mut current_token = self.peek(0).expect("Token is None");
-        while current_token.token_type == TokenType::Mul
-            || current_token.token_type == TokenType::Div
-        {
-            let operator = current_token.token_type.clone();
-            self.consume(); // Spiser enten * || /
-            let next_factor = self.parse_factor();
-
-            let new_factor = Some(ExprNode {
-                expr: Expr::BinaryOp(
-                    operator,
-                    Box::new(complete_factor.clone().expect("Incomplete term")),
-                    Box::new(next_factor.expect("Next term failed")),
-                ),
-            });
-            complete_factor = new_factor.clone();
-
-            current_token = self.peek(0).expect("Token is None");
+        while let Some(operator) = self.next_of(&[TokenType::Mul, TokenType::Div]) {
+            self.consume(); // Spiser enten * || /
+            let next_factor = self.parse_factor()?;
+
+            complete_factor = ExprNode::new(Expr::BinaryOp(
+                operator,
+                Box::new(complete_factor),
+                Box::new(next_factor),
+            ));
         }
 
-        complete_factor
+        Some(complete_factor)
     }
 
     fn parse_factor(&mut self) -> Option<ExprNode> {
+        self.nested(Self::parse_nested_factor)
+    }
+
+    fn parse_nested_factor(&mut self) -> Option<ExprNode> {
+        let is_cast = self
+            .peek(1)
+            .is_some_and(|token| is_type_keyword(&token.token_type));
+
         // Current tok vi kan matche på
-        let current_token = self.peek(0).expect("Token is None");
-        match current_token.token_type {
+        let Some(current_token) = self.peek(0) else {
+            self.error("Error Unexpected stream end".to_string());
+            return None;
+        };
+        match current_token.token_type.clone() {
+            // "(" <type> ")" <factor> case
+            TokenType::LParen if is_cast => self.parse_cast(),
             // "(" <expr> ")" case
             TokenType::LParen => {
                 self.consume(); // Consume '(' token
 
-                let expression = self.parse_expression();
+                let expression = self.parse_expression()?;
 
                 // Should expect ')'
                 if let Err(error) = self.expect(TokenType::RParen) {
-                    println!("Error {}", error);
+                    self.error(format!("Error {}", error));
                     return None;
                 }
 
                 // Consumer ')'
                 self.consume();
-                expression
+                self.parse_postfix(expression)
             }
             // Unary Op case
-            TokenType::BitComplement | TokenType::Minus | TokenType::Not => {
-                self.parse_unary_operation()
-            }
+            TokenType::BitComplement
+            | TokenType::Minus
+            | TokenType::Not
+            | TokenType::BitAnd
+            | TokenType::Mul => self.parse_unary_operation(),
+            TokenType::SizeofKeyword => self.parse_sizeof(),
             // IntLit case
             TokenType::IntLit => self.parse_integer(),
-            _ => {
-                println!("Expected factor, found {:?}", current_token.token_type);
+            TokenType::Char => self.parse_character(),
+            TokenType::Identifier => {
+                let variable = self.parse_variable()?;
+                self.parse_postfix(variable)
+            }
+            token_type => {
+                self.error(format!("Expected factor, found {:?}", token_type));
                 None
             }
         }
     }
 
     fn parse_add(&mut self) -> Option<ExprNode> {
-        let term = self.parse_term();
+        let mut complete_term = self.parse_term()?;
 
-        // Placeholder
-        let mut complete_term = term.clone();
-
-        // Skal peke på første token i en expr
-        let mut current_token = self.peek(0).expect("Token is None");
-        // Another term while this is true
-        while current_token.token_type == TokenType::Plus
-            || current_token.token_type == TokenType::Minus
-        {
-            let operator = current_token.token_type.clone();
+        // Another term while the next token is + or -
+        while let Some(operator) = self.next_of(&[TokenType::Plus, TokenType::Minus]) {
             // Advance stream
             self.consume();
-            let next_term = self.parse_term();
-
-            let new_term = Some(ExprNode {
-                expr: Expr::BinaryOp(
-                    operator,
-                    Box::new(complete_term.clone().expect("Incomplete term")),
-                    Box::new(next_term.expect("Next term failed")),
-                ),
-            });
-            complete_term = new_term.clone();
-            current_token = self.peek(0).expect("Token is None");
+            let next_term = self.parse_term()?;
+
+            complete_term = self.new_additive(operator, complete_term, next_term)?;
         }
 
-        complete_term
+        Some(complete_term)
     }
 
-    fn parse_unary_operation(&mut self) -> Option<ExprNode> {
-        let current_token = self.peek(0).expect("Token is None");
+    // Builds a + or - node, pointer operands are scaled by their base type during generation
+    fn new_additive(
+        &mut self,
+        operator: TokenType,
+        left: ExprNode,
+        right: ExprNode,
+    ) -> Option<ExprNode> {
+        let left_pointer = left.ty.base().is_some();
+        let right_pointer = right.ty.base().is_some();
+
+        if operator == TokenType::Plus && left_pointer && right_pointer {
+            self.error(format!(
+                "Error: Cannot add two pointers {} and {}",
+                left, right
+            ));
+            return None;
+        }
+        if operator == TokenType::Minus && !left_pointer && right_pointer {
+            self.error(format!(
+                "Error: Cannot subtract pointer {} from an integer",
+                right
+            ));
+            return None;
+        }
+        if operator == TokenType::Minus
+            && left_pointer
+            && right_pointer
+            && left.ty.base() != right.ty.base()
+        {
+            self.error("Error: Cannot subtract pointers to different types".to_string());
+            return None;
+        }
 
-        match current_token.token_type {
-            TokenType::BitComplement | TokenType::Minus | TokenType::Not => {
-                // Current op: ~, - || !
-                let operator = current_token.clone();
+        Some(ExprNode::new(Expr::BinaryOp(
+            operator,
+            Box::new(left),
+            Box::new(right),
+        )))
+    }
 
-                // Consume operator
-                self.consume();
+    fn new_dereference(&mut self, operand: ExprNode) -> Option<ExprNode> {
+        if operand.ty.base().is_none() {
+            self.error(format!("Error: Cannot dereference non-pointer {}", operand));
+            return None;
+        }
 
-                // Want to parse the expression recursively
-                let operand = self.parse_factor();
+        Some(ExprNode::new(Expr::UnaryOp(
+            TokenType::Mul,
+            Some(Box::new(operand)),
+        )))
+    }
 
-                // Create expression node
-                Some(ExprNode {
-                    expr: Expr::UnaryOp(operator.token_type, operand.map(Box::new)),
-                })
-            }
-            _ => {
-                println!(
-                    "Error: Expected a unary operator, found {:?}",
-                    current_token.token_type
-                );
-                None
+    // Subscripts and member access. a[i] is sugar for *(a + i) and thereby works on pointers
+    // and arrays alike, p->m is sugar for (*p).m
+    fn parse_postfix(&mut self, operand: ExprNode) -> Option<ExprNode> {
+        let mut complete_operand = operand;
+
+        while let Some(current_token) = self.peek(0).map(|token| token.token_type.clone()) {
+            match current_token {
+                TokenType::LBrack => {
+                    self.consume(); // Consume '['
+                    let index = self.parse_expression()?;
+
+                    if let Err(error) = self.expect(TokenType::RBrack) {
+                        self.error(format!("Error {}", error));
+                        return None;
+                    }
+                    self.consume(); // Consume ']'
+
+                    let address = self.new_additive(TokenType::Plus, complete_operand, index)?;
+                    complete_operand = self.new_dereference(address)?;
+                }
+                TokenType::Dot | TokenType::Arrow => {
+                    self.consume(); // Consume '.' or '->'
+                    if current_token == TokenType::Arrow {
+                        complete_operand = self.new_dereference(complete_operand)?;
+                    }
+                    complete_operand = self.parse_member(complete_operand)?;
+                }
+                _ => break,
             }
         }
+
+        Some(complete_operand)
     }
-    fn parse_integer(&mut self) -> Option<ExprNode> {
-        // Forventer at Expr skal være et heltall
-        if let Err(error) = self.expect(TokenType::IntLit) {
-            println!("Error {}", error);
+
+    fn parse_member(&mut self, base: ExprNode) -> Option<ExprNode> {
+        if let Err(error) = self.expect(TokenType::Identifier) {
+            self.error(format!("Error {}", error));
             return None;
         }
+        let name = self.peek(0).and_then(|token| token.value.clone())?;
+        self.consume();
 
-        let int_value = self.token_stream[self.token_index]
-            .value
-            .as_ref()
-            .map(|s| {
-                s.parse::<i32>().map_err(|e| {
-                    println!("Error: Failed to parse integer: {}", e);
-                })
-            })
-            .and_then(|result| result.ok());
-
-        let parsed = match int_value {
-            Some(parsed) => parsed,
-            None => return None,
-        };
+        if !base.ty.is_struct() {
+            self.error(format!(
+                "Error: Member access .{} on non-struct {}",
+                name, base
+            ));
+            return None;
+        }
+        if !base.ty.is_complete() {
+            self.error(format!(
+                "Error: Member access .{} on incomplete type {}",
+                name, base.ty
+            ));
+            return None;
+        }
 
-        // spiser expression
-        self.consume();
-        Some(ExprNode {
-            expr: Expr::Number(parsed),
-        })
+        match base.ty.member(&name) {
+            Some(member) => Some(ExprNode::new(Expr::Member(Box::new(base), member))),
+            None => {
+                self.error(format!("Error: {} has no member named {}", base.ty, name));
+                None
+            }
+        }
     }
 
-    fn parse_assignment(&mut self) -> Option<StatementNode> {
-        let keyword_type = match self.peek(0).expect("Token is None") {
-            current_token if current_token.token_type == TokenType::IntKeyword => {
-                current_token.clone()
+    // A variable, or an enumerator which stands for its constant value
+    fn parse_variable(&mut self) -> Option<ExprNode> {
+        let name = self.peek(0).and_then(|token| token.value.clone())?;
+
+        let expr = match self
+            .scopes
+            .iter()
+            .rev()
+            .find_map(|scope| scope.identifiers.get(&name))
+        {
+            Some(Symbol::Variable(variable)) => Expr::Variable(variable.clone()),
+            Some(Symbol::Enumerator(value)) => Expr::Number(*value),
+            Some(Symbol::Function(signature)) => {
+                let signature = signature.clone();
+                self.consume();
+                return self.parse_call(name, signature);
             }
-            current_token if current_token.token_type == TokenType::CharKeyword => {
-                current_token.clone()
+            None => {
+                self.error(format!("Error: Use of undeclared variable {}", name));
+                return None;
             }
-            _ => return None,
         };
         self.consume();
 
-        let identifier_name = match self.peek(0).expect("Token is None") {
-            current_token if current_token.token_type == TokenType::Identifier => {
-                current_token.clone()
+        Some(ExprNode::new(expr))
+    }
+
+    // f(a, b), every argument converted to the type of its parameter like an assignment
+    fn parse_call(&mut self, name: String, signature: Signature) -> Option<ExprNode> {
+        let Signature {
+            returns, params, ..
+        } = signature;
+        if let Err(error) = self.expect(TokenType::LParen) {
+            self.error(format!(
+                "Error: Function {} used as a value, {}",
+                name, error
+            ));
+            return None;
+        }
+        self.consume(); // Consume '('
+
+        let mut args = vec![];
+        while !self.next_is(TokenType::RParen) {
+            if !args.is_empty() {
+                if let Err(error) = self.expect(TokenType::Comma) {
+                    self.error(format!("Error {}", error));
+                    return None;
+                }
+                self.consume(); // Consume ','
             }
-            _ => return None,
-        };
-        self.consume();
+            args.push(self.parse_assign()?);
+        }
+        self.consume(); // Consume ')'
+
+        if args.len() != params.len() {
+            self.error(format!(
+                "Error: Function {} takes {} arguments but {} were given",
+                name,
+                params.len(),
+                args.len()
+            ));
+            return None;
+        }
 
-        let operator = match self.peek(0).expect("Token is None") {
-            current_token if current_token.token_type == TokenType::Assign => current_token.clone(),
-            _ => return None,
-        };
-        self.consume();
+        let mut converted = vec![];
+        for (arg, param) in args.into_iter().zip(params) {
+            if !converts_to(&arg, &param) {
+                self.error(format!(
+                    "Error: Passing {} of type {} to a parameter of type {} in call to {}",
+                    arg, arg.ty, param, name
+                ));
+                return None;
+            }
 
-        let assign_value = match self.peek(0).expect("Token is None") {
-            current_token if current_token.token_type == TokenType::IntLit => {
-                self.parse_expression()
+            if arg.ty == param {
+                converted.push(arg);
+            } else {
+                converted.push(ExprNode::new(Expr::Cast(param, Box::new(arg))));
             }
-            current_token if current_token.token_type == TokenType::Char => self.parse_character(),
-            _ => return None,
-        };
-        self.consume();
+        }
 
-        Some(StatementNode {
-            statement: Statement::Assignment(
-                keyword_type.token_type,
-                identifier_name.token_type,
-                operator.token_type,
-                assign_value.map(Box::new),
-            ),
+        Some(ExprNode {
+            expr: Expr::Call(name, converted),
+            ty: returns,
         })
     }
 
-    fn parse_character(&mut self) -> Option<ExprNode> {
-        if let Err(error) = self.expect(TokenType::Char) {
-            println!("Error: {}", error);
-            return None;
-        }
+    // sizeof is folded to a constant right away, both sizeof(type) and sizeof expr
+    fn parse_sizeof(&mut self) -> Option<ExprNode> {
+        self.consume(); // Consume sizeof
 
-        let char_value = match self.peek(0) {
-            Some(current_token) if current_token.token_type == TokenType::Char => {
-                current_token.clone()
-            }
-            _ => {
-                println!("Error: Expected a character token.");
+        let is_type_name = self.next_is(TokenType::LParen)
+            && self
+                .peek(1)
+                .is_some_and(|token| is_type_keyword(&token.token_type));
+
+        let ty = if is_type_name {
+            let ty = self.parse_type_name()?;
+            if !ty.is_complete() {
+                self.error(format!("Error: sizeof applied to incomplete type {}", ty));
                 return None;
             }
+            ty
+        } else {
+            let operand = self.parse_factor()?;
+            self.check_value(operand, false)?.ty
         };
 
-        let parsed_char = match &char_value.value {
-            Some(value) if value.len() == 3 && value.starts_with('\'') && value.ends_with('\'') => {
+        Some(ExprNode::new(Expr::Number(ty.size() as i32)))
+    }
+
+    // ( type ) operand, converting between chars, ints and pointers
+    fn parse_cast(&mut self) -> Option<ExprNode> {
+        let ty = self.parse_type_name()?;
+        let operand = self.parse_factor()?;
+
+        if !matches!(ty, Type::Int | Type::Char | Type::Pointer(_)) {
+            self.error(format!("Error: Cannot cast to {}", ty));
+            return None;
+        }
+        if operand.ty.is_struct() {
+            self.error(format!(
+                "Error: Cannot cast {} of type {}",
+                operand, operand.ty
+            ));
+            return None;
+        }
+
+        Some(ExprNode::new(Expr::Cast(ty, Box::new(operand))))
+    }
+
+    // A parenthesized type without a name, as in sizeof(int *) and casts
+    fn parse_type_name(&mut self) -> Option<Type> {
+        self.consume(); // Consume '('
+
+        let specifiers = self.parse_specifiers()?;
+        let Declarator { name, ty, open, .. } = self.parse_declarator(specifiers.ty, false)?;
+        if name.is_some() || open || specifiers.is_static {
+            self.error("Error: Expected a type name".to_string());
+            return None;
+        }
+
+        if let Err(error) = self.expect(TokenType::RParen) {
+            self.error(format!("Error {}", error));
+            return None;
+        }
+        self.consume(); // Consume ')'
+
+        Some(ty)
+    }
+
+    fn parse_unary_operation(&mut self) -> Option<ExprNode> {
+        let Some(current_token) = self.peek(0) else {
+            self.error("Error Unexpected stream end".to_string());
+            return None;
+        };
+
+        match current_token.token_type.clone() {
+            TokenType::BitComplement
+            | TokenType::Minus
+            | TokenType::Not
+            | TokenType::BitAnd
+            | TokenType::Mul => {
+                // Current op: ~, -, !, & || *
+                let operator = current_token.clone();
+
+                // Consume operator
+                self.consume();
+
+                // Want to parse the expression recursively
+                let operand = self.parse_factor()?;
+
+                match operator.token_type {
+                    // Dereference
+                    TokenType::Mul => self.new_dereference(operand),
+                    // Address-of
+                    TokenType::BitAnd => {
+                        if !operand.is_lvalue() {
+                            self.error(format!("Error: Cannot take the address of {}", operand));
+                            return None;
+                        }
+                        Some(ExprNode::new(Expr::UnaryOp(
+                            operator.token_type,
+                            Some(Box::new(operand)),
+                        )))
+                    }
+                    // Create expression node
+                    _ => Some(ExprNode::new(Expr::UnaryOp(
+                        operator.token_type,
+                        Some(Box::new(operand)),
+                    ))),
+                }
+            }
+            token_type => {
+                self.error(format!(
+                    "Error: Expected a unary operator, found {:?}",
+                    token_type
+                ));
+                None
+            }
+        }
+    }
+    fn parse_integer(&mut self) -> Option<ExprNode> {
+        // Forventer at Expr skal være et heltall
+        if let Err(error) = self.expect(TokenType::IntLit) {
+            self.error(format!("Error {}", error));
+            return None;
+        }
+
+        let value = self.token_stream[self.token_index].value.clone()?;
+        let parsed = match value.parse::<i32>() {
+            Ok(parsed) => parsed,
+            Err(e) => {
+                self.error(format!("Error: Failed to parse integer: {}", e));
+                return None;
+            }
+        };
+
+        // spiser expression
+        self.consume();
+        Some(ExprNode::new(Expr::Number(parsed)))
+    }
+
+    // static and const may surround the type, as in static const int or int const
+    fn parse_specifiers(&mut self) -> Option<Specifiers> {
+        let mut is_static = false;
+        let mut is_const = false;
+
+        let mut ty = None;
+        while let Some(token_type) = self.peek(0).map(|token| token.token_type.clone()) {
+            match token_type {
+                TokenType::StaticKeyword => {
+                    is_static = true;
+                    self.consume();
+                }
+                TokenType::ConstKeyword => {
+                    is_const = true;
+                    self.consume();
+                }
+                _ if ty.is_none() => ty = Some(self.parse_base_type()?),
+                _ => break,
+            }
+        }
+
+        match ty {
+            Some(ty) => Some(Specifiers {
+                ty,
+                is_static,
+                is_const,
+            }),
+            None => {
+                self.error("Error: Expected a type after qualifiers".to_string());
+                None
+            }
+        }
+    }
+
+    // Parses the type keyword, or struct/union specifier, that starts a declaration
+    fn parse_base_type(&mut self) -> Option<Type> {
+        let ty = match self.peek(0).map(|token| token.token_type.clone()) {
+            Some(TokenType::IntKeyword) => Type::Int,
+            Some(TokenType::CharKeyword) => Type::Char,
+            Some(TokenType::StructKeyword) => return self.parse_struct_specifier(false),
+            Some(TokenType::UnionKeyword) => return self.parse_struct_specifier(true),
+            Some(TokenType::EnumKeyword) => return self.parse_enum_specifier(),
+            other => {
+                self.error(format!("Error: Expected a type, found {:?}", other));
+                return None;
+            }
+        };
+        self.consume();
+
+        Some(ty)
+    }
+
+    // struct tag { members }, struct { members } or a reference to struct tag
+    fn parse_struct_specifier(&mut self, is_union: bool) -> Option<Type> {
+        self.nested(|parser| parser.parse_nested_struct_specifier(is_union))
+    }
+
+    fn parse_nested_struct_specifier(&mut self, is_union: bool) -> Option<Type> {
+        self.consume(); // Consume struct || union
+
+        let mut tag = None;
+        if self.next_is(TokenType::Identifier) {
+            tag = self.peek(0).and_then(|token| token.value.clone());
+            self.consume();
+        }
+
+        if !self.next_is(TokenType::LBrace) {
+            let tag = match tag {
+                Some(tag) => tag,
+                None => {
+                    self.error("Error: Expected a tag or member list after struct".to_string());
+                    return None;
+                }
+            };
+
+            // A tag that has not been seen yet declares an incomplete type, defined later
+            let existing = self
+                .scopes
+                .iter()
+                .rev()
+                .find_map(|scope| scope.tags.get(&tag))
+                .cloned();
+            let struct_ref = match existing {
+                Some(Tag::Struct(struct_ref)) => struct_ref,
+                Some(Tag::Enum) => {
+                    self.error(format!("Error: enum {} used as a struct or union", tag));
+                    return None;
+                }
+                None => {
+                    let struct_ref = StructRef::new(Some(tag.clone()), is_union);
+                    self.declare_tag(tag, Tag::Struct(struct_ref.clone()));
+                    struct_ref
+                }
+            };
+
+            if struct_ref.borrow().is_union != is_union {
+                self.error(format!(
+                    "Error: {:?} used with the wrong keyword",
+                    struct_ref
+                ));
+                return None;
+            }
+            return Some(Type::Struct(struct_ref));
+        }
+
+        // A definition completes an earlier declaration of the tag in the same scope
+        let struct_ref = match &tag {
+            Some(tag) => {
+                let existing = self
+                    .scopes
+                    .last()
+                    .and_then(|scope| scope.tags.get(tag))
+                    .cloned();
+                match existing {
+                    Some(Tag::Struct(struct_ref)) if !struct_ref.borrow().complete => struct_ref,
+                    Some(_) => {
+                        self.error(format!("Error: Redefinition of tag {}", tag));
+                        return None;
+                    }
+                    None => {
+                        let struct_ref = StructRef::new(Some(tag.clone()), is_union);
+                        self.declare_tag(tag.clone(), Tag::Struct(struct_ref.clone()));
+                        struct_ref
+                    }
+                }
+            }
+            None => StructRef::new(None, is_union),
+        };
+
+        self.consume(); // Consume '{'
+        let members = self.parse_members()?;
+        self.consume(); // Consume '}'
+
+        struct_ref.define(members);
+        Some(Type::Struct(struct_ref))
+    }
+
+    // Member declarations up to the closing brace, the last member may be a flexible array
+    fn parse_members(&mut self) -> Option<Vec<(String, Type)>> {
+        let mut members: Vec<(String, Type)> = vec![];
+        let mut flexible = false;
+
+        while !self.next_is(TokenType::RBrace) {
+            let specifiers = self.parse_specifiers()?;
+            if specifiers.is_static {
+                self.error("Error: Struct members cannot be static".to_string());
+                return None;
+            }
+
+            loop {
+                let Declarator { name, ty, open, .. } =
+                    self.parse_declarator(specifiers.ty.clone(), specifiers.is_const)?;
+                let name = match name {
+                    Some(name) => name,
+                    None => {
+                        self.error("Error: Expected a member name".to_string());
+                        return None;
+                    }
+                };
+
+                if flexible {
+                    self.error("Error: Flexible array member must be the last member".to_string());
+                    return None;
+                }
+                if !open && !ty.is_complete() {
+                    self.error(format!("Error: Member {} has incomplete type {}", name, ty));
+                    return None;
+                }
+                if members.iter().any(|(existing, _)| *existing == name) {
+                    self.error(format!("Error: Duplicate member {}", name));
+                    return None;
+                }
+
+                flexible = open;
+                members.push((name, ty));
+
+                if !self.next_is(TokenType::Comma) {
+                    break;
+                }
+                self.consume(); // Consume ','
+            }
+
+            if let Err(error) = self.expect(TokenType::Semi) {
+                self.error(format!("Error {}", error));
+                return None;
+            }
+            self.consume(); // Consume ';'
+        }
+
+        Some(members)
+    }
+
+    fn declare_tag(&mut self, name: String, tag: Tag) {
+        self.scopes
+            .last_mut()
+            .expect("No open scope")
+            .tags
+            .insert(name, tag);
+    }
+
+    // enum tag { A, B = 5, C }, enum { ... } or a reference to enum tag. Enumerators count up
+    // from 0, or from the previous explicit value, and enum types are plain ints
+    fn parse_enum_specifier(&mut self) -> Option<Type> {
+        self.consume(); // Consume enum
+
+        let mut tag = None;
+        if self.next_is(TokenType::Identifier) {
+            tag = self.peek(0).and_then(|token| token.value.clone());
+            self.consume();
+        }
+
+        if !self.next_is(TokenType::LBrace) {
+            let tag = match tag {
+                Some(tag) => tag,
+                None => {
+                    self.error("Error: Expected a tag or enumerator list after enum".to_string());
+                    return None;
+                }
+            };
+
+            return match self
+                .scopes
+                .iter()
+                .rev()
+                .find_map(|scope| scope.tags.get(&tag))
+            {
+                Some(Tag::Enum) => Some(Type::Int),
+                Some(Tag::Struct(_)) => {
+                    self.error(format!("Error: {} is not an enum", tag));
+                    None
+                }
+                None => {
+                    self.error(format!("Error: Use of undeclared enum {}", tag));
+                    None
+                }
+            };
+        }
+
+        if let Some(tag) = tag {
+            if self
+                .scopes
+                .last()
+                .is_some_and(|scope| scope.tags.contains_key(&tag))
+            {
+                self.error(format!("Error: Redefinition of tag {}", tag));
+                return None;
+            }
+            self.declare_tag(tag, Tag::Enum);
+        }
+
+        self.consume(); // Consume '{'
+
+        let mut next_value: Option<i32> = Some(0);
+        while !self.next_is(TokenType::RBrace) {
+            if let Err(error) = self.expect(TokenType::Identifier) {
+                self.error(format!("Error {}", error));
+                return None;
+            }
+            let name = self.peek(0).and_then(|token| token.value.clone())?;
+            self.consume();
+
+            // Later enumerators may use the earlier ones in their values
+            let value = if self.next_is(TokenType::Assign) {
+                self.consume(); // Consume '='
+                self.parse_constant_expression()?
+            } else {
+                match next_value {
+                    Some(value) => value,
+                    None => {
+                        self.error(format!("Error: Enumerator {} overflows int", name));
+                        return None;
+                    }
+                }
+            };
+            next_value = value.checked_add(1);
+
+            let scope = self.scopes.last_mut().expect("No open scope");
+            if scope.identifiers.contains_key(&name) {
+                self.error(format!("Error: Redeclaration of {}", name));
+                return None;
+            }
+            scope.identifiers.insert(name, Symbol::Enumerator(value));
+
+            if !self.next_is(TokenType::Comma) {
+                break;
+            }
+            self.consume(); // Consume ','
+        }
+
+        if let Err(error) = self.expect(TokenType::RBrace) {
+            self.error(format!("Error {}", error));
+            return None;
+        }
+        self.consume(); // Consume '}'
+
+        Some(Type::Int)
+    }
+
+    // Pointer stars, an optional name and array dimensions, e.g *p or grid[3][4]
+    // A const in the specifiers applies to the object itself only when there is no pointer
+    fn parse_declarator(&mut self, base_type: Type, base_const: bool) -> Option<Declarator> {
+        let mut ty = base_type;
+        let mut is_pointer = false;
+        let mut is_const = false;
+        while self.next_is(TokenType::Mul) {
+            self.consume();
+            ty = Type::pointer_to(ty);
+            is_pointer = true;
+
+            // Only a const after the last star makes the pointer itself read-only
+            is_const = self.next_is(TokenType::ConstKeyword);
+            while self.next_is(TokenType::ConstKeyword) {
+                self.consume();
+            }
+        }
+
+        let mut name = None;
+        if self.next_is(TokenType::Identifier) {
+            name = self.peek(0).and_then(|token| token.value.clone());
+            self.consume();
+        }
+
+        let mut dimensions: Vec<Option<usize>> = vec![];
+        while self.next_is(TokenType::LBrack) {
+            self.consume(); // Consume '['
+
+            if self.next_is(TokenType::RBrack) {
+                dimensions.push(None);
+            } else {
+                let length = self.parse_constant_expression()?;
+                if length <= 0 {
+                    self.error(format!(
+                        "Error: Array size must be positive, found {}",
+                        length
+                    ));
+                    return None;
+                }
+                dimensions.push(Some(length as usize));
+            }
+
+            if let Err(error) = self.expect(TokenType::RBrack) {
+                self.error(format!("Error {}", error));
+                return None;
+            }
+            self.consume(); // Consume ']'
+        }
+
+        if dimensions.iter().skip(1).any(|length| length.is_none()) {
+            self.error("Error: Only the first array dimension may be omitted".to_string());
+            return None;
+        }
+        let open = dimensions.first() == Some(&None);
+
+        // int a[2][3] is an array of 2 arrays of 3 ints, so the last dimension wraps first
+        for length in dimensions.iter().rev() {
+            ty = Type::array_of(ty, length.unwrap_or(0));
+        }
+
+        Some(Declarator {
+            name,
+            ty,
+            open,
+            is_const: if is_pointer { is_const } else { base_const },
+        })
+    }
+
+    fn parse_constant_expression(&mut self) -> Option<i32> {
+        let expression = self.parse_conditional()?;
+
+        match evaluate_constant(&expression) {
+            Some(value) => Some(value),
+            None => {
+                self.error(format!(
+                    "Error: Expected a constant expression, found {}",
+                    expression
+                ));
+                None
+            }
+        }
+    }
+
+    // A single expression for scalars and a braced list for aggregates. Nested lists may drop
+    // their braces, and a struct can also be initialized from another struct of the same type
+    fn parse_initializer(&mut self, ty: &Type) -> Option<Initializer> {
+        let braced = self.next_is(TokenType::LBrace);
+        if braced {
+            self.consume(); // Consume '{'
+        } else if ty.is_struct() {
+            let start = self.token_index;
+            let value = self.parse_assign()?;
+            if value.ty == *ty {
+                return Some(Initializer::Expr(value));
+            }
+            // Not a whole struct, so it starts the member values instead
+            self.token_index = start;
+        }
+
+        let initializer = match ty {
+            Type::Array(_, _) | Type::Struct(_) => {
+                Initializer::List(self.parse_initializer_items(ty, false, braced)?)
+            }
+            _ => {
+                let value = self.parse_assign()?;
+                let value = Initializer::Expr(self.check_value(value, false)?);
+                if braced && self.next_is(TokenType::Comma) {
+                    self.consume();
+                }
+                value
+            }
+        };
+
+        if braced {
+            if let Err(error) = self.expect(TokenType::RBrace) {
+                self.error(format!("Error {}", error));
+                return None;
+            }
+            self.consume(); // Consume '}'
+        }
+
+        Some(initializer)
+    }
+
+    // Comma separated initializers for the elements of an array or the members of a struct,
+    // a union only takes its first member. Without braces the list stops once the aggregate
+    // is full and leaves the next comma to the enclosing list
+    fn parse_initializer_items(
+        &mut self,
+        ty: &Type,
+        open: bool,
+        braced: bool,
+    ) -> Option<Vec<Initializer>> {
+        let item_types: Vec<Type> = match ty {
+            Type::Struct(struct_ref) => {
+                let struct_type = struct_ref.borrow();
+                let count = if struct_type.is_union { 1 } else { usize::MAX };
+                struct_type
+                    .members
+                    .iter()
+                    .take(count)
+                    .map(|member| member.ty.clone())
+                    .collect()
+            }
+            _ => vec![],
+        };
+        let capacity = match ty {
+            Type::Array(_, _) if open => None,
+            Type::Array(_, length) => Some(*length),
+            _ => Some(item_types.len()),
+        };
+
+        let mut items: Vec<Initializer> = vec![];
+
+        loop {
+            if braced && self.next_is(TokenType::RBrace) {
+                break;
+            }
+            if Some(items.len()) == capacity {
+                if braced {
+                    self.error(format!("Error: Excess elements in initializer for {}", ty));
+                    return None;
+                }
+                break;
+            }
+
+            let item_type = match ty {
+                Type::Array(element, _) => element.as_ref().clone(),
+                _ => item_types[items.len()].clone(),
+            };
+            items.push(self.parse_initializer(&item_type)?);
+
+            if !self.next_is(TokenType::Comma) || (!braced && Some(items.len()) == capacity) {
+                break;
+            }
+            self.consume(); // Consume ','
+        }
+
+        Some(items)
+    }
+
+    fn parse_declaration(&mut self) -> Option<StatementNode> {
+        let specifiers = self.parse_specifiers()?;
+
+        // struct tag { ... }; or enum { ... }; only declares the type
+        if self.next_is(TokenType::Semi) {
+            self.consume(); // Consume ';'
+            return Some(StatementNode {
+                statement: Statement::Empty,
+            });
+        }
+
+        let Declarator {
+            name,
+            mut ty,
+            open,
+            is_const,
+        } = self.parse_declarator(specifiers.ty, specifiers.is_const)?;
+        let name = match name {
+            Some(name) => name,
+            None => {
+                self.error("Error: Expected a variable name in declaration".to_string());
+                return None;
+            }
+        };
+
+        let mut initializer = None;
+        if self.next_is(TokenType::Assign) {
+            self.consume(); // Consume '='
+
+            if ty.is_array() && !self.next_is(TokenType::LBrace) {
+                self.error(format!(
+                    "Error: Array {} must be initialized with a braced list",
+                    name
+                ));
+                return None;
+            }
+
+            if open {
+                // int a[] = {...} takes its length from the initializer
+                let element = ty.base().cloned().expect("Open declarator is an array");
+                self.consume(); // Consume '{'
+                let items = self.parse_initializer_items(&ty, true, true)?;
+
+                if let Err(error) = self.expect(TokenType::RBrace) {
+                    self.error(format!("Error {}", error));
+                    return None;
+                }
+                self.consume(); // Consume '}'
+
+                if items.is_empty() {
+                    self.error(format!("Error: Array {} cannot have zero length", name));
+                    return None;
+                }
+                ty = Type::array_of(element, items.len());
+                initializer = Some(Initializer::List(items));
+            } else {
+                initializer = Some(self.parse_initializer(&ty)?);
+            }
+        } else if open {
+            self.error(format!(
+                "Error: Array size missing in declaration of {}",
+                name
+            ));
+            return None;
+        }
+
+        if !ty.is_complete() {
+            self.error(format!(
+                "Error: Variable {} has incomplete type {}",
+                name, ty
+            ));
+            return None;
+        }
+
+        if let Err(error) = self.expect(TokenType::Semi) {
+            self.error(format!("Error {}", error));
+            return None;
+        }
+        self.consume(); // Consume ';'
+
+        let is_global = self.scopes.len() == 1 || specifiers.is_static;
+        let earlier = self.earlier_declaration(&name);
+        let variable = match earlier {
+            Some(index) => self.redeclare_global(
+                index,
+                &ty,
+                is_const,
+                specifiers.is_static,
+                initializer.is_some(),
+            )?,
+            None => self.declare_variable(name, ty, is_const, is_global)?,
+        };
+
+        // Globals get their initial value at compile time instead of running code for it
+        if is_global {
+            let image = self.evaluate_data(&variable, initializer.as_ref())?;
+            let global = GlobalNode {
+                variable: variable.clone(),
+                initializer: initializer.clone(),
+                image,
+                is_static: specifiers.is_static,
+            };
+            match earlier {
+                Some(_) if initializer.is_none() => {}
+                Some(index) => self.globals[index] = global,
+                None => self.globals.push(global),
+            }
+        }
+
+        Some(StatementNode {
+            statement: Statement::Declaration(variable, initializer),
+        })
+    }
+
+    // The global of a file scope variable declared before, which a later declaration of the
+    // same name refers to
+    fn earlier_declaration(&self, name: &str) -> Option<usize> {
+        if self.scopes.len() != 1 {
+            return None;
+        }
+        self.globals
+            .iter()
+            .position(|global| global.variable.storage == Storage::Global(name.to_string()))
+    }
+
+    // A file scope variable without an initializer is a tentative definition, it may be
+    // declared again as long as the declarations agree and only one of them initializes it
+    fn redeclare_global(
+        &mut self,
+        index: usize,
+        ty: &Type,
+        is_const: bool,
+        is_static: bool,
+        initialized: bool,
+    ) -> Option<Variable> {
+        let earlier = &self.globals[index];
+        let variable = earlier.variable.clone();
+        let error = if variable.ty != *ty || variable.is_const != is_const {
+            Some("Conflicting types for")
+        } else if is_static && !earlier.is_static {
+            Some("Static declaration follows non-static declaration of")
+        } else if !is_static && earlier.is_static {
+            Some("Non-static declaration follows static declaration of")
+        } else if initialized && earlier.initializer.is_some() {
+            Some("Redefinition of")
+        } else {
+            None
+        };
+
+        if let Some(error) = error {
+            self.error(format!("Error: {} {}", error, variable.name));
+            return None;
+        }
+        Some(variable)
+    }
+
+    // Registers a variable in the innermost scope. Locals get the next free slot, file scope
+    // variables keep their C name and static locals get one with dots, which no C identifier
+    // can clash with. The backends turn these names into symbols
+    fn declare_variable(
+        &mut self,
+        name: String,
+        ty: Type,
+        is_const: bool,
+        is_global: bool,
+    ) -> Option<Variable> {
+        if self
+            .scopes
+            .last()
+            .expect("No open scope")
+            .identifiers
+            .contains_key(&name)
+        {
+            self.error(format!("Error: Redeclaration of {}", name));
+            return None;
+        }
+
+        let storage = if !is_global {
+            Storage::Local(self.locals.len())
+        } else if self.scopes.len() == 1 {
+            Storage::Global(name.clone())
+        } else {
+            Storage::Global(format!(
+                "{}.{}.{}",
+                self.function_name,
+                name,
+                self.globals.len()
+            ))
+        };
+
+        let variable = Variable {
+            name: name.clone(),
+            ty,
+            storage,
+            is_const,
+        };
+        self.scopes
+            .last_mut()
+            .expect("No open scope")
+            .identifiers
+            .insert(name, Symbol::Variable(variable.clone()));
+        if !is_global {
+            self.locals.push(variable.clone());
+        }
+
+        Some(variable)
+    }
+
+    // Lays out the initial bytes of a global, every value must be known at compile time
+    fn evaluate_data(
+        &mut self,
+        variable: &Variable,
+        initializer: Option<&Initializer>,
+    ) -> Option<DataImage> {
+        let mut image = DataImage {
+            bytes: vec![0; variable.ty.size()],
+            relocations: vec![],
+        };
+
+        let mut stores = vec![];
+        if let Some(initializer) = initializer {
+            initializer.flatten(&variable.ty, 0, &mut stores);
+        }
+
+        for (offset, value, ty) in stores {
+            if let (Type::Pointer(_), Some((symbol, addend))) = (&ty, evaluate_address(value)) {
+                image.relocations.push(Relocation {
+                    offset,
+                    symbol,
+                    addend,
+                });
+                continue;
+            }
+
+            let constant = match evaluate_constant(value) {
+                Some(constant) if !ty.is_struct() => constant,
+                _ => {
+                    self.error(format!(
+                        "Error: Initializer element {} of {} is not constant",
+                        value, variable.name
+                    ));
+                    return None;
+                }
+            };
+            // Pointers are 64 bits, so the constant is sign extended like a cast would
+            let bytes = (constant as i64).to_le_bytes();
+            image.bytes[offset..offset + ty.size()].copy_from_slice(&bytes[..ty.size()]);
+        }
+
+        Some(image)
+    }
+
+    fn parse_expression_statement(&mut self) -> Option<StatementNode> {
+        let expression = self.parse_expression()?;
+        let expression = self.check_value(expression, true)?;
+
+        if let Err(error) = self.expect(TokenType::Semi) {
+            self.error(format!("Error {}", error));
+            return None;
+        }
+        self.consume(); // Consume ';'
+
+        Some(StatementNode {
+            statement: Statement::Expression(expression),
+        })
+    }
+
+    fn parse_character(&mut self) -> Option<ExprNode> {
+        if let Err(error) = self.expect(TokenType::Char) {
+            self.error(format!("Error: {}", error));
+            return None;
+        }
+
+        let char_value = match self.peek(0) {
+            Some(current_token) if current_token.token_type == TokenType::Char => {
+                current_token.clone()
+            }
+            _ => {
+                self.error("Error: Expected a character token.".to_string());
+                return None;
+            }
+        };
+
+        let parsed_char = match &char_value.value {
+            Some(value) if value.len() == 3 && value.starts_with('\'') && value.ends_with('\'') => {
                 value.chars().nth(1) // Henter ut tegnet mellom apostrofene
             }
             _ => {
-                println!("Error: Invalid character literal format.");
+                self.error("Error: Invalid character literal format.".to_string());
                 return None;
             }
         };
@@ -395,7 +1844,7 @@ impl Parser {
         let parsed_char = match parsed_char {
             Some(c) => c,
             None => {
-                println!("Error: No character found in token.");
+                self.error("Error: No character found in token.".to_string());
                 return None;
             }
         };
@@ -403,31 +1852,57 @@ impl Parser {
         self.consume();
 
         // Gjør om char til ascii før den sendes til kode-generering, lar oss bruke Number som vanlig. Men ikke alltid ønskelig?
-        Some(ExprNode {
-            expr: Expr::Number(parsed_char as i32),
-        })
+        Some(ExprNode::new(Expr::Number(parsed_char as i32)))
     }
 
     fn parse_return(&mut self) -> Option<StatementNode> {
         // Forventer return da dette er eneste expression
         if let Err(error) = self.expect(TokenType::ReturnKeyword) {
-            println!("Error {}", error);
+            self.error(format!("Error {}", error));
             return None;
         }
 
         // Move into expression
         self.consume();
-        let expression;
-        if let Some(statement_expression) = self.parse_expression() {
-            expression = statement_expression;
+        let expression = if self.next_is(TokenType::Semi) {
+            None
+        } else if let Some(statement_expression) = self.parse_expression() {
+            Some(self.check_value(statement_expression, false)?)
         } else {
-            println!("Error: Failed to parse expression");
+            self.error("Error: Failed to parse expression".to_string());
             return None;
+        };
+
+        // The value is converted to the return type like an argument to a parameter
+        let return_type = self.return_type.clone();
+        match &expression {
+            Some(expression) if return_type == Type::Void => {
+                self.error(format!(
+                    "Error: Returning {} from void function {}",
+                    expression, self.function_name
+                ));
+                return None;
+            }
+            Some(expression) if !converts_to(expression, &return_type) => {
+                self.error(format!(
+                    "Error: Returning {} of type {} from function {} returning {}",
+                    expression, expression.ty, self.function_name, return_type
+                ));
+                return None;
+            }
+            None if return_type != Type::Void => {
+                self.error(format!(
+                    "Error: Missing return value in function {} returning {}",
+                    self.function_name, return_type
+                ));
+                return None;
+            }
+            _ => {}
         }
 
         // Neste token er forventet å være semikolon
         if let Err(error) = self.expect(TokenType::Semi) {
-            println!("Error {}", error);
+            self.error(format!("Error {}", error));
             return None;
         }
 
@@ -440,40 +1915,380 @@ impl Parser {
     }
 
     fn parse_statement(&mut self) -> Option<StatementNode> {
+        self.nested(Self::parse_nested_statement)
+    }
+
+    fn parse_nested_statement(&mut self) -> Option<StatementNode> {
         if self.token_index > self.token_stream.len() {
             return None;
         }
 
-        let current_token = self.peek(0).expect("Token is None");
+        let Some(current_token) = self.peek(0) else {
+            self.error("Error Unexpected stream end".to_string());
+            return None;
+        };
         match current_token.token_type {
-            TokenType::IntKeyword => self.parse_assignment(),
-            TokenType::CharKeyword => self.parse_assignment(),
+            TokenType::IntKeyword => self.parse_declaration(),
+            TokenType::CharKeyword => self.parse_declaration(),
+            TokenType::StructKeyword | TokenType::UnionKeyword | TokenType::EnumKeyword => {
+                self.parse_declaration()
+            }
+            TokenType::StaticKeyword | TokenType::ConstKeyword => self.parse_declaration(),
+            TokenType::Semi => {
+                self.consume();
+                Some(StatementNode {
+                    statement: Statement::Empty,
+                })
+            }
             TokenType::ReturnKeyword => self.parse_return(),
-            _ => None,
+            TokenType::LBrace => self.parse_block(),
+            TokenType::SwitchKeyword => self.parse_switch(),
+            TokenType::CaseKeyword | TokenType::DefaultKeyword => self.parse_case(),
+            TokenType::BreakKeyword => self.parse_break(),
+            TokenType::ContinueKeyword => self.parse_continue(),
+            TokenType::IfKeyword => self.parse_if(),
+            TokenType::WhileKeyword => self.parse_while(),
+            TokenType::ForKeyword => self.parse_for(),
+            TokenType::RBrace => None,
+            _ => self.parse_expression_statement(),
+        }
+    }
+
+    // { statements } with its own scope for the declarations inside
+    fn parse_block(&mut self) -> Option<StatementNode> {
+        self.consume(); // Consume '{'
+        self.scopes.push(Scope::default());
+
+        let mut statements = vec![];
+        while !self.next_is(TokenType::RBrace) {
+            if self.peek(0).is_none() {
+                self.error("Error: Expected }} before end of file".to_string());
+                return None;
+            }
+            statements.push(self.parse_statement()?);
         }
+
+        self.consume(); // Consume '}'
+        self.scopes.pop();
+
+        Some(StatementNode {
+            statement: Statement::Block(statements),
+        })
     }
 
-    fn parse_function(&mut self) -> Option<FunctionNode> {
-        if self.token_index >= self.token_stream.len() {
+    fn parse_switch(&mut self) -> Option<StatementNode> {
+        self.consume(); // Consume 'switch'
+
+        if let Err(error) = self.expect(TokenType::LParen) {
+            self.error(format!("Error {}", error));
+            return None;
+        }
+        self.consume(); // Consume '('
+
+        let condition = self.parse_expression()?;
+        let condition = self.check_value(condition, false)?;
+        if !matches!(condition.ty, Type::Int | Type::Char) {
+            self.error(format!(
+                "Error: Switch quantity {} has type {}, not an integer",
+                condition, condition.ty
+            ));
+            return None;
+        }
+
+        if let Err(error) = self.expect(TokenType::RParen) {
+            self.error(format!("Error {}", error));
             return None;
         }
+        self.consume(); // Consume ')'
 
-        // Holder statements
-        let mut statement_list: Vec<StatementNode> = vec![];
+        // The cases are collected while the body is parsed
+        self.switches.push(SwitchCases::default());
+        let body = self.parse_body();
+        let cases = self.switches.pop().expect("Switch was pushed");
+
+        Some(StatementNode {
+            statement: Statement::Switch(condition, Box::new(body?), cases),
+        })
+    }
+
+    // case constant: statement or default: statement, belonging to the innermost switch
+    fn parse_case(&mut self) -> Option<StatementNode> {
+        let is_default = self.next_is(TokenType::DefaultKeyword);
+        self.consume(); // Consume 'case' or 'default'
+
+        let value = if is_default {
+            None
+        } else {
+            Some(self.parse_constant_expression()?)
+        };
 
-        // Expect IntKeyword
-        if let Err(error) = self.expect(TokenType::IntKeyword) {
-            println!("Error {}", error);
+        if let Err(error) = self.expect(TokenType::Colon) {
+            self.error(format!("Error {}", error));
             return None;
-        } // int
+        }
+        self.consume(); // Consume ':'
+
+        let cases = match self.switches.last_mut() {
+            Some(cases) => cases,
+            None => {
+                self.error("Error: Case label not within a switch statement".to_string());
+                return None;
+            }
+        };
+        match value {
+            Some(value) if cases.values.contains(&value) => {
+                self.error(format!("Error: Duplicate case value {}", value));
+                return None;
+            }
+            Some(value) => cases.values.push(value),
+            None if cases.has_default => {
+                self.error("Error: Multiple default labels in one switch".to_string());
+                return None;
+            }
+            None => cases.has_default = true,
+        }
+
+        let body = Box::new(self.parse_statement()?);
+        let statement = match value {
+            Some(value) => Statement::Case(value, body),
+            None => Statement::Default(body),
+        };
+
+        Some(StatementNode { statement })
+    }
 
-        let return_type = self.token_stream[self.token_index].token_type.clone();
+    fn parse_break(&mut self) -> Option<StatementNode> {
+        self.consume(); // Consume 'break'
 
-        // Spiser returtype
-        self.consume();
+        if self.switches.is_empty() && self.loops == 0 {
+            self.error("Error: Break statement not within a loop or switch".to_string());
+            return None;
+        }
+
+        if let Err(error) = self.expect(TokenType::Semi) {
+            self.error(format!("Error {}", error));
+            return None;
+        }
+        self.consume(); // Consume ';'
+
+        Some(StatementNode {
+            statement: Statement::Break,
+        })
+    }
+
+    fn parse_continue(&mut self) -> Option<StatementNode> {
+        self.consume(); // Consume 'continue'
+
+        if self.loops == 0 {
+            self.error("Error: Continue statement not within a loop".to_string());
+            return None;
+        }
+
+        if let Err(error) = self.expect(TokenType::Semi) {
+            self.error(format!("Error {}", error));
+            return None;
+        }
+        self.consume(); // Consume ';'
+
+        Some(StatementNode {
+            statement: Statement::Continue,
+        })
+    }
+
+    // ( expression ) deciding an if or a while, compared with zero like the operand of !
+    fn parse_condition(&mut self) -> Option<ExprNode> {
+        if let Err(error) = self.expect(TokenType::LParen) {
+            self.error(format!("Error {}", error));
+            return None;
+        }
+        self.consume(); // Consume '('
+
+        let condition = self.parse_expression()?;
+        let condition = self.check_condition(condition)?;
+
+        if let Err(error) = self.expect(TokenType::RParen) {
+            self.error(format!("Error {}", error));
+            return None;
+        }
+        self.consume(); // Consume ')'
+
+        Some(condition)
+    }
+
+    fn check_condition(&mut self, condition: ExprNode) -> Option<ExprNode> {
+        if !condition.ty.decay().is_scalar() {
+            self.error(format!(
+                "Error: Condition {} has type {}, not a scalar",
+                condition, condition.ty
+            ));
+            return None;
+        }
+        self.check_value(condition, false)
+    }
+
+    // A call of a void function has no value to use, it can only be a statement of its own
+    // or the left of a comma
+    fn check_value(&mut self, expression: ExprNode, is_statement: bool) -> Option<ExprNode> {
+        let void = match expression.ty {
+            Type::Void if !is_statement => Some(&expression),
+            _ => expression.void_value(),
+        };
+        if let Some(void) = void {
+            self.error(format!("Error: Void value of {} used", void));
+            return None;
+        }
+        Some(expression)
+    }
+
+    // if ( condition ) statement [else statement], an else belongs to the nearest if
+    // An else if chain is parsed arm by arm, it is no deeper than a single if
+    fn parse_if(&mut self) -> Option<StatementNode> {
+        let mut arms = vec![];
+        let mut otherwise = None;
+        loop {
+            self.consume(); // Consume 'if'
+            let condition = self.parse_condition()?;
+            arms.push((condition, self.parse_body()?));
+
+            if !self.next_is(TokenType::ElseKeyword) {
+                break;
+            }
+            self.consume(); // Consume 'else'
+            if !self.next_is(TokenType::IfKeyword) {
+                otherwise = Some(self.parse_body()?);
+                break;
+            }
+        }
+
+        // Each arm is the else of the one before it
+        arms.into_iter()
+            .rev()
+            .fold(otherwise, |otherwise, (condition, then)| {
+                Some(StatementNode {
+                    statement: Statement::If(condition, Box::new(then), otherwise.map(Box::new)),
+                })
+            })
+    }
+
+    // The statement an if, else, loop or switch controls. A braced body is the level of nesting
+    // of that statement, only a single statement nests once more
+    fn parse_body(&mut self) -> Option<StatementNode> {
+        if self.next_is(TokenType::LBrace) {
+            self.parse_block()
+        } else {
+            self.parse_statement()
+        }
+    }
+
+    // The body of a loop, where break and continue are allowed
+    fn parse_loop_body(&mut self) -> Option<StatementNode> {
+        self.loops += 1;
+        let body = self.parse_body();
+        self.loops -= 1;
+
+        body
+    }
+
+    fn parse_while(&mut self) -> Option<StatementNode> {
+        self.consume(); // Consume 'while'
+
+        let condition = self.parse_condition()?;
+        let body = Box::new(self.parse_loop_body()?);
+
+        Some(StatementNode {
+            statement: Statement::While(condition, body),
+        })
+    }
+
+    // for ( init ; condition ; step ) statement. A variable declared in init is only visible
+    // in the loop
+    fn parse_for(&mut self) -> Option<StatementNode> {
+        self.consume(); // Consume 'for'
+
+        if let Err(error) = self.expect(TokenType::LParen) {
+            self.error(format!("Error {}", error));
+            return None;
+        }
+        self.consume(); // Consume '('
+
+        self.scopes.push(Scope::default());
+        let statement = self.parse_for_rest();
+        self.scopes.pop();
+
+        statement
+    }
+
+    fn parse_for_rest(&mut self) -> Option<StatementNode> {
+        let init = match self.peek(0).map(|token| token.token_type.clone()) {
+            Some(TokenType::IntKeyword | TokenType::CharKeyword | TokenType::StructKeyword)
+            | Some(TokenType::UnionKeyword | TokenType::EnumKeyword | TokenType::ConstKeyword) => {
+                self.parse_declaration()?
+            }
+            Some(TokenType::Semi) => {
+                self.consume();
+                StatementNode {
+                    statement: Statement::Empty,
+                }
+            }
+            _ => self.parse_expression_statement()?,
+        };
+
+        let condition = if self.next_is(TokenType::Semi) {
+            None
+        } else {
+            let condition = self.parse_expression()?;
+            Some(self.check_condition(condition)?)
+        };
+        if let Err(error) = self.expect(TokenType::Semi) {
+            self.error(format!("Error {}", error));
+            return None;
+        }
+        self.consume(); // Consume ';'
+
+        let step = if self.next_is(TokenType::RParen) {
+            None
+        } else {
+            let step = self.parse_expression()?;
+            Some(self.check_value(step, true)?)
+        };
+        if let Err(error) = self.expect(TokenType::RParen) {
+            self.error(format!("Error {}", error));
+            return None;
+        }
+        self.consume(); // Consume ')'
+
+        let body = Box::new(self.parse_loop_body()?);
+
+        Some(StatementNode {
+            statement: Statement::For(Box::new(init), condition, step, body),
+        })
+    }
+
+    // [static] int name ( parameters ), the part a prototype and a definition share
+    fn parse_function_header(&mut self) -> Option<FunctionHeader> {
+        let is_static = self.next_is(TokenType::StaticKeyword);
+        if is_static {
+            self.consume();
+        }
+
+        // The return type, an integer, a pointer or void
+        let mut return_type = if self.next_is(TokenType::VoidKeyword) {
+            self.consume();
+            Type::Void
+        } else {
+            self.parse_base_type()?
+        };
+        while self.next_is(TokenType::Mul) {
+            self.consume();
+            if return_type == Type::Void {
+                self.error("Error: Pointers to void are not supported".to_string());
+                return None;
+            }
+            return_type = Type::pointer_to(return_type);
+        }
 
         if let Err(error) = self.expect(TokenType::Identifier) {
-            println!("Error {}", error);
+            self.error(format!("Error {}", error));
             return None;
         } // main or other function ident
 
@@ -481,82 +2296,285 @@ impl Parser {
             Some(token) => match &token.value {
                 Some(value) => value.clone(),
                 None => {
-                    println!("Error: Missing function name");
+                    self.error("Error: Missing function name".to_string());
                     return None;
                 }
             },
             None => {
-                println!("Error: Token index out of range");
+                self.error("Error: Token index out of range".to_string());
                 return None;
             }
         };
 
+        if return_type.is_struct() {
+            self.error(format!(
+                "Error: Function {} returns {}, only integers, pointers and void can be returned",
+                function_name, return_type
+            ));
+            return None;
+        }
+
         // Consume Identifier
         self.consume();
         if let Err(error) = self.expect(TokenType::LParen) {
-            println!("Error {}", error);
+            self.error(format!("Error {}", error));
             return None;
         } // (
           // Consume LParen
         self.consume();
+
+        let params = self.parse_parameters(&function_name)?;
+
         if let Err(error) = self.expect(TokenType::RParen) {
-            println!("Error {}", error);
+            self.error(format!("Error {}", error));
             return None;
         } // )
           // Consume RParen
         self.consume();
+
+        Some(FunctionHeader {
+            name: function_name,
+            return_type,
+            params,
+            is_static,
+        })
+    }
+
+    // Parameters are declared like variables, an array parameter being a pointer to its first element
+    fn parse_parameters(
+        &mut self,
+        function_name: &str,
+    ) -> Option<Vec<(Option<String>, Type, bool)>> {
+        let mut params = vec![];
+
+        if self.next_is(TokenType::VoidKeyword)
+            && self
+                .peek(1)
+                .is_some_and(|token| token.token_type == TokenType::RParen)
+        {
+            self.consume(); // Consume void
+            return Some(params);
+        }
+
+        while !self.next_is(TokenType::RParen) {
+            if !params.is_empty() {
+                if let Err(error) = self.expect(TokenType::Comma) {
+                    self.error(format!("Error {}", error));
+                    return None;
+                }
+                self.consume(); // Consume ','
+            }
+
+            let specifiers = self.parse_specifiers()?;
+            if specifiers.is_static {
+                self.error(format!(
+                    "Error: Parameter of {} declared static",
+                    function_name
+                ));
+                return None;
+            }
+            let declarator = self.parse_declarator(specifiers.ty, specifiers.is_const)?;
+            let ty = declarator.ty.decay();
+
+            if !ty.is_scalar() {
+                self.error(format!(
+                    "Error: Parameter of {} has type {}, only integers and pointers can be passed",
+                    function_name, ty
+                ));
+                return None;
+            }
+            params.push((declarator.name, ty, declarator.is_const));
+        }
+
+        if params.len() > MAX_PARAMS {
+            self.error(format!(
+                "Error: Function {} has {} parameters, at most {} are supported",
+                function_name,
+                params.len(),
+                MAX_PARAMS
+            ));
+            return None;
+        }
+
+        Some(params)
+    }
+
+    // Makes the function callable from here on. Every declaration of it must agree, and it
+    // may only be defined once
+    fn declare_function(&mut self, header: &FunctionHeader, is_definition: bool) -> Option<()> {
+        let mut signature = Signature {
+            returns: header.return_type.clone(),
+            params: header.params.iter().map(|param| param.1.clone()).collect(),
+            is_static: header.is_static,
+            defined: is_definition,
+        };
+
+        let file_scope = self.scopes.first_mut().expect("No file scope");
+        match file_scope.identifiers.get(&header.name) {
+            Some(Symbol::Function(previous)) => {
+                if previous.returns != signature.returns || previous.params != signature.params {
+                    self.error(format!(
+                        "Error: Conflicting types for function {}",
+                        header.name
+                    ));
+                    return None;
+                }
+                if previous.defined && is_definition {
+                    self.error(format!("Error: Redefinition of function {}", header.name));
+                    return None;
+                }
+                // static on the first declaration makes the function static
+                signature.is_static |= previous.is_static;
+                signature.defined |= previous.defined;
+            }
+            Some(_) => {
+                self.error(format!(
+                    "Error: {} redeclared as a different kind of symbol",
+                    header.name
+                ));
+                return None;
+            }
+            None => {}
+        }
+
+        file_scope
+            .identifiers
+            .insert(header.name.clone(), Symbol::Function(signature));
+        Some(())
+    }
+
+    fn parse_function(&mut self, header: FunctionHeader) -> Option<FunctionNode> {
+        // Holder statements
+        let mut statement_list: Vec<StatementNode> = vec![];
+
         if let Err(error) = self.expect(TokenType::LBrace) {
-            println!("Error {}", error);
+            self.error(format!("Error {}", error));
             return None;
         } // {
 
         // Consume LBrace
         self.consume();
 
-        while let Some(statement) = self.parse_statement() {
-            statement_list.push(statement);
+        self.declare_function(&header, true)?;
+        let is_static = match self.scopes[0].identifiers.get(&header.name) {
+            Some(Symbol::Function(signature)) => signature.is_static,
+            _ => header.is_static,
+        };
 
-            if let Some(token_type) = self
-                .token_stream
-                .get(self.token_index)
-                .map(|t| t.token_type.clone())
-            {
-                if token_type == TokenType::RBrace {
-                    break;
-                }
+        // Every function body opens a scope and starts a fresh set of locals, the parameters first
+        self.scopes.push(Scope::default());
+        self.locals.clear();
+        self.function_name = header.name.clone();
+        self.return_type = header.return_type.clone();
+
+        let mut params = vec![];
+        for (name, ty, is_const) in header.params {
+            let Some(name) = name else {
+                self.error(format!(
+                    "Error: Parameter of {} is missing a name",
+                    header.name
+                ));
+                return None;
+            };
+            params.push(self.declare_variable(name, ty, is_const, false)?);
+        }
+
+        while !self.next_is(TokenType::RBrace) {
+            match self.parse_statement() {
+                Some(statement) => statement_list.push(statement),
+                None => return None,
             }
         }
 
         if let Err(error) = self.expect(TokenType::RBrace) {
-            println!("Error {}", error);
+            self.error(format!("Error {}", error));
             return None;
         } // }
 
         self.consume(); // Consume }
+        self.scopes.pop();
 
         Some(FunctionNode {
-            return_value: return_type,
-            name: function_name,
+            return_type: header.return_type,
+            name: header.name,
+            params,
             body: statement_list,
+            locals: std::mem::take(&mut self.locals),
+            is_static,
         })
     }
 
+    // [static] type *name ( starts a function, anything else at file scope is a declaration. The
+    // type is a keyword or a struct, union or enum tag
+    fn is_function_definition(&mut self) -> bool {
+        let is = |parser: &mut Self, index: usize, token_type: TokenType| {
+            parser
+                .peek(index)
+                .is_some_and(|token| token.token_type == token_type)
+        };
+
+        let mut index = usize::from(is(self, 0, TokenType::StaticKeyword));
+        index += match self.peek(index).map(|token| token.token_type.clone()) {
+            Some(TokenType::IntKeyword | TokenType::CharKeyword | TokenType::VoidKeyword) => 1,
+            Some(TokenType::StructKeyword | TokenType::UnionKeyword | TokenType::EnumKeyword) => 2,
+            _ => return false,
+        };
+        while is(self, index, TokenType::Mul) {
+            index += 1;
+        }
+
+        is(self, index, TokenType::Identifier) && is(self, index + 1, TokenType::LParen)
+    }
+
     pub fn parse_program(&mut self) -> Option<ProgramNode> {
         if self.token_index > self.token_stream.len() {
             return None;
         }
 
+        // What the lexer did not recognize is kept as Error tokens
+        for token in &self.token_stream {
+            if token.token_type == TokenType::Error {
+                let lexeme = token.value.clone().unwrap_or_default();
+                self.diagnostics.push(format!("Unrecognized {}", lexeme));
+            }
+        }
+
         let mut function_list: Vec<FunctionNode> = vec![];
         let mut function_identifiers: Vec<String> = vec![];
 
-        while let Some(function) = self.parse_function() {
-            function_identifiers.push(function.name.clone());
-            function_list.push(function);
+        while self.token_index < self.token_stream.len() {
+            // Types and global variables can be declared at file scope, between the functions
+            if !self.is_function_definition() {
+                self.parse_declaration()?;
+                continue;
+            }
+
+            let Some(header) = self.parse_function_header() else {
+                break;
+            };
+
+            // A prototype only declares the function, e.g for calls ahead of its definition
+            if self.next_is(TokenType::Semi) {
+                self.consume(); // Consume ';'
+                if self.declare_function(&header, false).is_none() {
+                    break;
+                }
+                continue;
+            }
+
+            match self.parse_function(header) {
+                Some(function) => {
+                    function_identifiers.push(function.name.clone());
+                    function_list.push(function);
+                }
+                None => break,
+            }
         }
 
         Some(ProgramNode {
             body: function_list,
             function_names: function_identifiers,
+            globals: std::mem::take(&mut self.globals),
         })
     }
 }
@@ -0,0 +1,172 @@
+use crate::asm::{Instruction, Line};
+
+// Cleans up the generated assembly by looking at one or two neighbouring instructions at a
+// time. Instructions separated by a label are never combined, since control can arrive at
+// the label from elsewhere. The rules are applied until none of them matches anymore
+
+// Instructions that only write their first operand from the others, without touching memory
+// or the flags, so they can be dropped when their result is overwritten right away
+const PURE: [&str; 16] = [
+    "mov", "movz", "add", "sub", "mul", "sdiv", "neg", "mvn", "cset", "sxtw", "sxtb", "adrp",
+    "adr", "ldr", "ldrsb", "ldrsw",
+];
+
+pub fn optimize(mut lines: Vec<Line>) -> Vec<Line> {
+    loop {
+        let (optimized, changed) = optimize_pass(&lines);
+        lines = optimized;
+        if !changed {
+            return lines;
+        }
+    }
+}
+
+fn optimize_pass(lines: &[Line]) -> (Vec<Line>, bool) {
+    let mut optimized = Vec::with_capacity(lines.len());
+    let mut changed = false;
+
+    let mut index = 0;
+    while index < lines.len() {
+        if let Line::Instruction(first) = &lines[index] {
+            if is_redundant(first) || branches_to_next(first, &lines[index + 1..]) {
+                changed = true;
+                index += 1;
+                continue;
+            }
+
+            if let Some(Line::Instruction(second)) = lines.get(index + 1) {
+                if let Some(replacement) = combine(first, second) {
+                    optimized.extend(replacement.into_iter().map(Line::Instruction));
+                    changed = true;
+                    index += 2;
+                    continue;
+                }
+            }
+        }
+
+        optimized.push(lines[index].clone());
+        index += 1;
+    }
+
+    (optimized, changed)
+}
+
+// A move of a 64 bit register to itself. mov w9, w9 is not one, it clears the upper half
+fn is_redundant(instruction: &Instruction) -> bool {
+    instruction.opcode == "mov"
+        && instruction.operands[0] == instruction.operands[1]
+        && !instruction.operands[0].starts_with('w')
+}
+
+// b to a label that directly follows, possibly among other labels
+fn branches_to_next(instruction: &Instruction, rest: &[Line]) -> bool {
+    if instruction.opcode != "b" {
+        return false;
+    }
+
+    for line in rest {
+        match line {
+            Line::Label(label) if *label == instruction.operands[0] => return true,
+            Line::Label(_) => continue,
+            _ => return false,
+        }
+    }
+    false
+}
+
+// What a pair of neighbouring instructions can be replaced by, if anything
+fn combine(first: &Instruction, second: &Instruction) -> Option<Vec<Instruction>> {
+    // sub sp, sp, #n and add sp, sp, #n cancel out
+    let adjusts_stack = |instruction: &Instruction| {
+        (instruction.opcode == "sub" || instruction.opcode == "add")
+            && instruction.operands.len() == 3
+            && instruction.operands[0] == "sp"
+            && instruction.operands[1] == "sp"
+    };
+    if adjusts_stack(first)
+        && adjusts_stack(second)
+        && first.opcode != second.opcode
+        && first.operands[2] == second.operands[2]
+    {
+        return Some(vec![]);
+    }
+
+    // Reloading what was just stored, the value is still in the register
+    if first.opcode == "str"
+        && second.opcode == "ldr"
+        && first.operands.len() == 2
+        && second.operands.len() == 2
+        && first.operands[1] == second.operands[1]
+        && first.operands[0][..1] == second.operands[0][..1]
+    {
+        if first.operands[0] == second.operands[0] {
+            return Some(vec![first.clone()]);
+        }
+        let copy = Instruction::new("mov", &[&second.operands[0], &first.operands[0]]);
+        return Some(vec![first.clone(), copy]);
+    }
+
+    // A result overwritten by the next instruction without being read
+    if let (Some(first_written), Some(second_written)) = (writes(first), writes(second)) {
+        if first_written == second_written
+            && first_written != "sp"
+            && !reads(second).contains(&first_written)
+        {
+            return Some(vec![second.clone()]);
+        }
+    }
+
+    None
+}
+
+// The register a pure instruction writes. Loads with writeback are not pure
+fn writes(instruction: &Instruction) -> Option<String> {
+    if !is_pure(instruction) {
+        return None;
+    }
+    register(&instruction.operands[0])
+}
+
+// Every register the instruction may read. For anything but a pure instruction this is all
+// registers among its operands, to be safe
+fn reads(instruction: &Instruction) -> Vec<String> {
+    let operands = if is_pure(instruction) {
+        &instruction.operands[1..]
+    } else {
+        &instruction.operands[..]
+    };
+
+    let mut registers = vec![];
+    for operand in operands {
+        for part in operand.trim_matches(['[', ']', '!']).split(',') {
+            if let Some(register) = register(part.trim()) {
+                registers.push(register);
+            }
+        }
+    }
+    registers
+}
+
+fn is_pure(instruction: &Instruction) -> bool {
+    PURE.contains(&instruction.opcode.as_str())
+        && !instruction.operands.is_empty()
+        && !(instruction.opcode.starts_with("ldr")
+            && (instruction.operands.len() != 2 || instruction.operands[1].ends_with('!')))
+}
+
+// The register an operand names, with w9 and x9 both being register 9. The zero register
+// holds no value, so it is not one
+fn register(operand: &str) -> Option<String> {
+    if operand == "sp" {
+        return Some(operand.to_string());
+    }
+
+    let number = operand
+        .strip_prefix('x')
+        .or_else(|| operand.strip_prefix('w'))?;
+    if !number.is_empty() && number.chars().all(|c| c.is_ascii_digit()) {
+        Some(number.to_string())
+    } else {
+        None
+    }
+}
@@ -1,9 +1,21 @@
-use crate::parser::{Expr, ExprNode, FunctionNode, ProgramNode, Statement, StatementNode};
+use crate::asm::{Instruction, Line};
+use crate::ir::{
+    BinaryOp, BlockId, Conversion, Instr, IrFunction, IrGlobal, IrProgram, IrType, Terminator,
+    UnaryOp, VReg,
+};
+use crate::parser::{
+    Expr, ExprNode, FunctionNode, GlobalNode, Initializer, ProgramNode, Statement, StatementNode,
+};
 use crate::token::TokenType;
+use crate::types::Type;
+use crate::Diagnostics;
 use std::fmt::{self};
 
 impl fmt::Display for ProgramNode {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for global_node in &self.globals {
+            write!(f, "{}", global_node)?;
+        }
         for function_node in &self.body {
             write!(f, "{}", function_node)?;
         }
@@ -11,6 +23,16 @@ impl fmt::Display for ProgramNode {
     }
 }
 
+// One error per line, as the compiler prints them
+impl fmt::Display for Diagnostics {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for error in &self.errors {
+            writeln!(f, "{}", error)?;
+        }
+        Ok(())
+    }
+}
+
 impl fmt::Display for TokenType {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         match self {
@@ -28,6 +50,7 @@ impl fmt::Display for TokenType {
             TokenType::Comma => write!(f, ","),
             TokenType::Dot => write!(f, "."),
             TokenType::Colon => write!(f, ":"),
+            TokenType::QuestionMark => write!(f, "?"),
             TokenType::Plus => write!(f, "+"),
             TokenType::Minus => write!(f, "-"),
             TokenType::Div => write!(f, "/"),
@@ -41,6 +64,7 @@ impl fmt::Display for TokenType {
             TokenType::Xor => write!(f, "^"),
             TokenType::Mod => write!(f, "%"),
             TokenType::BitComplement => write!(f, "~"),
+            TokenType::Arrow => write!(f, "->"),
             TokenType::Eq => write!(f, "=="),
             TokenType::Lt => write!(f, "<"),
             TokenType::Gt => write!(f, ">"),
@@ -62,6 +86,11 @@ impl fmt::Display for TokenType {
             TokenType::BreakKeyword => write!(f, "break"),
             TokenType::StaticKeyword => write!(f, "static"),
             TokenType::VoidKeyword => write!(f, "void"),
+            TokenType::SizeofKeyword => write!(f, "sizeof"),
+            TokenType::ConstKeyword => write!(f, "const"),
+            TokenType::SwitchKeyword => write!(f, "switch"),
+            TokenType::CaseKeyword => write!(f, "case"),
+            TokenType::DefaultKeyword => write!(f, "default"),
             TokenType::Error => write!(f, "error"),
             TokenType::Char => write!(f, "char"),
         }
@@ -70,7 +99,18 @@ impl fmt::Display for TokenType {
 
 impl fmt::Display for FunctionNode {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        writeln!(f, "fn {}() -> {} {{\n", self.name, self.return_value)?;
+        let params: Vec<String> = self
+            .params
+            .iter()
+            .map(|param| format!("{} {}", param.ty, param.name))
+            .collect();
+        writeln!(
+            f,
+            "fn {}({}) -> {} {{\n",
+            self.name,
+            params.join(", "),
+            self.return_type
+        )?;
         for statement_node in &self.body {
             write!(f, "{}", statement_node)?;
         }
@@ -78,12 +118,69 @@ impl fmt::Display for FunctionNode {
     }
 }
 
+impl fmt::Display for GlobalNode {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if self.is_static {
+            write!(f, "static ")?;
+        }
+        if self.variable.is_const {
+            write!(f, "const ")?;
+        }
+        write!(f, "{} {}", self.variable.ty, self.variable.name)?;
+        if let Some(initializer) = &self.initializer {
+            write!(f, " = {}", initializer)?;
+        }
+        write!(f, ";\n\n")
+    }
+}
+
 impl fmt::Display for Statement {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         match self {
-            Statement::Return(expr_node) => write!(f, "return {};", expr_node),
-            Statement::Assignment(data_type, name, assign, expr_node) => {
-                write!(f, "{:?} {:?} {:?} {:?}", data_type, name, assign, expr_node)
+            Statement::Return(Some(expr_node)) => write!(f, "return {};", expr_node),
+            Statement::Return(None) => write!(f, "return;"),
+            Statement::Declaration(variable, Some(initializer)) => {
+                write!(f, "{} {} = {};", variable.ty, variable.name, initializer)
+            }
+            Statement::Declaration(variable, None) => {
+                write!(f, "{} {};", variable.ty, variable.name)
+            }
+            Statement::Expression(expr_node) => write!(f, "{};", expr_node),
+            Statement::Empty => write!(f, ";"),
+            Statement::Block(statements) => {
+                write!(f, "{{")?;
+                for statement_node in statements {
+                    write!(f, " {}", statement_node.statement)?;
+                }
+                write!(f, " }}")
+            }
+            Statement::Switch(condition, body, _) => {
+                write!(f, "switch ({}) {}", condition, body.statement)
+            }
+            Statement::Case(value, body) => write!(f, "case {}: {}", value, body.statement),
+            Statement::Default(body) => write!(f, "default: {}", body.statement),
+            Statement::Break => write!(f, "break;"),
+            Statement::Continue => write!(f, "continue;"),
+            Statement::If(condition, then, otherwise) => {
+                write!(f, "if ({}) {}", condition, then.statement)?;
+                match otherwise {
+                    Some(otherwise) => write!(f, " else {}", otherwise.statement),
+                    None => Ok(()),
+                }
+            }
+            Statement::While(condition, body) => {
+                write!(f, "while ({}) {}", condition, body.statement)
+            }
+            Statement::For(init, condition, step, body) => {
+                write!(f, "for ({}", init.statement)?;
+                if let Some(condition) = condition {
+                    write!(f, " {}", condition)?;
+                }
+                write!(f, ";")?;
+                if let Some(step) = step {
+                    write!(f, " {}", step)?;
+                }
+                write!(f, ") {}", body.statement)
             }
         }
     }
@@ -105,6 +202,7 @@ impl fmt::Display for Expr {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         match self {
             Expr::Number(num) => write!(f, "{}", num),
+            Expr::Variable(variable) => write!(f, "{}", variable.name),
             Expr::UnaryOp(operator, expr) => {
                 if let Some(expr) = expr {
                     write!(f, "({:?} {})", operator, expr)
@@ -115,6 +213,287 @@ impl fmt::Display for Expr {
             Expr::BinaryOp(operator, left, right) => {
                 write!(f, "({} {:?} {})", left, operator, right)
             }
+            Expr::Assign(target, value) => write!(f, "({} = {})", target, value),
+            Expr::Member(base, member) => write!(f, "{}.{}", base, member.name),
+            Expr::Conditional(condition, then, otherwise) => {
+                write!(f, "({} ? {} : {})", condition, then, otherwise)
+            }
+            Expr::Comma(left, right) => write!(f, "({}, {})", left, right),
+            Expr::Cast(ty, operand) => write!(f, "(({}) {})", ty, operand),
+            Expr::Call(name, args) => {
+                let args: Vec<String> = args.iter().map(|arg| arg.to_string()).collect();
+                write!(f, "{}({})", name, args.join(", "))
+            }
+        }
+    }
+}
+
+impl fmt::Display for Initializer {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Initializer::Expr(expr_node) => write!(f, "{}", expr_node),
+            Initializer::List(items) => {
+                write!(f, "{{")?;
+                for (index, item) in items.iter().enumerate() {
+                    if index > 0 {
+                        write!(f, ", ")?;
+                    }
+                    write!(f, "{}", item)?;
+                }
+                write!(f, "}}")
+            }
+        }
+    }
+}
+
+impl fmt::Display for Type {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Type::Int => write!(f, "int"),
+            Type::Char => write!(f, "char"),
+            Type::Pointer(base) => write!(f, "{}*", base),
+            Type::Array(element, length) => {
+                // Print dimensions outermost first, int[2][3] rather than int[3][2]
+                let mut innermost = element.as_ref();
+                let mut dimensions = format!("[{}]", length);
+                while let Type::Array(inner, inner_length) = innermost {
+                    dimensions += &format!("[{}]", inner_length);
+                    innermost = inner;
+                }
+                write!(f, "{}{}", innermost, dimensions)
+            }
+            Type::Struct(struct_ref) => write!(f, "{:?}", struct_ref),
+            Type::Void => write!(f, "void"),
+        }
+    }
+}
+
+impl fmt::Display for IrProgram {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for global in &self.globals {
+            writeln!(f, "{}", global)?;
+        }
+        for function in &self.functions {
+            write!(f, "\n{}", function)?;
+        }
+        Ok(())
+    }
+}
+
+impl fmt::Display for IrGlobal {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let linkage = if self.is_static { "static" } else { "global" };
+        let kind = if self.is_const { "const" } else { "var" };
+        write!(
+            f,
+            "{} {} @{}: {} bytes, align {}",
+            linkage, kind, self.symbol, self.size, self.align
+        )?;
+
+        if self.image.is_zero() {
+            return write!(f, " = zero");
+        }
+        let bytes: Vec<String> = self
+            .image
+            .bytes
+            .iter()
+            .map(|byte| format!("{:02x}", byte))
+            .collect();
+        write!(f, " = [{}]", bytes.join(" "))?;
+        for relocation in &self.image.relocations {
+            write!(
+                f,
+                ", @{}{:+} at {}",
+                relocation.symbol, relocation.addend, relocation.offset
+            )?;
+        }
+        Ok(())
+    }
+}
+
+impl fmt::Display for IrFunction {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let linkage = if self.is_static { "static" } else { "global" };
+        writeln!(f, "{} function @{} {{", linkage, self.name)?;
+        for (index, slot) in self.slots.iter().enumerate() {
+            writeln!(
+                f,
+                "  slot s{} {}: {} bytes, align {}",
+                index, slot.name, slot.size, slot.align
+            )?;
+        }
+
+        for (index, block) in self.blocks.iter().enumerate() {
+            writeln!(f, "bb{}:", index)?;
+            for instr in &block.instrs {
+                writeln!(f, "  {}", IrInstrDisplay(instr, self))?;
+            }
+            writeln!(f, "  {}", block.terminator)?;
+        }
+        writeln!(f, "}}")
+    }
+}
+
+// Instructions print the type of the register they define, which lives in the function
+struct IrInstrDisplay<'a>(&'a Instr, &'a IrFunction);
+
+impl fmt::Display for IrInstrDisplay<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let IrInstrDisplay(instr, function) = self;
+        let ty = |vreg: &VReg| function.vregs[vreg.0];
+
+        match instr {
+            Instr::Const { dst, value } => write!(f, "{} = const {} {}", dst, ty(dst), value),
+            Instr::Copy { dst, src } => write!(f, "{} = copy {} {}", dst, ty(dst), src),
+            Instr::Unary { op, dst, src } => write!(f, "{} = {} {} {}", dst, op, ty(dst), src),
+            Instr::Binary { op, dst, lhs, rhs } => {
+                // Comparisons are typed by their operands, the result is always i32
+                write!(f, "{} = {} {} {}, {}", dst, op, ty(lhs), lhs, rhs)
+            }
+            Instr::Convert {
+                conversion,
+                dst,
+                src,
+            } => write!(f, "{} = {} {} to {}", dst, conversion, src, ty(dst)),
+            Instr::FrameAddr { dst, slot } => write!(f, "{} = frame s{}", dst, slot),
+            Instr::GlobalAddr { dst, symbol } => write!(f, "{} = global @{}", dst, symbol),
+            Instr::Load { dst, ty, addr } => write!(f, "{} = load {} [{}]", dst, ty, addr),
+            Instr::Store { ty, addr, value } => write!(f, "store {} {}, [{}]", ty, value, addr),
+            Instr::MemCopy { dst, src, size } => {
+                write!(f, "memcopy [{}], [{}], {}", dst, src, size)
+            }
+            Instr::MemZero { dst, size } => write!(f, "memzero [{}], {}", dst, size),
+            Instr::Param { dst, index } => write!(f, "{} = param {} {}", dst, ty(dst), index),
+            Instr::Call {
+                dst,
+                function,
+                args,
+            } => write!(f, "{} = call @{}({})", dst, function, vreg_list(args)),
+            Instr::Phi { dst, incoming } => {
+                let incoming: Vec<String> = incoming
+                    .iter()
+                    .map(|(block, value)| format!("[{}: {}]", block, value))
+                    .collect();
+                write!(f, "{} = phi {} {}", dst, ty(dst), incoming.join(", "))
+            }
+        }
+    }
+}
+
+impl fmt::Display for Terminator {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Terminator::Jump(block) => write!(f, "jump {}", block),
+            Terminator::Branch {
+                condition,
+                then,
+                otherwise,
+            } => write!(f, "branch {}, {}, {}", condition, then, otherwise),
+            Terminator::Switch {
+                value,
+                cases,
+                default,
+            } => {
+                let cases: Vec<String> = cases
+                    .iter()
+                    .map(|(value, block)| format!("{}: {}", value, block))
+                    .collect();
+                write!(
+                    f,
+                    "switch {} [{}], default {}",
+                    value,
+                    cases.join(", "),
+                    default
+                )
+            }
+            Terminator::Return(value) => write!(f, "ret {}", value),
+            Terminator::TailCall { function, args } => {
+                write!(f, "tailcall @{}({})", function, vreg_list(args))
+            }
+        }
+    }
+}
+
+fn vreg_list(vregs: &[VReg]) -> String {
+    let vregs: Vec<String> = vregs.iter().map(|vreg| vreg.to_string()).collect();
+    vregs.join(", ")
+}
+
+impl fmt::Display for VReg {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "%{}", self.0)
+    }
+}
+
+impl fmt::Display for BlockId {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "bb{}", self.0)
+    }
+}
+
+impl fmt::Display for IrType {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            IrType::I8 => write!(f, "i8"),
+            IrType::I32 => write!(f, "i32"),
+            IrType::Ptr => write!(f, "ptr"),
+        }
+    }
+}
+
+impl fmt::Display for UnaryOp {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            UnaryOp::Neg => write!(f, "neg"),
+            UnaryOp::Not => write!(f, "not"),
+        }
+    }
+}
+
+impl fmt::Display for BinaryOp {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let name = match self {
+            BinaryOp::Add => "add",
+            BinaryOp::Sub => "sub",
+            BinaryOp::Mul => "mul",
+            BinaryOp::Div => "div",
+            BinaryOp::Eq => "eq",
+            BinaryOp::Ne => "ne",
+            BinaryOp::Lt => "lt",
+            BinaryOp::Le => "le",
+            BinaryOp::Gt => "gt",
+            BinaryOp::Ge => "ge",
+        };
+        write!(f, "{}", name)
+    }
+}
+
+impl fmt::Display for Conversion {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Conversion::Sext => write!(f, "sext"),
+            Conversion::Trunc => write!(f, "trunc"),
+            Conversion::SextI8 => write!(f, "sext.i8"),
+        }
+    }
+}
+
+impl fmt::Display for Line {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Line::Label(label) => write!(f, "{}:", label),
+            Line::Instruction(instruction) => write!(f, "\t{}", instruction),
+            Line::Directive(directive) => write!(f, "\t{}", directive),
+        }
+    }
+}
+
+impl fmt::Display for Instruction {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if self.operands.is_empty() {
+            write!(f, "{}", self.opcode)
+        } else {
+            write!(f, "{} {}", self.opcode, self.operands.join(", "))
         }
     }
 }
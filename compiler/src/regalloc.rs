@@ -0,0 +1,208 @@
+use std::collections::HashSet;
+
+use crate::ir::{Instr, IrFunction, VReg};
+
+// Where a virtual register lives for its whole lifetime, an index into the registers the
+// backend offered or into the spill slots of the frame
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum Location {
+    Register(usize),
+    Spill(usize),
+}
+
+pub struct Allocation {
+    pub locations: Vec<Option<Location>>, // Indexed by VReg, None when the register is never used
+    pub spill_slots: usize,
+    pub used_registers: Vec<usize>, // Registers handed out, for saving the callee-saved ones
+}
+
+// The positions from the first to the last instruction a register is live at
+struct Interval {
+    vreg: VReg,
+    start: usize,
+    end: usize,
+    crosses_call: bool, // Live while a call runs, which may clobber the caller-saved registers
+}
+
+// Linear scan allocation over the blocks in order. Registers are handed out lowest index
+// first, so the backend lists its preferred registers first. When they run out the interval
+// ending last is spilled, which frees a register for the longest time. The registers below
+// first_callee_saved do not survive a call and are never given to an interval crossing one
+pub fn allocate(
+    function: &IrFunction,
+    register_count: usize,
+    first_callee_saved: usize,
+) -> Allocation {
+    let mut intervals = live_intervals(function);
+    intervals.sort_by_key(|interval| (interval.start, interval.vreg));
+
+    let mut allocation = Allocation {
+        locations: vec![None; function.vregs.len()],
+        spill_slots: 0,
+        used_registers: vec![],
+    };
+    let mut free: Vec<bool> = vec![true; register_count];
+    let mut active: Vec<(Interval, usize)> = vec![]; // Sorted by end
+
+    for interval in intervals {
+        // Registers whose interval ended are free again. An interval ending where this one
+        // starts is only read by the instruction that defines this one, so they can share
+        active.retain(|(other, register)| {
+            if other.end <= interval.start {
+                free[*register] = true;
+                return false;
+            }
+            true
+        });
+
+        let first_allowed = if interval.crosses_call {
+            first_callee_saved
+        } else {
+            0
+        };
+        let register = match (first_allowed..register_count).find(|register| free[*register]) {
+            Some(register) => register,
+            None => {
+                let spill_slot = allocation.spill_slots;
+                allocation.spill_slots += 1;
+
+                // The interval ending last among those holding a register this one may use
+                let victim = active
+                    .iter()
+                    .rposition(|(_, register)| *register >= first_allowed);
+                match victim {
+                    Some(index) if active[index].0.end > interval.end => {
+                        let (last, register) = active.remove(index);
+                        allocation.locations[last.vreg.0] = Some(Location::Spill(spill_slot));
+                        register
+                    }
+                    _ => {
+                        allocation.locations[interval.vreg.0] = Some(Location::Spill(spill_slot));
+                        continue;
+                    }
+                }
+            }
+        };
+
+        free[register] = false;
+        if !allocation.used_registers.contains(&register) {
+            allocation.used_registers.push(register);
+        }
+        allocation.locations[interval.vreg.0] = Some(Location::Register(register));
+
+        let position = active
+            .iter()
+            .position(|(other, _)| other.end > interval.end)
+            .unwrap_or(active.len());
+        active.insert(position, (interval, register));
+    }
+
+    allocation
+}
+
+// Numbers the instructions of all blocks in order, the entry to and the terminator of a block
+// counting as one each, and covers every position a register is live at with a single interval
+fn live_intervals(function: &IrFunction) -> Vec<Interval> {
+    let (live_in, live_out) = liveness(function);
+    let mut ranges: Vec<Option<(usize, usize)>> = vec![None; function.vregs.len()];
+    let mut extend = |vreg: VReg, position: usize| {
+        let range = ranges[vreg.0].get_or_insert((position, position));
+        range.0 = range.0.min(position);
+        range.1 = range.1.max(position);
+    };
+
+    let mut calls = vec![];
+    let mut position = 0;
+    for (index, block) in function.blocks.iter().enumerate() {
+        // Registers live on entry are live before the first instruction, e.g before a call
+        let block_start = position;
+        for vreg in &live_in[index] {
+            extend(*vreg, block_start);
+        }
+        position += 1;
+
+        for instr in &block.instrs {
+            for vreg in instr.uses() {
+                extend(vreg, position);
+            }
+            if let Some(dst) = instr.dst() {
+                extend(dst, position);
+            }
+            if let Instr::Call { .. } = instr {
+                calls.push(position);
+            }
+            position += 1;
+        }
+        for vreg in block.terminator.uses() {
+            extend(vreg, position);
+        }
+
+        for vreg in &live_out[index] {
+            extend(*vreg, position);
+        }
+        position += 1;
+    }
+
+    ranges
+        .into_iter()
+        .enumerate()
+        .filter_map(|(index, range)| {
+            range.map(|(start, end)| Interval {
+                vreg: VReg(index),
+                start,
+                end,
+                crosses_call: calls.iter().any(|call| start < *call && *call < end),
+            })
+        })
+        .collect()
+}
+
+// The registers live on entry to and exit from every block, iterated until nothing changes
+pub fn liveness(function: &IrFunction) -> (Vec<HashSet<VReg>>, Vec<HashSet<VReg>>) {
+    let count = function.blocks.len();
+
+    // Registers read before being written in the block, and registers written in it
+    let mut used_first: Vec<HashSet<VReg>> = vec![HashSet::new(); count];
+    let mut defined: Vec<HashSet<VReg>> = vec![HashSet::new(); count];
+    for (index, block) in function.blocks.iter().enumerate() {
+        for instr in &block.instrs {
+            for vreg in instr.uses() {
+                if !defined[index].contains(&vreg) {
+                    used_first[index].insert(vreg);
+                }
+            }
+            if let Some(dst) = instr.dst() {
+                defined[index].insert(dst);
+            }
+        }
+        for vreg in block.terminator.uses() {
+            if !defined[index].contains(&vreg) {
+                used_first[index].insert(vreg);
+            }
+        }
+    }
+
+    let mut live_in: Vec<HashSet<VReg>> = vec![HashSet::new(); count];
+    let mut live_out: Vec<HashSet<VReg>> = vec![HashSet::new(); count];
+    let mut changed = true;
+    while changed {
+        changed = false;
+        for index in (0..count).rev() {
+            let mut out = HashSet::new();
+            for successor in function.blocks[index].terminator.successors() {
+                out.extend(live_in[successor.0].iter().copied());
+            }
+
+            let mut incoming = used_first[index].clone();
+            incoming.extend(out.difference(&defined[index]).copied());
+
+            if incoming != live_in[index] || out != live_out[index] {
+                live_in[index] = incoming;
+                live_out[index] = out;
+                changed = true;
+            }
+        }
+    }
+
+    (live_in, live_out)
+}
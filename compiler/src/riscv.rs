@@ -0,0 +1,439 @@
+use crate::{
+    asm::{directive, emit, label, Line},
+    backend::{elf_symbol, global_asm, jump_table_range, Backend, CodegenContext, Registers},
+    ir::{
+        BinaryOp, BlockId, Conversion, Instr, IrFunction, IrProgram, IrType, Terminator, UnaryOp,
+        VReg,
+    },
+};
+
+// The RV64GC backend for Linux, following the LP64 calling convention: arguments in a0-a7,
+// the result in a0, the return address in ra and s0 as the frame pointer. Ints are kept
+// sign extended to 64 bits in their registers, which the 32 bit instructions ending in w do
+// for their results, so the comparisons and branches can look at the whole register
+
+pub struct Riscv64;
+
+impl Backend for Riscv64 {
+    fn generate(&self, program: &IrProgram) -> Vec<Line> {
+        program_asm(&mut CodegenContext::new(program, &REGISTERS), program)
+    }
+}
+
+// a0-a7 and t4-t6 are left as scratch registers for the code generator, spilled values go
+// through t5 and t6
+const REGISTERS: Registers = Registers {
+    names: &[
+        "t0", "t1", "t2", "t3", "s1", "s2", "s3", "s4", "s5", "s6", "s7", "s8", "s9", "s10", "s11",
+    ],
+    first_callee_saved: 4,
+    scratch: ["t5", "t6"],
+    load: load_spill,
+    store: store_spill,
+};
+
+// Holds addresses and sizes too far out for the 12 bit immediates
+const FAR: &str = "t4";
+
+fn load_spill(code: &mut Vec<Line>, register: &str, offset: usize) {
+    frame_access(code, "ld", register, offset);
+}
+
+fn store_spill(code: &mut Vec<Line>, register: &str, offset: usize) {
+    frame_access(code, "sd", register, offset);
+}
+
+// The immediates of addi, loads and stores are 12 bits, signed
+fn fits_immediate(value: i64) -> bool {
+    (-2048..=2047).contains(&value)
+}
+
+// li expands to as many instructions as the value needs
+fn load_immediate(code: &mut Vec<Line>, register: &str, value: i64) {
+    emit(code, "li", &[register, &value.to_string()]);
+}
+
+// Puts the address s0 - offset in register
+fn frame_address(code: &mut Vec<Line>, register: &str, offset: usize) {
+    if fits_immediate(-(offset as i64)) {
+        emit(code, "addi", &[register, "s0", &format!("-{}", offset)]);
+    } else {
+        load_immediate(code, FAR, offset as i64);
+        emit(code, "sub", &[register, "s0", FAR]);
+    }
+}
+
+// Loads or stores register at s0 - offset, going through t4 when the offset is out of reach
+fn frame_access(code: &mut Vec<Line>, instruction: &str, register: &str, offset: usize) {
+    if fits_immediate(-(offset as i64)) {
+        emit(code, instruction, &[register, &format!("-{}(s0)", offset)]);
+    } else {
+        frame_address(code, FAR, offset);
+        emit(code, instruction, &[register, &format!("0({})", FAR)]);
+    }
+}
+
+// Grows or shrinks the stack by size bytes, t4 holds sizes too wide for an immediate
+fn adjust_stack(code: &mut Vec<Line>, instruction: &str, size: usize) {
+    let signed = if instruction == "sub" {
+        -(size as i64)
+    } else {
+        size as i64
+    };
+    if fits_immediate(signed) {
+        emit(code, "addi", &["sp", "sp", &signed.to_string()]);
+    } else {
+        load_immediate(code, FAR, size as i64);
+        emit(code, instruction, &["sp", "sp", FAR]);
+    }
+}
+
+// The 32 bit form of an instruction for ints, e.g addw, the 64 bit one for addresses
+fn sized(instruction: &str, ty: IrType) -> String {
+    match ty {
+        IrType::Ptr => instruction.to_string(),
+        _ => format!("{}w", instruction),
+    }
+}
+
+// Copies size bytes from the address in a2 to the address in a3, eight at a time counted
+// down in a4 and then the remainder
+fn copy_asm(ctx: &mut CodegenContext, code: &mut Vec<Line>, size: usize) {
+    let words = size / 8;
+    if words > 0 {
+        let loop_label = ctx.reserve_labels(1);
+        load_immediate(code, "a4", words as i64);
+        label(code, loop_label);
+        emit(code, "ld", &["a5", "0(a2)"]);
+        emit(code, "sd", &["a5", "0(a3)"]);
+        emit(code, "addi", &["a2", "a2", "8"]);
+        emit(code, "addi", &["a3", "a3", "8"]);
+        emit(code, "addi", &["a4", "a4", "-1"]);
+        emit(code, "bnez", &["a4", &format!(".L{}", loop_label)]);
+    }
+    for offset in 0..size % 8 {
+        emit(code, "lb", &["a5", &format!("{}(a2)", offset)]);
+        emit(code, "sb", &["a5", &format!("{}(a3)", offset)]);
+    }
+}
+
+// Clears size bytes starting at the address in a1, eight at a time and then the remainder
+fn zero_fill_asm(ctx: &mut CodegenContext, code: &mut Vec<Line>, size: usize) {
+    let words = size / 8;
+    if words > 0 {
+        let loop_label = ctx.reserve_labels(1);
+        load_immediate(code, "a2", words as i64);
+        label(code, loop_label);
+        emit(code, "sd", &["zero", "0(a1)"]);
+        emit(code, "addi", &["a1", "a1", "8"]);
+        emit(code, "addi", &["a2", "a2", "-1"]);
+        emit(code, "bnez", &["a2", &format!(".L{}", loop_label)]);
+    }
+    for offset in 0..size % 8 {
+        emit(code, "sb", &["zero", &format!("{}(a1)", offset)]);
+    }
+}
+
+fn program_asm(ctx: &mut CodegenContext, program: &IrProgram) -> Vec<Line> {
+    let mut code = vec![];
+    directive(&mut code, ".text");
+
+    // Static functions stay local to the file
+    for function in &program.functions {
+        if !function.is_static {
+            directive(&mut code, &format!(".global {}", function.name));
+        }
+    }
+
+    for function in &program.functions {
+        code.push(Line::Label(function.name.clone()));
+        function_asm(ctx, function, &mut code);
+    }
+
+    ctx.emit_rodata(&mut code);
+
+    for global in &program.globals {
+        global_asm(&mut code, global, ".dword");
+    }
+
+    // The stack is not executable, the linker warns unless told so
+    directive(&mut code, ".section .note.GNU-stack,\"\",@progbits");
+    code
+}
+
+fn function_asm(ctx: &mut CodegenContext, function: &IrFunction, code: &mut Vec<Line>) {
+    ctx.enter_function(function);
+
+    // Prologue, s0 points at the saved ra and s0, just above the saved registers and locals
+    emit(code, "addi", &["sp", "sp", "-16"]);
+    emit(code, "sd", &["ra", "8(sp)"]);
+    emit(code, "sd", &["s0", "0(sp)"]);
+    emit(code, "mv", &["s0", "sp"]);
+    if ctx.frame.size > 0 {
+        adjust_stack(code, "sub", ctx.frame.size);
+    }
+    for (register, offset) in &ctx.frame.saved_registers {
+        frame_access(code, "sd", REGISTERS.names[*register], *offset);
+    }
+
+    for (index, block) in function.blocks.iter().enumerate() {
+        code.push(Line::Label(ctx.block_label(BlockId(index))));
+        for instr in &block.instrs {
+            instr_asm(ctx, code, instr);
+        }
+        terminator_asm(ctx, code, &block.terminator);
+    }
+
+    // Epilogue, every return jumps here with its value in a0
+    label(code, ctx.frame.epilogue_label);
+    release_frame(ctx, code);
+    emit(code, "ret", &[]);
+}
+
+// Restores the saved registers and pops the frame, leaving ra as the caller left it
+fn release_frame(ctx: &CodegenContext, code: &mut Vec<Line>) {
+    for (register, offset) in &ctx.frame.saved_registers {
+        frame_access(code, "ld", REGISTERS.names[*register], *offset);
+    }
+    emit(code, "mv", &["sp", "s0"]);
+    emit(code, "ld", &["ra", "8(sp)"]);
+    emit(code, "ld", &["s0", "0(sp)"]);
+    emit(code, "addi", &["sp", "sp", "16"]);
+}
+
+// Arguments go in a0-a7 in order. Their values never live there, so they can be moved in one
+// at a time
+fn pass_arguments(ctx: &CodegenContext, code: &mut Vec<Line>, args: &[VReg]) {
+    for (index, arg) in args.iter().enumerate() {
+        let value = ctx.read(*arg, 0, code);
+        emit(code, "mv", &[&format!("a{}", index), value]);
+    }
+}
+
+fn instr_asm(ctx: &mut CodegenContext, code: &mut Vec<Line>, instr: &Instr) {
+    match instr {
+        Instr::Const { dst, value } => {
+            let value = match ctx.ty(*dst) {
+                IrType::Ptr => *value,
+                _ => *value as i32 as i64,
+            };
+            load_immediate(code, ctx.target(*dst), value);
+        }
+
+        Instr::Copy { dst, src } => {
+            let src = ctx.read(*src, 0, code);
+            emit(code, "mv", &[ctx.target(*dst), src]);
+        }
+
+        Instr::Unary { op, dst, src } => {
+            let ty = ctx.ty(*dst);
+            let src = ctx.read(*src, 0, code);
+            match op {
+                UnaryOp::Neg => emit(code, &sized("neg", ty), &[ctx.target(*dst), src]),
+                UnaryOp::Not => emit(code, "not", &[ctx.target(*dst), src]),
+            }
+        }
+
+        Instr::Binary { op, dst, lhs, rhs } => {
+            let ty = ctx.ty(*dst);
+            let is_address = ctx.ty(*lhs) == IrType::Ptr;
+            let lhs = ctx.read(*lhs, 0, code);
+            let rhs = ctx.read(*rhs, 1, code);
+            let dst = ctx.target(*dst);
+
+            // There is only set if less than, the others swap its operands or flip its result.
+            // Addresses compare unsigned, integers signed
+            let less = if is_address { "sltu" } else { "slt" };
+            match op {
+                BinaryOp::Add => emit(code, &sized("add", ty), &[dst, lhs, rhs]),
+                BinaryOp::Sub => emit(code, &sized("sub", ty), &[dst, lhs, rhs]),
+                BinaryOp::Mul => emit(code, &sized("mul", ty), &[dst, lhs, rhs]),
+                BinaryOp::Div => emit(code, &sized("div", ty), &[dst, lhs, rhs]),
+                BinaryOp::Eq => {
+                    emit(code, "xor", &[dst, lhs, rhs]);
+                    emit(code, "seqz", &[dst, dst]);
+                }
+                BinaryOp::Ne => {
+                    emit(code, "xor", &[dst, lhs, rhs]);
+                    emit(code, "snez", &[dst, dst]);
+                }
+                BinaryOp::Lt => emit(code, less, &[dst, lhs, rhs]),
+                BinaryOp::Gt => emit(code, less, &[dst, rhs, lhs]),
+                BinaryOp::Le => {
+                    emit(code, less, &[dst, rhs, lhs]);
+                    emit(code, "xori", &[dst, dst, "1"]);
+                }
+                BinaryOp::Ge => {
+                    emit(code, less, &[dst, lhs, rhs]);
+                    emit(code, "xori", &[dst, dst, "1"]);
+                }
+            }
+        }
+
+        // Truncating keeps the low 32 bits sign extended like every int
+        Instr::Convert {
+            conversion,
+            dst,
+            src,
+        } => {
+            let src = ctx.read(*src, 0, code);
+            let dst = ctx.target(*dst);
+            match conversion {
+                Conversion::Sext | Conversion::Trunc => emit(code, "sext.w", &[dst, src]),
+                Conversion::SextI8 => {
+                    emit(code, "slli", &[dst, src, "56"]);
+                    emit(code, "srai", &[dst, dst, "56"]);
+                }
+            }
+        }
+
+        Instr::FrameAddr { dst, slot } => {
+            frame_address(code, ctx.target(*dst), ctx.frame.slot_offsets[*slot]);
+        }
+
+        // Relative to the instruction, so the code can be loaded anywhere
+        Instr::GlobalAddr { dst, symbol } => {
+            emit(code, "lla", &[ctx.target(*dst), &elf_symbol(symbol)]);
+        }
+
+        // Chars are signed, unlike in the LP64 ABI, and sign extended like C promotes them, as are ints by lw
+        Instr::Load { dst, ty, addr } => {
+            let addr = format!("0({})", ctx.read(*addr, 0, code));
+            let instruction = match ty {
+                IrType::I8 => "lb",
+                IrType::I32 => "lw",
+                IrType::Ptr => "ld",
+            };
+            emit(code, instruction, &[ctx.target(*dst), &addr]);
+        }
+
+        Instr::Store { ty, addr, value } => {
+            let addr = format!("0({})", ctx.read(*addr, 0, code));
+            let value = ctx.read(*value, 1, code);
+            let instruction = match ty {
+                IrType::I8 => "sb",
+                IrType::I32 => "sw",
+                IrType::Ptr => "sd",
+            };
+            emit(code, instruction, &[value, &addr]);
+        }
+
+        Instr::MemCopy { dst, src, size } => {
+            let dst = ctx.read(*dst, 0, code);
+            let src = ctx.read(*src, 1, code);
+            emit(code, "mv", &["a3", dst]);
+            emit(code, "mv", &["a2", src]);
+            copy_asm(ctx, code, *size);
+        }
+
+        Instr::MemZero { dst, size } => {
+            let dst = ctx.read(*dst, 0, code);
+            emit(code, "mv", &["a1", dst]);
+            zero_fill_asm(ctx, code, *size);
+        }
+
+        Instr::Param { dst, index } => {
+            emit(code, "mv", &[ctx.target(*dst), &format!("a{}", index)]);
+        }
+
+        // The result comes back in a0
+        Instr::Call {
+            dst,
+            function,
+            args,
+        } => {
+            pass_arguments(ctx, code, args);
+            emit(code, "call", &[function]);
+            emit(code, "mv", &[ctx.target(*dst), "a0"]);
+        }
+
+        Instr::Phi { .. } => panic!("Phi left for code generation, out-of-ssa did not run"),
+    }
+
+    if let Some(dst) = instr.dst() {
+        ctx.write_back(dst, code);
+    }
+}
+
+fn terminator_asm(ctx: &mut CodegenContext, code: &mut Vec<Line>, terminator: &Terminator) {
+    match terminator {
+        Terminator::Jump(block) => {
+            emit(code, "j", &[&ctx.block_label(*block)]);
+        }
+
+        Terminator::Branch {
+            condition,
+            then,
+            otherwise,
+        } => {
+            let condition = ctx.read(*condition, 0, code);
+            emit(code, "bnez", &[condition, &ctx.block_label(*then)]);
+            emit(code, "j", &[&ctx.block_label(*otherwise)]);
+        }
+
+        Terminator::Switch {
+            value,
+            cases,
+            default,
+        } => {
+            let value = ctx.read(*value, 0, code);
+            switch_asm(ctx, code, value, cases, *default);
+        }
+
+        Terminator::Return(value) => {
+            let value = ctx.read(*value, 0, code);
+            emit(code, "mv", &["a0", value]);
+            emit(code, "j", &[&format!(".L{}", ctx.frame.epilogue_label)]);
+        }
+
+        // The callee returns straight to our caller, through the ra we got
+        Terminator::TailCall { function, args } => {
+            pass_arguments(ctx, code, args);
+            release_frame(ctx, code);
+            emit(code, "tail", &[function]);
+        }
+    }
+}
+
+// Jumps to the block of the case matching the 32 bit value. Dense cases index a table of
+// offsets in .rodata, sparse ones are compared one at a time
+fn switch_asm(
+    ctx: &mut CodegenContext,
+    code: &mut Vec<Line>,
+    value: &str,
+    cases: &[(i64, BlockId)],
+    default: BlockId,
+) {
+    let default_label = ctx.block_label(default);
+
+    if let Some(table_range @ (min, range)) = jump_table_range(cases) {
+        let table_label = ctx.reserve_labels(1);
+        let table = format!(".L{}", table_label);
+
+        // Rebase to 0, anything outside the table is unsigned above its last index
+        load_immediate(code, "t6", min);
+        emit(code, "subw", &["t5", value, "t6"]);
+        load_immediate(code, "t6", range - 1);
+        emit(code, "bgtu", &["t5", "t6", &default_label]);
+
+        // The entries are offsets from the table, so it needs no relocations
+        emit(code, "slli", &["t5", "t5", "2"]);
+        emit(code, "lla", &["t6", &table]);
+        emit(code, "add", &["t5", "t6", "t5"]);
+        emit(code, "lw", &["t5", "0(t5)"]);
+        emit(code, "add", &["t5", "t6", "t5"]);
+        emit(code, "jr", &["t5"]);
+
+        directive(&mut ctx.rodata, ".balign 4");
+        label(&mut ctx.rodata, table_label);
+        for target in ctx.jump_table_targets(cases, table_range, default) {
+            directive(&mut ctx.rodata, &format!(".word {} - {}", target, table));
+        }
+    } else {
+        for (case_value, block) in cases {
+            load_immediate(code, "t6", *case_value);
+            emit(code, "beq", &[value, "t6", &ctx.block_label(*block)]);
+        }
+        emit(code, "j", &[&default_label]);
+    }
+}
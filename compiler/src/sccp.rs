@@ -0,0 +1,292 @@
+use std::collections::HashSet;
+
+use crate::{
+    dce,
+    ir::{
+        BinaryOp, BlockId, Conversion, Instr, IrFunction, IrProgram, IrType, Terminator, UnaryOp,
+        VReg,
+    },
+};
+
+// Sparse conditional constant propagation, the algorithm of Wegman and Zadeck, run on SSA form.
+// Registers start out unknown and only get lower in the lattice, to a constant and then to
+// overdefined, while only the edges of branches whose condition can go that way are followed.
+// Constants found this way are propagated through phis of loops as well, which folding the
+// syntax tree can not do, and branches on them become jumps
+
+#[derive(Debug, PartialEq, Clone, Copy)]
+enum Value {
+    Unknown,
+    Constant(i64),
+    Overdefined,
+}
+
+fn meet(a: Value, b: Value) -> Value {
+    match (a, b) {
+        (Value::Unknown, other) | (other, Value::Unknown) => other,
+        (Value::Constant(a), Value::Constant(b)) if a == b => Value::Constant(a),
+        _ => Value::Overdefined,
+    }
+}
+
+// A value as held in a register of the type, i32 arithmetic wrapping like the machine's
+fn normalize(value: i64, ty: IrType) -> i64 {
+    match ty {
+        IrType::I8 => value as i8 as i64,
+        IrType::I32 => value as i32 as i64,
+        IrType::Ptr => value,
+    }
+}
+
+pub fn propagate_constants(program: &mut IrProgram) {
+    for function in &mut program.functions {
+        propagate_in_function(function);
+    }
+}
+
+// Where a register is read, an instruction or the terminator of a block
+#[derive(Clone, Copy)]
+enum Use {
+    Instr(BlockId, usize),
+    Terminator(BlockId),
+}
+
+struct Solver<'a> {
+    function: &'a IrFunction,
+    values: Vec<Value>,
+    users: Vec<Vec<Use>>,
+    executable: Vec<bool>,
+    edges: HashSet<(BlockId, BlockId)>,
+    edge_worklist: Vec<(BlockId, BlockId)>,
+    use_worklist: Vec<Use>,
+}
+
+impl Solver<'_> {
+    fn value(&self, vreg: &VReg) -> Value {
+        self.values[vreg.0]
+    }
+
+    fn evaluate(&self, block: BlockId, instr: &Instr) -> Value {
+        let ty = |vreg: &VReg| self.function.vregs[vreg.0];
+        match instr {
+            Instr::Const { dst, value } => Value::Constant(normalize(*value, ty(dst))),
+            Instr::Copy { src, .. } => self.value(src),
+            Instr::Unary { op, dst, src } => match self.value(src) {
+                Value::Constant(value) => Value::Constant(normalize(
+                    match op {
+                        UnaryOp::Neg => value.wrapping_neg(),
+                        UnaryOp::Not => !value,
+                    },
+                    ty(dst),
+                )),
+                other => other,
+            },
+            Instr::Binary { op, dst, lhs, rhs } => match (self.value(lhs), self.value(rhs)) {
+                (Value::Constant(a), Value::Constant(b)) => match evaluate_binary(*op, a, b) {
+                    Some(value) => Value::Constant(normalize(value, ty(dst))),
+                    None => Value::Overdefined, // Left for the program to divide by zero
+                },
+                (Value::Overdefined, _) | (_, Value::Overdefined) => Value::Overdefined,
+                _ => Value::Unknown,
+            },
+            Instr::Convert {
+                conversion, src, ..
+            } => match self.value(src) {
+                Value::Constant(value) => Value::Constant(match conversion {
+                    Conversion::Sext => value,
+                    Conversion::Trunc => normalize(value, IrType::I32),
+                    Conversion::SextI8 => normalize(value, IrType::I8),
+                }),
+                other => other,
+            },
+            Instr::Phi { incoming, .. } => incoming
+                .iter()
+                .filter(|(predecessor, _)| self.edges.contains(&(*predecessor, block)))
+                .fold(Value::Unknown, |value, (_, vreg)| {
+                    meet(value, self.value(vreg))
+                }),
+            _ => Value::Overdefined,
+        }
+    }
+
+    fn visit_instr(&mut self, block: BlockId, index: usize) {
+        let instr = &self.function.blocks[block.0].instrs[index];
+        let Some(dst) = instr.dst() else {
+            return;
+        };
+        let value = meet(self.values[dst.0], self.evaluate(block, instr));
+        if value != self.values[dst.0] {
+            self.values[dst.0] = value;
+            self.use_worklist.extend(self.users[dst.0].iter().copied());
+        }
+    }
+
+    fn visit_terminator(&mut self, block: BlockId) {
+        let successors = match &self.function.blocks[block.0].terminator {
+            Terminator::Branch {
+                condition,
+                then,
+                otherwise,
+            } => match self.value(condition) {
+                Value::Unknown => vec![],
+                Value::Constant(0) => vec![*otherwise],
+                Value::Constant(_) => vec![*then],
+                Value::Overdefined => vec![*then, *otherwise],
+            },
+            Terminator::Switch {
+                value,
+                cases,
+                default,
+            } => match self.value(value) {
+                Value::Unknown => vec![],
+                Value::Constant(value) => vec![taken_case(cases, *default, value)],
+                Value::Overdefined => self.function.blocks[block.0].terminator.successors(),
+            },
+            terminator => terminator.successors(),
+        };
+
+        for successor in successors {
+            self.edge_worklist.push((block, successor));
+        }
+    }
+
+    fn visit_edge(&mut self, from: BlockId, to: BlockId) {
+        if self.edges.insert((from, to)) {
+            self.visit_block(to);
+        }
+    }
+
+    // The first visit evaluates the whole block, later ones only the phis seeing a new edge
+    fn visit_block(&mut self, to: BlockId) {
+        let block = &self.function.blocks[to.0];
+        if self.executable[to.0] {
+            for (index, instr) in block.instrs.iter().enumerate() {
+                if matches!(instr, Instr::Phi { .. }) {
+                    self.visit_instr(to, index);
+                }
+            }
+            return;
+        }
+
+        self.executable[to.0] = true;
+        for index in 0..block.instrs.len() {
+            self.visit_instr(to, index);
+        }
+        self.visit_terminator(to);
+    }
+
+    fn solve(&mut self) {
+        self.visit_block(BlockId(0));
+        loop {
+            if let Some((from, to)) = self.edge_worklist.pop() {
+                self.visit_edge(from, to);
+            } else if let Some(used) = self.use_worklist.pop() {
+                match used {
+                    Use::Instr(block, index) if self.executable[block.0] => {
+                        self.visit_instr(block, index)
+                    }
+                    Use::Terminator(block) if self.executable[block.0] => {
+                        self.visit_terminator(block)
+                    }
+                    _ => {}
+                }
+            } else {
+                return;
+            }
+        }
+    }
+}
+
+fn evaluate_binary(op: BinaryOp, a: i64, b: i64) -> Option<i64> {
+    Some(match op {
+        BinaryOp::Add => a.wrapping_add(b),
+        BinaryOp::Sub => a.wrapping_sub(b),
+        BinaryOp::Mul => a.wrapping_mul(b),
+        BinaryOp::Div if b == 0 => return None,
+        BinaryOp::Div => a.wrapping_div(b),
+        BinaryOp::Eq => (a == b) as i64,
+        BinaryOp::Ne => (a != b) as i64,
+        BinaryOp::Lt => (a < b) as i64,
+        BinaryOp::Le => (a <= b) as i64,
+        BinaryOp::Gt => (a > b) as i64,
+        BinaryOp::Ge => (a >= b) as i64,
+    })
+}
+
+fn taken_case(cases: &[(i64, BlockId)], default: BlockId, value: i64) -> BlockId {
+    cases
+        .iter()
+        .find(|(case, _)| *case == value)
+        .map(|(_, block)| *block)
+        .unwrap_or(default)
+}
+
+fn propagate_in_function(function: &mut IrFunction) {
+    let mut users = vec![vec![]; function.vregs.len()];
+    for (index, block) in function.blocks.iter().enumerate() {
+        for (position, instr) in block.instrs.iter().enumerate() {
+            for vreg in instr.uses() {
+                users[vreg.0].push(Use::Instr(BlockId(index), position));
+            }
+        }
+        for vreg in block.terminator.uses() {
+            users[vreg.0].push(Use::Terminator(BlockId(index)));
+        }
+    }
+
+    let mut solver = Solver {
+        function,
+        values: vec![Value::Unknown; function.vregs.len()],
+        users,
+        executable: vec![false; function.blocks.len()],
+        edges: HashSet::new(),
+        edge_worklist: vec![],
+        use_worklist: vec![],
+    };
+    solver.solve();
+    let Solver { values, edges, .. } = solver;
+
+    for (index, block) in function.blocks.iter_mut().enumerate() {
+        let block_id = BlockId(index);
+        for instr in &mut block.instrs {
+            if let Instr::Phi { incoming, .. } = instr {
+                incoming.retain(|(predecessor, _)| edges.contains(&(*predecessor, block_id)));
+            }
+            if let (Some(dst), true) = (instr.dst(), instr.is_pure()) {
+                if let Value::Constant(value) = values[dst.0] {
+                    *instr = Instr::Const { dst, value };
+                }
+            }
+        }
+        // Phis stay first, ahead of the constants some of them became
+        block
+            .instrs
+            .sort_by_key(|instr| !matches!(instr, Instr::Phi { .. }));
+
+        let taken = match &block.terminator {
+            Terminator::Branch {
+                condition,
+                then,
+                otherwise,
+            } => match values[condition.0] {
+                Value::Constant(0) => Some(*otherwise),
+                Value::Constant(_) => Some(*then),
+                _ => None,
+            },
+            Terminator::Switch {
+                value,
+                cases,
+                default,
+            } => match values[value.0] {
+                Value::Constant(value) => Some(taken_case(cases, *default, value)),
+                _ => None,
+            },
+            _ => None,
+        };
+        if let Some(taken) = taken {
+            block.terminator = Terminator::Jump(taken);
+        }
+    }
+
+    dce::remove_unreachable_in_function(function);
+}
@@ -0,0 +1,664 @@
+use std::collections::HashMap;
+use std::rc::Rc;
+
+use crate::asm::{Instruction, Line};
+
+// Runs the AArch64 assembly we generate without an ARM machine, for --simulate and tests that
+// check what main returns. Only the instructions and directives the code generator uses are
+// understood: moves, integer arithmetic, compares and flags, branches, calls, loads and stores
+// of bytes, words and pairs, and the data directives. Instructions sit at addresses of their
+// own above the data, so labels, adr and jump tables work like in the real program. main is
+// called with the link register at an exit address, returning there or an exit system call
+// ends the program
+
+const DATA_BASE: u64 = 0x1000; // Below is the null page, where every access fails
+const STACK_SIZE: u64 = 8 << 20;
+const CODE_BASE: u64 = 0x1_0000_0000;
+const EXIT_ADDRESS: u64 = 0xdead_0000;
+const MAX_STEPS: u64 = 100_000_000;
+const ZERO_REGISTER: usize = 31; // Also the number of sp, which operands tell apart
+const LINK_REGISTER: usize = 30;
+
+// Assembles and runs the program, giving back x0 when main returns
+pub fn run_assembly(source: &str) -> Result<i64, String> {
+    let lines = parse_assembly(source)?;
+    let mut machine = Machine::load(&lines)?;
+    machine.run("main")
+}
+
+// Splits assembly text into the lines the generator works with, the reverse of render
+pub fn parse_assembly(source: &str) -> Result<Vec<Line>, String> {
+    let mut lines = vec![];
+    for (number, text) in source.lines().enumerate() {
+        let text = match text.find("//") {
+            Some(comment) => &text[..comment],
+            None => text,
+        }
+        .trim();
+
+        if text.is_empty() {
+        } else if let Some(label) = text.strip_suffix(':') {
+            lines.push(Line::Label(label.to_string()));
+        } else if text.starts_with('.') {
+            lines.push(Line::Directive(text.to_string()));
+        } else {
+            let (opcode, operands) = text.split_once(char::is_whitespace).unwrap_or((text, ""));
+            if opcode.is_empty() {
+                return Err(format!("Line {}: Expected an instruction", number + 1));
+            }
+            lines.push(Line::Instruction(Instruction {
+                opcode: opcode.to_string(),
+                operands: split_operands(operands),
+            }));
+        }
+    }
+    Ok(lines)
+}
+
+// Commas inside brackets separate the parts of an address, not operands
+fn split_operands(text: &str) -> Vec<String> {
+    let mut operands = vec![];
+    let mut current = "".to_string();
+    let mut depth = 0;
+    for character in text.chars() {
+        match character {
+            '[' => depth += 1,
+            ']' => depth -= 1,
+            ',' if depth == 0 => {
+                operands.push(current.trim().to_string());
+                current.clear();
+                continue;
+            }
+            _ => {}
+        }
+        current.push(character);
+    }
+    if !current.trim().is_empty() {
+        operands.push(current.trim().to_string());
+    }
+    operands
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+struct Register {
+    number: usize,
+    wide: bool,  // An x register, w registers are the lower 32 bits
+    is_sp: bool, // Number 31 is sp here instead of the zero register
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Operand {
+    Register(Register),
+    Immediate(i64),
+    Symbol(String, i64),             // A label plus an addend
+    Low12(String),                   // :lo12:symbol, the offset within its page
+    Address(Register, Offset, bool), // [base, offset] with ! writing back the address
+    Shift(u32),                      // lsl n
+    Condition(String),
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Offset {
+    Immediate(i64),
+    Register(Register, u32), // Index register shifted left
+}
+
+fn parse_register(text: &str) -> Option<Register> {
+    let (number, wide, is_sp) = match text {
+        "sp" => (31, true, true),
+        "xzr" => (31, true, false),
+        "wzr" => (31, false, false),
+        "fp" => (29, true, false),
+        "lr" => (30, true, false),
+        _ => {
+            let wide = text.starts_with('x');
+            if !wide && !text.starts_with('w') {
+                return None;
+            }
+            let number: usize = text[1..].parse().ok()?;
+            if number > 30 {
+                return None;
+            }
+            (number, wide, false)
+        }
+    };
+    Some(Register {
+        number,
+        wide,
+        is_sp,
+    })
+}
+
+fn parse_immediate(text: &str) -> Option<i64> {
+    let text = text.strip_prefix('#').unwrap_or(text);
+    let (negative, digits) = match text.strip_prefix('-') {
+        Some(digits) => (true, digits),
+        None => (false, text),
+    };
+    let value = match digits.strip_prefix("0x") {
+        Some(hex) => i64::from_str_radix(hex, 16).ok()?,
+        None => digits.parse().ok()?,
+    };
+    Some(if negative { -value } else { value })
+}
+
+const CONDITIONS: [&str; 10] = ["eq", "ne", "lt", "le", "gt", "ge", "hi", "hs", "lo", "ls"];
+
+fn parse_operand(text: &str) -> Result<Operand, String> {
+    if let Some(register) = parse_register(text) {
+        return Ok(Operand::Register(register));
+    }
+    if let Some(value) = parse_immediate(text) {
+        return Ok(Operand::Immediate(value));
+    }
+    if let Some(symbol) = text.strip_prefix(":lo12:") {
+        return Ok(Operand::Low12(symbol.to_string()));
+    }
+    if let Some(amount) = text.strip_prefix("lsl ") {
+        let amount = parse_immediate(amount.trim()).ok_or(format!("Invalid shift {}", text))?;
+        return Ok(Operand::Shift(amount as u32));
+    }
+    if CONDITIONS.contains(&text) {
+        return Ok(Operand::Condition(text.to_string()));
+    }
+
+    if let Some(inner) = text.strip_prefix('[') {
+        let (inner, writeback) = match inner.strip_suffix("]!") {
+            Some(inner) => (inner, true),
+            None => (inner.strip_suffix(']').ok_or("Unclosed [")?, false),
+        };
+        let parts: Vec<&str> = inner.split(',').map(|part| part.trim()).collect();
+        let base = parse_register(parts[0]).ok_or(format!("Invalid base register {}", parts[0]))?;
+        let offset = match parts[1..] {
+            [] => Offset::Immediate(0),
+            [offset] => match parse_register(offset) {
+                Some(index) => Offset::Register(index, 0),
+                None => Offset::Immediate(
+                    parse_immediate(offset).ok_or(format!("Invalid offset {}", offset))?,
+                ),
+            },
+            [index, shift] => {
+                let index = parse_register(index).ok_or(format!("Invalid index {}", index))?;
+                match parse_operand(shift)? {
+                    Operand::Shift(amount) => Offset::Register(index, amount),
+                    _ => return Err(format!("Invalid shift {}", shift)),
+                }
+            }
+            _ => return Err(format!("Invalid address {}", text)),
+        };
+        return Ok(Operand::Address(base, offset, writeback));
+    }
+
+    // symbol, symbol+addend or symbol-addend
+    let split = text.rfind(['+', '-']).filter(|index| *index > 0);
+    if let Some(index) = split {
+        if let Some(addend) = parse_immediate(&text[index..].replace('+', "")) {
+            return Ok(Operand::Symbol(text[..index].to_string(), addend));
+        }
+    }
+    Ok(Operand::Symbol(text.to_string(), 0))
+}
+
+// An instruction with its operands decoded once before the program runs
+struct Decoded {
+    opcode: String,
+    operands: Vec<Operand>,
+    instruction: Instruction, // As written, for the error messages
+}
+
+struct Machine {
+    registers: [u64; 32], // x0 to x30, then sp
+    negative: bool,
+    zero: bool,
+    carry: bool,
+    overflow: bool,
+    memory: Vec<u8>, // From DATA_BASE, the data first and then the stack
+    code: Rc<Vec<Decoded>>,
+    labels: HashMap<String, u64>,
+}
+
+impl Machine {
+    // Lays out the data and decodes the instructions. Data words can hold addresses of
+    // labels defined further down, so they are written once every label is known
+    fn load(lines: &[Line]) -> Result<Self, String> {
+        let mut labels = HashMap::new();
+        let mut code = vec![];
+        let mut data: Vec<u8> = vec![];
+        let mut words = vec![]; // (offset into data, size, expression)
+        let mut in_text = true;
+
+        for line in lines {
+            match line {
+                Line::Label(label) => {
+                    let address = if in_text {
+                        CODE_BASE + 4 * code.len() as u64
+                    } else {
+                        DATA_BASE + data.len() as u64
+                    };
+                    labels.insert(label.clone(), address);
+                }
+                Line::Instruction(instruction) => {
+                    if !in_text {
+                        return Err(format!("Instruction {} outside .text", instruction));
+                    }
+                    let mut operands = vec![];
+                    for operand in &instruction.operands {
+                        operands.push(parse_operand(operand)?);
+                    }
+                    code.push(Decoded {
+                        opcode: instruction.opcode.clone(),
+                        operands,
+                        instruction: instruction.clone(),
+                    });
+                }
+                Line::Directive(directive) => {
+                    let (name, argument) = directive
+                        .split_once(char::is_whitespace)
+                        .unwrap_or((directive, ""));
+                    let argument = argument.trim();
+                    match name {
+                        ".text" => in_text = true,
+                        ".data" | ".bss" => in_text = false,
+                        ".section" => in_text = argument.starts_with(".text"),
+                        ".global" | ".globl" | ".type" | ".size" => {}
+                        ".balign" | ".align" | ".p2align" if !in_text => {
+                            let value = parse_immediate(argument)
+                                .ok_or(format!("Invalid {}", directive))?;
+                            let align = if name == ".balign" { value } else { 1 << value } as usize;
+                            data.resize(data.len().div_ceil(align) * align, 0);
+                        }
+                        ".balign" | ".align" | ".p2align" => {}
+                        ".zero" | ".space" => {
+                            let size = parse_immediate(argument)
+                                .ok_or(format!("Invalid {}", directive))?;
+                            data.resize(data.len() + size as usize, 0);
+                        }
+                        ".byte" | ".word" | ".xword" | ".quad" => {
+                            let size = match name {
+                                ".byte" => 1,
+                                ".word" => 4,
+                                _ => 8,
+                            };
+                            for value in argument.split(',') {
+                                words.push((data.len(), size, value.trim().to_string()));
+                                data.resize(data.len() + size, 0);
+                            }
+                        }
+                        _ => return Err(format!("Unsupported directive {}", directive)),
+                    }
+                }
+            }
+        }
+
+        let mut memory = data;
+        for (offset, size, expression) in words {
+            let value = evaluate_data(&labels, &expression)?;
+            memory[offset..offset + size].copy_from_slice(&value.to_le_bytes()[..size]);
+        }
+        let stack_start = (memory.len() as u64).div_ceil(16) * 16;
+        memory.resize((stack_start + STACK_SIZE) as usize, 0);
+
+        let mut registers = [0; 32];
+        registers[31] = DATA_BASE + memory.len() as u64;
+
+        Ok(Machine {
+            registers,
+            negative: false,
+            zero: false,
+            carry: false,
+            overflow: false,
+            memory,
+            code: Rc::new(code),
+            labels,
+        })
+    }
+
+    fn run(&mut self, entry: &str) -> Result<i64, String> {
+        let mut pc = self.label(entry)?;
+        self.registers[LINK_REGISTER] = EXIT_ADDRESS;
+
+        for _ in 0..MAX_STEPS {
+            if pc == EXIT_ADDRESS {
+                return Ok(self.registers[0] as i64);
+            }
+            let index = pc.wrapping_sub(CODE_BASE) / 4;
+            if pc < CODE_BASE || index as usize >= self.code.len() {
+                return Err(format!("Jump to {:#x} outside the code", pc));
+            }
+
+            match self.step(index as usize, pc) {
+                Ok(Some(next)) => pc = next,
+                Ok(None) => return Ok(self.registers[0] as i64),
+                Err(error) => {
+                    let instruction = &self.code[index as usize].instruction;
+                    return Err(format!("{} in {}", error, instruction));
+                }
+            }
+        }
+
+        Err(format!("No exit after {} instructions", MAX_STEPS))
+    }
+
+    fn label(&self, label: &str) -> Result<u64, String> {
+        match self.labels.get(label) {
+            Some(address) => Ok(*address),
+            None => Err(format!("Undefined symbol {}", label)),
+        }
+    }
+
+    fn read_register(&self, register: Register) -> u64 {
+        if register.number == ZERO_REGISTER && !register.is_sp {
+            return 0;
+        }
+        let value = self.registers[register.number];
+        if register.wide {
+            value
+        } else {
+            value & 0xffff_ffff
+        }
+    }
+
+    // Writing a w register clears the upper half of the x register
+    fn write_register(&mut self, register: Register, value: u64) {
+        if register.number == ZERO_REGISTER && !register.is_sp {
+            return;
+        }
+        self.registers[register.number] = if register.wide {
+            value
+        } else {
+            value & 0xffff_ffff
+        };
+    }
+
+    fn value(&self, operand: &Operand) -> Result<u64, String> {
+        match operand {
+            Operand::Register(register) => Ok(self.read_register(*register)),
+            Operand::Immediate(value) => Ok(*value as u64),
+            Operand::Low12(symbol) => Ok(self.label(symbol)? & 0xfff),
+            Operand::Symbol(symbol, addend) => Ok(self.label(symbol)?.wrapping_add(*addend as u64)),
+            _ => Err(format!("Expected a value, found {:?}", operand)),
+        }
+    }
+
+    fn register(operand: &Operand) -> Result<Register, String> {
+        match operand {
+            Operand::Register(register) => Ok(*register),
+            _ => Err(format!("Expected a register, found {:?}", operand)),
+        }
+    }
+
+    // The address an operand points to, and the base register written back after the access.
+    // A post-index immediate following the address moves the base once the access is done
+    fn address(
+        &self,
+        operand: &Operand,
+        post_index: Option<&Operand>,
+    ) -> Result<(u64, Option<(Register, u64)>), String> {
+        let Operand::Address(base, offset, writeback) = operand else {
+            return Err(format!("Expected an address, found {:?}", operand));
+        };
+        let base_value = self.read_register(*base);
+        let address = match offset {
+            Offset::Immediate(offset) => base_value.wrapping_add(*offset as u64),
+            Offset::Register(index, shift) => {
+                base_value.wrapping_add(self.read_register(*index) << shift)
+            }
+        };
+
+        if let Some(step) = post_index {
+            let step = self.value(step)?;
+            return Ok((address, Some((*base, base_value.wrapping_add(step)))));
+        }
+        Ok((address, writeback.then_some((*base, address))))
+    }
+
+    fn memory_range(&self, address: u64, size: u64) -> Result<std::ops::Range<usize>, String> {
+        let start = address.wrapping_sub(DATA_BASE);
+        if address < DATA_BASE || start + size > self.memory.len() as u64 {
+            return Err(format!("Invalid memory access at address {:#x}", address));
+        }
+        Ok(start as usize..(start + size) as usize)
+    }
+
+    fn read(&self, address: u64, size: u64) -> Result<u64, String> {
+        let mut bytes = [0; 8];
+        bytes[..size as usize].copy_from_slice(&self.memory[self.memory_range(address, size)?]);
+        Ok(u64::from_le_bytes(bytes))
+    }
+
+    fn write(&mut self, address: u64, size: u64, value: u64) -> Result<(), String> {
+        let range = self.memory_range(address, size)?;
+        self.memory[range].copy_from_slice(&value.to_le_bytes()[..size as usize]);
+        Ok(())
+    }
+
+    // a - b with the flags of subs and cmp, in 32 or 64 bits
+    fn subtract(&mut self, a: u64, b: u64, wide: bool) -> u64 {
+        let bits = if wide { 64 } else { 32 };
+        let mask = if wide { u64::MAX } else { 0xffff_ffff };
+        let (a, b) = (a & mask, b & mask);
+        let result = a.wrapping_sub(b) & mask;
+
+        self.negative = (result >> (bits - 1)) & 1 == 1;
+        self.zero = result == 0;
+        self.carry = a >= b; // No borrow
+        self.overflow = (((a ^ b) & (a ^ result)) >> (bits - 1)) & 1 == 1;
+        result
+    }
+
+    fn condition(&self, condition: &str) -> Result<bool, String> {
+        Ok(match condition {
+            "eq" => self.zero,
+            "ne" => !self.zero,
+            "lt" => self.negative != self.overflow,
+            "ge" => self.negative == self.overflow,
+            "gt" => !self.zero && self.negative == self.overflow,
+            "le" => self.zero || self.negative != self.overflow,
+            "hi" => self.carry && !self.zero,
+            "ls" => !self.carry || self.zero,
+            "hs" => self.carry,
+            "lo" => !self.carry,
+            _ => return Err(format!("Unknown condition {}", condition)),
+        })
+    }
+
+    // Executes one instruction, giving the address of the next one or None on exit
+    fn step(&mut self, index: usize, pc: u64) -> Result<Option<u64>, String> {
+        let code = Rc::clone(&self.code);
+        let Decoded {
+            opcode, operands, ..
+        } = &code[index];
+        let mut next = pc + 4;
+
+        let operand = |number: usize| {
+            operands
+                .get(number)
+                .ok_or(format!("Missing operand {}", number + 1))
+        };
+
+        match opcode.as_str() {
+            "mov" | "movz" => {
+                let mut value = self.value(operand(1)?)?;
+                if let Some(Operand::Shift(amount)) = operands.get(2) {
+                    value <<= amount;
+                }
+                self.write_register(Self::register(operand(0)?)?, value);
+            }
+            "movk" => {
+                let destination = Self::register(operand(0)?)?;
+                let shift = match operands.get(2) {
+                    Some(Operand::Shift(amount)) => *amount,
+                    _ => 0,
+                };
+                let value = (self.value(operand(1)?)? & 0xffff) << shift;
+                let kept = self.read_register(destination) & !(0xffff << shift);
+                self.write_register(destination, kept | value);
+            }
+            "add" | "sub" | "subs" | "cmp" => {
+                let (destination, first) = match opcode.as_str() {
+                    "cmp" => (None, 0),
+                    _ => (Some(Self::register(operand(0)?)?), 1),
+                };
+                let a = self.value(operand(first)?)?;
+                let b = self.value(operand(first + 1)?)?;
+                let wide = Self::register(operand(first)?)?.wide;
+                let result = match opcode.as_str() {
+                    "add" => a.wrapping_add(b),
+                    "sub" => a.wrapping_sub(b),
+                    _ => self.subtract(a, b, wide),
+                };
+                if let Some(destination) = destination {
+                    self.write_register(destination, result);
+                }
+            }
+            "mul" => {
+                let result = self
+                    .value(operand(1)?)?
+                    .wrapping_mul(self.value(operand(2)?)?);
+                self.write_register(Self::register(operand(0)?)?, result);
+            }
+            // Division by zero gives 0 and the overflowing division wraps, without a trap
+            "sdiv" => {
+                let destination = Self::register(operand(0)?)?;
+                let (a, b) = (self.value(operand(1)?)?, self.value(operand(2)?)?);
+                let result = if destination.wide {
+                    (a as i64)
+                        .checked_div(b as i64)
+                        .unwrap_or(if b == 0 { 0 } else { a as i64 }) as u64
+                } else {
+                    let (a, b) = (a as u32 as i32, b as u32 as i32);
+                    a.checked_div(b).unwrap_or(if b == 0 { 0 } else { a }) as u32 as u64
+                };
+                self.write_register(destination, result);
+            }
+            "neg" => {
+                let result = self.value(operand(1)?)?.wrapping_neg();
+                self.write_register(Self::register(operand(0)?)?, result);
+            }
+            "mvn" => {
+                let result = !self.value(operand(1)?)?;
+                self.write_register(Self::register(operand(0)?)?, result);
+            }
+            "sxtb" => {
+                let result = self.value(operand(1)?)? as u8 as i8 as i64 as u64;
+                self.write_register(Self::register(operand(0)?)?, result);
+            }
+            "sxtw" => {
+                let result = self.value(operand(1)?)? as u32 as i32 as i64 as u64;
+                self.write_register(Self::register(operand(0)?)?, result);
+            }
+            "cset" => {
+                let Operand::Condition(condition) = operand(1)? else {
+                    return Err("Expected a condition".to_string());
+                };
+                let result = self.condition(condition)? as u64;
+                self.write_register(Self::register(operand(0)?)?, result);
+            }
+            "adrp" => {
+                let page = self.value(operand(1)?)? & !0xfff;
+                self.write_register(Self::register(operand(0)?)?, page);
+            }
+            "adr" => {
+                let address = self.value(operand(1)?)?;
+                self.write_register(Self::register(operand(0)?)?, address);
+            }
+            "ldr" | "ldrb" | "ldrsb" | "ldrsw" | "str" | "strb" => {
+                let register = Self::register(operand(0)?)?;
+                let (address, writeback) = self.address(operand(1)?, operands.get(2))?;
+                let size = match opcode.as_str() {
+                    "ldrb" | "ldrsb" | "strb" => 1,
+                    "ldrsw" => 4,
+                    _ if register.wide => 8,
+                    _ => 4,
+                };
+
+                if opcode.starts_with("ld") {
+                    let value = self.read(address, size)?;
+                    let value = match opcode.as_str() {
+                        "ldrsb" => value as u8 as i8 as i64 as u64,
+                        "ldrsw" => value as u32 as i32 as i64 as u64,
+                        _ => value,
+                    };
+                    self.write_register(register, value);
+                } else {
+                    self.write(address, size, self.read_register(register))?;
+                }
+                if let Some((base, value)) = writeback {
+                    self.write_register(base, value);
+                }
+            }
+            "ldp" | "stp" => {
+                let first = Self::register(operand(0)?)?;
+                let second = Self::register(operand(1)?)?;
+                let (address, writeback) = self.address(operand(2)?, operands.get(3))?;
+                let size = if first.wide { 8 } else { 4 };
+
+                if opcode.as_str() == "ldp" {
+                    let (low, high) = (self.read(address, size)?, self.read(address + size, size)?);
+                    self.write_register(first, low);
+                    self.write_register(second, high);
+                } else {
+                    self.write(address, size, self.read_register(first))?;
+                    self.write(address + size, size, self.read_register(second))?;
+                }
+                if let Some((base, value)) = writeback {
+                    self.write_register(base, value);
+                }
+            }
+            "b" => next = self.value(operand(0)?)?,
+            "bl" => {
+                self.registers[LINK_REGISTER] = pc + 4;
+                next = self.value(operand(0)?)?;
+            }
+            "br" => next = self.value(operand(0)?)?,
+            "ret" => next = self.registers[LINK_REGISTER],
+            "cbz" | "cbnz" => {
+                let is_zero = self.value(operand(0)?)? == 0;
+                if is_zero == (opcode.as_str() == "cbz") {
+                    next = self.value(operand(1)?)?;
+                }
+            }
+            // Only exit, with the code in x0 and the system call number 93 in x8
+            "svc" => {
+                if self.registers[8] == 93 || self.registers[8] == 94 {
+                    return Ok(None);
+                }
+                return Err(format!("Unsupported system call {}", self.registers[8]));
+            }
+            "nop" => {}
+            _ => {
+                // b.eq, and the older spelling beq
+                let condition = opcode.strip_prefix("b.").or(opcode
+                    .strip_prefix('b')
+                    .filter(|condition| CONDITIONS.contains(condition)));
+                match condition {
+                    Some(condition) => {
+                        if self.condition(condition)? {
+                            next = self.value(operand(0)?)?;
+                        }
+                    }
+                    None => return Err(format!("Unsupported instruction {}", opcode)),
+                }
+            }
+        }
+
+        Ok(Some(next))
+    }
+}
+
+// The value of a data word, a number, a symbol with an addend or the difference of two labels
+fn evaluate_data(labels: &HashMap<String, u64>, expression: &str) -> Result<u64, String> {
+    if let Some((left, right)) = expression.split_once(" - ") {
+        return Ok(evaluate_data(labels, left)?.wrapping_sub(evaluate_data(labels, right)?));
+    }
+    if let Some(value) = parse_immediate(expression) {
+        return Ok(value as u64);
+    }
+    match parse_operand(expression)? {
+        Operand::Symbol(symbol, addend) => match labels.get(&symbol) {
+            Some(address) => Ok(address.wrapping_add(addend as u64)),
+            None => Err(format!("Undefined symbol {}", symbol)),
+        },
+        _ => Err(format!("Invalid data {}", expression)),
+    }
+}
@@ -0,0 +1,428 @@
+use std::collections::HashMap;
+
+use crate::{
+    cfg::{self, Dominators},
+    dce,
+    ir::{BlockId, Conversion, Instr, IrFunction, IrProgram, IrType, VReg},
+};
+
+// Construction and destruction of SSA form, where every register is written exactly once.
+// mem2reg turns the stack slots of locals whose address is never taken into registers and
+// places phis where the values from different paths meet, the way Cytron et al. describe it.
+// Registers written in several places, like the result of ?:, are renamed the same way.
+// out-of-ssa turns the phis back into copies before code generation
+
+pub fn mem2reg(program: &mut IrProgram) {
+    for function in &mut program.functions {
+        promote_function(function);
+    }
+}
+
+pub fn out_of_ssa(program: &mut IrProgram) {
+    for function in &mut program.functions {
+        remove_phis(function);
+    }
+}
+
+fn memory_size(ty: IrType) -> usize {
+    match ty {
+        IrType::I8 => 1,
+        IrType::I32 => 4,
+        IrType::Ptr => 8,
+    }
+}
+
+// A slot can live in a register when its address is only used to load and store it, always
+// with the type of the whole slot
+fn promotable_slots(function: &IrFunction) -> Vec<Option<Option<IrType>>> {
+    let mut slots: Vec<Option<Option<IrType>>> = vec![Some(None); function.slots.len()];
+    let mut addresses: HashMap<VReg, usize> = HashMap::new();
+    for block in &function.blocks {
+        for instr in &block.instrs {
+            if let Instr::FrameAddr { dst, slot } = instr {
+                if addresses.insert(*dst, *slot).is_some() {
+                    slots[*slot] = None;
+                }
+            }
+        }
+    }
+
+    let access = |slots: &mut Vec<Option<Option<IrType>>>, slot: usize, ty: IrType| {
+        slots[slot] = match slots[slot] {
+            Some(None) if memory_size(ty) == function.slots[slot].size => Some(Some(ty)),
+            Some(Some(other)) if other == ty => Some(Some(ty)),
+            _ => None,
+        };
+    };
+
+    for block in &function.blocks {
+        for instr in &block.instrs {
+            let mut escaping = instr.uses();
+            match instr {
+                Instr::Load { ty, addr, .. } if addresses.contains_key(addr) => {
+                    access(&mut slots, addresses[addr], *ty);
+                    escaping.clear();
+                }
+                Instr::Store { ty, addr, value } if addresses.contains_key(addr) => {
+                    access(&mut slots, addresses[addr], *ty);
+                    escaping = vec![*value];
+                }
+                _ => {}
+            }
+            for vreg in escaping {
+                if let Some(slot) = addresses.get(&vreg) {
+                    slots[*slot] = None;
+                }
+            }
+        }
+        for vreg in block.terminator.uses() {
+            if let Some(slot) = addresses.get(&vreg) {
+                slots[*slot] = None;
+            }
+        }
+    }
+
+    slots
+}
+
+fn promote_function(function: &mut IrFunction) {
+    dce::remove_unreachable_in_function(function);
+
+    // Some(type) for the slots to promote, the type being None when it is never accessed
+    let promoted = promotable_slots(function);
+    let addresses: HashMap<VReg, usize> = function
+        .blocks
+        .iter()
+        .flat_map(|block| &block.instrs)
+        .filter_map(|instr| match instr {
+            Instr::FrameAddr { dst, slot } if promoted[*slot].is_some() => Some((*dst, *slot)),
+            _ => None,
+        })
+        .collect();
+
+    let mut definitions = vec![0; function.vregs.len()];
+    for instr in function.blocks.iter().flat_map(|block| &block.instrs) {
+        if let Some(dst) = instr.dst() {
+            definitions[dst.0] += 1;
+        }
+    }
+
+    // Promoted slots and registers written more than once, every write being a definition of
+    // a new register. Variables are numbered in the order of their types
+    let mut variable_types = vec![];
+    let mut slot_variable = vec![None; function.slots.len()];
+    let mut register_variable = vec![None; function.vregs.len()];
+    for (slot, ty) in promoted.iter().enumerate() {
+        if let Some(Some(ty)) = ty {
+            slot_variable[slot] = Some(variable_types.len());
+            variable_types.push(if *ty == IrType::Ptr {
+                IrType::Ptr
+            } else {
+                IrType::I32
+            });
+        }
+    }
+    for (vreg, count) in definitions.iter().enumerate() {
+        if *count > 1 {
+            register_variable[vreg] = Some(variable_types.len());
+            variable_types.push(function.vregs[vreg]);
+        }
+    }
+
+    // Where every variable is written
+    let mut definition_blocks = vec![vec![]; variable_types.len()];
+    for (index, block) in function.blocks.iter().enumerate() {
+        for instr in &block.instrs {
+            let variable = match instr {
+                Instr::Store { addr, .. } => addresses.get(addr).and_then(|s| slot_variable[*s]),
+                _ => instr.dst().and_then(|dst| register_variable[dst.0]),
+            };
+            if let Some(variable) = variable {
+                if !definition_blocks[variable].contains(&BlockId(index)) {
+                    definition_blocks[variable].push(BlockId(index));
+                }
+            }
+        }
+    }
+
+    // Phis go in the iterated dominance frontier of the definitions
+    let dominators = Dominators::new(function);
+    let frontiers = dominators.frontiers(function);
+    let mut phis: Vec<Vec<(usize, VReg)>> = vec![vec![]; function.blocks.len()];
+    for (variable, blocks) in definition_blocks.iter().enumerate() {
+        let mut worklist = blocks.clone();
+        while let Some(block) = worklist.pop() {
+            for frontier in &frontiers[block.0] {
+                if phis[frontier.0].iter().any(|(other, _)| *other == variable) {
+                    continue;
+                }
+                function.vregs.push(variable_types[variable]);
+                phis[frontier.0].push((variable, VReg(function.vregs.len() - 1)));
+                if !blocks.contains(frontier) {
+                    worklist.push(*frontier);
+                }
+            }
+        }
+    }
+
+    let mut renamer = Renamer {
+        addresses: &addresses,
+        slot_variable: &slot_variable,
+        register_variable: &register_variable,
+        variable_types: &variable_types,
+        stacks: vec![vec![]; variable_types.len()],
+        undefined: vec![None; variable_types.len()],
+        incoming: phis.iter().map(|block| vec![vec![]; block.len()]).collect(),
+        phis: &phis,
+    };
+    renamer.rename(function, &dominators);
+    let Renamer {
+        undefined,
+        incoming,
+        ..
+    } = renamer;
+
+    for (index, block) in function.blocks.iter_mut().enumerate() {
+        let phi_instrs = phis[index]
+            .iter()
+            .zip(&incoming[index])
+            .map(|((_, dst), incoming)| Instr::Phi {
+                dst: *dst,
+                incoming: incoming.clone(),
+            });
+        block.instrs.splice(0..0, phi_instrs);
+    }
+
+    // Reading a variable before writing it gives 0, defined after the parameters are read
+    let params = function.blocks[0]
+        .instrs
+        .iter()
+        .take_while(|instr| matches!(instr, Instr::Param { .. }))
+        .count();
+    let zeros = undefined.iter().flatten().map(|dst| Instr::Const {
+        dst: *dst,
+        value: 0,
+    });
+    function.blocks[0].instrs.splice(params..params, zeros);
+
+    remove_promoted_slots(function, &promoted);
+    propagate_copies(function);
+    dce::remove_dead_instructions(function);
+}
+
+struct Renamer<'a> {
+    addresses: &'a HashMap<VReg, usize>,
+    slot_variable: &'a [Option<usize>],
+    register_variable: &'a [Option<usize>],
+    variable_types: &'a [IrType],
+    phis: &'a [Vec<(usize, VReg)>],
+    stacks: Vec<Vec<VReg>>,       // Current definition of every variable last
+    undefined: Vec<Option<VReg>>, // The 0 a variable read before any write has
+    incoming: Vec<Vec<Vec<(BlockId, VReg)>>>, // Entries of every phi, by block
+}
+
+impl Renamer<'_> {
+    fn current(&mut self, function: &mut IrFunction, variable: usize) -> VReg {
+        if let Some(value) = self.stacks[variable].last() {
+            return *value;
+        }
+        *self.undefined[variable].get_or_insert_with(|| {
+            function.vregs.push(self.variable_types[variable]);
+            VReg(function.vregs.len() - 1)
+        })
+    }
+
+    fn new_vreg(function: &mut IrFunction, ty: IrType) -> VReg {
+        function.vregs.push(ty);
+        VReg(function.vregs.len() - 1)
+    }
+
+    // Walks the dominator tree, so the definition reaching every read is the last one pushed
+    fn rename(&mut self, function: &mut IrFunction, dominators: &Dominators) {
+        // None marks leaving the block, popping what it pushed
+        let mut worklist = vec![Some(BlockId(0))];
+        let mut pushed: Vec<Vec<usize>> = vec![];
+
+        while let Some(entry) = worklist.pop() {
+            let Some(block) = entry else {
+                for variable in pushed.pop().expect("Entered block") {
+                    self.stacks[variable].pop();
+                }
+                continue;
+            };
+
+            let mut defined = vec![];
+            for (variable, dst) in &self.phis[block.0] {
+                self.stacks[*variable].push(*dst);
+                defined.push(*variable);
+            }
+
+            let instrs = std::mem::take(&mut function.blocks[block.0].instrs);
+            let mut renamed = vec![];
+            for mut instr in instrs {
+                let mut uses = HashMap::new();
+                for vreg in instr.uses() {
+                    if let Some(variable) = self.register_variable[vreg.0] {
+                        uses.insert(vreg, self.current(function, variable));
+                    }
+                }
+                instr.rename_uses(|vreg| uses.get(&vreg).copied().unwrap_or(vreg));
+
+                let slot_variable = match &instr {
+                    Instr::Load { addr, .. } | Instr::Store { addr, .. } => self
+                        .addresses
+                        .get(addr)
+                        .and_then(|slot| self.slot_variable[*slot]),
+                    _ => None,
+                };
+                match instr {
+                    Instr::FrameAddr { dst, .. } if self.addresses.contains_key(&dst) => continue,
+                    Instr::Load { dst, .. } if slot_variable.is_some() => {
+                        let variable = slot_variable.expect("Promoted slot");
+                        let src = self.current(function, variable);
+                        instr = Instr::Copy { dst, src };
+                    }
+                    Instr::Store { ty, value, .. } if slot_variable.is_some() => {
+                        let variable = slot_variable.expect("Promoted slot");
+                        // A char slot keeps only the low byte of what is stored
+                        let value = if ty == IrType::I8 {
+                            let dst = Self::new_vreg(function, IrType::I32);
+                            renamed.push(Instr::Convert {
+                                conversion: Conversion::SextI8,
+                                dst,
+                                src: value,
+                            });
+                            dst
+                        } else {
+                            value
+                        };
+                        self.stacks[variable].push(value);
+                        defined.push(variable);
+                        continue;
+                    }
+                    _ => {}
+                }
+
+                if let Some(variable) = instr.dst().and_then(|dst| self.register_variable[dst.0]) {
+                    let fresh = Self::new_vreg(function, self.variable_types[variable]);
+                    let old = instr.dst();
+                    instr.rename_vregs(|vreg| if Some(vreg) == old { fresh } else { vreg });
+                    self.stacks[variable].push(fresh);
+                    defined.push(variable);
+                }
+                renamed.push(instr);
+            }
+            function.blocks[block.0].instrs = renamed;
+
+            let mut terminator = function.blocks[block.0].terminator.clone();
+            let mut uses = HashMap::new();
+            for vreg in terminator.uses() {
+                if let Some(variable) = self.register_variable[vreg.0] {
+                    uses.insert(vreg, self.current(function, variable));
+                }
+            }
+            terminator.rename_vregs(|vreg| uses.get(&vreg).copied().unwrap_or(vreg));
+            function.blocks[block.0].terminator = terminator;
+
+            for successor in cfg::successors(function, block) {
+                for (index, (variable, _)) in self.phis[successor.0].iter().enumerate() {
+                    let value = self.current(function, *variable);
+                    self.incoming[successor.0][index].push((block, value));
+                }
+            }
+
+            pushed.push(defined);
+            worklist.push(None);
+            worklist.extend(dominators.children(block).iter().rev().map(|c| Some(*c)));
+        }
+    }
+}
+
+// Drops the promoted slots, renumbering the frame addresses of the others
+fn remove_promoted_slots(function: &mut IrFunction, promoted: &[Option<Option<IrType>>]) {
+    let mut renumbered = vec![];
+    let mut count = 0;
+    for is_promoted in promoted {
+        renumbered.push(count);
+        if is_promoted.is_none() {
+            count += 1;
+        }
+    }
+
+    let mut index = 0;
+    function.slots.retain(|_| {
+        index += 1;
+        promoted[index - 1].is_none()
+    });
+    for instr in function
+        .blocks
+        .iter_mut()
+        .flat_map(|block| &mut block.instrs)
+    {
+        if let Instr::FrameAddr { slot, .. } = instr {
+            *slot = renumbered[*slot];
+        }
+    }
+}
+
+// In SSA form a copy is just another name for its source, so readers can use the source
+fn propagate_copies(function: &mut IrFunction) {
+    let mut sources = HashMap::new();
+    for instr in function.blocks.iter().flat_map(|block| &block.instrs) {
+        if let Instr::Copy { dst, src } = instr {
+            if function.vregs[dst.0] == function.vregs[src.0] {
+                sources.insert(*dst, *src);
+            }
+        }
+    }
+
+    let resolve = |mut vreg: VReg| {
+        while let Some(src) = sources.get(&vreg) {
+            vreg = *src;
+        }
+        vreg
+    };
+    for block in &mut function.blocks {
+        block.instrs.retain(|instr| match instr {
+            Instr::Copy { dst, .. } => !sources.contains_key(dst),
+            _ => true,
+        });
+        for instr in &mut block.instrs {
+            instr.rename_uses(resolve);
+        }
+        block.terminator.rename_vregs(resolve);
+    }
+}
+
+// Every phi gets a register of its own, copied to at the end of each predecessor and copied
+// from where the phi was. Phis reading each other's values then still see the old ones
+fn remove_phis(function: &mut IrFunction) {
+    let mut copies: Vec<Vec<Instr>> = vec![vec![]; function.blocks.len()];
+    for index in 0..function.blocks.len() {
+        let instrs = std::mem::take(&mut function.blocks[index].instrs);
+        let mut lowered = vec![];
+        for instr in instrs {
+            let Instr::Phi { dst, incoming } = instr else {
+                lowered.push(instr);
+                continue;
+            };
+            function.vregs.push(function.vregs[dst.0]);
+            let temporary = VReg(function.vregs.len() - 1);
+            for (predecessor, value) in incoming {
+                copies[predecessor.0].push(Instr::Copy {
+                    dst: temporary,
+                    src: value,
+                });
+            }
+            lowered.push(Instr::Copy {
+                dst,
+                src: temporary,
+            });
+        }
+        function.blocks[index].instrs = lowered;
+    }
+
+    for (block, copies) in function.blocks.iter_mut().zip(copies) {
+        block.instrs.extend(copies);
+    }
+}
@@ -0,0 +1,104 @@
+use std::collections::HashSet;
+
+use crate::ir::{BlockId, Instr, IrFunction, IrProgram, Terminator, VReg};
+
+// Tail call optimization, run on the IR from -O2. A call whose value is returned right away,
+// at most passing through some copies and jumps on the way, becomes a TailCall terminator.
+// The backends then release the frame before branching to the callee instead of calling it,
+// so a function returning a call to itself runs in constant stack like a loop would
+
+pub fn optimize_tail_calls(program: &mut IrProgram) {
+    for function in &mut program.functions {
+        // The callee reuses the stack of the caller, so nothing may point into it
+        if frame_escapes(function) {
+            continue;
+        }
+
+        for index in 0..function.blocks.len() {
+            if let Some(call) = tail_call(function, BlockId(index)) {
+                let block = &mut function.blocks[index];
+                let Instr::Call { function, args, .. } = block.instrs.remove(call) else {
+                    panic!("Tail call of something that is not a call");
+                };
+                block.instrs.truncate(call);
+                block.terminator = Terminator::TailCall { function, args };
+            }
+        }
+    }
+}
+
+// The index of the call in the block if the function returns what the call returns
+fn tail_call(function: &IrFunction, block: BlockId) -> Option<usize> {
+    let instrs = &function.blocks[block.0].instrs;
+    let call = instrs
+        .iter()
+        .rposition(|instr| matches!(instr, Instr::Call { .. }))?;
+    let mut value = instrs[call].dst()?;
+    value = follow_copies(&instrs[call + 1..], value)?;
+
+    let mut terminator = &function.blocks[block.0].terminator;
+    let mut visited = HashSet::new();
+    loop {
+        match terminator {
+            Terminator::Return(returned) => return (*returned == value).then_some(call),
+            Terminator::Jump(target) if visited.insert(*target) => {
+                let target = &function.blocks[target.0];
+                value = follow_copies(&target.instrs, value)?;
+                terminator = &target.terminator;
+            }
+            _ => return None,
+        }
+    }
+}
+
+// The register holding the value after the instructions, if they only copy it around
+fn follow_copies(instrs: &[Instr], mut value: VReg) -> Option<VReg> {
+    for instr in instrs {
+        match instr {
+            Instr::Copy { dst, src } if *src == value => value = *dst,
+            _ => return None,
+        }
+    }
+    Some(value)
+}
+
+// Whether the address of a stack slot, or something computed from it, is stored to memory,
+// passed to a call or returned
+fn frame_escapes(function: &IrFunction) -> bool {
+    let mut addresses = HashSet::new();
+    let mut changed = true;
+    while changed {
+        changed = false;
+        for block in &function.blocks {
+            for instr in &block.instrs {
+                let derived = match instr {
+                    Instr::FrameAddr { .. } => true,
+                    Instr::Copy { src, .. }
+                    | Instr::Unary { src, .. }
+                    | Instr::Convert { src, .. } => addresses.contains(src),
+                    Instr::Binary { lhs, rhs, .. } => {
+                        addresses.contains(lhs) || addresses.contains(rhs)
+                    }
+                    _ => false,
+                };
+                if derived {
+                    changed |= addresses.insert(instr.dst().expect("No destination"));
+                }
+            }
+        }
+    }
+
+    function.blocks.iter().any(|block| {
+        let escaping = block.instrs.iter().any(|instr| match instr {
+            Instr::Store { value, .. } => addresses.contains(value),
+            Instr::Call { args, .. } => args.iter().any(|arg| addresses.contains(arg)),
+            _ => false,
+        });
+        escaping
+            || match &block.terminator {
+                Terminator::Return(value) => addresses.contains(value),
+                Terminator::TailCall { args, .. } => args.iter().any(|arg| addresses.contains(arg)),
+                _ => false,
+            }
+    })
+}
@@ -24,6 +24,7 @@ pub enum TokenType {
     Comma,
     Dot,
     Colon,
+    QuestionMark,
     // Operators
     Plus,   // +
     Minus,  // -
@@ -38,6 +39,7 @@ pub enum TokenType {
     Xor,    // ^
     Mod,    // %
     BitComplement,
+    Arrow, // ->
     // Comparators
     Eq,  // ==
     Lt,  // <
@@ -61,6 +63,11 @@ pub enum TokenType {
     BreakKeyword,
     StaticKeyword,
     VoidKeyword,
+    SizeofKeyword,
+    ConstKeyword,
+    SwitchKeyword,
+    CaseKeyword,
+    DefaultKeyword,
     // Error Token
     Error,
 }
@@ -102,10 +109,15 @@ lazy_static! {
         map.insert("struct", TokenType::StructKeyword);
         map.insert("enum", TokenType::EnumKeyword);
         map.insert("static", TokenType::StaticKeyword);
+        map.insert("const", TokenType::ConstKeyword);
+        map.insert("switch", TokenType::SwitchKeyword);
+        map.insert("case", TokenType::CaseKeyword);
+        map.insert("default", TokenType::DefaultKeyword);
         map.insert("break", TokenType::BreakKeyword);
         map.insert("continue", TokenType::ContinueKeyword);
         map.insert("void", TokenType::VoidKeyword);
         map.insert("union", TokenType::UnionKeyword);
+        map.insert("sizeof", TokenType::SizeofKeyword);
         map
     };
 }
@@ -126,6 +138,7 @@ lazy_static! {
         map.insert("^", TokenType::Xor);
         map.insert("!", TokenType::Not);
         map.insert("~", TokenType::BitComplement);
+        map.insert("->", TokenType::Arrow);
         map
     };
 }
@@ -143,6 +156,7 @@ lazy_static! {
         map.insert(",", TokenType::Comma);
         map.insert(";", TokenType::Semi);
         map.insert(":", TokenType::Colon);
+        map.insert("?", TokenType::QuestionMark);
         map
     };
 }
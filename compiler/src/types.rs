@@ -0,0 +1,198 @@
+use std::fmt;
+use std::sync::{Arc, RwLock, RwLockReadGuard, RwLockWriteGuard};
+
+// Types known to the compiler, sizes and alignments follow the AArch64 LP64 ABI
+#[derive(Debug, PartialEq, Clone)]
+pub enum Type {
+    Int,
+    Char,
+    Pointer(Box<Type>),
+    Array(Box<Type>, usize),
+    Struct(StructRef),
+    Void, // Only what a function returns, a call to it has no value
+}
+
+#[derive(Debug, PartialEq, Clone)]
+pub struct Member {
+    pub name: String,
+    pub ty: Type,
+    pub offset: usize,
+}
+
+// A struct or union, incomplete until its member list has been seen
+#[derive(Debug)]
+pub struct StructType {
+    pub tag: Option<String>,
+    pub is_union: bool,
+    pub members: Vec<Member>,
+    pub size: usize,
+    pub align: usize,
+    pub complete: bool,
+}
+
+// Shared handle to a struct type. A struct can hold pointers to itself, so the handle
+// compares by identity and prints only the tag instead of recursing into the members. It can
+// be sent along with the syntax tree to the thread compiling it
+#[derive(Clone)]
+pub struct StructRef(Arc<RwLock<StructType>>);
+
+impl Type {
+    pub fn pointer_to(base: Type) -> Type {
+        Type::Pointer(Box::new(base))
+    }
+
+    pub fn array_of(element: Type, length: usize) -> Type {
+        Type::Array(Box::new(element), length)
+    }
+
+    // Bytes occupied by a value of this type
+    pub fn size(&self) -> usize {
+        match self {
+            Type::Void => 0,
+            Type::Char => 1,
+            Type::Int => 4,
+            Type::Pointer(_) => 8,
+            Type::Array(element, length) => element.size() * length,
+            Type::Struct(struct_ref) => struct_ref.borrow().size,
+        }
+    }
+
+    // Arrays are aligned like their elements, scalars to their own size
+    pub fn align(&self) -> usize {
+        match self {
+            Type::Array(element, _) => element.align(),
+            Type::Struct(struct_ref) => struct_ref.borrow().align,
+            Type::Void => 1,
+            _ => self.size(),
+        }
+    }
+
+    // The pointee of a pointer or the element of an array, what a subscript yields
+    pub fn base(&self) -> Option<&Type> {
+        match self {
+            Type::Pointer(base) | Type::Array(base, _) => Some(base),
+            _ => None,
+        }
+    }
+
+    // Arrays used as values turn into a pointer to their first element
+    pub fn decay(&self) -> Type {
+        match self {
+            Type::Array(element, _) => Type::pointer_to(element.as_ref().clone()),
+            _ => self.clone(),
+        }
+    }
+
+    pub fn is_array(&self) -> bool {
+        matches!(self, Type::Array(_, _))
+    }
+
+    pub fn is_struct(&self) -> bool {
+        matches!(self, Type::Struct(_))
+    }
+
+    // Integers and pointers, the types that fit in a register
+    pub fn is_scalar(&self) -> bool {
+        matches!(self, Type::Int | Type::Char | Type::Pointer(_))
+    }
+
+    // Structs declared but not yet defined have no size, and neither do arrays of them
+    pub fn is_complete(&self) -> bool {
+        match self {
+            Type::Struct(struct_ref) => struct_ref.borrow().complete,
+            Type::Array(element, _) => element.is_complete(),
+            Type::Void => false,
+            _ => true,
+        }
+    }
+
+    pub fn member(&self, name: &str) -> Option<Member> {
+        match self {
+            Type::Struct(struct_ref) => struct_ref
+                .borrow()
+                .members
+                .iter()
+                .find(|member| member.name == name)
+                .cloned(),
+            _ => None,
+        }
+    }
+}
+
+impl StructRef {
+    pub fn new(tag: Option<String>, is_union: bool) -> Self {
+        StructRef(Arc::new(RwLock::new(StructType {
+            tag,
+            is_union,
+            members: vec![],
+            size: 0,
+            align: 1,
+            complete: false,
+        })))
+    }
+
+    pub fn borrow(&self) -> RwLockReadGuard<'_, StructType> {
+        self.0.read().expect("Struct type poisoned")
+    }
+
+    fn borrow_mut(&self) -> RwLockWriteGuard<'_, StructType> {
+        self.0.write().expect("Struct type poisoned")
+    }
+
+    // Completes the type with the standard C layout, which is what gcc uses on AArch64 too.
+    // Every member goes at the next offset aligned for it, union members all start at 0,
+    // and the size is padded to a multiple of the strictest member alignment
+    pub fn define(&self, members: Vec<(String, Type)>) {
+        let mut struct_type = self.borrow_mut();
+
+        let mut offset = 0;
+        let mut size = 0;
+        let mut align = 1;
+        for (name, ty) in members {
+            if !struct_type.is_union {
+                offset = align_to(offset, ty.align());
+            }
+            size = size.max(offset + ty.size());
+            align = align.max(ty.align());
+
+            struct_type.members.push(Member {
+                name,
+                offset,
+                ty: ty.clone(),
+            });
+            if !struct_type.is_union {
+                offset += ty.size();
+            }
+        }
+
+        struct_type.size = align_to(size, align);
+        struct_type.align = align;
+        struct_type.complete = true;
+    }
+}
+
+impl PartialEq for StructRef {
+    fn eq(&self, other: &Self) -> bool {
+        Arc::ptr_eq(&self.0, &other.0)
+    }
+}
+
+impl fmt::Debug for StructRef {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let struct_type = self.borrow();
+        let keyword = if struct_type.is_union {
+            "union"
+        } else {
+            "struct"
+        };
+        match &struct_type.tag {
+            Some(tag) => write!(f, "{} {}", keyword, tag),
+            None => write!(f, "{} <anonymous>", keyword),
+        }
+    }
+}
+
+// Rounds value up to the nearest multiple of align
+pub fn align_to(value: usize, align: usize) -> usize {
+    value.div_ceil(align) * align
+}
@@ -0,0 +1,416 @@
+use std::collections::HashMap;
+
+use crate::{
+    asm::{directive, Line},
+    backend::Backend,
+    ir::{
+        BinaryOp, BlockId, Conversion, Instr, IrFunction, IrProgram, IrType, Terminator, UnaryOp,
+        VReg,
+    },
+    types::align_to,
+};
+
+// The WebAssembly backend, a module in the text format for wasm32. Virtual registers become
+// locals of the function, all of them i32 since addresses are 32 bits. Slots live on a stack
+// in linear memory below the global $sp, growing down from the top of the memory, and the
+// globals at fixed addresses from the bottom. Pointers keep their 8 bytes in memory so the
+// layout of structs stays the same, only the low 4 are read and written.
+//
+// Wasm only has structured control flow, so the blocks are dispatched in a loop: the block to
+// run next is put in $next and br_table branches out of the nested wasm blocks to the code of
+// that one. A jump to the block right after falls through instead.
+//
+// Non-static functions are exported, called functions without a definition imported from
+// "env", e.g wasmtime --invoke main out.wat runs main
+
+pub struct Wasm32;
+
+impl Backend for Wasm32 {
+    fn generate(&self, program: &IrProgram) -> Vec<Line> {
+        program_asm(program)
+    }
+}
+
+// Stack for the slots, 1 MiB
+const STACK_SIZE: usize = 16 * PAGE_SIZE;
+const PAGE_SIZE: usize = 65536;
+
+// Globals start here, address 0 is the null pointer
+const DATA_START: usize = 16;
+
+// Adds a line of the module, indented by depth
+fn line(code: &mut Vec<Line>, depth: usize, text: &str) {
+    directive(code, &format!("{}{}", "  ".repeat(depth), text));
+}
+
+fn program_asm(program: &IrProgram) -> Vec<Line> {
+    let mut code = vec![];
+    line(&mut code, 0, "(module");
+
+    // Functions called without a definition, with as many parameters as they are called with
+    let mut imports: Vec<(&str, usize)> = vec![];
+    for function in &program.functions {
+        for block in &function.blocks {
+            let calls = block.instrs.iter().filter_map(|instr| match instr {
+                Instr::Call { function, args, .. } => Some((function, args.len())),
+                _ => None,
+            });
+            let tail_call = match &block.terminator {
+                Terminator::TailCall { function, args } => Some((function, args.len())),
+                _ => None,
+            };
+            for (name, params) in calls.chain(tail_call) {
+                let defined = program.functions.iter().any(|other| other.name == *name);
+                if !defined && !imports.iter().any(|(import, _)| import == name) {
+                    imports.push((name, params));
+                }
+            }
+        }
+    }
+    for (name, params) in imports {
+        line(
+            &mut code,
+            1,
+            &format!(
+                "(import \"env\" \"{}\" (func ${}{} (result i32)))",
+                name,
+                name,
+                " (param i32)".repeat(params)
+            ),
+        );
+    }
+
+    // The globals one after the other, the stack above them
+    let mut addresses = HashMap::new();
+    let mut end = DATA_START;
+    for global in &program.globals {
+        let address = align_to(end, global.align);
+        addresses.insert(global.symbol.clone(), address);
+        end = address + global.size.max(1);
+    }
+    let pages = align_to(end + STACK_SIZE, PAGE_SIZE) / PAGE_SIZE;
+    line(
+        &mut code,
+        1,
+        &format!("(memory (export \"memory\") {})", pages),
+    );
+    line(
+        &mut code,
+        1,
+        &format!("(global $sp (mut i32) (i32.const {}))", pages * PAGE_SIZE),
+    );
+
+    // Memory starts out zeroed, so only the globals with a value need a data segment
+    for global in &program.globals {
+        if global.image.is_zero() {
+            continue;
+        }
+        let mut bytes = global.image.bytes.clone();
+        for relocation in &global.image.relocations {
+            let address = addresses[&relocation.symbol] as i64 + relocation.addend;
+            bytes[relocation.offset..relocation.offset + 4]
+                .copy_from_slice(&(address as u32).to_le_bytes());
+        }
+        let escaped: String = bytes.iter().map(|byte| format!("\\{:02x}", byte)).collect();
+        line(
+            &mut code,
+            1,
+            &format!(
+                "(data (i32.const {}) \"{}\") ;; {}",
+                addresses[&global.symbol], escaped, global.symbol
+            ),
+        );
+    }
+
+    for function in &program.functions {
+        function_asm(&mut code, function, &addresses);
+    }
+
+    line(&mut code, 0, ")");
+    code
+}
+
+// The offsets of the slots from $fp and the size of the frame, keeping $sp 16 byte aligned
+fn frame_layout(function: &IrFunction) -> (Vec<usize>, usize) {
+    let mut offsets = vec![];
+    let mut size = 0;
+    for slot in &function.slots {
+        let offset = align_to(size, slot.align);
+        offsets.push(offset);
+        size = offset + slot.size;
+    }
+    (offsets, align_to(size, 16))
+}
+
+struct FunctionContext<'a> {
+    function: &'a IrFunction,
+    slot_offsets: Vec<usize>,
+    frame_size: usize,
+    addresses: &'a HashMap<String, usize>,
+}
+
+fn function_asm(code: &mut Vec<Line>, function: &IrFunction, addresses: &HashMap<String, usize>) {
+    let (slot_offsets, frame_size) = frame_layout(function);
+    let ctx = FunctionContext {
+        function,
+        slot_offsets,
+        frame_size,
+        addresses,
+    };
+
+    let export = if function.is_static {
+        "".to_string()
+    } else {
+        format!(" (export \"{}\")", function.name)
+    };
+    let params: String = (0..function.params.len())
+        .map(|index| format!(" (param $p{} i32)", index))
+        .collect();
+    line(
+        code,
+        1,
+        &format!("(func ${}{}{} (result i32)", function.name, export, params),
+    );
+    line(code, 2, "(local $next i32) (local $fp i32)");
+    for index in 0..function.vregs.len() {
+        line(code, 2, &format!("(local $v{} i32)", index));
+    }
+
+    // Prologue, the slots are at $fp and up
+    if frame_size > 0 {
+        line(code, 2, "global.get $sp");
+        line(code, 2, &format!("i32.const {}", frame_size));
+        line(code, 2, "i32.sub");
+        line(code, 2, "local.tee $fp");
+        line(code, 2, "global.set $sp");
+    }
+
+    // Branching out of the wasm block named after a block continues with its code
+    let count = function.blocks.len();
+    line(code, 2, "loop $dispatch");
+    for index in (0..count).rev() {
+        line(code, 2, &format!("block $bb{}", index));
+    }
+    line(code, 2, "local.get $next");
+    let targets: String = (0..count).map(|index| format!("$bb{} ", index)).collect();
+    line(code, 2, &format!("br_table {}$bb0", targets));
+
+    for (index, block) in function.blocks.iter().enumerate() {
+        line(code, 2, &format!("end ;; bb{}", index));
+        for instr in &block.instrs {
+            instr_asm(&ctx, code, instr);
+        }
+        terminator_asm(&ctx, code, &block.terminator, BlockId(index));
+    }
+    line(code, 2, "end");
+    line(code, 2, "unreachable");
+    line(code, 1, ")");
+}
+
+fn get(code: &mut Vec<Line>, vreg: VReg) {
+    line(code, 2, &format!("local.get $v{}", vreg.0));
+}
+
+fn set(code: &mut Vec<Line>, vreg: VReg) {
+    line(code, 2, &format!("local.set $v{}", vreg.0));
+}
+
+fn instr_asm(ctx: &FunctionContext, code: &mut Vec<Line>, instr: &Instr) {
+    match instr {
+        Instr::Const { value, .. } => {
+            line(code, 2, &format!("i32.const {}", *value as i32));
+        }
+
+        Instr::Copy { src, .. } => get(code, *src),
+
+        Instr::Unary { op, src, .. } => match op {
+            UnaryOp::Neg => {
+                line(code, 2, "i32.const 0");
+                get(code, *src);
+                line(code, 2, "i32.sub");
+            }
+            UnaryOp::Not => {
+                get(code, *src);
+                line(code, 2, "i32.const -1");
+                line(code, 2, "i32.xor");
+            }
+        },
+
+        // Addresses compare unsigned, integers signed
+        Instr::Binary { op, lhs, rhs, .. } => {
+            let sign = if ctx.function.vregs[lhs.0] == IrType::Ptr {
+                "u"
+            } else {
+                "s"
+            };
+            let instruction = match op {
+                BinaryOp::Add => "add".to_string(),
+                BinaryOp::Sub => "sub".to_string(),
+                BinaryOp::Mul => "mul".to_string(),
+                BinaryOp::Div => "div_s".to_string(),
+                BinaryOp::Eq => "eq".to_string(),
+                BinaryOp::Ne => "ne".to_string(),
+                BinaryOp::Lt => format!("lt_{}", sign),
+                BinaryOp::Le => format!("le_{}", sign),
+                BinaryOp::Gt => format!("gt_{}", sign),
+                BinaryOp::Ge => format!("ge_{}", sign),
+            };
+            get(code, *lhs);
+            get(code, *rhs);
+            line(code, 2, &format!("i32.{}", instruction));
+        }
+
+        // Addresses and ints are both i32, only chars need extending
+        Instr::Convert {
+            conversion, src, ..
+        } => {
+            get(code, *src);
+            if *conversion == Conversion::SextI8 {
+                line(code, 2, "i32.extend8_s");
+            }
+        }
+
+        Instr::FrameAddr { slot, .. } => {
+            line(code, 2, "local.get $fp");
+            line(code, 2, &format!("i32.const {}", ctx.slot_offsets[*slot]));
+            line(code, 2, "i32.add");
+        }
+
+        Instr::GlobalAddr { symbol, .. } => {
+            line(
+                code,
+                2,
+                &format!("i32.const {} ;; {}", ctx.addresses[symbol], symbol),
+            );
+        }
+
+        // Chars are sign extended to 32 bits, like C promotes them
+        Instr::Load { ty, addr, .. } => {
+            get(code, *addr);
+            let instruction = match ty {
+                IrType::I8 => "i32.load8_s",
+                _ => "i32.load",
+            };
+            line(code, 2, instruction);
+        }
+
+        Instr::Store { ty, addr, value } => {
+            get(code, *addr);
+            get(code, *value);
+            let instruction = match ty {
+                IrType::I8 => "i32.store8",
+                _ => "i32.store",
+            };
+            line(code, 2, instruction);
+        }
+
+        Instr::MemCopy { dst, src, size } => {
+            get(code, *dst);
+            get(code, *src);
+            line(code, 2, &format!("i32.const {}", size));
+            line(code, 2, "memory.copy");
+        }
+
+        Instr::MemZero { dst, size } => {
+            get(code, *dst);
+            line(code, 2, "i32.const 0");
+            line(code, 2, &format!("i32.const {}", size));
+            line(code, 2, "memory.fill");
+        }
+
+        Instr::Param { index, .. } => {
+            line(code, 2, &format!("local.get $p{}", index));
+        }
+
+        Instr::Call { function, args, .. } => {
+            for arg in args {
+                get(code, *arg);
+            }
+            line(code, 2, &format!("call ${}", function));
+        }
+
+        Instr::Phi { .. } => panic!("Phi left for code generation, out-of-ssa did not run"),
+    }
+
+    // Every instruction with a result left it on the operand stack
+    if let Some(dst) = instr.dst() {
+        set(code, dst);
+    }
+}
+
+// Pops the frame of the function off the stack in linear memory
+fn release_frame(ctx: &FunctionContext, code: &mut Vec<Line>) {
+    if ctx.frame_size > 0 {
+        line(code, 2, "local.get $fp");
+        line(code, 2, &format!("i32.const {}", ctx.frame_size));
+        line(code, 2, "i32.add");
+        line(code, 2, "global.set $sp");
+    }
+}
+
+// Continues with the block, falling through when it comes right after this one
+fn jump(code: &mut Vec<Line>, current: BlockId, target: BlockId) {
+    if target.0 == current.0 + 1 {
+        return;
+    }
+    line(code, 2, &format!("i32.const {}", target.0));
+    line(code, 2, "local.set $next");
+    line(code, 2, "br $dispatch");
+}
+
+fn terminator_asm(
+    ctx: &FunctionContext,
+    code: &mut Vec<Line>,
+    terminator: &Terminator,
+    current: BlockId,
+) {
+    match terminator {
+        Terminator::Jump(block) => jump(code, current, *block),
+
+        Terminator::Branch {
+            condition,
+            then,
+            otherwise,
+        } => {
+            line(code, 2, &format!("i32.const {}", then.0));
+            line(code, 2, &format!("i32.const {}", otherwise.0));
+            get(code, *condition);
+            line(code, 2, "select");
+            line(code, 2, "local.set $next");
+            line(code, 2, "br $dispatch");
+        }
+
+        Terminator::Switch {
+            value,
+            cases,
+            default,
+        } => {
+            for (case_value, block) in cases {
+                get(code, *value);
+                line(code, 2, &format!("i32.const {}", *case_value as i32));
+                line(code, 2, "i32.eq");
+                line(code, 2, "if");
+                line(code, 2, &format!("i32.const {}", block.0));
+                line(code, 2, "local.set $next");
+                line(code, 2, "br $dispatch");
+                line(code, 2, "end");
+            }
+            jump(code, current, *default);
+        }
+
+        Terminator::Return(value) => {
+            release_frame(ctx, code);
+            get(code, *value);
+            line(code, 2, "return");
+        }
+
+        // The callee replaces this function on the call stack
+        Terminator::TailCall { function, args } => {
+            release_frame(ctx, code);
+            for arg in args {
+                get(code, *arg);
+            }
+            line(code, 2, &format!("return_call ${}", function));
+        }
+    }
+}
@@ -0,0 +1,632 @@
+use crate::{
+    asm::{directive, emit, label, Line},
+    backend::{elf_symbol, global_asm, jump_table_range, Backend, CodegenContext, Registers},
+    ir::{
+        BinaryOp, BlockId, Conversion, Instr, IrFunction, IrProgram, IrType, Terminator, UnaryOp,
+        VReg,
+    },
+};
+
+// The x86-64 backend for Linux, GNU assembler in AT&T syntax following the System V calling
+// convention: the first six arguments in rdi, rsi, rdx, rcx, r8 and r9, the rest on the
+// stack, and the result in eax. Functions keep their names as they are, so the output links
+// with the C runtime as is, e.g gcc out.s -o out
+
+pub struct X86_64;
+
+impl Backend for X86_64 {
+    fn generate(&self, program: &IrProgram) -> Vec<Line> {
+        program_asm(&mut CodegenContext::new(program, &REGISTERS), program)
+    }
+}
+
+// rax and rdx are taken by division and the results, rdx also by the arguments, so they are
+// never allocated. r10 and r11 are the scratch registers
+const REGISTERS: Registers = Registers {
+    names: &[
+        "rcx", "rsi", "rdi", "r8", "r9", "rbx", "r12", "r13", "r14", "r15",
+    ],
+    first_callee_saved: 5,
+    scratch: ["r10", "r11"],
+    load: load_spill,
+    store: store_spill,
+};
+
+const ARGUMENT_REGISTERS: [&str; 6] = ["rdi", "rsi", "rdx", "rcx", "r8", "r9"];
+
+// The operand for offset bytes below rbp. A displacement only has 32 bits, offsets past that
+// go through register, which is overwritten
+fn frame_operand(code: &mut Vec<Line>, offset: usize, register: &str) -> String {
+    if offset <= 1 << 31 {
+        format!("-{}(%rbp)", offset)
+    } else {
+        emit(
+            code,
+            "movabsq",
+            &[&format!("$-{}", offset), &name(register, 8)],
+        );
+        format!("(%rbp,{})", name(register, 8))
+    }
+}
+
+fn load_spill(code: &mut Vec<Line>, register: &str, offset: usize) {
+    let operand = frame_operand(code, offset, register);
+    emit(code, "movq", &[&operand, &name(register, 8)]);
+}
+
+// Spills are stored from a scratch register, the other one is free to hold a wide offset
+fn store_spill(code: &mut Vec<Line>, register: &str, offset: usize) {
+    let free = REGISTERS.scratch[usize::from(register == REGISTERS.scratch[0])];
+    let operand = frame_operand(code, offset, free);
+    emit(code, "movq", &[&name(register, 8), &operand]);
+}
+
+// Functions outside the program may live in a shared library
+fn call_target(ctx: &CodegenContext, function: &str) -> String {
+    if ctx.defined.contains(function) {
+        function.to_string()
+    } else {
+        format!("{}@PLT", function)
+    }
+}
+
+// The name of the low size bytes of a register, e.g %ecx, %r8d and %sil
+fn name(register: &str, size: usize) -> String {
+    if let Some(number) = register
+        .strip_prefix('r')
+        .filter(|rest| rest.starts_with(|c: char| c.is_ascii_digit()))
+    {
+        return match size {
+            8 => format!("%r{}", number),
+            4 => format!("%r{}d", number),
+            _ => format!("%r{}b", number),
+        };
+    }
+
+    let base = &register[1..]; // ax, cx, si etc
+    match size {
+        8 => format!("%{}", register),
+        4 => format!("%e{}", base),
+        _ if base.ends_with('x') => format!("%{}l", &base[..1]),
+        _ => format!("%{}l", base),
+    }
+}
+
+// Chars live in registers sign extended to 32 bits, like C promotes them
+fn size_of(ty: IrType) -> usize {
+    match ty {
+        IrType::Ptr => 8,
+        _ => 4,
+    }
+}
+
+// The operand size suffix of an instruction working on a value of the type
+fn suffix(ty: IrType) -> &'static str {
+    match ty {
+        IrType::Ptr => "q",
+        _ => "l",
+    }
+}
+
+fn sized(register: &str, ty: IrType) -> String {
+    name(register, size_of(ty))
+}
+
+// movq only takes sign extended 32 bit immediates, wider values need movabsq
+fn load_immediate(code: &mut Vec<Line>, register: &str, ty: IrType, value: i64) {
+    let destination = sized(register, ty);
+    match ty {
+        IrType::Ptr if i32::try_from(value).is_err() => {
+            emit(code, "movabsq", &[&format!("${}", value), &destination])
+        }
+        IrType::Ptr => emit(code, "movq", &[&format!("${}", value), &destination]),
+        _ => emit(code, "movl", &[&format!("${}", value as i32), &destination]),
+    }
+}
+
+fn program_asm(ctx: &mut CodegenContext, program: &IrProgram) -> Vec<Line> {
+    let mut code = vec![];
+    directive(&mut code, ".text");
+
+    // Static functions stay local to the file
+    for function in &program.functions {
+        if !function.is_static {
+            directive(&mut code, &format!(".global {}", function.name));
+        }
+    }
+
+    for function in &program.functions {
+        code.push(Line::Label(function.name.clone()));
+        function_asm(ctx, function, &mut code);
+    }
+
+    ctx.emit_rodata(&mut code);
+
+    for global in &program.globals {
+        global_asm(&mut code, global, ".quad");
+    }
+
+    // The stack is not executable, the linker warns unless told so
+    directive(&mut code, ".section .note.GNU-stack,\"\",@progbits");
+    code
+}
+
+fn function_asm(ctx: &mut CodegenContext, function: &IrFunction, code: &mut Vec<Line>) {
+    ctx.enter_function(function);
+
+    // Prologue, rbp points just above the saved registers and the locals
+    emit(code, "pushq", &["%rbp"]);
+    emit(code, "movq", &["%rsp", "%rbp"]);
+    if ctx.frame.size > i32::MAX as usize {
+        emit(code, "movabsq", &[&format!("${}", ctx.frame.size), "%r11"]);
+        emit(code, "subq", &["%r11", "%rsp"]);
+    } else if ctx.frame.size > 0 {
+        emit(code, "subq", &[&format!("${}", ctx.frame.size), "%rsp"]);
+    }
+    for (register, offset) in &ctx.frame.saved_registers {
+        emit(
+            code,
+            "movq",
+            &[
+                &name(REGISTERS.names[*register], 8),
+                &format!("-{}(%rbp)", offset),
+            ],
+        );
+    }
+
+    for (index, block) in function.blocks.iter().enumerate() {
+        code.push(Line::Label(ctx.block_label(BlockId(index))));
+
+        let mut instrs = &block.instrs[..];
+        if index == 0 {
+            let params = instrs
+                .iter()
+                .take_while(|instr| matches!(instr, Instr::Param { .. }))
+                .count();
+            read_parameters(ctx, code, &instrs[..params]);
+            instrs = &instrs[params..];
+        }
+        for instr in instrs {
+            instr_asm(ctx, code, instr);
+        }
+        terminator_asm(ctx, code, &block.terminator);
+    }
+
+    // Epilogue, every return jumps here with its value in eax
+    label(code, ctx.frame.epilogue_label);
+    release_frame(ctx, code);
+    emit(code, "ret", &[]);
+}
+
+// Restores the saved registers and pops the frame, leaving the return address on top
+fn release_frame(ctx: &CodegenContext, code: &mut Vec<Line>) {
+    for (register, offset) in &ctx.frame.saved_registers {
+        emit(
+            code,
+            "movq",
+            &[
+                &format!("-{}(%rbp)", offset),
+                &name(REGISTERS.names[*register], 8),
+            ],
+        );
+    }
+    emit(code, "leave", &[]);
+}
+
+// The parameters in registers may be allocated to each others argument registers, so they
+// are all pushed before any is popped into place. The rest are above the return address
+fn read_parameters(ctx: &CodegenContext, code: &mut Vec<Line>, params: &[Instr]) {
+    let params: Vec<(VReg, usize)> = params
+        .iter()
+        .map(|instr| match instr {
+            Instr::Param { dst, index } => (*dst, *index),
+            _ => unreachable!(),
+        })
+        .collect();
+    let (in_registers, on_stack): (Vec<_>, Vec<_>) = params
+        .iter()
+        .partition(|(_, index)| *index < ARGUMENT_REGISTERS.len());
+
+    for (_, index) in &in_registers {
+        emit(code, "pushq", &[&name(ARGUMENT_REGISTERS[*index], 8)]);
+    }
+    for (dst, _) in in_registers.iter().rev() {
+        emit(code, "popq", &[&name(ctx.target(*dst), 8)]);
+        ctx.write_back(*dst, code);
+    }
+
+    for (dst, index) in on_stack {
+        let offset = 16 + 8 * (index - ARGUMENT_REGISTERS.len());
+        emit(
+            code,
+            "movq",
+            &[&format!("{}(%rbp)", offset), &name(ctx.target(dst), 8)],
+        );
+        ctx.write_back(dst, code);
+    }
+}
+
+// Pushes the arguments past the sixth in reverse order, then moves the first six into their
+// registers through the stack, since their values may live in each others registers. Returns
+// how many bytes to pop after the call, rsp is kept 16 byte aligned for it
+fn pass_arguments(ctx: &CodegenContext, code: &mut Vec<Line>, args: &[VReg]) -> usize {
+    let split = args.len().min(ARGUMENT_REGISTERS.len());
+    let (in_registers, on_stack) = args.split_at(split);
+
+    let padding = if on_stack.len() % 2 == 1 { 8 } else { 0 };
+    if padding > 0 {
+        emit(code, "subq", &[&format!("${}", padding), "%rsp"]);
+    }
+    for arg in on_stack.iter().rev().chain(in_registers) {
+        let value = ctx.read(*arg, 0, code);
+        emit(code, "pushq", &[&name(value, 8)]);
+    }
+    for register in ARGUMENT_REGISTERS[..split].iter().rev() {
+        emit(code, "popq", &[&name(register, 8)]);
+    }
+
+    padding + 8 * on_stack.len()
+}
+
+fn call_asm(ctx: &CodegenContext, code: &mut Vec<Line>, function: &str, args: &[VReg]) {
+    let pushed = pass_arguments(ctx, code, args);
+    emit(code, "call", &[&call_target(ctx, function)]);
+    if pushed > 0 {
+        emit(code, "addq", &[&format!("${}", pushed), "%rsp"]);
+    }
+}
+
+fn instr_asm(ctx: &mut CodegenContext, code: &mut Vec<Line>, instr: &Instr) {
+    match instr {
+        Instr::Const { dst, value } => {
+            load_immediate(code, ctx.target(*dst), ctx.ty(*dst), *value);
+        }
+
+        Instr::Copy { dst, src } => {
+            let ty = ctx.ty(*dst);
+            let src = ctx.read(*src, 0, code);
+            let dst = ctx.target(*dst);
+            if src != dst {
+                emit(
+                    code,
+                    &format!("mov{}", suffix(ty)),
+                    &[&sized(src, ty), &sized(dst, ty)],
+                );
+            }
+        }
+
+        Instr::Unary { op, dst, src } => {
+            let ty = ctx.ty(*dst);
+            let src = ctx.read(*src, 0, code);
+            let dst = sized(ctx.target(*dst), ty);
+            let instruction = match op {
+                UnaryOp::Neg => "neg",
+                UnaryOp::Not => "not",
+            };
+            emit(
+                code,
+                &format!("mov{}", suffix(ty)),
+                &[&sized(src, ty), &dst],
+            );
+            emit(code, &format!("{}{}", instruction, suffix(ty)), &[&dst]);
+        }
+
+        Instr::Binary { op, dst, lhs, rhs } => binary_asm(ctx, code, *op, *dst, *lhs, *rhs),
+
+        Instr::Convert {
+            conversion,
+            dst,
+            src,
+        } => {
+            let src = ctx.read(*src, 0, code);
+            let dst = ctx.target(*dst);
+            match conversion {
+                Conversion::Sext => emit(code, "movslq", &[&name(src, 4), &name(dst, 8)]),
+                Conversion::Trunc => emit(code, "movl", &[&name(src, 4), &name(dst, 4)]),
+                Conversion::SextI8 => emit(code, "movsbl", &[&name(src, 1), &name(dst, 4)]),
+            }
+        }
+
+        Instr::FrameAddr { dst, slot } => {
+            let register = ctx.target(*dst);
+            let operand = frame_operand(code, ctx.frame.slot_offsets[*slot], register);
+            emit(code, "leaq", &[&operand, &name(register, 8)]);
+        }
+
+        Instr::GlobalAddr { dst, symbol } => {
+            emit(
+                code,
+                "leaq",
+                &[
+                    &format!("{}(%rip)", elf_symbol(symbol)),
+                    &name(ctx.target(*dst), 8),
+                ],
+            );
+        }
+
+        // Chars are sign extended to 32 bits, like C promotes them
+        Instr::Load { dst, ty, addr } => {
+            let addr = format!("({})", name(ctx.read(*addr, 0, code), 8));
+            let dst = ctx.target(*dst);
+            match ty {
+                IrType::I8 => emit(code, "movsbl", &[&addr, &name(dst, 4)]),
+                IrType::I32 => emit(code, "movl", &[&addr, &name(dst, 4)]),
+                IrType::Ptr => emit(code, "movq", &[&addr, &name(dst, 8)]),
+            }
+        }
+
+        Instr::Store { ty, addr, value } => {
+            let addr = format!("({})", name(ctx.read(*addr, 0, code), 8));
+            let value = ctx.read(*value, 1, code);
+            match ty {
+                IrType::I8 => emit(code, "movb", &[&name(value, 1), &addr]),
+                IrType::I32 => emit(code, "movl", &[&name(value, 4), &addr]),
+                IrType::Ptr => emit(code, "movq", &[&name(value, 8), &addr]),
+            }
+        }
+
+        // The source goes in r10 and the destination in r11, going through rax since either
+        // may have been loaded into the other
+        Instr::MemCopy { dst, src, size } => {
+            let dst = ctx.read(*dst, 0, code);
+            emit(code, "movq", &[&name(dst, 8), "%rax"]);
+            let src = ctx.read(*src, 1, code);
+            emit(code, "movq", &[&name(src, 8), "%r10"]);
+            emit(code, "movq", &["%rax", "%r11"]);
+            copy_asm(ctx, code, *size);
+        }
+
+        Instr::MemZero { dst, size } => {
+            let dst = ctx.read(*dst, 0, code);
+            emit(code, "movq", &[&name(dst, 8), "%r11"]);
+            zero_fill_asm(ctx, code, *size);
+        }
+
+        Instr::Param { .. } => panic!("Parameters are read at the start of the entry block"),
+
+        // The result comes back in eax
+        Instr::Call {
+            dst,
+            function,
+            args,
+        } => {
+            call_asm(ctx, code, function, args);
+            let ty = ctx.ty(*dst);
+            emit(
+                code,
+                &format!("mov{}", suffix(ty)),
+                &[&sized("rax", ty), &sized(ctx.target(*dst), ty)],
+            );
+        }
+
+        Instr::Phi { .. } => panic!("Phi left for code generation, out-of-ssa did not run"),
+    }
+
+    if let Some(dst) = instr.dst() {
+        ctx.write_back(dst, code);
+    }
+}
+
+// x86 instructions overwrite their first operand, so the result is computed in place of the
+// left one. The right one may share its register with the result, then the order is swapped
+// or the result computed in eax
+fn binary_asm(
+    ctx: &mut CodegenContext,
+    code: &mut Vec<Line>,
+    op: BinaryOp,
+    dst: VReg,
+    lhs: VReg,
+    rhs: VReg,
+) {
+    let ty = ctx.ty(dst);
+    let operand_ty = ctx.ty(lhs);
+    let lhs = ctx.read(lhs, 0, code);
+    let rhs = ctx.read(rhs, 1, code);
+    let dst = ctx.target(dst);
+
+    let instruction = match op {
+        BinaryOp::Add => "add",
+        BinaryOp::Sub => "sub",
+        BinaryOp::Mul => "imul",
+        BinaryOp::Div => {
+            // idiv divides rdx:rax, the sign extension of the dividend, by its operand
+            let (extend, size) = match ty {
+                IrType::Ptr => ("cqto", 8),
+                _ => ("cltd", 4),
+            };
+            emit(
+                code,
+                &format!("mov{}", suffix(ty)),
+                &[&name(lhs, size), &name("rax", size)],
+            );
+            emit(code, extend, &[]);
+            emit(code, &format!("idiv{}", suffix(ty)), &[&name(rhs, size)]);
+            emit(
+                code,
+                &format!("mov{}", suffix(ty)),
+                &[&name("rax", size), &name(dst, size)],
+            );
+            return;
+        }
+        _ => {
+            // Addresses compare unsigned, integers signed
+            let is_address = operand_ty == IrType::Ptr;
+            let condition = match op {
+                BinaryOp::Eq => "e",
+                BinaryOp::Ne => "ne",
+                BinaryOp::Lt if is_address => "b",
+                BinaryOp::Le if is_address => "be",
+                BinaryOp::Gt if is_address => "a",
+                BinaryOp::Ge if is_address => "ae",
+                BinaryOp::Lt => "l",
+                BinaryOp::Le => "le",
+                BinaryOp::Gt => "g",
+                _ => "ge",
+            };
+            emit(
+                code,
+                &format!("cmp{}", suffix(operand_ty)),
+                &[&sized(rhs, operand_ty), &sized(lhs, operand_ty)],
+            );
+            emit(code, &format!("set{}", condition), &["%al"]);
+            emit(code, "movzbl", &["%al", &name(dst, 4)]);
+            return;
+        }
+    };
+
+    let instruction = format!("{}{}", instruction, suffix(ty));
+    let mov = format!("mov{}", suffix(ty));
+    if dst != rhs {
+        if dst != lhs {
+            emit(code, &mov, &[&sized(lhs, ty), &sized(dst, ty)]);
+        }
+        emit(code, &instruction, &[&sized(rhs, ty), &sized(dst, ty)]);
+    } else if op != BinaryOp::Sub {
+        emit(code, &instruction, &[&sized(lhs, ty), &sized(dst, ty)]);
+    } else {
+        emit(code, &mov, &[&sized(lhs, ty), &sized("rax", ty)]);
+        emit(code, &instruction, &[&sized(rhs, ty), &sized("rax", ty)]);
+        emit(code, &mov, &[&sized("rax", ty), &sized(dst, ty)]);
+    }
+}
+
+// Copies size bytes from the address in r10 to the address in r11, eight at a time counted
+// down in rdx and then the remainder
+fn copy_asm(ctx: &mut CodegenContext, code: &mut Vec<Line>, size: usize) {
+    let words = size / 8;
+    if words > 0 {
+        let loop_label = ctx.reserve_labels(1);
+        emit(code, "movq", &[&format!("${}", words), "%rdx"]);
+        label(code, loop_label);
+        emit(code, "movq", &["(%r10)", "%rax"]);
+        emit(code, "movq", &["%rax", "(%r11)"]);
+        emit(code, "addq", &["$8", "%r10"]);
+        emit(code, "addq", &["$8", "%r11"]);
+        emit(code, "subq", &["$1", "%rdx"]);
+        emit(code, "jne", &[&format!(".L{}", loop_label)]);
+    }
+    for offset in 0..size % 8 {
+        emit(code, "movb", &[&format!("{}(%r10)", offset), "%al"]);
+        emit(code, "movb", &["%al", &format!("{}(%r11)", offset)]);
+    }
+}
+
+// Clears size bytes starting at the address in r11, eight at a time and then the remainder
+fn zero_fill_asm(ctx: &mut CodegenContext, code: &mut Vec<Line>, size: usize) {
+    let words = size / 8;
+    if words > 0 {
+        let loop_label = ctx.reserve_labels(1);
+        emit(code, "movq", &[&format!("${}", words), "%rdx"]);
+        label(code, loop_label);
+        emit(code, "movq", &["$0", "(%r11)"]);
+        emit(code, "addq", &["$8", "%r11"]);
+        emit(code, "subq", &["$1", "%rdx"]);
+        emit(code, "jne", &[&format!(".L{}", loop_label)]);
+    }
+    for offset in 0..size % 8 {
+        emit(code, "movb", &["$0", &format!("{}(%r11)", offset)]);
+    }
+}
+
+fn terminator_asm(ctx: &mut CodegenContext, code: &mut Vec<Line>, terminator: &Terminator) {
+    match terminator {
+        Terminator::Jump(block) => {
+            emit(code, "jmp", &[&ctx.block_label(*block)]);
+        }
+
+        Terminator::Branch {
+            condition,
+            then,
+            otherwise,
+        } => {
+            let ty = ctx.ty(*condition);
+            let condition = sized(ctx.read(*condition, 0, code), ty);
+            emit(
+                code,
+                &format!("test{}", suffix(ty)),
+                &[&condition, &condition],
+            );
+            emit(code, "jne", &[&ctx.block_label(*then)]);
+            emit(code, "jmp", &[&ctx.block_label(*otherwise)]);
+        }
+
+        Terminator::Switch {
+            value,
+            cases,
+            default,
+        } => {
+            let value = ctx.read(*value, 0, code);
+            switch_asm(ctx, code, value, cases, *default);
+        }
+
+        Terminator::Return(value) => {
+            let ty = ctx.ty(*value);
+            let value = ctx.read(*value, 0, code);
+            emit(
+                code,
+                &format!("mov{}", suffix(ty)),
+                &[&sized(value, ty), &sized("rax", ty)],
+            );
+            emit(code, "jmp", &[&format!(".L{}", ctx.frame.epilogue_label)]);
+        }
+
+        // The callee returns straight to our caller. Arguments on the stack would have to
+        // replace our own, so those calls return through the epilogue instead
+        Terminator::TailCall { function, args } => {
+            if args.len() > ARGUMENT_REGISTERS.len() {
+                call_asm(ctx, code, function, args);
+                emit(code, "jmp", &[&format!(".L{}", ctx.frame.epilogue_label)]);
+                return;
+            }
+            pass_arguments(ctx, code, args);
+            release_frame(ctx, code);
+            emit(code, "jmp", &[&call_target(ctx, function)]);
+        }
+    }
+}
+
+// Jumps to the block of the case matching the 32 bit value. Dense cases index a table of
+// offsets in .rodata, sparse ones are compared one at a time
+fn switch_asm(
+    ctx: &mut CodegenContext,
+    code: &mut Vec<Line>,
+    value: &str,
+    cases: &[(i64, BlockId)],
+    default: BlockId,
+) {
+    let default_label = ctx.block_label(default);
+
+    if let Some(table_range @ (min, range)) = jump_table_range(cases) {
+        let table_label = ctx.reserve_labels(1);
+        let table = format!(".L{}", table_label);
+
+        // Rebase to 0, anything outside the table is unsigned above its last index.
+        // Writing eax clears the upper half of rax, so it can index the table as is
+        emit(code, "movl", &[&name(value, 4), "%eax"]);
+        emit(code, "subl", &[&format!("${}", min as i32), "%eax"]);
+        emit(code, "cmpl", &[&format!("${}", range - 1), "%eax"]);
+        emit(code, "ja", &[&default_label]);
+
+        // The entries are offsets from the table, so it needs no relocations
+        emit(code, "leaq", &[&format!("{}(%rip)", table), "%rdx"]);
+        emit(code, "movslq", &["(%rdx,%rax,4)", "%rax"]);
+        emit(code, "addq", &["%rdx", "%rax"]);
+        emit(code, "jmp", &["*%rax"]);
+
+        directive(&mut ctx.rodata, ".balign 4");
+        label(&mut ctx.rodata, table_label);
+        for target in ctx.jump_table_targets(cases, table_range, default) {
+            directive(&mut ctx.rodata, &format!(".long {} - {}", target, table));
+        }
+    } else {
+        for (case_value, block) in cases {
+            emit(
+                code,
+                "cmpl",
+                &[&format!("${}", *case_value as i32), &name(value, 4)],
+            );
+            emit(code, "je", &[&ctx.block_label(*block)]);
+        }
+        emit(code, "jmp", &[&default_label]);
+    }
+}
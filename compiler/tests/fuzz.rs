@@ -0,0 +1,126 @@
+use std::process::Command;
+
+// A few generated programs must agree between --run and the simulated code at every level.
+// Failing ones are reduced into the target directory, the output names the files
+#[test]
+fn generated_programs_agree() {
+    let result = Command::new(env!("CARGO_BIN_EXE_compiler"))
+        .args(["--fuzz=10", "--seed=1"])
+        .current_dir(env!("CARGO_TARGET_TMPDIR"))
+        .output()
+        .expect("Failed to start the compiler");
+    assert!(
+        result.status.success(),
+        "--fuzz found mismatches in {}\n{}{}",
+        env!("CARGO_TARGET_TMPDIR"),
+        String::from_utf8_lossy(&result.stdout),
+        String::from_utf8_lossy(&result.stderr)
+    );
+}
+
+// Nesting past the limit of the parser is reported rather than overflowing the stack
+#[test]
+fn deep_nesting_is_an_error() {
+    let depth = 2000;
+    let sources = [
+        format!(
+            "int main() {{ return {}1{}; }}",
+            "(".repeat(depth),
+            ")".repeat(depth)
+        ),
+        format!("int main() {{ return {}1; }}", "-".repeat(depth)),
+        format!(
+            "int main() {{ {}return 1;{} }}",
+            "{".repeat(depth),
+            "}".repeat(depth)
+        ),
+    ];
+    for source in sources {
+        let diagnostics =
+            compiler::parse(compiler::lex(&source)).expect_err("Accepted nesting past the limit");
+        assert_eq!(
+            diagnostics.errors.first().map(String::as_str),
+            Some("Error: Nesting deeper than 256 levels")
+        );
+    }
+}
+
+// The nesting C asks compilers to take is accepted: 127 levels of blocks, here each the body of
+// an if, with 63 levels of parentheses inside, and an else if chain much longer than either
+#[test]
+fn nesting_within_the_limit_is_accepted() {
+    let arms: Vec<String> = (0..1000)
+        .map(|arm| format!("if (x == {}) {{ y = {}; }}", arm, arm % 100))
+        .collect();
+    let sources = [
+        (
+            format!(
+                "int main() {{ int x; x = 0; {}x = {}7{};{} return x; }}",
+                "if (1) {".repeat(127),
+                "(".repeat(63),
+                ")".repeat(63),
+                "}".repeat(127)
+            ),
+            7,
+        ),
+        (
+            format!(
+                "int main() {{ int x; int y; x = 999; y = 0; {} else {{ y = 1; }} return y; }}",
+                arms.join(" else ")
+            ),
+            99,
+        ),
+    ];
+    for (source, exit_code) in sources {
+        match compiler::run(&source, &compiler::Options::default()) {
+            Ok(output) => assert_eq!(output.value, exit_code),
+            Err(diagnostics) => panic!("Rejected nesting within the limit: {:?}", diagnostics),
+        }
+    }
+}
+
+// run interprets the calls of the program with calls of its own, on a stack of the library's
+// rather than the small one of a test thread. Past its call limit it reports an error
+#[test]
+fn deep_recursion_runs() {
+    let program = |depth: usize| {
+        format!(
+            "int depth(int n) {{ if (n == 0) return 0; return 1 + depth(n - 1); }}\n\
+             int main() {{ return depth({}) - {} + 7; }}",
+            depth, depth
+        )
+    };
+    let options = compiler::Options::default();
+
+    let output = compiler::run(&program(1000), &options).expect("Failed to run 1000 calls deep");
+    assert_eq!(output.value, 7);
+
+    let diagnostics =
+        compiler::run(&program(100000), &options).expect_err("Ran past the call limit");
+    assert!(
+        diagnostics.errors[0].starts_with("Error: Call depth exceeded"),
+        "{:?}",
+        diagnostics.errors
+    );
+}
+
+// Tools can match the tokens and walk the syntax tree through the types the crate exports
+#[test]
+fn syntax_tree_is_public() {
+    use compiler::{Expr, ExprNode, Statement, TokenType, Type};
+
+    let tokens = compiler::lex("char first(char *s) { return *s; }");
+    assert_eq!(tokens[0].token_type, TokenType::CharKeyword);
+
+    let program = compiler::parse(tokens).expect("Failed to parse");
+    let function = &program.body[0];
+    assert_eq!(function.return_type, Type::Char);
+    assert_eq!(function.params[0].ty, Type::pointer_to(Type::Char));
+    assert!(matches!(
+        &function.body[0].statement,
+        Statement::Return(Some(ExprNode {
+            expr: Expr::UnaryOp(TokenType::Mul, _),
+            ty: Type::Char,
+        }))
+    ));
+}
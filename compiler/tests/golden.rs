@@ -0,0 +1,369 @@
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+
+// Golden file tests over the C files in tests/lexer_tests and tests/parser_tests. Each file goes
+// through the compiler binary and its token dump, AST dump and assembly for every target are
+// compared with the checked in name.tokens.expected, name.ast.expected, name.s.expected etc next
+// to it. Running
+//
+//     BLESS=1 cargo test
+//
+// writes the current output to the .expected files instead, review the diff before committing.
+// Programs with a // EXPECT-EXIT: n line are also run at -O0 and -O2 and must exit with n: by
+// --run on the syntax tree, by --simulate on the AArch64 code, and where the tools are
+// installed the x86-64 code built by gcc, the --emit=c output built by gcc -fwrapv, the
+// --emit=llvm output run by lli, the RV64 code built by riscv64-linux-gnu-gcc -static and run
+// by qemu-riscv64 and the wasm32 code run by wasmtime
+
+// What is compared, the option giving it and the suffix of its expected file
+const TOKENS: (&str, &str) = ("--emit=tokens", "tokens");
+const AST: (&str, &str) = ("--emit=ast", "ast");
+const ASSEMBLY: (&str, &str) = ("--emit=asm", "s");
+const RISCV64: (&str, &str) = ("--target=riscv64-linux", "riscv64.s");
+const WASM32: (&str, &str) = ("--target=wasm32", "wat");
+
+const EXIT_ANNOTATION: &str = "// EXPECT-EXIT:";
+
+fn fixtures(directory: &str) -> Vec<PathBuf> {
+    let directory = Path::new(env!("CARGO_MANIFEST_DIR"))
+        .join("tests")
+        .join(directory);
+    let mut files: Vec<PathBuf> = fs::read_dir(&directory)
+        .unwrap_or_else(|error| panic!("Cannot read {}: {}", directory.display(), error))
+        .map(|entry| entry.expect("Failed to read directory entry").path())
+        .filter(|path| path.extension().is_some_and(|extension| extension == "c"))
+        .collect();
+    files.sort();
+    files
+}
+
+fn name(file: &Path) -> String {
+    file.file_stem()
+        .expect("Fixture without a name")
+        .to_string_lossy()
+        .to_string()
+}
+
+// A file in the target directory for what is made from the fixture, e.g parser_tests_calls.s
+fn scratch_path(file: &Path, suffix: &str) -> PathBuf {
+    let directory = file.parent().expect("Fixture outside a directory");
+    Path::new(env!("CARGO_TARGET_TMPDIR")).join(format!(
+        "{}_{}.{}",
+        directory.file_name().unwrap_or_default().to_string_lossy(),
+        name(file),
+        suffix
+    ))
+}
+
+// Compiles the file with the options into the target directory and gives back the path
+fn compile_to(file: &Path, options: &[&str], suffix: &str) -> Result<PathBuf, String> {
+    let output = scratch_path(file, suffix);
+    let result = Command::new(env!("CARGO_BIN_EXE_compiler"))
+        .args(options)
+        .arg("-o")
+        .arg(&output)
+        .arg(file)
+        .output()
+        .expect("Failed to start the compiler");
+    if !result.status.success() {
+        return Err(format!(
+            "compiler {} failed with {}\n{}{}",
+            options.join(" "),
+            result.status,
+            String::from_utf8_lossy(&result.stdout),
+            String::from_utf8_lossy(&result.stderr)
+        ));
+    }
+    Ok(output)
+}
+
+// Compiles the file with the option and gives back what was written to the output file
+fn compile(file: &Path, option: &str, suffix: &str) -> Result<String, String> {
+    let output = compile_to(file, &[option], suffix)?;
+    fs::read_to_string(&output).map_err(|error| format!("No output from {}: {}", option, error))
+}
+
+// Compares the output with the expected file, or replaces the file when blessing
+fn check(file: &Path, (option, suffix): (&str, &str), failures: &mut Vec<String>) {
+    let expected_path = file.with_extension(format!("{}.expected", suffix));
+    let actual = match compile(file, option, suffix) {
+        Ok(actual) => actual,
+        Err(error) => {
+            failures.push(format!("{}: {}", file.display(), error));
+            return;
+        }
+    };
+
+    if std::env::var("BLESS").is_ok_and(|bless| bless == "1") {
+        fs::write(&expected_path, actual).expect("Failed to write expected file");
+        return;
+    }
+
+    let expected = match fs::read_to_string(&expected_path) {
+        Ok(expected) => expected,
+        Err(_) => {
+            failures.push(format!(
+                "{} is missing, run BLESS=1 cargo test to create it",
+                expected_path.display()
+            ));
+            return;
+        }
+    };
+    if actual == expected {
+        return;
+    }
+
+    // The first line that differs, with its number
+    let (number, expected_line, actual_line) = expected
+        .lines()
+        .map(Some)
+        .chain(std::iter::repeat(None))
+        .zip(actual.lines().map(Some).chain(std::iter::repeat(None)))
+        .enumerate()
+        .find(|(_, (expected, actual))| expected != actual)
+        .map(|(number, (expected, actual))| (number + 1, expected, actual))
+        .unwrap_or((0, None, None));
+    failures.push(format!(
+        "{} differs at line {}\n  expected: {}\n    actual: {}",
+        expected_path.display(),
+        number,
+        expected_line.unwrap_or("<end of file>"),
+        actual_line.unwrap_or("<end of file>")
+    ));
+}
+
+fn expected_exit(file: &Path) -> Option<i32> {
+    let source = fs::read_to_string(file).expect("Failed to read fixture");
+    let value = source
+        .lines()
+        .find_map(|line| line.trim().strip_prefix(EXIT_ANNOTATION))?;
+    Some(
+        value.trim().parse().unwrap_or_else(|_| {
+            panic!("{}: Invalid {} {}", file.display(), EXIT_ANNOTATION, value)
+        }),
+    )
+}
+
+fn report(failures: Vec<String>) {
+    if !failures.is_empty() {
+        panic!("{} failures\n\n{}", failures.len(), failures.join("\n\n"));
+    }
+}
+
+#[test]
+fn lexer_tests_match_expected_tokens() {
+    let mut failures = vec![];
+    for file in fixtures("lexer_tests") {
+        check(&file, TOKENS, &mut failures);
+    }
+    report(failures);
+}
+
+#[test]
+fn parser_tests_match_expected_output() {
+    let mut failures = vec![];
+    for file in fixtures("parser_tests") {
+        for kind in [TOKENS, AST, ASSEMBLY, RISCV64, WASM32] {
+            check(&file, kind, &mut failures);
+        }
+    }
+    report(failures);
+}
+
+// Whether the tool runs at all, the tests needing one it does not find are skipped
+fn installed(tool: &str, args: &[&str]) -> bool {
+    Command::new(tool)
+        .args(args)
+        .output()
+        .is_ok_and(|result| result.status.success())
+}
+
+// The exit code of the command, or what went wrong running it
+fn exit_code(command: &mut Command) -> Result<i32, String> {
+    let result = command
+        .output()
+        .map_err(|error| format!("Cannot start {:?}: {}", command, error))?;
+    result.status.code().ok_or(format!(
+        "{:?} was killed by a signal\n{}",
+        command,
+        String::from_utf8_lossy(&result.stderr)
+    ))
+}
+
+// Builds the compiler output with gcc, or a cross gcc, and gives back the executable
+fn build(source: &Path, gcc: &str, gcc_options: &[&str]) -> Result<PathBuf, String> {
+    let executable = source.with_extension("out");
+    let result = Command::new(gcc)
+        .args(gcc_options)
+        .arg("-o")
+        .arg(&executable)
+        .arg(source)
+        .output()
+        .map_err(|error| format!("Cannot start {}: {}", gcc, error))?;
+    if !result.status.success() {
+        return Err(format!(
+            "{} failed on {}\n{}",
+            gcc,
+            source.display(),
+            String::from_utf8_lossy(&result.stderr)
+        ));
+    }
+    Ok(executable)
+}
+
+// How the program is run: by the compiler itself, or compiled and handed to an outside tool
+enum Runner {
+    Compiler(&'static str),                     // --run or --simulate
+    Gcc(&'static str, &'static [&'static str]), // Compiler option and gcc options
+    Lli(&'static [&'static str]),               // lli options
+    Qemu,     // RV64 code built by riscv64-linux-gnu-gcc -static and run by qemu-riscv64
+    Wasmtime, // wasm32 code run by wasmtime --invoke main, which prints what main returns
+}
+
+fn run(file: &Path, runner: &Runner, level: &str) -> Result<i32, String> {
+    match runner {
+        Runner::Compiler(option) => exit_code(
+            Command::new(env!("CARGO_BIN_EXE_compiler"))
+                .args([option, level])
+                .arg(file),
+        ),
+        Runner::Gcc(option, gcc_options) => {
+            let extension = if *option == "--emit=c" { "c" } else { "s" };
+            let suffix = format!("{}.{}", level.trim_start_matches('-'), extension);
+            let source = compile_to(file, &[option, level], &suffix)?;
+            exit_code(&mut Command::new(build(&source, "gcc", gcc_options)?))
+        }
+        Runner::Qemu => {
+            let source = compile_to(
+                file,
+                &["--target=riscv64-linux", level],
+                &format!("{}.riscv64.s", level.trim_start_matches('-')),
+            )?;
+            let executable = build(&source, "riscv64-linux-gnu-gcc", &["-static"])?;
+            exit_code(Command::new("qemu-riscv64").arg(executable))
+        }
+        Runner::Wasmtime => {
+            let module = compile_to(
+                file,
+                &["--target=wasm32", level],
+                &format!("{}.wat", level.trim_start_matches('-')),
+            )?;
+            let result = Command::new("wasmtime")
+                .args(["--invoke", "main"])
+                .arg(&module)
+                .output()
+                .map_err(|error| format!("Cannot start wasmtime: {}", error))?;
+            let printed = String::from_utf8_lossy(&result.stdout);
+            match printed.trim().parse::<i64>() {
+                // Cut down to the low byte like the exit code of a process
+                Ok(value) if result.status.success() => Ok((value & 0xFF) as i32),
+                _ => Err(format!(
+                    "wasmtime failed on {}\n{}{}",
+                    module.display(),
+                    printed,
+                    String::from_utf8_lossy(&result.stderr)
+                )),
+            }
+        }
+        Runner::Lli(lli_options) => {
+            let source = compile_to(
+                file,
+                &["--emit=llvm", level],
+                &format!("{}.ll", level.trim_start_matches('-')),
+            )?;
+            exit_code(Command::new("lli").args(*lli_options).arg(source))
+        }
+    }
+}
+
+// The ways of running the programs this machine has the tools for. LLVM before 15 only reads
+// opaque pointers with -opaque-pointers, later versions no longer know the option
+fn runners() -> Vec<(String, Runner)> {
+    let mut runners = vec![
+        ("--run".to_string(), Runner::Compiler("--run")),
+        ("--simulate".to_string(), Runner::Compiler("--simulate")),
+    ];
+
+    if installed("gcc", &["--version"]) {
+        if cfg!(all(target_arch = "x86_64", target_os = "linux")) {
+            runners.push((
+                "gcc --target=x86_64-linux".to_string(),
+                Runner::Gcc("--target=x86_64-linux", &[]),
+            ));
+        }
+        runners.push((
+            "gcc -fwrapv --emit=c".to_string(),
+            Runner::Gcc("--emit=c", &["-fwrapv", "-w"]),
+        ));
+    } else {
+        eprintln!("gcc not found, skipping the x86-64 and --emit=c runs");
+    }
+
+    if installed("lli", &["-opaque-pointers", "--version"]) {
+        runners.push((
+            "lli --emit=llvm".to_string(),
+            Runner::Lli(&["-opaque-pointers"]),
+        ));
+    } else if installed("lli", &["--version"]) {
+        runners.push(("lli --emit=llvm".to_string(), Runner::Lli(&[])));
+    } else {
+        eprintln!("lli not found, skipping the --emit=llvm runs");
+    }
+
+    if installed("riscv64-linux-gnu-gcc", &["--version"])
+        && installed("qemu-riscv64", &["--version"])
+    {
+        runners.push((
+            "qemu-riscv64 --target=riscv64-linux".to_string(),
+            Runner::Qemu,
+        ));
+    } else {
+        eprintln!("riscv64-linux-gnu-gcc or qemu-riscv64 not found, skipping the RV64 runs");
+    }
+
+    if installed("wasmtime", &["--version"]) {
+        runners.push(("wasmtime --target=wasm32".to_string(), Runner::Wasmtime));
+    } else {
+        eprintln!("wasmtime not found, skipping the wasm32 runs");
+    }
+    runners
+}
+
+#[test]
+fn annotated_programs_exit_with_expected_code() {
+    let mut failures = vec![];
+    let runners = runners();
+    let files = fixtures("lexer_tests")
+        .into_iter()
+        .chain(fixtures("parser_tests"));
+
+    for file in files {
+        let Some(expected) = expected_exit(&file) else {
+            continue;
+        };
+        for (description, runner) in &runners {
+            for level in ["-O0", "-O2"] {
+                match run(&file, runner, level) {
+                    Ok(code) if code == expected => {}
+                    Ok(code) => failures.push(format!(
+                        "{} {} {}: expected exit code {}, got {}",
+                        file.display(),
+                        description,
+                        level,
+                        expected,
+                        code
+                    )),
+                    Err(error) => failures.push(format!(
+                        "{} {} {}: {}",
+                        file.display(),
+                        description,
+                        level,
+                        error
+                    )),
+                }
+            }
+        }
+    }
+    report(failures);
+}
@@ -0,0 +1,8 @@
+// A comparator or a quote at the end of a line used to read past it
+int main() {
+    int a = 1 <
+        2;
+    char c = '
+x';
+    return a >
+}
@@ -0,0 +1,13 @@
+// EXPECT-EXIT: 53
+int main()
+{
+    int grid[2][3] = {{1, 2, 3}, {4}};
+    int primes[] = {2, 3, 5, 7};
+    char letters[5] = {'a', 'b'};
+    int *p = primes;
+    int total = 0;
+    grid[1][2] = sizeof(grid) + sizeof primes[0] + sizeof(char[5]);
+    total = grid[0][2] + grid[1][0] + grid[1][1] + grid[1][2];
+    total = total + *(p + 3) + p[1] + letters[2] + (&primes[3] - p);
+    return total;
+}
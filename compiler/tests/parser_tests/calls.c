@@ -0,0 +1,33 @@
+// EXPECT-EXIT: 243
+int twice(int x);
+
+static int add(int a, int b) {
+    return a + b;
+}
+
+int fact(int n) {
+    return n <= 1 ? 1 : n * fact(n - 1);
+}
+
+int sum_to(int n, int acc) {
+    return n == 0 ? acc : sum_to(n - 1, acc + n);
+}
+
+int first(char *s, char c) {
+    *s = c;
+    return s[1];
+}
+
+int main(void) {
+    int x;
+    int y;
+    char buf[3];
+    x = add(2, 3);
+    y = twice(x) + add(x, twice(1));
+    buf[1] = 7;
+    return fact(5) + y + sum_to(10, 0) + first(buf, 300) + buf[0];
+}
+
+int twice(int x) {
+    return add(x, x);
+}
@@ -0,0 +1,32 @@
+// EXPECT-EXIT: 202
+struct pair {
+    int first;
+    int second;
+};
+
+int values[3] = {4, 5, 6};
+int *middle = (int *) &values[1];
+char wrapped = (char) 300;
+
+int main() {
+    struct pair a = {1, 2};
+    struct pair b = {30, 40};
+    struct pair chosen;
+    int count = 0;
+    int limit = sizeof(char) ? 2 : 3;
+    int *none = 0;
+    char small;
+
+    chosen = count ? a : b;
+    count = (count = count + 1, count + 1);
+    none = count > 1 ? middle : 0;
+    small = (char) (255 + limit);
+    count = count == 2 ? (limit == 2 ? 7 : 8) : 9;
+
+    switch (limit > 1 ? 3 : 4) {
+    case 1 ? 3 : 4:
+        count = count + 100;
+    }
+
+    return chosen.second + count + *none + small + wrapped + (int) (char) -1 + (values[0], values[2]);
+}
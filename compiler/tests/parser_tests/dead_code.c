@@ -0,0 +1,25 @@
+// EXPECT-EXIT: 8
+int main() {
+    int a;
+    int b;
+    a = 3;
+    b = 4;
+    1 + 3 - b < a;
+    switch (a) {
+        case 1:
+            return 10;
+            a = 5;
+        case 3:
+            b = b + 1;
+            break;
+            b = 100;
+            a = 7;
+        default:
+            return 1;
+    }
+    {
+        return a + b;
+    }
+    a = 9;
+    return a;
+}
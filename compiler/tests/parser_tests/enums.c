@@ -0,0 +1,20 @@
+// EXPECT-EXIT: 60
+enum state { IDLE, RUNNING = 5, STOPPED, LAST = STOPPED * 2 };
+
+struct machine {
+    enum state current;
+    enum { SLOW, FAST } speed;
+    int history[LAST];
+};
+
+int main()
+{
+    struct machine m;
+    enum state next = STOPPED;
+    enum colour { RED = -1, GREEN, BLUE };
+    int counts[BLUE + 1] = {1, 2};
+    m.current = RUNNING;
+    m.speed = FAST;
+    m.history[LAST - 1] = next;
+    return sizeof(m.history) + m.current + m.speed + m.history[11] + counts[GREEN] + RED;
+}
@@ -0,0 +1,12 @@
+// EXPECT-EXIT: 75
+int main() {
+    int x;
+    int y;
+    int *p;
+    x = 5;
+    p = &x;
+    y = 0 * (x = 9);
+    y = 2147483647 + 1;
+    y = y - (-2147483647 - 1) + (char) 300 + x * 1 + (x - 0) + *(p + 0);
+    return y + (0 && x) + (3 || x) + ~0 + !7 + 1 + 2 * 3 - 10 / 3;
+}
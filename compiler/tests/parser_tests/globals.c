@@ -0,0 +1,27 @@
+// EXPECT-EXIT: 51
+struct point {
+    int x;
+    int y;
+};
+
+int counter;
+static int table[4] = {1, 2, 3, 4};
+const int limit = 7;
+int *cursor = &table[2];
+struct point origin = {3, 4};
+int *const y_of_origin = &origin.y;
+char letter = 'a';
+int grid[2][3];
+int counter = 2;
+static int table[4];
+
+int main() {
+    static int calls = 5;
+    const char offset = 'b' - letter;
+
+    counter = limit + offset;
+    calls = calls + 1;
+    grid[1][2] = *cursor + table[3];
+    *y_of_origin = origin.x * 10;
+    return counter + calls + grid[1][2] + origin.y;
+}
@@ -0,0 +1,24 @@
+// EXPECT-EXIT: 3
+// The frame of spill is wider than a 32 bit displacement. It is never called, the code for it
+// only has to assemble
+int spill(int n) {
+    int big[600000000];
+    int a = n + 1;
+    int b = a * n;
+    int c = b - a;
+    int d = c * b;
+    int e = d + c;
+    int f = e * d;
+    int g = f - e;
+    int h = g * f;
+    int i = h + g;
+    int j = i * h;
+    int k = j - i;
+    int l = k * j;
+    big[n] = a + b + c + d + e + f + g + h + i + j + k + l;
+    return big[n] + a + b + c + d + e + f + g + h + i + j + k + l;
+}
+
+int main() {
+    return 3;
+}
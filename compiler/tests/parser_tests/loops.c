@@ -0,0 +1,48 @@
+// EXPECT-EXIT: 249
+int collatz(int n) {
+    int steps;
+    steps = 0;
+    while (n != 1) {
+        if (n - n / 2 * 2 == 0)
+            n = n / 2;
+        else
+            n = 3 * n + 1;
+        steps = steps + 1;
+    }
+    return steps;
+}
+
+int main(void) {
+    int total;
+    int i;
+    int a[10];
+    char *p;
+    total = 0;
+    for (i = 0; i < 10; i = i + 1) {
+        a[i] = i * i;
+    }
+    for (int j = 0; j < 10; j = j + 1) {
+        if (j == 3)
+            continue;
+        if (j == 8)
+            break;
+        total = total + a[j];
+    }
+    p = 0;
+    if (p)
+        total = 1000;
+    i = 0;
+    for (;;) {
+        i = i + 1;
+        if (i > 5) break;
+    }
+    switch (i) {
+    case 6:
+        while (1) {
+            total = total + 1;
+            break;
+        }
+        break;
+    }
+    return total + collatz(27) + i;
+}
@@ -1,3 +1,4 @@
+// EXPECT-EXIT: 0
 
 int main()
 {
@@ -0,0 +1,63 @@
+// EXPECT-EXIT: 67
+struct pair {
+    int first;
+    int second;
+};
+
+char narrow(int value);
+
+int total;
+
+char narrow(int value) {
+    return value;
+}
+
+int *largest(int *values, int count) {
+    int *best;
+    int index;
+    best = values;
+    for (index = 1; index < count; index = index + 1) {
+        if (values[index] > *best) {
+            best = &values[index];
+        }
+    }
+    return best;
+}
+
+struct pair *second_of(struct pair *pairs) {
+    return pairs + 1;
+}
+
+int *skip(int *values, int count) {
+    if (count == 0) {
+        return values;
+    }
+    return skip(values + 1, count - 1);
+}
+
+static void add(int amount) {
+    if (amount < 0) {
+        return;
+    }
+    total = total + amount;
+}
+
+void clear(void) {
+    total = 0;
+}
+
+int main(void) {
+    int values[4];
+    struct pair pairs[2];
+    values[0] = 3;
+    values[1] = 9;
+    values[2] = 4;
+    values[3] = 7;
+    pairs[1].second = 11;
+    clear();
+    add(5);
+    add(-2);
+    *largest(values, 4) = 1;
+    return narrow(300) + narrow(255) + values[1] + *skip(values, 3) + second_of(pairs)->second
+        + total;
+}
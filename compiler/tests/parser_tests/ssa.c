@@ -0,0 +1,93 @@
+// EXPECT-EXIT: 234
+int g;
+
+static int scale(int x, int k) {
+    return x * k + g;
+}
+
+int nested(int n) {
+    int i;
+    int j;
+    int s;
+    char c;
+    s = 0;
+    c = 0;
+    for (i = 0; i < n; i = i + 1) {
+        for (j = 0; j < n; j = j + 1) {
+            int inv;
+            inv = n * 3 + 7;
+            s = s + inv + i * j;
+            c = c + 100;
+            if (s > 100000)
+                s = s - 100000;
+        }
+    }
+    return s + c;
+}
+
+int swap_loop(int n) {
+    int a;
+    int b;
+    int t;
+    a = 1;
+    b = 2;
+    while (n > 0) {
+        t = a;
+        a = b;
+        b = t;
+        n = n - 1;
+    }
+    return a * 10 + b;
+}
+
+int uninit(int n) {
+    int x;
+    if (n > 5)
+        x = 3;
+    return n > 5 ? x : 0;
+}
+
+int ptrs(void) {
+    int arr[5];
+    int *p;
+    int k;
+    int sum;
+    for (k = 0; k < 5; k = k + 1)
+        arr[k] = k + 1;
+    p = arr;
+    sum = 0;
+    while (p != arr + 5) {
+        sum = sum + *p;
+        p = p + 1;
+    }
+    return sum;
+}
+
+int sw(int n) {
+    int r;
+    int i;
+    r = 0;
+    for (i = 0; i < n; i = i + 1) {
+        switch (i - i / 3 * 3) {
+        case 0:
+            r = r + 1;
+            break;
+        case 1:
+            r = r + 10;
+            continue;
+        default:
+            r = r + 100;
+        }
+        r = r + 1000;
+    }
+    return r;
+}
+
+int main(void) {
+    int a;
+    int b;
+    g = 4;
+    a = nested(13) + swap_loop(7) + uninit(9) + uninit(2) + ptrs() + sw(10);
+    b = scale(a, 2) + scale(3, 3);
+    return a + b;
+}
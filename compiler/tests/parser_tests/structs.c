@@ -0,0 +1,26 @@
+// EXPECT-EXIT: 218
+struct node {
+    int value;
+    struct node *next;
+};
+
+union number {
+    char small;
+    int big;
+};
+
+int main()
+{
+    struct point { char tag; int x; int y; } origin = {'o', 3, 4};
+    struct point copy;
+    struct { struct point corners[2]; char name[3]; } box = {{{'a', 1, 2}, {'b', 5, 6}}, {'b', 'x'}};
+    struct node second = {20};
+    struct node first = {10, &second};
+    struct node *head = &first;
+    union number n;
+    n.big = 258;
+    copy = origin;
+    copy.x = copy.x + 10;
+    return sizeof(struct point) + sizeof box + sizeof(union number) + copy.x + origin.x + box.corners[1].y
+        + head->next->value + head->value + n.small + box.name[1];
+}
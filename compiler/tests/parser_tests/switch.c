@@ -0,0 +1,48 @@
+// EXPECT-EXIT: 100
+enum color { RED, GREEN, BLUE, ALPHA };
+
+int main() {
+    int total = 0;
+    int weight = 3;
+    enum color channel = BLUE;
+
+    switch (channel) {
+    case RED:
+        total = 1;
+        break;
+    case GREEN:
+        total = 2;
+    case BLUE:
+        total = total + 10;
+    case ALPHA:
+        total = total + 100;
+        break;
+    default:
+        total = 1000;
+    }
+
+    switch (weight * 100) {
+    case -5:
+        total = total + 1;
+        break;
+    case 300: {
+        int bonus = 20;
+        total = total + bonus;
+        break;
+    }
+    case 70000:
+        total = total + 3;
+    }
+
+    switch (total + 40) {
+    case 0:
+    case 1:
+    case 2:
+    case 3:
+        return 0;
+    default:
+        total = total - 30;
+    }
+
+    return total;
+}